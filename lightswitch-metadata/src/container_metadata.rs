@@ -0,0 +1,314 @@
+#[cfg(feature = "cri")]
+use crate::cri::{CriClient, KubernetesContainer};
+use crate::types::{MetadataLabel, TaskKey, TaskMetadataProvider, TaskMetadataProviderError};
+
+#[cfg(feature = "cri")]
+use lru::LruCache;
+use std::fs;
+#[cfg(feature = "cri")]
+use std::num::NonZeroUsize;
+#[cfg(feature = "cri")]
+use std::sync::Mutex;
+#[cfg(feature = "cri")]
+use std::time::{Duration, Instant};
+
+/// How many containers the Kubernetes metadata is kept in memory for.
+#[cfg(feature = "cri")]
+const KUBERNETES_CONTAINERS_CACHE_SIZE: usize = 1000;
+/// How long a failed lookup of a container's Kubernetes metadata is remembered for, so
+/// the container runtime isn't queried for every task while it's unavailable.
+#[cfg(feature = "cri")]
+const KUBERNETES_LOOKUP_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Container a task runs in, as found in its cgroup path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CgroupContainer {
+    pub id: String,
+    /// Container runtime, if it can be told from the cgroup path.
+    pub runtime: Option<&'static str>,
+    pub pod_uid: Option<String>,
+}
+
+fn is_container_id(id: &str) -> bool {
+    id.len() == 64 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Parses the container id of a segment of a cgroup path, for the cgroupfs and systemd
+/// cgroup drivers of docker, containerd and CRI-O, with cgroup v1 or v2.
+fn parse_container_segment(segment: &str) -> Option<(String, Option<&'static str>)> {
+    let segment = segment.strip_suffix(".scope").unwrap_or(segment);
+    for (prefix, runtime) in [
+        ("docker-", "docker"),
+        ("cri-containerd-", "containerd"),
+        ("crio-", "cri-o"),
+    ] {
+        if let Some(id) = segment.strip_prefix(prefix) {
+            return is_container_id(id).then(|| (id.to_string(), Some(runtime)));
+        }
+    }
+    // With the cgroupfs driver containers are named after their id, such as in
+    // `/docker/<id>` or `/kubepods/burstable/pod<uid>/<id>`.
+    is_container_id(segment).then(|| (segment.to_string(), None))
+}
+
+/// Parses the pod uid of a segment of a cgroup path, such as `pod<uid>` with the cgroupfs
+/// driver or `kubepods-burstable-pod<uid>.slice` with the systemd one, where dashes in
+/// the uid are replaced with underscores.
+fn parse_pod_segment(segment: &str) -> Option<String> {
+    let segment = segment.strip_suffix(".slice").unwrap_or(segment);
+    let (_, uid) = segment.rsplit_once("pod")?;
+    let uid = uid.replace('_', "-");
+    (uid.len() == 36 && uid.chars().all(|c| c.is_ascii_hexdigit() || c == '-')).then_some(uid)
+}
+
+/// Returns the container of a task from the contents of `/proc/<pid>/cgroup`, if any.
+pub fn container_from_cgroup(cgroup: &str) -> Option<CgroupContainer> {
+    for line in cgroup.lines() {
+        // Lines are `hierarchy-id:controllers:path`, cgroup v2 only has `0::path`.
+        let Some(path) = line.splitn(3, ':').nth(2) else {
+            continue;
+        };
+
+        let mut pod_uid = None;
+        for segment in path.split('/') {
+            if let Some(uid) = parse_pod_segment(segment) {
+                pod_uid = Some(uid);
+            }
+            if let Some((id, runtime)) = parse_container_segment(segment) {
+                // With the cgroupfs driver docker puts containers in `/docker`.
+                let runtime = runtime.or(path.starts_with("/docker/").then_some("docker"));
+                return Some(CgroupContainer {
+                    id,
+                    runtime,
+                    pod_uid,
+                });
+            }
+        }
+    }
+    None
+}
+
+#[cfg(feature = "cri")]
+#[derive(Debug, Clone)]
+enum KubernetesLookup {
+    Done(Option<KubernetesContainer>),
+    /// The lookup failed at this time, with this error.
+    Failed(Instant, String),
+}
+
+/// Kubernetes metadata of containers, looked up with the CRI of the container runtime
+/// and cached, as containers don't move between pods.
+#[cfg(feature = "cri")]
+struct KubernetesContainers {
+    cri_client: CriClient,
+    cache: Mutex<LruCache<String, KubernetesLookup>>,
+}
+
+#[cfg(feature = "cri")]
+impl KubernetesContainers {
+    fn new(cri_client: CriClient) -> Self {
+        Self {
+            cri_client,
+            cache: Mutex::new(LruCache::new(
+                NonZeroUsize::new(KUBERNETES_CONTAINERS_CACHE_SIZE).unwrap(),
+            )),
+        }
+    }
+
+    /// Returns the Kubernetes metadata of a container. Failed lookups are only retried
+    /// once [`KUBERNETES_LOOKUP_RETRY_INTERVAL`] has passed.
+    fn get(&self, container_id: &str, now: Instant) -> Result<Option<KubernetesContainer>, String> {
+        let lookup = match self.cache.lock().unwrap().get(container_id) {
+            Some(KubernetesLookup::Failed(failed_at, _))
+                if now.saturating_duration_since(*failed_at)
+                    >= KUBERNETES_LOOKUP_RETRY_INTERVAL =>
+            {
+                None
+            }
+            lookup => lookup.cloned(),
+        };
+        let lookup = lookup.unwrap_or_else(|| {
+            let lookup = match self.cri_client.kubernetes_container(container_id) {
+                Ok(container) => KubernetesLookup::Done(container),
+                Err(status) => KubernetesLookup::Failed(now, status.to_string()),
+            };
+            self.cache
+                .lock()
+                .unwrap()
+                .put(container_id.to_string(), lookup.clone());
+            lookup
+        });
+
+        match lookup {
+            KubernetesLookup::Done(container) => Ok(container),
+            KubernetesLookup::Failed(_, error) => Err(error),
+        }
+    }
+}
+
+/// Labels the tasks running in containers with their container id and, for Kubernetes
+/// pods, with the pod name, namespace and container name, which are looked up with the
+/// CRI of the container runtime if a client is given.
+#[derive(Default)]
+pub struct ContainerMetadata {
+    #[cfg(feature = "cri")]
+    kubernetes_containers: Option<KubernetesContainers>,
+}
+
+impl ContainerMetadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Looks up the Kubernetes pods of the containers with this client.
+    #[cfg(feature = "cri")]
+    pub fn with_cri_client(mut self, cri_client: CriClient) -> Self {
+        self.kubernetes_containers = Some(KubernetesContainers::new(cri_client));
+        self
+    }
+}
+
+impl TaskMetadataProvider for ContainerMetadata {
+    fn get_metadata(
+        &self,
+        task_key: TaskKey,
+    ) -> Result<Vec<MetadataLabel>, TaskMetadataProviderError> {
+        let cgroup = fs::read_to_string(format!("/proc/{}/cgroup", task_key.pid)).map_err(|e| {
+            TaskMetadataProviderError::ErrorRetrievingMetadata(task_key, e.to_string())
+        })?;
+
+        let Some(container) = container_from_cgroup(&cgroup) else {
+            return Ok(Vec::new());
+        };
+
+        let mut labels = vec![MetadataLabel::from_string_value(
+            "container.id".into(),
+            container.id.clone(),
+        )];
+        if let Some(runtime) = container.runtime {
+            labels.push(MetadataLabel::from_string_value(
+                "container.runtime".into(),
+                runtime.into(),
+            ));
+        }
+        if let Some(pod_uid) = container.pod_uid {
+            labels.push(MetadataLabel::from_string_value(
+                "k8s.pod.uid".into(),
+                pod_uid,
+            ));
+        }
+        #[cfg(feature = "cri")]
+        if let Some(kubernetes_containers) = &self.kubernetes_containers {
+            // The labels from the cgroup are still used if the lookup fails, but they
+            // aren't cached without the pod, so it's retried.
+            let kubernetes_container =
+                match kubernetes_containers.get(&container.id, Instant::now()) {
+                    Ok(kubernetes_container) => kubernetes_container,
+                    Err(e) => {
+                        return Err(TaskMetadataProviderError::PartialMetadata(
+                            task_key, e, labels,
+                        ))
+                    }
+                };
+            if let Some(kubernetes_container) = kubernetes_container {
+                labels.extend([
+                    MetadataLabel::from_string_value(
+                        "k8s.pod.name".into(),
+                        kubernetes_container.pod_name,
+                    ),
+                    MetadataLabel::from_string_value(
+                        "k8s.namespace.name".into(),
+                        kubernetes_container.namespace,
+                    ),
+                    MetadataLabel::from_string_value(
+                        "k8s.container.name".into(),
+                        kubernetes_container.container_name,
+                    ),
+                ]);
+            }
+        }
+        Ok(labels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "3f1b8e0c5d2a4f6e9b7c1d0e2f3a4b5c6d7e8f9a0b1c2d3e4f5a6b7c8d9e0f1a";
+    const POD_UID: &str = "6f1c2a3b-4d5e-4f60-8a9b-0c1d2e3f4a5b";
+
+    #[test]
+    fn test_container_from_cgroup() {
+        let pod_uid = Some(POD_UID.to_string());
+        let systemd_pod_uid = POD_UID.replace('-', "_");
+
+        for (cgroup, runtime, pod_uid) in [
+            // docker, cgroupfs and systemd drivers.
+            (format!("12:pids:/docker/{ID}\n1:cpu:/docker/{ID}"), Some("docker"), None),
+            (format!("0::/system.slice/docker-{ID}.scope"), Some("docker"), None),
+            // containerd.
+            (
+                format!("0::/kubepods/burstable/pod{POD_UID}/{ID}"),
+                None,
+                pod_uid.clone(),
+            ),
+            (
+                format!(
+                    "0::/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod{systemd_pod_uid}.slice/cri-containerd-{ID}.scope"
+                ),
+                Some("containerd"),
+                pod_uid.clone(),
+            ),
+            // CRI-O.
+            (
+                format!(
+                    "0::/kubepods.slice/kubepods-pod{systemd_pod_uid}.slice/crio-{ID}.scope"
+                ),
+                Some("cri-o"),
+                pod_uid.clone(),
+            ),
+        ] {
+            assert_eq!(
+                container_from_cgroup(&cgroup),
+                Some(CgroupContainer {
+                    id: ID.to_string(),
+                    runtime,
+                    pod_uid,
+                }),
+                "{cgroup}"
+            );
+        }
+
+        assert_eq!(
+            container_from_cgroup("0::/user.slice/user-1000.slice/session-2.scope"),
+            None
+        );
+        assert_eq!(container_from_cgroup("0::/"), None);
+    }
+
+    #[cfg(feature = "cri")]
+    #[test]
+    fn test_kubernetes_containers_retry_interval() {
+        let kubernetes_containers = KubernetesContainers::new(
+            CriClient::new(std::path::Path::new("/nonexistent/containerd.sock")).unwrap(),
+        );
+        let failed_at = || match kubernetes_containers.cache.lock().unwrap().peek(ID) {
+            Some(KubernetesLookup::Failed(failed_at, _)) => Some(*failed_at),
+            _ => None,
+        };
+        let now = Instant::now();
+
+        assert!(kubernetes_containers.get(ID, now).is_err());
+        assert_eq!(failed_at(), Some(now));
+        // The failure is remembered rather than querying the runtime again.
+        assert!(kubernetes_containers
+            .get(ID, now + Duration::from_secs(10))
+            .is_err());
+        assert_eq!(failed_at(), Some(now));
+        // Until the retry interval passes.
+        let later = now + KUBERNETES_LOOKUP_RETRY_INTERVAL;
+        assert!(kubernetes_containers.get(ID, later).is_err());
+        assert_eq!(failed_at(), Some(later));
+    }
+}
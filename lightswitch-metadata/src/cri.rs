@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use hyper_util::rt::TokioIo;
+use tokio::net::UnixStream;
+use tokio::runtime::Runtime;
+use tonic::client::Grpc;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tonic::Code;
+use tonic_prost::ProstCodec;
+use tower::service_fn;
+
+/// How long a request to the container runtime can take, including connecting to it.
+const CRI_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Subset of the `ContainerStatusRequest` message of the CRI `runtime.v1` API.
+#[derive(Clone, PartialEq, prost::Message)]
+struct ContainerStatusRequest {
+    #[prost(string, tag = "1")]
+    container_id: String,
+    #[prost(bool, tag = "2")]
+    verbose: bool,
+}
+
+/// Subset of the `ContainerStatusResponse` message of the CRI `runtime.v1` API.
+#[derive(Clone, PartialEq, prost::Message)]
+struct ContainerStatusResponse {
+    #[prost(message, optional, tag = "1")]
+    status: Option<ContainerStatus>,
+}
+
+/// Subset of the `ContainerStatus` message of the CRI `runtime.v1` API.
+#[derive(Clone, PartialEq, prost::Message)]
+struct ContainerStatus {
+    #[prost(map = "string, string", tag = "12")]
+    labels: HashMap<String, String>,
+}
+
+/// Kubernetes workload a container belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KubernetesContainer {
+    pub pod_name: String,
+    pub namespace: String,
+    pub container_name: String,
+}
+
+impl KubernetesContainer {
+    /// Reads the labels the kubelet sets on the containers it creates.
+    fn from_labels(labels: &HashMap<String, String>) -> Option<Self> {
+        Some(Self {
+            pod_name: labels.get("io.kubernetes.pod.name")?.clone(),
+            namespace: labels.get("io.kubernetes.pod.namespace")?.clone(),
+            container_name: labels.get("io.kubernetes.container.name")?.clone(),
+        })
+    }
+}
+
+/// Client for the Container Runtime Interface (CRI) of the container runtime used by the
+/// kubelet, such as containerd or CRI-O, listening on a Unix socket.
+pub struct CriClient {
+    runtime: Runtime,
+    channel: Channel,
+}
+
+impl CriClient {
+    /// Creates a client for the runtime listening on `socket`. It connects on the first
+    /// request, and reconnects if the runtime restarts.
+    pub fn new(socket: &Path) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let socket = socket.to_path_buf();
+        // The URI is ignored as requests go to the socket.
+        let endpoint = Endpoint::from_static("http://localhost")
+            .connect_timeout(CRI_TIMEOUT)
+            .timeout(CRI_TIMEOUT);
+        let channel = {
+            let _guard = runtime.enter();
+            endpoint.connect_with_connector_lazy(service_fn(move |_: Uri| {
+                let socket: PathBuf = socket.clone();
+                async move { Ok::<_, io::Error>(TokioIo::new(UnixStream::connect(socket).await?)) }
+            }))
+        };
+        Ok(Self { runtime, channel })
+    }
+
+    /// Returns the Kubernetes workload of a container, or `None` if the runtime doesn't
+    /// know about it or it wasn't created by the kubelet.
+    pub fn kubernetes_container(
+        &self,
+        container_id: &str,
+    ) -> Result<Option<KubernetesContainer>, tonic::Status> {
+        let request = ContainerStatusRequest {
+            container_id: container_id.to_string(),
+            verbose: false,
+        };
+        let response = self.runtime.block_on(async {
+            let mut grpc = Grpc::new(self.channel.clone());
+            grpc.ready()
+                .await
+                .map_err(|e| tonic::Status::unavailable(e.to_string()))?;
+            grpc.unary(
+                tonic::Request::new(request),
+                PathAndQuery::from_static("/runtime.v1.RuntimeService/ContainerStatus"),
+                ProstCodec::<ContainerStatusRequest, ContainerStatusResponse>::default(),
+            )
+            .await
+        });
+
+        match response {
+            Ok(response) => Ok(response
+                .into_inner()
+                .status
+                .and_then(|status| KubernetesContainer::from_labels(&status.labels))),
+            Err(status) if status.code() == Code::NotFound => Ok(None),
+            Err(status) => Err(status),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kubernetes_container_from_labels() {
+        let mut labels = HashMap::from([
+            (
+                "io.kubernetes.pod.name".to_string(),
+                "web-7d4b9c-x2k8p".to_string(),
+            ),
+            (
+                "io.kubernetes.pod.namespace".to_string(),
+                "shop".to_string(),
+            ),
+            (
+                "io.kubernetes.container.name".to_string(),
+                "nginx".to_string(),
+            ),
+        ]);
+        assert_eq!(
+            KubernetesContainer::from_labels(&labels),
+            Some(KubernetesContainer {
+                pod_name: "web-7d4b9c-x2k8p".into(),
+                namespace: "shop".into(),
+                container_name: "nginx".into(),
+            })
+        );
+
+        // Containers not created by the kubelet.
+        labels.remove("io.kubernetes.container.name");
+        assert_eq!(KubernetesContainer::from_labels(&labels), None);
+    }
+
+    #[test]
+    fn test_cri_client_unavailable() {
+        let client = CriClient::new(Path::new("/nonexistent/containerd.sock")).unwrap();
+        assert!(client.kubernetes_container("abc").is_err());
+    }
+}
@@ -1,3 +1,6 @@
+pub mod container_metadata;
+#[cfg(feature = "cri")]
+pub mod cri;
 pub mod metadata_provider;
 pub mod system_metadata;
 pub mod task_metadata;
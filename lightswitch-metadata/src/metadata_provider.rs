@@ -1,6 +1,8 @@
 use crate::system_metadata::SystemMetadata;
 use crate::task_metadata::TaskMetadata;
-use crate::types::{MetadataLabel, SystemMetadataProvider, TaskKey, TaskMetadataProvider};
+use crate::types::{
+    MetadataLabel, SystemMetadataProvider, TaskKey, TaskMetadataProvider, TaskMetadataProviderError,
+};
 
 use lru::LruCache;
 use std::num::NonZeroUsize;
@@ -51,7 +53,8 @@ impl GlobalMetadataProvider {
         self.custom_system_metadata_providers.extend(providers);
     }
 
-    fn get_labels(&mut self, task_key: TaskKey) -> Vec<MetadataLabel> {
+    /// Returns the labels of a task, and whether all of them could be retrieved.
+    fn get_labels(&mut self, task_key: TaskKey) -> (Vec<MetadataLabel>, bool) {
         let mut labels = self
             .default_task_metadata
             .get_metadata(task_key)
@@ -81,6 +84,7 @@ impl GlobalMetadataProvider {
             }
         }
 
+        let mut complete = true;
         for provider in &self.custom_task_metadata_providers {
             match provider.get_metadata(task_key) {
                 Ok(custom_task_labels) => {
@@ -88,26 +92,35 @@ impl GlobalMetadataProvider {
                 }
                 Err(err) => {
                     warn!("Failed to retrieve custom task metadata, error = {}", err);
+                    if let TaskMetadataProviderError::PartialMetadata(_, _, partial_labels) = err {
+                        labels.extend(partial_labels);
+                    }
+                    complete = false;
                 }
             }
         }
-        labels
+        (labels, complete)
     }
 
     pub fn get_metadata(&mut self, task_key: TaskKey) -> Vec<MetadataLabel> {
         if let Some(cached_labels) = self.process_label_cache.get(&task_key) {
             cached_labels.to_vec()
         } else {
-            let labels = self.get_labels(task_key);
-            self.process_label_cache.push(task_key, labels.clone());
+            let (labels, complete) = self.get_labels(task_key);
+            // Incomplete labels aren't cached so the failed providers are retried.
+            if complete {
+                self.process_label_cache.push(task_key, labels.clone());
+            }
             labels
         }
     }
 
     pub fn register_task(&mut self, task_key: TaskKey) {
         if !self.process_label_cache.contains(&task_key) {
-            let labels = self.get_labels(task_key);
-            self.process_label_cache.push(task_key, labels);
+            let (labels, complete) = self.get_labels(task_key);
+            if complete {
+                self.process_label_cache.push(task_key, labels);
+            }
         }
     }
 }
@@ -118,6 +131,7 @@ mod tests {
     use crate::taskname::TaskName;
     use crate::types::MetadataLabelValue;
     use nix::unistd;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     #[test]
     fn test_get_metadata_returns_minimal_labels() {
@@ -152,4 +166,60 @@ mod tests {
             MetadataLabelValue::Number(pid.into(), "task-tgid".into())
         );
     }
+
+    /// Fails the first time it's called.
+    struct FlakyMetadata {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl TaskMetadataProvider for FlakyMetadata {
+        fn get_metadata(
+            &self,
+            task_key: TaskKey,
+        ) -> Result<Vec<MetadataLabel>, TaskMetadataProviderError> {
+            let label =
+                |value: &str| MetadataLabel::from_string_value("flaky".into(), value.into());
+            match self.calls.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(TaskMetadataProviderError::ErrorRetrievingMetadata(
+                    task_key,
+                    "unavailable".into(),
+                )),
+                1 => Err(TaskMetadataProviderError::PartialMetadata(
+                    task_key,
+                    "partially unavailable".into(),
+                    vec![label("partial")],
+                )),
+                _ => Ok(vec![label("complete")]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_metadata_retries_failed_providers() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut metadata_provider = GlobalMetadataProvider::default();
+        metadata_provider.register_task_metadata_providers(vec![Box::new(FlakyMetadata {
+            calls: calls.clone(),
+        })]);
+        let task_key = TaskKey {
+            tid: unistd::gettid().as_raw(),
+            pid: unistd::getpgrp().as_raw(),
+        };
+        let mut flaky_label = || {
+            metadata_provider
+                .get_metadata(task_key)
+                .into_iter()
+                .find(|label| label.key == "flaky")
+                .map(|label| label.value)
+        };
+        let value = |value: &str| Some(MetadataLabelValue::String(value.into()));
+
+        assert_eq!(flaky_label(), None);
+        // The labels that could be retrieved are used, but not cached.
+        assert_eq!(flaky_label(), value("partial"));
+        assert_eq!(flaky_label(), value("complete"));
+        // Cached once complete.
+        assert_eq!(flaky_label(), value("complete"));
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
 }
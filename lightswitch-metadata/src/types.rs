@@ -46,6 +46,10 @@ impl Display for TaskKey {
 pub enum TaskMetadataProviderError {
     #[error("Failed to retrieve metadata for task_key={0}, error={1}")]
     ErrorRetrievingMetadata(TaskKey, String),
+    /// Only some of the labels could be retrieved. They are used, but not cached, so the
+    /// rest are retried.
+    #[error("Failed to retrieve some metadata for task_key={0}, error={1}")]
+    PartialMetadata(TaskKey, String, Vec<MetadataLabel>),
 }
 
 pub trait TaskMetadataProvider {
@@ -0,0 +1,321 @@
+use std::io::{self, Seek, SeekFrom, Write};
+use std::mem;
+
+use anyhow::{Result, anyhow};
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use object::elf::{
+    ELFCOMPRESS_ZLIB, FileHeader32, FileHeader64, SHF_ALLOC, SHF_COMPRESSED, SHT_DYNSYM,
+    SHT_NOBITS, SHT_NOTE, SHT_STRTAB, SHT_SYMTAB, SHT_SYMTAB_SHNDX,
+};
+use object::read::elf::{ElfFile, FileHeader, SectionHeader};
+use object::write::WritableBuffer;
+use object::write::elf::Writer;
+use object::{Endian, Endianness, ReadRef};
+
+/// Where the data of a section of the stripped file was written. Sections without data
+/// are kept as `SHT_NOBITS` so section indices, which symbols refer to, don't change.
+struct StrippedSection {
+    data: Option<SectionData>,
+    compressed: bool,
+}
+
+struct SectionData {
+    offset: usize,
+    size: usize,
+}
+
+/// Writes a minimal ELF file with the symbol tables, notes, such as the build id, and
+/// debug sections of an object, which is what is needed to symbolize it. Sections
+/// addresses and indices are preserved, like `objcopy --only-keep-debug` does, and the
+/// debug sections are compressed with zlib.
+///
+/// The sections are compressed straight into `out`, so the file is never held in memory.
+pub fn write_debug_sections_elf<W: Write + Seek>(object: &object::File, out: &mut W) -> Result<()> {
+    match object {
+        object::File::Elf32(elf) => strip_elf(elf, out),
+        object::File::Elf64(elf) => strip_elf(elf, out),
+        _ => Err(anyhow!("only ELF files are supported")),
+    }
+}
+
+fn is_debug_section(name: &[u8]) -> bool {
+    name.starts_with(b".debug_") || name.starts_with(b".zdebug_") || name == b".gnu_debugdata"
+}
+
+fn strip_elf<'data, Elf, R, W>(elf: &ElfFile<'data, Elf, R>, out: &mut W) -> Result<()>
+where
+    Elf: FileHeader<Endian = Endianness>,
+    R: ReadRef<'data>,
+    W: Write + Seek,
+{
+    let endian = elf.endian();
+    let header = elf.elf_header();
+    let sections = elf.elf_section_table();
+    let shstrndx = header.shstrndx(endian, elf.data())? as usize;
+    let is_64 = header.is_type_64();
+
+    // The section data is written first, after the file header, as the size of the
+    // compressed sections is needed for the headers.
+    let mut offset = if is_64 {
+        mem::size_of::<FileHeader64<Endianness>>()
+    } else {
+        mem::size_of::<FileHeader32<Endianness>>()
+    };
+    let mut stripped_sections = Vec::new();
+    for (index, section) in sections.iter().enumerate().skip(1) {
+        if index == shstrndx {
+            stripped_sections.push(None);
+            continue;
+        }
+
+        let name = sections.section_name(endian, section)?;
+        let sh_type = section.sh_type(endian);
+        let keep = is_debug_section(name)
+            || matches!(
+                sh_type,
+                SHT_SYMTAB | SHT_DYNSYM | SHT_SYMTAB_SHNDX | SHT_STRTAB | SHT_NOTE
+            );
+
+        let mut stripped_section = StrippedSection {
+            data: None,
+            compressed: false,
+        };
+        if keep && sh_type != SHT_NOBITS {
+            let data = section
+                .data(endian, elf.data())
+                .map_err(|_| anyhow!("could not read section data"))?;
+            let already_compressed = section.sh_flags(endian).into() & SHF_COMPRESSED as u64 != 0;
+
+            offset = offset.next_multiple_of(8);
+            out.seek(SeekFrom::Start(offset as u64))?;
+            if name.starts_with(b".debug_") && !already_compressed && !data.is_empty() {
+                compress(
+                    data,
+                    endian,
+                    is_64,
+                    section.sh_addralign(endian).into(),
+                    &mut *out,
+                )?;
+                stripped_section.compressed = true;
+            } else {
+                out.write_all(data)?;
+            }
+            let size = out.stream_position()? as usize - offset;
+            stripped_section.data = Some(SectionData { offset, size });
+            offset += size;
+        }
+        stripped_sections.push(Some(stripped_section));
+    }
+
+    let mut buffer = SeekingBuffer::new(out);
+    let mut writer = Writer::new(endian, is_64, &mut buffer);
+    writer.reserve_file_header();
+    writer.reserve_null_section_index();
+    let mut names = Vec::new();
+    for (section, stripped_section) in sections.iter().skip(1).zip(&stripped_sections) {
+        if stripped_section.is_none() {
+            writer.reserve_shstrtab_section_index();
+            names.push(None);
+            continue;
+        }
+        writer.reserve_section_index();
+        names.push(Some(
+            writer.add_section_name(sections.section_name(endian, section)?),
+        ));
+    }
+    for data in stripped_sections
+        .iter()
+        .flatten()
+        .filter_map(|stripped_section| stripped_section.data.as_ref())
+    {
+        if writer.reserve(data.size, 8) != data.offset {
+            return Err(anyhow!("section data written at an unexpected offset"));
+        }
+    }
+    writer.reserve_shstrtab();
+    writer.reserve_section_headers();
+
+    let ident = header.e_ident();
+    writer.write_file_header(&object::write::elf::FileHeader {
+        os_abi: ident.os_abi,
+        abi_version: ident.abi_version,
+        e_type: header.e_type(endian),
+        e_machine: header.e_machine(endian),
+        e_entry: header.e_entry(endian).into(),
+        e_flags: header.e_flags(endian),
+    })?;
+    // Skips over the data written above.
+    writer.pad_until(offset);
+    writer.write_shstrtab();
+
+    writer.write_null_section_header();
+    for ((section, stripped_section), name) in
+        sections.iter().skip(1).zip(&stripped_sections).zip(names)
+    {
+        let Some(stripped_section) = stripped_section else {
+            writer.write_shstrtab_section_header();
+            continue;
+        };
+
+        let mut sh_flags = section.sh_flags(endian).into();
+        let mut sh_addralign = section.sh_addralign(endian).into();
+        let (sh_type, sh_offset, sh_size) = match &stripped_section.data {
+            Some(data) => (
+                section.sh_type(endian),
+                data.offset as u64,
+                data.size as u64,
+            ),
+            None => (SHT_NOBITS, 0, section.sh_size(endian).into()),
+        };
+        if stripped_section.compressed {
+            sh_flags |= SHF_COMPRESSED as u64;
+            sh_addralign = if is_64 { 8 } else { 4 };
+        }
+        // Debug sections are never loaded, the rest keep their flags so their addresses
+        // are still meaningful.
+        if is_debug_section(sections.section_name(endian, section)?) {
+            sh_flags &= !(SHF_ALLOC as u64);
+        }
+
+        writer.write_section_header(&object::write::elf::SectionHeader {
+            name,
+            sh_type,
+            sh_flags,
+            sh_addr: section.sh_addr(endian).into(),
+            sh_offset,
+            sh_size,
+            sh_link: section.sh_link(endian),
+            sh_info: section.sh_info(endian),
+            sh_addralign,
+            sh_entsize: section.sh_entsize(endian).into(),
+        });
+    }
+
+    Ok(buffer.result?)
+}
+
+/// Compresses the data of a section into `out`, prefixed with the compression header
+/// that `SHF_COMPRESSED` sections have.
+fn compress<W: Write>(
+    data: &[u8],
+    endian: Endianness,
+    is_64: bool,
+    addralign: u64,
+    mut out: W,
+) -> Result<()> {
+    out.write_all(&endian.write_u32_bytes(ELFCOMPRESS_ZLIB))?;
+    if is_64 {
+        out.write_all(&[0; 4])?;
+        out.write_all(&endian.write_u64_bytes(data.len() as u64))?;
+        out.write_all(&endian.write_u64_bytes(addralign))?;
+    } else {
+        out.write_all(&endian.write_u32_bytes(data.len() as u32))?;
+        out.write_all(&endian.write_u32_bytes(addralign as u32))?;
+    }
+
+    let mut encoder = ZlibEncoder::new(out, Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Writes the headers of the file around the section data, which was written beforehand,
+/// by seeking over the ranges it's padded to rather than writing zeros.
+struct SeekingBuffer<'a, W> {
+    out: &'a mut W,
+    len: usize,
+    position: Option<usize>,
+    result: io::Result<()>,
+}
+
+impl<'a, W> SeekingBuffer<'a, W> {
+    fn new(out: &'a mut W) -> Self {
+        Self {
+            out,
+            len: 0,
+            position: None,
+            result: Ok(()),
+        }
+    }
+}
+
+impl<W: Write + Seek> WritableBuffer for SeekingBuffer<'_, W> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn reserve(&mut self, _size: usize) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn resize(&mut self, new_len: usize) {
+        debug_assert!(self.len <= new_len);
+        self.len = new_len;
+    }
+
+    fn write_bytes(&mut self, val: &[u8]) {
+        if self.result.is_ok() {
+            if self.position != Some(self.len) {
+                self.result = self.out.seek(SeekFrom::Start(self.len as u64)).map(|_| ());
+            }
+            if self.result.is_ok() {
+                self.result = self.out.write_all(val);
+            }
+        }
+        self.len += val.len();
+        self.position = Some(self.len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object::{Object, ObjectSection, ObjectSymbol};
+
+    #[test]
+    fn test_write_debug_sections_elf() {
+        let data = std::fs::read("/proc/self/exe").unwrap();
+        let original = object::File::parse(&data[..]).unwrap();
+        let mut out = io::Cursor::new(Vec::new());
+        write_debug_sections_elf(&original, &mut out).unwrap();
+        let stripped_data = out.into_inner();
+        let stripped = object::File::parse(&stripped_data[..]).unwrap();
+
+        assert!(stripped_data.len() < data.len());
+        assert_eq!(stripped.build_id().unwrap(), original.build_id().unwrap());
+        assert_eq!(
+            stripped.sections().count(),
+            original.sections().count(),
+            "section indices are preserved"
+        );
+
+        // Code isn't kept.
+        let text = stripped.section_by_name(".text").unwrap();
+        assert_eq!(
+            text.address(),
+            original.section_by_name(".text").unwrap().address()
+        );
+        assert!(text.data().unwrap().is_empty());
+
+        // Symbols are kept.
+        let symbol_names = |object: &object::File| -> Vec<String> {
+            object
+                .symbols()
+                .map(|symbol| symbol.name().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(symbol_names(&stripped), symbol_names(&original));
+
+        // Debug sections are compressed and decompress to the same data.
+        let debug_info = stripped.section_by_name(".debug_info").unwrap();
+        let original_debug_info = original.section_by_name(".debug_info").unwrap();
+        assert!(
+            debug_info.compressed_file_range().unwrap().format != object::CompressionFormat::None
+        );
+        assert_eq!(
+            debug_info.uncompressed_data().unwrap(),
+            original_debug_info.uncompressed_data().unwrap()
+        );
+    }
+}
@@ -1,12 +1,17 @@
 mod buildid;
+mod debug_sections;
 pub mod kernel;
 mod object;
 
+pub use object::CPythonInfo;
 pub use object::ElfLoad;
 pub use object::ObjectFile;
 pub use object::Runtime;
 pub use object::StopUnwindingFrames;
 pub use object::code_hash;
 
+pub use debug_sections::write_debug_sections_elf;
+
 pub use buildid::BuildId;
+pub use buildid::BuildIdFlavour;
 pub use buildid::ExecutableId;
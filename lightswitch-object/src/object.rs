@@ -45,6 +45,21 @@ pub enum Runtime {
     /// V8, used by Node.js which is always compiled with frame pointers and has handwritten
     /// code sections that aren't covered by the unwind information
     V8,
+    /// CPython interpreter, either the `python` executable or `libpython`. Its native code is
+    /// unwound like C-like code, while the Python frames are read from the interpreter's state
+    CPython(CPythonInfo),
+}
+
+#[derive(Debug, Clone)]
+pub struct CPythonInfo {
+    /// Major and minor version, read from `Py_Version`, which is only present since 3.11.
+    pub version: Option<(u8, u8)>,
+    /// Address of `_PyRuntime`, from which all the interpreter state can be reached.
+    pub runtime_address: u64,
+    /// Start address of `_PyEval_EvalFrameDefault`, the interpreter loop.
+    pub eval_frame_start_address: u64,
+    /// End address of `_PyEval_EvalFrameDefault`.
+    pub eval_frame_end_address: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -100,7 +115,7 @@ impl ObjectFile {
 
     /// Returns the executable build ID if present. If no GNU build ID and no Go build ID
     /// are found it returns the hash of the text section.
-    pub fn read_build_id(object: &object::File<'static>) -> Result<BuildId> {
+    pub fn read_build_id(object: &object::File) -> Result<BuildId> {
         let gnu_build_id = object.build_id()?;
 
         if let Some(data) = gnu_build_id {
@@ -128,6 +143,11 @@ impl ObjectFile {
         self.object.has_debug_symbols()
     }
 
+    /// Returns whether the object links to a separate debug file with `.gnu_debuglink`.
+    pub fn has_gnu_debuglink(&self) -> bool {
+        matches!(self.object.gnu_debuglink(), Ok(Some(_)))
+    }
+
     pub fn is_dynamic(&self) -> bool {
         self.object.kind() == ObjectKind::Dynamic
     }
@@ -135,6 +155,8 @@ impl ObjectFile {
     pub fn runtime(&self) -> Runtime {
         if self.is_go() {
             Runtime::Go(self.go_stop_unwinding_frames())
+        } else if let Some(cpython_info) = self.cpython_info() {
+            Runtime::CPython(cpython_info)
         } else {
             let mut is_zig = false;
             let mut zig_first_frame = None;
@@ -179,6 +201,54 @@ impl ObjectFile {
         false
     }
 
+    /// Returns the information needed to walk the Python stacks if this object contains the
+    /// CPython interpreter. The symbols we need are exported, so they can also be found in
+    /// stripped executables.
+    pub fn cpython_info(&self) -> Option<CPythonInfo> {
+        let mut runtime_address = None;
+        let mut eval_frame = None;
+        let mut version = None;
+
+        for symbol in self.object.symbols().chain(self.object.dynamic_symbols()) {
+            let Ok(name) = symbol.name() else { continue };
+            match name {
+                "_PyRuntime" => runtime_address = Some(symbol.address()),
+                "_PyEval_EvalFrameDefault" => {
+                    eval_frame = Some((symbol.address(), symbol.address() + symbol.size()))
+                }
+                "Py_Version" => {
+                    // Encoded as `PY_VERSION_HEX`, such as 0x030b04f0 for 3.11.4.
+                    version = self
+                        .read_u64(&symbol)
+                        .map(|hex_version| ((hex_version >> 24) as u8, (hex_version >> 16) as u8))
+                }
+                _ => {}
+            }
+        }
+
+        let (eval_frame_start_address, eval_frame_end_address) = eval_frame?;
+        Some(CPythonInfo {
+            version,
+            runtime_address: runtime_address?,
+            eval_frame_start_address,
+            eval_frame_end_address,
+        })
+    }
+
+    /// Reads the value of a 64 bit symbol stored in the object file.
+    fn read_u64(&self, symbol: &object::Symbol) -> Option<u64> {
+        let section = self.object.section_by_index(symbol.section_index()?).ok()?;
+        let data = section.data().ok()?;
+        let offset = symbol.address().checked_sub(section.address())? as usize;
+        let bytes: [u8; 8] = data.get(offset..offset + 8)?.try_into().ok()?;
+
+        if self.object.is_little_endian() {
+            Some(u64::from_le_bytes(bytes))
+        } else {
+            Some(u64::from_be_bytes(bytes))
+        }
+    }
+
     pub fn go_stop_unwinding_frames(&self) -> Vec<StopUnwindingFrames> {
         let mut r = Vec::new();
 
@@ -3,4 +3,10 @@ fn main() {
     config
         .compile_protos(&["src/protos/profile.proto"], &["src/protos"])
         .expect("build profile.proto");
+    config
+        .compile_protos(
+            &["src/protos/opentelemetry/proto/collector/profiles/v1development/profiles_service.proto"],
+            &["src/protos"],
+        )
+        .expect("build profiles_service.proto");
 }
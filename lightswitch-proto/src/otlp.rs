@@ -0,0 +1,532 @@
+#[allow(clippy::all)]
+pub mod opentelemetry {
+    pub mod proto {
+        pub mod common {
+            pub mod v1 {
+                include!(concat!(
+                    env!("OUT_DIR"),
+                    "/opentelemetry.proto.common.v1.rs"
+                ));
+            }
+        }
+        pub mod resource {
+            pub mod v1 {
+                include!(concat!(
+                    env!("OUT_DIR"),
+                    "/opentelemetry.proto.resource.v1.rs"
+                ));
+            }
+        }
+        pub mod profiles {
+            pub mod v1development {
+                include!(concat!(
+                    env!("OUT_DIR"),
+                    "/opentelemetry.proto.profiles.v1development.rs"
+                ));
+            }
+        }
+        pub mod collector {
+            pub mod profiles {
+                pub mod v1development {
+                    include!(concat!(
+                        env!("OUT_DIR"),
+                        "/opentelemetry.proto.collector.profiles.v1development.rs"
+                    ));
+                }
+            }
+        }
+    }
+}
+
+use std::collections::hash_map::{Entry, RandomState};
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+
+use crate::profile::pprof;
+use opentelemetry::proto::collector::profiles::v1development::ExportProfilesServiceRequest;
+use opentelemetry::proto::common::v1::{any_value, AnyValue, InstrumentationScope, KeyValue};
+use opentelemetry::proto::profiles::v1development as otlp;
+use opentelemetry::proto::resource::v1::Resource;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum AttributeValue {
+    String(String),
+    Int(i64),
+}
+
+impl From<AttributeValue> for AnyValue {
+    fn from(value: AttributeValue) -> Self {
+        AnyValue {
+            value: Some(match value {
+                AttributeValue::String(value) => any_value::Value::StringValue(value),
+                AttributeValue::Int(value) => any_value::Value::IntValue(value),
+            }),
+        }
+    }
+}
+
+/// Start and limit addresses, file offset, filename and attributes.
+type MappingKey = (u64, u64, u64, i32, Vec<i32>);
+/// Mapping, address and (function, line) pairs.
+type LocationKey = (Option<i32>, u64, Vec<(i32, i64)>);
+/// Name, system name, filename and start line.
+type FunctionKey = (i32, i32, i32, i64);
+
+/// Builds an OpenTelemetry profiles export request out of pprof profiles, each of them
+/// for a different resource, such as a process. The mappings, locations, functions,
+/// strings and attributes of all the profiles are deduplicated in the dictionary the
+/// whole request shares.
+pub struct OtlpBuilder {
+    scope: InstrumentationScope,
+
+    known_strings: HashMap<String, i32>,
+    string_table: Vec<String>,
+
+    known_attributes: HashMap<(String, AttributeValue), i32>,
+    attribute_table: Vec<KeyValue>,
+    known_attribute_units: HashSet<(i32, i32)>,
+    attribute_units: Vec<otlp::AttributeUnit>,
+
+    known_mappings: HashMap<MappingKey, i32>,
+    mapping_table: Vec<otlp::Mapping>,
+
+    known_locations: HashMap<LocationKey, i32>,
+    location_table: Vec<otlp::Location>,
+
+    known_functions: HashMap<FunctionKey, i32>,
+    function_table: Vec<otlp::Function>,
+
+    resource_profiles: Vec<otlp::ResourceProfiles>,
+}
+
+impl OtlpBuilder {
+    pub fn new(scope_name: &str, scope_version: &str) -> Self {
+        Self {
+            scope: InstrumentationScope {
+                name: scope_name.to_string(),
+                version: scope_version.to_string(),
+                ..Default::default()
+            },
+
+            known_strings: HashMap::from([("".to_string(), 0)]),
+            // The first element of every table is its zero value.
+            string_table: vec!["".to_string()],
+
+            known_attributes: HashMap::new(),
+            attribute_table: vec![KeyValue::default()],
+            known_attribute_units: HashSet::new(),
+            attribute_units: Vec::new(),
+
+            known_mappings: HashMap::new(),
+            mapping_table: vec![otlp::Mapping::default()],
+
+            known_locations: HashMap::new(),
+            location_table: vec![otlp::Location::default()],
+
+            known_functions: HashMap::new(),
+            function_table: vec![otlp::Function::default()],
+
+            resource_profiles: Vec::new(),
+        }
+    }
+
+    /// Inserts a string in the string table and returns its index.
+    pub fn get_or_insert_string(&mut self, string: &str) -> i32 {
+        match self.known_strings.entry(string.to_string()) {
+            Entry::Occupied(o) => *o.get(),
+            Entry::Vacant(v) => {
+                let index = self.string_table.len() as i32;
+                v.insert(index);
+                self.string_table.push(string.to_string());
+                index
+            }
+        }
+    }
+
+    fn add_attribute(&mut self, key: &str, value: AttributeValue) -> i32 {
+        match self
+            .known_attributes
+            .entry((key.to_string(), value.clone()))
+        {
+            Entry::Occupied(o) => *o.get(),
+            Entry::Vacant(v) => {
+                let index = self.attribute_table.len() as i32;
+                v.insert(index);
+                self.attribute_table.push(KeyValue {
+                    key: key.to_string(),
+                    value: Some(value.into()),
+                });
+                index
+            }
+        }
+    }
+
+    fn add_attribute_unit(&mut self, key: &str, unit: &str) {
+        let attribute_unit = (
+            self.get_or_insert_string(key),
+            self.get_or_insert_string(unit),
+        );
+        if self.known_attribute_units.insert(attribute_unit) {
+            self.attribute_units.push(otlp::AttributeUnit {
+                attribute_key_strindex: attribute_unit.0,
+                unit_strindex: attribute_unit.1,
+            });
+        }
+    }
+
+    /// Converts a pprof label to an attribute. The process and thread ids, which are
+    /// both `pid` labels with different units, get their semantic convention names.
+    fn add_label(&mut self, profile: &pprof::Profile, label: &pprof::Label) -> i32 {
+        let key = pprof_string(profile, label.key);
+        if label.str != 0 {
+            let value = pprof_string(profile, label.str);
+            return self.add_attribute(key, AttributeValue::String(value.to_string()));
+        }
+
+        let key = match (key, pprof_string(profile, label.num_unit)) {
+            ("pid", "task-tgid") => "process.pid",
+            ("pid", "task-id") => "thread.id",
+            (key, "") => key,
+            (key, unit) => {
+                self.add_attribute_unit(key, unit);
+                key
+            }
+        };
+        self.add_attribute(key, AttributeValue::Int(label.num))
+    }
+
+    fn add_mapping(&mut self, profile: &pprof::Profile, mapping: &pprof::Mapping) -> i32 {
+        let mut attribute_indices = Vec::new();
+        if let Some((flavour @ ("gnu" | "go" | "sha256"), build_id)) =
+            pprof_string(profile, mapping.build_id).split_once('-')
+        {
+            attribute_indices.push(self.add_attribute(
+                &format!("process.executable.build_id.{flavour}"),
+                AttributeValue::String(build_id.to_string()),
+            ));
+        }
+        let filename_strindex = self.get_or_insert_string(pprof_string(profile, mapping.filename));
+
+        let key = (
+            mapping.memory_start,
+            mapping.memory_limit,
+            mapping.file_offset,
+            filename_strindex,
+            attribute_indices.clone(),
+        );
+        match self.known_mappings.entry(key) {
+            Entry::Occupied(o) => *o.get(),
+            Entry::Vacant(v) => {
+                let index = self.mapping_table.len() as i32;
+                v.insert(index);
+                self.mapping_table.push(otlp::Mapping {
+                    memory_start: mapping.memory_start,
+                    memory_limit: mapping.memory_limit,
+                    file_offset: mapping.file_offset,
+                    filename_strindex,
+                    attribute_indices,
+                    has_functions: mapping.has_functions,
+                    has_filenames: mapping.has_filenames,
+                    has_line_numbers: mapping.has_line_numbers,
+                    has_inline_frames: mapping.has_inline_frames,
+                });
+                index
+            }
+        }
+    }
+
+    fn add_function(&mut self, profile: &pprof::Profile, function: &pprof::Function) -> i32 {
+        let key = (
+            self.get_or_insert_string(pprof_string(profile, function.name)),
+            self.get_or_insert_string(pprof_string(profile, function.system_name)),
+            self.get_or_insert_string(pprof_string(profile, function.filename)),
+            function.start_line,
+        );
+        match self.known_functions.entry(key) {
+            Entry::Occupied(o) => *o.get(),
+            Entry::Vacant(v) => {
+                let index = self.function_table.len() as i32;
+                v.insert(index);
+                self.function_table.push(otlp::Function {
+                    name_strindex: key.0,
+                    system_name_strindex: key.1,
+                    filename_strindex: key.2,
+                    start_line: key.3,
+                });
+                index
+            }
+        }
+    }
+
+    fn add_location(
+        &mut self,
+        mapping_index: Option<i32>,
+        address: u64,
+        lines: Vec<otlp::Line>,
+    ) -> i32 {
+        let key = (
+            mapping_index,
+            address,
+            lines
+                .iter()
+                .map(|line| (line.function_index, line.line))
+                .collect(),
+        );
+        match self.known_locations.entry(key) {
+            Entry::Occupied(o) => *o.get(),
+            Entry::Vacant(v) => {
+                let index = self.location_table.len() as i32;
+                v.insert(index);
+                self.location_table.push(otlp::Location {
+                    mapping_index,
+                    address,
+                    line: lines,
+                    ..Default::default()
+                });
+                index
+            }
+        }
+    }
+
+    fn value_type(
+        &mut self,
+        profile: &pprof::Profile,
+        value_type: &pprof::ValueType,
+    ) -> otlp::ValueType {
+        otlp::ValueType {
+            type_strindex: self.get_or_insert_string(pprof_string(profile, value_type.r#type)),
+            unit_strindex: self.get_or_insert_string(pprof_string(profile, value_type.unit)),
+            aggregation_temporality: otlp::AggregationTemporality::Delta.into(),
+        }
+    }
+
+    /// Adds the profile of a resource. The labels all its samples have in common are
+    /// set as resource attributes and the rest as sample attributes.
+    pub fn add_profile(&mut self, profile: &pprof::Profile) {
+        // Locations refer to mappings by their position in the table, starting at 1, as
+        // their ids are derived from the code regions they describe.
+        let mapping_indices: HashMap<u64, i32> = profile
+            .mapping
+            .iter()
+            .enumerate()
+            .map(|(i, mapping)| (i as u64 + 1, self.add_mapping(profile, mapping)))
+            .collect();
+        let function_indices: HashMap<u64, i32> = profile
+            .function
+            .iter()
+            .map(|function| (function.id, self.add_function(profile, function)))
+            .collect();
+        let location_indices: HashMap<u64, i32> = profile
+            .location
+            .iter()
+            .map(|location| {
+                let lines = location
+                    .line
+                    .iter()
+                    .map(|line| otlp::Line {
+                        function_index: function_indices
+                            .get(&line.function_id)
+                            .copied()
+                            .unwrap_or_default(),
+                        line: line.line,
+                        column: line.column,
+                    })
+                    .collect();
+                let mapping_index = mapping_indices.get(&location.mapping_id).copied();
+                (
+                    location.id,
+                    self.add_location(mapping_index, location.address, lines),
+                )
+            })
+            .collect();
+
+        let sample_attributes: Vec<Vec<i32>> = profile
+            .sample
+            .iter()
+            .map(|sample| {
+                sample
+                    .label
+                    .iter()
+                    .map(|label| self.add_label(profile, label))
+                    .collect()
+            })
+            .collect();
+        let mut resource_attributes: Vec<i32> =
+            sample_attributes.first().cloned().unwrap_or_default();
+        resource_attributes.dedup();
+        resource_attributes.retain(|attribute| {
+            sample_attributes
+                .iter()
+                .all(|attributes| attributes.contains(attribute))
+        });
+
+        let mut otlp_profile = otlp::Profile {
+            sample_type: profile
+                .sample_type
+                .iter()
+                .map(|sample_type| self.value_type(profile, sample_type))
+                .collect(),
+            time_nanos: profile.time_nanos,
+            duration_nanos: profile.duration_nanos,
+            period_type: profile
+                .period_type
+                .as_ref()
+                .map(|period_type| self.value_type(profile, period_type)),
+            period: profile.period,
+            comment_strindices: profile
+                .comment
+                .iter()
+                .map(|comment| self.get_or_insert_string(pprof_string(profile, *comment)))
+                .collect(),
+            profile_id: profile_id(),
+            ..Default::default()
+        };
+
+        for (sample, attributes) in profile.sample.iter().zip(sample_attributes) {
+            let locations_start_index = otlp_profile.location_indices.len() as i32;
+            otlp_profile.location_indices.extend(
+                sample
+                    .location_id
+                    .iter()
+                    .filter_map(|location_id| location_indices.get(location_id)),
+            );
+            otlp_profile.sample.push(otlp::Sample {
+                locations_start_index,
+                locations_length: otlp_profile.location_indices.len() as i32
+                    - locations_start_index,
+                value: sample.value.clone(),
+                attribute_indices: attributes
+                    .into_iter()
+                    .filter(|attribute| !resource_attributes.contains(attribute))
+                    .collect(),
+                ..Default::default()
+            });
+        }
+
+        self.resource_profiles.push(otlp::ResourceProfiles {
+            resource: Some(Resource {
+                attributes: resource_attributes
+                    .iter()
+                    .map(|attribute| self.attribute_table[*attribute as usize].clone())
+                    .collect(),
+                dropped_attributes_count: 0,
+            }),
+            scope_profiles: vec![otlp::ScopeProfiles {
+                scope: Some(self.scope.clone()),
+                profiles: vec![otlp_profile],
+                schema_url: String::new(),
+            }],
+            schema_url: String::new(),
+        });
+    }
+
+    pub fn build(self) -> ExportProfilesServiceRequest {
+        ExportProfilesServiceRequest {
+            resource_profiles: self.resource_profiles,
+            dictionary: Some(otlp::ProfilesDictionary {
+                mapping_table: self.mapping_table,
+                location_table: self.location_table,
+                function_table: self.function_table,
+                link_table: Vec::new(),
+                string_table: self.string_table,
+                attribute_table: self.attribute_table,
+                attribute_units: self.attribute_units,
+            }),
+        }
+    }
+}
+
+fn pprof_string(profile: &pprof::Profile, index: i64) -> &str {
+    profile
+        .string_table
+        .get(index as usize)
+        .map(String::as_str)
+        .unwrap_or_default()
+}
+
+/// Profile ids are 16 random bytes.
+fn profile_id() -> Vec<u8> {
+    let random = || RandomState::new().build_hasher().finish().to_ne_bytes();
+    [random(), random()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::{LabelStringOrNumber, PprofBuilder};
+    use std::time::{Duration, SystemTime};
+
+    fn process_profile(pid: i64, tids: &[i64]) -> pprof::Profile {
+        let mut pprof = PprofBuilder::new(SystemTime::now(), Duration::from_secs(5), 27);
+        let mapping_id = pprof.add_mapping(1, 0x1000, 0x2000, 0, "libc.so.6", "gnu-abcd");
+        let (line, _) = pprof.add_line("malloc", None, None);
+        let location_id = pprof.add_location(0x1234, mapping_id, vec![line]);
+
+        for tid in tids {
+            let labels = vec![
+                pprof.new_label("pid", LabelStringOrNumber::Number(*tid, "task-id".into())),
+                pprof.new_label("pid", LabelStringOrNumber::Number(pid, "task-tgid".into())),
+                pprof.new_label("hostname", LabelStringOrNumber::String("host".into())),
+                pprof.new_label("bytes", LabelStringOrNumber::Number(10, "bytes".into())),
+            ];
+            pprof.add_sample(vec![location_id], 10, &labels);
+        }
+        pprof.build()
+    }
+
+    fn attribute_keys(builder: &OtlpBuilder, indices: &[i32]) -> Vec<String> {
+        indices
+            .iter()
+            .map(|index| builder.attribute_table[*index as usize].key.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_add_profile() {
+        let mut builder = OtlpBuilder::new("lightswitch", "0.1.0");
+        builder.add_profile(&process_profile(100, &[100, 101]));
+        builder.add_profile(&process_profile(200, &[200]));
+
+        // Both processes share the same mapping, function and location.
+        assert_eq!(builder.mapping_table.len(), 2);
+        assert_eq!(builder.function_table.len(), 2);
+        assert_eq!(builder.location_table.len(), 2);
+        assert_eq!(builder.string_table[0], "");
+        assert_eq!(
+            attribute_keys(&builder, &builder.mapping_table[1].attribute_indices),
+            vec!["process.executable.build_id.gnu"]
+        );
+        assert_eq!(builder.attribute_units.len(), 1);
+
+        let resource_profiles = &builder.resource_profiles[0];
+        let resource_keys: Vec<_> = resource_profiles
+            .resource
+            .as_ref()
+            .unwrap()
+            .attributes
+            .iter()
+            .map(|attribute| attribute.key.as_str())
+            .collect();
+        assert_eq!(resource_keys, vec!["process.pid", "hostname", "bytes"]);
+
+        let profile = &resource_profiles.scope_profiles[0].profiles[0];
+        assert_eq!(profile.sample.len(), 2);
+        assert_eq!(profile.location_indices, vec![1, 1]);
+        assert_eq!(profile.sample[1].locations_start_index, 1);
+        assert_eq!(profile.sample[1].locations_length, 1);
+        assert_eq!(profile.sample[1].value, profile.sample[0].value);
+        assert_eq!(
+            attribute_keys(&builder, &profile.sample[0].attribute_indices),
+            vec!["thread.id"]
+        );
+        assert_eq!(profile.profile_id.len(), 16);
+
+        // A single thread process has all its labels as resource attributes.
+        let profile = &builder.resource_profiles[1].scope_profiles[0].profiles[0];
+        assert!(profile.sample[0].attribute_indices.is_empty());
+
+        let request = builder.build();
+        assert_eq!(request.resource_profiles.len(), 2);
+        assert!(request.dictionary.is_some());
+    }
+}
@@ -10,10 +10,28 @@ use std::collections::HashMap;
 use std::time::{Duration, SystemTime};
 use thiserror;
 
+/// Describes one of the values recorded for every sample, such as `cpu` in `nanoseconds`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleType {
+    pub r#type: String,
+    pub unit: String,
+}
+
+impl SampleType {
+    pub fn new(r#type: &str, unit: &str) -> Self {
+        Self {
+            r#type: r#type.to_string(),
+            unit: unit.to_string(),
+        }
+    }
+}
+
 pub struct PprofBuilder {
     time_nanos: i64,
     duration: Duration,
-    freq_in_hz: i64,
+    sample_types: Vec<SampleType>,
+    period_type: SampleType,
+    period: i64,
 
     known_mappings: HashMap<u64, u64>,
     mappings: Vec<pprof::Mapping>,
@@ -62,14 +80,37 @@ pub enum PprofError {
 }
 
 impl PprofBuilder {
+    /// Creates a builder for CPU profiles, where every sample records the number of times
+    /// it was seen and the CPU time this represents.
     pub fn new(profile_start: SystemTime, duration: Duration, freq_in_hz: u64) -> Self {
+        let cpu = SampleType::new("cpu", "nanoseconds");
+        Self::with_sample_types(
+            profile_start,
+            duration,
+            vec![SampleType::new("samples", "count"), cpu.clone()],
+            cpu,
+            1_000_000_000 / freq_in_hz as i64,
+        )
+    }
+
+    /// Creates a builder for profiles with arbitrary sample types. Every sample must have
+    /// one value per sample type, in the same order.
+    pub fn with_sample_types(
+        profile_start: SystemTime,
+        duration: Duration,
+        sample_types: Vec<SampleType>,
+        period_type: SampleType,
+        period: i64,
+    ) -> Self {
         Self {
             time_nanos: profile_start
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .unwrap()
                 .as_nanos() as i64,
             duration,
-            freq_in_hz: freq_in_hz as i64,
+            sample_types,
+            period_type,
+            period,
 
             known_mappings: HashMap::new(),
             mappings: Vec::new(),
@@ -278,10 +319,36 @@ impl PprofBuilder {
             }
         }
     }
+
+    /// Adds a sample whose first two values are its count and its count multiplied by
+    /// the period. Any other values are set to zero.
     pub fn add_sample(&mut self, location_ids: Vec<u64>, count: i64, labels: &[pprof::Label]) {
+        let mut values = vec![0; self.sample_types.len()];
+        for (i, value) in [count, count * self.period].into_iter().enumerate() {
+            if let Some(v) = values.get_mut(i) {
+                *v = value;
+            }
+        }
+
+        self.add_sample_with_values(location_ids, values, labels);
+    }
+
+    /// Adds a sample with one value per sample type.
+    pub fn add_sample_with_values(
+        &mut self,
+        location_ids: Vec<u64>,
+        values: Vec<i64>,
+        labels: &[pprof::Label],
+    ) {
+        debug_assert_eq!(
+            values.len(),
+            self.sample_types.len(),
+            "every sample type needs a value"
+        );
+
         let sample = pprof::Sample {
             location_id: location_ids, // from the source code: `The leaf is at location_id\[0\].`
-            value: vec![count, count * 1_000_000_000 / self.freq_in_hz],
+            value: values,
             label: labels.to_vec(),
         };
 
@@ -307,16 +374,21 @@ impl PprofBuilder {
         label
     }
 
-    pub fn build(mut self) -> pprof::Profile {
-        let sample_type = pprof::ValueType {
-            r#type: self.get_or_insert_string("samples"),
-            unit: self.get_or_insert_string("count"),
-        };
+    fn value_type(&mut self, sample_type: &SampleType) -> pprof::ValueType {
+        pprof::ValueType {
+            r#type: self.get_or_insert_string(&sample_type.r#type),
+            unit: self.get_or_insert_string(&sample_type.unit),
+        }
+    }
 
-        let period_type = pprof::ValueType {
-            r#type: self.get_or_insert_string("cpu"),
-            unit: self.get_or_insert_string("nanoseconds"),
-        };
+    pub fn build(mut self) -> pprof::Profile {
+        let sample_types = self
+            .sample_types
+            .clone()
+            .iter()
+            .map(|sample_type| self.value_type(sample_type))
+            .collect();
+        let period_type = self.value_type(&self.period_type.clone());
 
         // Used to identify profiles generated by lightswitch.
         // This is useful because the mapping ID is used in a non-standard way
@@ -324,7 +396,7 @@ impl PprofBuilder {
         let comments = vec![self.get_or_insert_string("lightswitch")];
 
         pprof::Profile {
-            sample_type: vec![sample_type, period_type],
+            sample_type: sample_types,
             sample: self.samples,
             mapping: self.mappings,
             location: self.locations,
@@ -335,13 +407,31 @@ impl PprofBuilder {
             time_nanos: self.time_nanos,
             duration_nanos: self.duration.as_nanos() as i64,
             period_type: Some(period_type),
-            period: 1_000_000_000 / self.freq_in_hz,
+            period: self.period,
             comment: comments,
             default_sample_type: 0,
         }
     }
 }
 
+impl pprof::Profile {
+    /// Adds a free-form note to the profile, such as why it was taken.
+    pub fn add_comment(&mut self, comment: &str) {
+        let index = match self
+            .string_table
+            .iter()
+            .position(|string| string == comment)
+        {
+            Some(index) => index,
+            None => {
+                self.string_table.push(comment.to_string());
+                self.string_table.len() - 1
+            }
+        };
+        self.comment.push(index as i64);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     // Cheat sheet:
@@ -445,6 +535,42 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sample_values() {
+        let mut pprof = PprofBuilder::new(SystemTime::now(), Duration::from_secs(5), 20);
+        pprof.add_sample(vec![1, 2, 3], 10, &[]);
+        assert_eq!(pprof.samples[0].value, vec![10, 500_000_000]);
+
+        let mut pprof = PprofBuilder::with_sample_types(
+            SystemTime::now(),
+            Duration::from_secs(5),
+            vec![
+                SampleType::new("samples", "count"),
+                SampleType::new("cycles", "count"),
+                SampleType::new("off-cpu", "nanoseconds"),
+            ],
+            SampleType::new("cycles", "count"),
+            100_000,
+        );
+        pprof.add_sample(vec![1, 2, 3], 10, &[]);
+        pprof.add_sample_with_values(vec![1, 2, 3], vec![0, 0, 5_000_000], &[]);
+
+        assert_eq!(pprof.samples[0].value, vec![10, 1_000_000, 0]);
+        assert_eq!(pprof.samples[1].value, vec![0, 0, 5_000_000]);
+
+        let profile = pprof.build();
+        assert_eq!(profile.sample_type.len(), 3);
+        assert_eq!(profile.period, 100_000);
+        assert_eq!(
+            profile.string_table[profile.sample_type[1].r#type as usize],
+            "cycles"
+        );
+        assert_eq!(
+            profile.string_table[profile.sample_type[2].r#type as usize],
+            "off-cpu"
+        );
+    }
+
     #[test]
     fn test_profile() {
         let mut pprof = PprofBuilder::new(SystemTime::now(), Duration::from_secs(5), 27);
@@ -484,4 +610,28 @@ mod tests {
         assert!(pprof.validate().is_ok());
         pprof.build();
     }
+
+    #[test]
+    fn test_add_comment() {
+        let mut pprof = PprofBuilder::new(SystemTime::now(), Duration::from_secs(5), 27);
+        let _ = pprof.get_or_insert_string("trigger");
+        let mut profile = pprof.build();
+        profile.add_comment("trigger");
+        profile.add_comment("another trigger");
+
+        let comments: Vec<&str> = profile
+            .comment
+            .iter()
+            .map(|comment| profile.string_table[*comment as usize].as_str())
+            .collect();
+        assert_eq!(comments, ["lightswitch", "trigger", "another trigger"]);
+        assert_eq!(
+            profile
+                .string_table
+                .iter()
+                .filter(|string| *string == "trigger")
+                .count(),
+            1
+        );
+    }
 }
@@ -28,10 +28,14 @@ impl Aggregator {
             sample.hash(&mut hasher);
             let sample_hash = hasher.finish();
 
+            let value = sample.value;
             sample_hash_to_aggregated
                 .entry(sample_hash)
-                .and_modify(|aggregated_sample| aggregated_sample.count += 1)
-                .or_insert(RawAggregatedSample { sample, count: 1 });
+                .and_modify(|aggregated_sample| aggregated_sample.count += value)
+                .or_insert(RawAggregatedSample {
+                    sample,
+                    count: value,
+                });
         }
         sample_hash_to_aggregated.into_values().collect()
     }
@@ -40,7 +44,7 @@ impl Aggregator {
 #[cfg(test)]
 mod tests {
     use crate::aggregator::Aggregator;
-    use crate::profile::RawSample;
+    use crate::profile::{RawSample, SampleKind};
 
     #[test]
     fn test_aggregate_raw_samples() {
@@ -48,16 +52,24 @@ mod tests {
             pid: 1234,
             tid: 1235,
             collected_at: 1748865070,
+            value: 1,
+            kind: SampleKind::OnCpu,
+            event: 0,
             ustack: vec![0xffff, 0xffff],
             kstack: vec![0xffff, 0xdddd, 0xaaaa, 0xeeee, 0xaaae],
+            python_stack: vec![],
         };
 
         let raw_sample_2 = RawSample {
             pid: 1234,
             tid: 1235,
             collected_at: 1748865070,
+            value: 1,
+            kind: SampleKind::OnCpu,
+            event: 0,
             ustack: vec![0xdddd, 0xfeedbee, 0xddddef, 0xbeefdad],
             kstack: vec![],
+            python_stack: vec![],
         };
 
         let raw_samples = vec![
@@ -91,16 +103,24 @@ mod tests {
             pid: 1234,
             tid: 1235,
             collected_at: 1748865070,
+            value: 1,
+            kind: SampleKind::OnCpu,
+            event: 0,
             ustack: vec![0xffff, 0xdeadbeef],
             kstack: vec![0xffff, 0xdddd, 0xaaaa, 0xeeee, 0xaaae],
+            python_stack: vec![],
         };
 
         let raw_sample_2 = RawSample {
             pid: 1234,
             tid: 1235,
             collected_at: 1748865070,
+            value: 1,
+            kind: SampleKind::OnCpu,
+            event: 0,
             ustack: raw_sample_1.ustack.clone(),
             kstack: vec![],
+            python_stack: vec![],
         };
 
         let raw_samples = vec![
@@ -131,16 +151,24 @@ mod tests {
             pid: 1234,
             tid: 1235,
             collected_at: 1748865070,
+            value: 1,
+            kind: SampleKind::OnCpu,
+            event: 0,
             ustack: vec![0xffff, 0xdeadbeef],
             kstack: vec![0xffff, 0xdddd, 0xaaaa, 0xeeee, 0xaaae],
+            python_stack: vec![],
         };
 
         let raw_sample_2 = RawSample {
             pid: 1234,
             tid: 1235,
             collected_at: 1748865070,
+            value: 1,
+            kind: SampleKind::OnCpu,
+            event: 0,
             ustack: vec![0xdddd, 0xfeedbee, 0xddddef, 0xbeefdad],
             kstack: raw_sample_1.kstack.clone(),
+            python_stack: vec![],
         };
 
         let raw_samples = vec![
@@ -176,24 +204,36 @@ mod tests {
             pid: 1234,
             tid: 1235,
             collected_at: 1748865070,
+            value: 1,
+            kind: SampleKind::OnCpu,
+            event: 0,
             ustack: ustack.clone(),
             kstack: kstack.clone(),
+            python_stack: vec![],
         };
 
         let raw_sample_2 = RawSample {
             pid: 1234,
             tid: 1236,
             collected_at: 1748865070,
+            value: 1,
+            kind: SampleKind::OnCpu,
+            event: 0,
             ustack: ustack.clone(),
             kstack: kstack.clone(),
+            python_stack: vec![],
         };
 
         let raw_sample_3 = RawSample {
             pid: 123,
             tid: 124,
             collected_at: 1748865070,
+            value: 1,
+            kind: SampleKind::OnCpu,
+            event: 0,
             ustack: ustack.clone(),
             kstack: kstack.clone(),
+            python_stack: vec![],
         };
 
         let raw_samples = vec![raw_sample_1, raw_sample_2, raw_sample_3];
@@ -206,4 +246,95 @@ mod tests {
         // Then
         assert_eq!(raw_aggregated_profile.len(), 3);
     }
+
+    #[test]
+    fn test_aggregate_off_cpu_samples() {
+        let ustack = vec![0xffff, 0xdeadbeef];
+
+        let on_cpu_sample = RawSample {
+            pid: 1234,
+            tid: 1235,
+            collected_at: 1748865070,
+            value: 1,
+            kind: SampleKind::OnCpu,
+            event: 0,
+            ustack: ustack.clone(),
+            kstack: vec![],
+            python_stack: vec![],
+        };
+
+        let off_cpu_sample_1 = RawSample {
+            value: 1_000,
+            kind: SampleKind::OffCpu,
+            ..on_cpu_sample.clone()
+        };
+
+        let off_cpu_sample_2 = RawSample {
+            value: 2_500,
+            ..off_cpu_sample_1.clone()
+        };
+
+        let raw_samples = vec![
+            on_cpu_sample.clone(),
+            off_cpu_sample_1,
+            on_cpu_sample,
+            off_cpu_sample_2,
+        ];
+
+        let aggregator = Aggregator::default();
+
+        // When
+        let raw_aggregated_profile = aggregator.aggregate(raw_samples);
+
+        // Then
+        assert_eq!(raw_aggregated_profile.len(), 2);
+        for sample in raw_aggregated_profile {
+            match sample.sample.kind {
+                SampleKind::OnCpu => assert_eq!(sample.count, 2),
+                SampleKind::OffCpu => assert_eq!(sample.count, 3_500),
+            }
+        }
+    }
+
+    #[test]
+    fn test_aggregate_samples_per_event() {
+        let cpu_clock_sample = RawSample {
+            pid: 1234,
+            tid: 1235,
+            collected_at: 1748865070,
+            value: 1,
+            kind: SampleKind::OnCpu,
+            event: 0,
+            ustack: vec![0xffff, 0xdeadbeef],
+            kstack: vec![],
+            python_stack: vec![],
+        };
+
+        let page_faults_sample = RawSample {
+            event: 1,
+            ..cpu_clock_sample.clone()
+        };
+
+        let raw_samples = vec![
+            cpu_clock_sample.clone(),
+            page_faults_sample.clone(),
+            cpu_clock_sample.clone(),
+            page_faults_sample.clone(),
+            page_faults_sample,
+        ];
+
+        let aggregator = Aggregator::default();
+
+        // When
+        let raw_aggregated_profile = aggregator.aggregate(raw_samples);
+
+        // Then
+        assert_eq!(raw_aggregated_profile.len(), 2);
+        for sample in raw_aggregated_profile {
+            match sample.sample.event {
+                0 => assert_eq!(sample.count, 2),
+                _ => assert_eq!(sample.count, 3),
+            }
+        }
+    }
 }
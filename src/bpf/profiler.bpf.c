@@ -31,6 +31,15 @@ struct {
   __type(value, unwind_state_t);
 } heap SEC(".maps");
 
+// Unwinding state for off-CPU samples. Kept separate from `heap` so the
+// unwinders for on-CPU and off-CPU samples never share their state.
+struct {
+  __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+  __uint(max_entries, 1);
+  __type(key, u32);
+  __type(value, unwind_state_t);
+} off_cpu_heap SEC(".maps");
+
 
 // Holds BPF array maps which store unwind information.
 
@@ -48,6 +57,15 @@ struct {
   __type(value, u32);
 } programs SEC(".maps");
 
+// Programs can only tail call into programs of the same type, the off-CPU
+// unwinder is a kprobe rather than a perf event program.
+struct {
+  __uint(type, BPF_MAP_TYPE_PROG_ARRAY);
+  __uint(max_entries, 5);
+  __type(key, u32);
+  __type(value, u32);
+} off_cpu_programs SEC(".maps");
+
 struct {
   __uint(type, BPF_MAP_TYPE_PERF_EVENT_ARRAY);
   __uint(key_size, sizeof(u32));
@@ -74,6 +92,32 @@ struct {
   __type(value, bool);
 } rate_limits SEC(".maps");
 
+// Processes running the CPython interpreter, keyed by pid.
+struct {
+  __uint(type, BPF_MAP_TYPE_HASH);
+  __uint(max_entries, MAX_PROCESSES);
+  __type(key, int);
+  __type(value, python_process_t);
+} python_processes SEC(".maps");
+
+// Cgroups whose tasks are profiled, keyed by cgroup id. Only used if filtering
+// by cgroup has been enabled in `cgroup_filter_enabled`.
+struct {
+  __uint(type, BPF_MAP_TYPE_HASH);
+  __uint(max_entries, MAX_CGROUPS);
+  __type(key, u64);
+  __type(value, bool);
+} cgroup_filter SEC(".maps");
+
+// Set from userspace once the profiler has been loaded, as which processes
+// should be profiled can change while it runs.
+struct {
+  __uint(type, BPF_MAP_TYPE_ARRAY);
+  __uint(max_entries, 1);
+  __type(key, u32);
+  __type(value, bool);
+} cgroup_filter_enabled SEC(".maps");
+
 
 // Binary search the unwind table to find the row index containing the unwind
 // information for a given program counter (pc) relative to the object file.
@@ -138,7 +182,7 @@ find_page(mapping_t *mapping, u64 object_relative_pc, u64 *low_index, u64 *high_
   return NULL;
 }
 
-static __always_inline void send_event(Event *event, struct bpf_perf_event_data *ctx) {
+static __always_inline void send_event(Event *event, void *ctx) {
   bool *is_rate_limited = bpf_map_lookup_elem(&rate_limits, event);
   if (is_rate_limited != NULL && *is_rate_limited) {
     LOG("[debug] send_event was rate limited");
@@ -184,11 +228,11 @@ static __always_inline u64 remove_pac(u64 addr) {
 // Arm64 supports pointer authentication, we need to remove the signatured during
 // unwinding.
 static __always_inline u64 remove_pac(u64 addr) {
-  // The signature is stored in the top 55 - virtual address size bits [0], which
-  // is typically 48 bytes, hence we need to clear the top 7 bits. Clearing 8 bits
-  // as they are all the non-addressable anyways.
+  // The signature is stored in the top 55 - virtual address size bits [0], and
+  // the top byte might have a tag. The virtual address size depends on the kernel
+  // configuration, 39, 48 or 52 bits, so the mask is computed in userspace.
   // - [0]: https://docs.kernel.org/arch/arm64/pointer-authentication.html#basic-support
-  addr &= 0x0000FFFFFFFFFFFF;
+  addr &= lightswitch_config.arm64_address_mask;
   return addr;
 }
 #endif
@@ -196,6 +240,18 @@ static __always_inline u64 remove_pac(u64 addr) {
 // Kernel addresses have the top bits set.
 static __always_inline bool in_kernel(u64 ip) { return ip & (1UL << 63); }
 
+// Whether the current task belongs to one of the cgroups that are profiled.
+static __always_inline bool cgroup_is_profiled() {
+  u32 zero = 0;
+  bool *enabled = bpf_map_lookup_elem(&cgroup_filter_enabled, &zero);
+  if (enabled == NULL || !*enabled) {
+    return true;
+  }
+
+  u64 cgroup_id = bpf_get_current_cgroup_id();
+  return bpf_map_lookup_elem(&cgroup_filter, &cgroup_id) != NULL;
+}
+
 // kthreads mm's is not set.
 //
 // We don't check for the return value of `retrieve_task_registers`, it's
@@ -218,8 +274,8 @@ static __always_inline bool is_kthread() {
 
 // avoid R0 invalid mem access 'scalar'
 // Port of `task_pt_regs` in BPF.
-static __always_inline bool retrieve_task_registers(u64 *ip, u64 *sp, u64 *bp, u64 *lr) {
-  if (ip == NULL || sp == NULL || bp == NULL || lr == NULL) {
+static __always_inline bool retrieve_task_registers(u64 *ip, u64 *sp, u64 *bp, u64 *lr, u64 *r10, u64 *r13) {
+  if (ip == NULL || sp == NULL || bp == NULL || lr == NULL || r10 == NULL || r13 == NULL) {
     return false;
   }
 
@@ -254,16 +310,20 @@ static __always_inline bool retrieve_task_registers(u64 *ip, u64 *sp, u64 *bp, u
   *bp = PT_REGS_FP_CORE(regs);
 #ifdef __TARGET_ARCH_arm64
   *lr = PT_REGS_RET_CORE(regs);
+#endif
+#ifdef __TARGET_ARCH_x86
+  *r10 = BPF_CORE_READ(regs, r10);
+  *r13 = BPF_CORE_READ(regs, r13);
 #endif
   return true;
 }
 
-static __always_inline void add_stack(struct bpf_perf_event_data *ctx,
+static __always_inline void add_stack(void *ctx,
 unwind_state_t *unwind_state) {
-  // Unwind and copy kernel stack.
-  u32 ulen = unwind_state->sample.stack.ulen;
-  if (ulen < MAX_STACK_DEPTH) {
-    int ret = bpf_get_stack(ctx, &unwind_state->sample.stack.addresses[ulen], MAX_STACK_DEPTH * sizeof(u64), 0);
+  // Unwind and copy kernel stack, after the user and Python stacks.
+  u32 kernel_stack_start = unwind_state->sample.stack.ulen + 2 * unwind_state->sample.stack.plen;
+  if (kernel_stack_start < MAX_STACK_ADDRESSES - MAX_STACK_DEPTH) {
+    int ret = bpf_get_stack(ctx, &unwind_state->sample.stack.addresses[kernel_stack_start], MAX_STACK_DEPTH * sizeof(u64), 0);
     if (ret > 0) {
       unwind_state->sample.stack.klen = ret / sizeof(u64);
     }
@@ -281,9 +341,9 @@ unwind_state_t *unwind_state) {
 
   u32 sample_size = sizeof(sample_t)
     // Remove the actual stack buffer which was doubled to appease the verifier.
-    - 2 * MAX_STACK_DEPTH * sizeof(u64)
+    - MAX_STACK_ADDRESSES * sizeof(u64)
     // Add the actual stack size in bytes.
-    + (unwind_state->sample.stack.ulen + unwind_state->sample.stack.klen) * sizeof(u64);
+    + (unwind_state->sample.stack.ulen + unwind_state->sample.stack.klen + 2 * unwind_state->sample.stack.plen) * sizeof(u64);
 
   // Appease the verifier.
   if (sample_size > sizeof(sample_t)) {
@@ -307,9 +367,9 @@ unwind_state_t *unwind_state) {
   }
 }
 
-// The unwinding machinery lives here.
-SEC("perf_event")
-int dwarf_unwind(struct bpf_perf_event_data *ctx) {
+// The unwinding machinery lives here. It's shared by the on-CPU and off-CPU
+// unwinders, which pass their own unwinding state and programs map.
+static __always_inline int unwind_native_stack(void *ctx, void *state_map, void *programs_map) {
 	struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
 	unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
 	int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
@@ -318,7 +378,7 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
   bool reached_bottom_of_stack = false;
   u64 zero = 0;
 
-  unwind_state_t *unwind_state = bpf_map_lookup_elem(&heap, &zero);
+  unwind_state_t *unwind_state = bpf_map_lookup_elem(state_map, &zero);
   if (unwind_state == NULL) {
     LOG("unwind_state is NULL, should not happen");
     return 1;
@@ -346,9 +406,62 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
     }
 
     if (mapping->type == MAPPING_TYPE_ANON) {
-      LOG("JIT section, stopping");
+      LOG("JIT section, unwinding with frame pointers");
       bump_unwind_jit_encountered();
-      return 1;
+    }
+
+    // There is no unwind information for this code, so follow the frame
+    // pointer chain until we are back in an object we know how to unwind. On
+    // both x86_64 and arm64 the frame pointer points to the saved frame
+    // pointer of the caller, which is followed by the return address.
+    if (mapping->type == MAPPING_TYPE_ANON ||
+        mapping->type == MAPPING_TYPE_FRAME_POINTER) {
+      u32 ulen = unwind_state->sample.stack.ulen;
+      // Appease the verifier.
+      if (ulen < MAX_STACK_DEPTH) {
+        unwind_state->sample.stack.addresses[ulen] = unwind_state->ip;
+        unwind_state->sample.stack.ulen++;
+      }
+
+      if (unwind_state->bp == 0) {
+        LOG("[info] frame pointer is zero, end of stack");
+        reached_bottom_of_stack = true;
+        break;
+      }
+
+      u64 previous_rbp = 0;
+      u64 previous_rip = 0;
+      if (bpf_probe_read_user(&previous_rbp, 8, (void *)unwind_state->bp) < 0 ||
+          bpf_probe_read_user(&previous_rip, 8, (void *)(unwind_state->bp + 8)) < 0) {
+        LOG("[error] reading frame record at %llx failed", unwind_state->bp);
+        bump_unwind_error_frame_pointer_read();
+        return 1;
+      }
+
+      if (previous_rip == 0) {
+        LOG("[info] null return address, end of stack");
+        reached_bottom_of_stack = true;
+        break;
+      }
+
+      // The stack grows downwards, anything else means that the frame pointer
+      // was used as a general purpose register.
+      if (previous_rbp != 0 && previous_rbp <= unwind_state->bp) {
+        LOG("[error] frame pointer %llx is not above %llx", previous_rbp, unwind_state->bp);
+        bump_unwind_error_frame_pointer_read();
+        return 1;
+      }
+
+      LOG("\tprevious ip: %llx (fp)", previous_rip);
+      LOG("\tprevious bp: %llx (fp)", previous_rbp);
+      unwind_state->ip = previous_instruction_addr(remove_pac(previous_rip));
+      unwind_state->sp = unwind_state->bp + 16;
+      unwind_state->bp = previous_rbp;
+      unwind_state->lr = 0;
+      unwind_state->r10 = 0;
+      unwind_state->r13 = 0;
+      unwind_state->frame_pointer_frames++;
+      continue;
     }
 
     if (mapping->type == MAPPING_TYPE_VDSO) {
@@ -413,7 +526,8 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
 
     u64 found_pc = object_relative_pc_high + row->pc_low;
     u8 found_cfa_type = row->cfa_type;
-    u8 found_rbp_type = row->rbp_type;
+    u8 found_rbp_type = row->rbp_type & RBP_TYPE_MASK;
+    u8 found_ra_type = row->rbp_type >> RA_TYPE_SHIFT;
     s16 found_cfa_offset = row->cfa_offset;
     s16 found_rbp_offset = row->rbp_offset;
     LOG("\tcfa type: %d, offset: %d (row pc: %llx)", found_cfa_type,
@@ -458,7 +572,14 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
       return 1;
     }
 
+    if (found_ra_type == RA_TYPE_UNSUPPORTED) {
+      LOG("\t[error] return address rule not supported");
+      bump_unwind_error_unsupported_return_address();
+      return 1;
+    }
+
     u64 previous_rsp = 0;
+    bool signal_frame = false;
     if (found_cfa_type == CFA_TYPE_RBP) {
       previous_rsp = unwind_state->bp + found_cfa_offset;
     } else if (found_cfa_type == CFA_TYPE_RSP) {
@@ -474,6 +595,45 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
         bump_unwind_error_previous_rsp_read();
       }
       previous_rsp += addition;
+    } else if (found_cfa_type == CFA_TYPE_RBP_DEREF_AND_ADD) {
+      s8 offset = (u16)found_cfa_offset >> 8;
+      u8 addition = found_cfa_offset;
+      LOG("dwarf exp: *($rbp + %d) + %d", offset, addition);
+      int ret =
+          bpf_probe_read_user(&previous_rsp, 8, (void *)(unwind_state->bp + offset));
+      if (ret < 0) {
+        LOG("[error] reading previous rsp failed with %d", ret);
+        bump_unwind_error_previous_rsp_read();
+        return 1;
+      }
+      previous_rsp += addition;
+    } else if (found_cfa_type == CFA_TYPE_REGISTER_OFFSET) {
+      u8 reg = (u16)found_cfa_offset >> 8;
+      u8 offset = found_cfa_offset;
+      u64 reg_value = 0;
+      if (reg == X86_DWARF_REGISTER_R10) {
+        reg_value = unwind_state->r10;
+      } else if (reg == X86_DWARF_REGISTER_R13) {
+        reg_value = unwind_state->r13;
+      }
+      // The register is only known for the first frame.
+      if (reg_value == 0) {
+        LOG("[error] value of register %d is unknown", reg);
+        bump_unwind_error_unsupported_cfa_register();
+        return 1;
+      }
+      previous_rsp = reg_value + offset;
+    } else if (found_cfa_type == CFA_TYPE_SIGNAL_FRAME) {
+      // The registers of the interrupted code are saved in the `ucontext`
+      // pushed by the kernel. The offset points to the saved stack pointer.
+      int ret = bpf_probe_read_user(&previous_rsp, 8,
+                                    (void *)(unwind_state->sp + found_cfa_offset));
+      if (ret < 0) {
+        LOG("[error] reading previous rsp failed with %d", ret);
+        bump_unwind_error_previous_rsp_read();
+        return 1;
+      }
+      signal_frame = true;
     } else if (found_cfa_type == CFA_TYPE_CFA_TYPE_UNSUP_EXP) {
         bump_unwind_error_unsupported_expression();
         return 1;
@@ -505,6 +665,11 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
     // Set rbp register.
     u64 previous_rbp = 0;
     u64 previous_rbp_addr = previous_rsp + found_rbp_offset;
+    if (found_rbp_type == RBP_TYPE_RBP_OFFSET) {
+      previous_rbp_addr = unwind_state->bp + found_rbp_offset;
+    } else if (found_rbp_type == RBP_TYPE_RSP_OFFSET) {
+      previous_rbp_addr = unwind_state->sp + found_rbp_offset;
+    }
 
     if (found_rbp_type == RBP_TYPE_UNCHANGED) {
       previous_rbp = unwind_state->bp;
@@ -527,20 +692,39 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
     // The return address is guaranteed to be 8 bytes ahead of
     // the previous stack pointer in x86_64.
     previous_rip_addr = previous_rsp - 8;
+    // Except for signal frames, where the interrupted program counter is
+    // saved right after the stack pointer.
+    if (signal_frame) {
+      previous_rip_addr = unwind_state->sp + found_cfa_offset + 8;
+    }
 #endif
 
 #ifdef __TARGET_ARCH_arm64
-    // Special handling for leaf frame.
-    if (unwind_state->sample.stack.ulen == 0) {
+    if (found_ra_type == RA_TYPE_LINK_REGISTER) {
+      // Leaf functions, as well as the prologue and epilogue of any other
+      // function, have the return address in the link register, which is
+      // only known for the first frame.
+      if (unwind_state->lr == 0) {
+        LOG("[error] return address in the link register, which is unknown");
+        bump_unwind_error_previous_rip_zero();
+        return 1;
+      }
       previous_rip = unwind_state->lr;
+    } else if (found_ra_type == RA_TYPE_CFA_OFFSET) {
+      // The frame pointer wasn't saved, the offset is the one of the return
+      // address.
+      previous_rip_addr = previous_rsp + found_rbp_offset;
     } else {
-      // This is guaranteed by the Aarch64 ABI.
+      // The frame record holds the frame pointer followed by the return
+      // address, as per the Aarch64 ABI.
       previous_rip_addr = previous_rbp_addr + 8;
     }
 #endif
 
-    int err =
-        bpf_probe_read_user(&previous_rip, 8, (void *)(previous_rip_addr));
+    int err = 0;
+    if (previous_rip_addr != 0) {
+      err = bpf_probe_read_user(&previous_rip, 8, (void *)(previous_rip_addr));
+    }
 
     if (previous_rip == 0) {
       if (err == 0) {
@@ -557,12 +741,21 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
 
     LOG("\tprevious ip: %llx (@ %llx)", previous_rip, previous_rip_addr);
     LOG("\tprevious sp: %llx", previous_rsp);
-    // Set rsp and rip registers
-    unwind_state->ip = previous_instruction_addr(remove_pac(previous_rip));
+    // Set rsp and rip registers. The program counter of the code interrupted
+    // by a signal is not a return address.
+    if (signal_frame) {
+      unwind_state->ip = remove_pac(previous_rip);
+    } else {
+      unwind_state->ip = previous_instruction_addr(remove_pac(previous_rip));
+    }
     unwind_state->sp = previous_rsp;
     // Set rbp
     LOG("\tprevious bp: %llx", previous_rbp);
     unwind_state->bp = previous_rbp;
+    // Other registers are not restored.
+    unwind_state->lr = 0;
+    unwind_state->r10 = 0;
+    unwind_state->r13 = 0;
 
     // Frame finished! :)
   }
@@ -588,8 +781,18 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
     }
 
     LOG("======= reached bottom frame! =======");
-    add_stack(ctx, unwind_state);
     bump_unwind_success_dwarf();
+    if (unwind_state->frame_pointer_frames > 0) {
+      bump_unwind_success_frame_pointer();
+    }
+
+    // Python processes also need their interpreter stack walked. If the tail
+    // call fails we still send the native stack.
+    if (bpf_map_lookup_elem(&python_processes, &per_process_id) != NULL) {
+      bpf_tail_call(ctx, programs_map, PROGRAM_PYTHON_UNWINDER);
+    }
+
+    add_stack(ctx, unwind_state);
     return 0;
 
   } else if (unwind_state->sample.stack.ulen < MAX_STACK_DEPTH &&
@@ -597,7 +800,7 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
     LOG("Continuing walking the stack in a tail call, current tail %d",
         unwind_state->tail_calls);
     unwind_state->tail_calls++;
-    bpf_tail_call(ctx, &programs, PROGRAM_NATIVE_UNWINDER);
+    bpf_tail_call(ctx, programs_map, PROGRAM_NATIVE_UNWINDER);
   }
 
   // We couldn't get the whole stacktrace.
@@ -606,18 +809,206 @@ int dwarf_unwind(struct bpf_perf_event_data *ctx) {
   return 0;
 }
 
-// Set up the initial unwinding state.
-static __always_inline bool set_initial_state(unwind_state_t *unwind_state, bpf_user_pt_regs_t *regs) {
+SEC("perf_event")
+int dwarf_unwind(struct bpf_perf_event_data *ctx) {
+  return unwind_native_stack(ctx, &heap, &programs);
+}
+
+SEC("kprobe")
+int dwarf_unwind_off_cpu(struct pt_regs *ctx) {
+  return unwind_native_stack(ctx, &off_cpu_heap, &off_cpu_programs);
+}
+
+// Finds the `PyThreadState` of the current thread by walking the thread states
+// of the main interpreter. Returns 0 if it can't be found.
+static __always_inline u64 find_python_thread_state(python_process_t *python, int per_thread_id) {
+  python_offsets_t *offsets = &python->offsets;
+  u64 interpreter = 0;
+  u64 thread_state = 0;
+
+  if (bpf_probe_read_user(&interpreter, sizeof(u64), (void *)(python->runtime_address + offsets->runtime_interpreters_head)) < 0) {
+    return 0;
+  }
+  if (bpf_probe_read_user(&thread_state, sizeof(u64), (void *)(interpreter + offsets->interpreter_threads_head)) < 0) {
+    return 0;
+  }
+
+  u64 pthread_id = 0;
+  if (offsets->thread_state_native_thread_id == PYTHON_OFFSET_NONE) {
+    // Before 3.11 thread states can only be matched with `pthread_self()`,
+    // which is the thread pointer on x86_64.
+#ifdef __TARGET_ARCH_x86
+    struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+    pthread_id = BPF_CORE_READ(task, thread.fsbase);
+#elif __TARGET_ARCH_arm64
+    return 0;
+#endif
+  }
+
+  for (int i = 0; i < MAX_PYTHON_THREADS; i++) {
+    if (thread_state == 0) {
+      return 0;
+    }
+
+    if (offsets->thread_state_native_thread_id != PYTHON_OFFSET_NONE) {
+      u64 native_thread_id = 0;
+      bpf_probe_read_user(&native_thread_id, sizeof(u64), (void *)(thread_state + offsets->thread_state_native_thread_id));
+      if (native_thread_id == (u64)per_thread_id) {
+        return thread_state;
+      }
+    } else {
+      u64 thread_id = 0;
+      bpf_probe_read_user(&thread_id, sizeof(u64), (void *)(thread_state + offsets->thread_state_thread_id));
+      if (thread_id == pthread_id) {
+        return thread_state;
+      }
+    }
+
+    if (bpf_probe_read_user(&thread_state, sizeof(u64), (void *)(thread_state + offsets->thread_state_next)) < 0) {
+      return 0;
+    }
+  }
+
+  return 0;
+}
+
+// Walks the Python frames of the current thread, which are stored after the
+// native user stack, and sends the sample. Native frames for the interpreter
+// loop are replaced with the Python frames in userspace.
+static __always_inline int unwind_python_stack(void *ctx, void *state_map) {
+  struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+  unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
+  int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
+  int per_thread_id = BPF_CORE_READ(task, thread_pid, numbers[level].nr);
+
+  u64 zero = 0;
+  unwind_state_t *unwind_state = bpf_map_lookup_elem(state_map, &zero);
+  if (unwind_state == NULL) {
+    LOG("unwind_state is NULL, should not happen");
+    return 1;
+  }
+
+  python_process_t *python = bpf_map_lookup_elem(&python_processes, &per_process_id);
+  if (python == NULL) {
+    add_stack(ctx, unwind_state);
+    return 0;
+  }
+  python_offsets_t *offsets = &python->offsets;
+
+  u64 thread_state = find_python_thread_state(python, per_thread_id);
+  if (thread_state == 0) {
+    // Threads that never ran Python code, such as the ones created by native
+    // extensions, don't have a thread state.
+    LOG("[warn] Python thread state not found for tid %d", per_thread_id);
+    bump_unwind_error_python_thread_not_found();
+    add_stack(ctx, unwind_state);
+    return 0;
+  }
+
+  u64 frame = 0;
+  bpf_probe_read_user(&frame, sizeof(u64), (void *)(thread_state + offsets->thread_state_frame));
+  if (frame != 0 && offsets->cframe_current_frame != PYTHON_OFFSET_NONE) {
+    bpf_probe_read_user(&frame, sizeof(u64), (void *)(frame + offsets->cframe_current_frame));
+  }
+
+  for (int i = 0; i < MAX_PYTHON_STACK_DEPTH; i++) {
+    if (frame == 0) {
+      break;
+    }
+
+    u32 ulen = unwind_state->sample.stack.ulen;
+    u32 plen = unwind_state->sample.stack.plen;
+    u32 index = ulen + 2 * plen;
+    // Appease the verifier.
+    if (index >= MAX_STACK_ADDRESSES - 1) {
+      break;
+    }
+
+    if (offsets->frame_owner != PYTHON_OFFSET_NONE) {
+      u8 owner = 0;
+      bpf_probe_read_user(&owner, sizeof(u8), (void *)(frame + offsets->frame_owner));
+      if (owner == PYTHON_FRAME_OWNED_BY_CSTACK) {
+        // Shim frame pushed when entering the interpreter loop, the previous
+        // frame was the first one it evaluated.
+        if (plen > 0 && index >= 1) {
+          unwind_state->sample.stack.addresses[index - 1] |= PYTHON_FRAME_ENTRY;
+        }
+        bpf_probe_read_user(&frame, sizeof(u64), (void *)(frame + offsets->frame_back));
+        continue;
+      }
+    }
+
+    u64 code = 0;
+    bpf_probe_read_user(&code, sizeof(u64), (void *)(frame + offsets->frame_code));
+
+    u64 instruction_offset = 0;
+    if (offsets->lasti_kind == PYTHON_LASTI_INSTRUCTION_POINTER) {
+      u64 instruction_pointer = 0;
+      bpf_probe_read_user(&instruction_pointer, sizeof(u64), (void *)(frame + offsets->frame_lasti));
+      instruction_offset = instruction_pointer - (code + offsets->code_code_adaptive);
+    } else {
+      int lasti = 0;
+      bpf_probe_read_user(&lasti, sizeof(int), (void *)(frame + offsets->frame_lasti));
+      instruction_offset = offsets->lasti_kind == PYTHON_LASTI_CODE_UNITS ? lasti * 2 : lasti;
+    }
+
+    bool is_entry = true;
+    if (offsets->frame_is_entry != PYTHON_OFFSET_NONE) {
+      bpf_probe_read_user(&is_entry, sizeof(bool), (void *)(frame + offsets->frame_is_entry));
+    } else if (offsets->frame_owner != PYTHON_OFFSET_NONE) {
+      // Entry frames are marked when their shim frame is found.
+      is_entry = false;
+    }
+
+    unwind_state->sample.stack.addresses[index] = code;
+    unwind_state->sample.stack.addresses[index + 1] = (instruction_offset & 0xFFFFFFFF) | (is_entry ? PYTHON_FRAME_ENTRY : 0);
+    unwind_state->sample.stack.plen++;
+
+    if (bpf_probe_read_user(&frame, sizeof(u64), (void *)(frame + offsets->frame_back)) < 0) {
+      break;
+    }
+  }
+
+  bump_unwind_success_python();
+  add_stack(ctx, unwind_state);
+  return 0;
+}
+
+SEC("perf_event")
+int python_unwind(struct bpf_perf_event_data *ctx) {
+  return unwind_python_stack(ctx, &heap);
+}
+
+SEC("kprobe")
+int python_unwind_off_cpu(struct pt_regs *ctx) {
+  return unwind_python_stack(ctx, &off_cpu_heap);
+}
+
+// Reset the unwinding state before walking a new stack.
+static __always_inline void reset_unwind_state(unwind_state_t *unwind_state, u32 kind, u64 value, u32 event) {
  unwind_state->sample.stack.ulen = 0;
  unwind_state->sample.stack.klen = 0;
+ unwind_state->sample.stack.plen = 0;
  unwind_state->tail_calls = 0;
+ unwind_state->frame_pointer_frames = 0;
+ unwind_state->lr = 0;
+ unwind_state->r10 = 0;
+ unwind_state->r13 = 0;
 
  unwind_state->sample.pid = 0;
  unwind_state->sample.tid = 0;
  unwind_state->sample.collected_at = 0;
+ unwind_state->sample.kind = kind;
+ unwind_state->sample.value = value;
+ unwind_state->sample.event = event;
+}
+
+// Set up the initial unwinding state.
+static __always_inline bool set_initial_state(unwind_state_t *unwind_state, bpf_user_pt_regs_t *regs, u32 event) {
+  reset_unwind_state(unwind_state, SAMPLE_KIND_ON_CPU, 1, event);
 
   if (in_kernel(PT_REGS_IP(regs))) {
-    if (!retrieve_task_registers(&unwind_state->ip, &unwind_state->sp, &unwind_state->bp, &unwind_state->lr)) {
+    if (!retrieve_task_registers(&unwind_state->ip, &unwind_state->sp, &unwind_state->bp, &unwind_state->lr, &unwind_state->r10, &unwind_state->r13)) {
       // in kernelspace, but failed, probs a kworker
       // todo: bump counter
       return false;
@@ -628,6 +1019,10 @@ static __always_inline bool set_initial_state(unwind_state_t *unwind_state, bpf_
     unwind_state->sp = PT_REGS_SP(regs);
     unwind_state->bp = PT_REGS_FP(regs);
     unwind_state->lr = remove_pac(PT_REGS_RET(regs));
+#ifdef __TARGET_ARCH_x86
+    unwind_state->r10 = regs->r10;
+    unwind_state->r13 = regs->r13;
+#endif
   }
 
   return true;
@@ -649,6 +1044,10 @@ int on_event(struct bpf_perf_event_data *ctx) {
     return 0;
   }
 
+  if (!cgroup_is_profiled()) {
+    return 0;
+  }
+
   if (process_is_known(per_process_id)) {
     bump_unwind_total();
 
@@ -658,7 +1057,10 @@ int on_event(struct bpf_perf_event_data *ctx) {
       LOG("[error] profiler state should never be NULL");
       return 0;
     }
-    set_initial_state(profiler_state, &ctx->regs);
+    // The cookie is the index of the event that triggered this sample, set
+    // when the program is attached to each perf event.
+    u32 event = bpf_get_attach_cookie(ctx);
+    set_initial_state(profiler_state, &ctx->regs, event);
 
     bpf_tail_call(ctx, &programs, PROGRAM_NATIVE_UNWINDER);
     return 0;
@@ -672,4 +1074,52 @@ int on_event(struct bpf_perf_event_data *ctx) {
   return 0;
 }
 
+// Runs once a thread has been scheduled back in. If it was switched out while
+// being tracked by `tracer_sched_switch`, its stack is unwound and reported with
+// the time it spent off-CPU as the sample value. Its user stack hasn't changed
+// since it was switched out.
+SEC("kprobe")
+int on_finish_task_switch(struct pt_regs *ctx) {
+  u64 pid_tgid = bpf_get_current_pid_tgid();
+  u64 *switched_out_at = bpf_map_lookup_elem(&off_cpu_start, &pid_tgid);
+  if (switched_out_at == NULL) {
+    return 0;
+  }
+
+  u64 off_cpu_ns = bpf_ktime_get_ns() - *switched_out_at;
+  bpf_map_delete_elem(&off_cpu_start, &pid_tgid);
+
+  if (off_cpu_ns < lightswitch_config.off_cpu_threshold_ns) {
+    return 0;
+  }
+
+	struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+	unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
+	int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
+
+  if (!process_is_known(per_process_id)) {
+    return 0;
+  }
+
+  bump_unwind_off_cpu_total();
+
+  u32 zero = 0;
+  unwind_state_t *unwind_state = bpf_map_lookup_elem(&off_cpu_heap, &zero);
+  if (unwind_state == NULL) {
+    LOG("[error] off-CPU unwind state should never be NULL");
+    return 0;
+  }
+  reset_unwind_state(unwind_state, SAMPLE_KIND_OFF_CPU, off_cpu_ns, 0);
+
+  // We are always in kernel space here, the user registers were saved
+  // when the thread entered the kernel.
+  if (!retrieve_task_registers(&unwind_state->ip, &unwind_state->sp, &unwind_state->bp, &unwind_state->lr, &unwind_state->r10, &unwind_state->r13)) {
+    return 0;
+  }
+  unwind_state->lr = remove_pac(unwind_state->lr);
+
+  bpf_tail_call(ctx, &off_cpu_programs, PROGRAM_NATIVE_UNWINDER);
+  return 0;
+}
+
 char LICENSE[] SEC("license") = "Dual MIT/GPL";
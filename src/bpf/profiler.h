@@ -22,6 +22,16 @@ _Static_assert(MAX_TAIL_CALLS *MAX_STACK_DEPTH_PER_PROGRAM >= MAX_STACK_DEPTH,
 #define MAX_BINARY_SEARCH_DEPTH 17
 // Number of entries in the 'outer' unwind map.
 #define MAX_OUTER_UNWIND_MAP_ENTRIES 3000
+// Maximum number of threads that can be off-CPU at the same time while being tracked.
+#define MAX_OFF_CPU_THREADS 32 * 1024
+// Maximum number of Python frames.
+#define MAX_PYTHON_STACK_DEPTH 64
+// Maximum number of Python thread states that will be walked to find the current thread's.
+#define MAX_PYTHON_THREADS 64
+// Maximum number of cgroups that can be selected for profiling.
+#define MAX_CGROUPS 8 * 1024
+// Size of the stack buffer. Each Python frame takes two entries.
+#define MAX_STACK_ADDRESSES (MAX_STACK_DEPTH * 2 + MAX_PYTHON_STACK_DEPTH * 2)
 
 #define UNWIND_INFO_PAGE_BIT_LEN 16
 #define UNWIND_INFO_PAGE_SIZE (1 << UNWIND_INFO_PAGE_BIT_LEN)
@@ -37,6 +47,9 @@ _Static_assert(MAX_BINARY_SEARCH_DEPTH >= UNWIND_INFO_PAGE_BIT_LEN, "unwind tabl
 #define MAPPING_TYPE_FILE 0
 #define MAPPING_TYPE_ANON 1
 #define MAPPING_TYPE_VDSO 2
+// Code without unwind information, such as objects without an `.eh_frame`
+// section, which is unwound by following the frame pointer chain.
+#define MAPPING_TYPE_FRAME_POINTER 3
 
 
 typedef struct {
@@ -59,6 +72,13 @@ typedef struct {
 #define CFA_TYPE_END_OF_FDE_MARKER      7
 #define CFA_TYPE_UNSUP_REGISTER_OFFSET  8   // not used in the unwinder yet.
 #define CFA_TYPE_OFFSET_DID_NOT_FIT     9
+#define CFA_TYPE_REGISTER_OFFSET        10
+#define CFA_TYPE_RBP_DEREF_AND_ADD      11
+#define CFA_TYPE_SIGNAL_FRAME           12
+
+// DWARF register numbers of the registers `CFA_TYPE_REGISTER_OFFSET` supports.
+#define X86_DWARF_REGISTER_R10          10
+#define X86_DWARF_REGISTER_R13          13
 
 // Values for the unwind table's frame pointer type.
 #define RBP_TYPE_UNCHANGED                0
@@ -67,6 +87,19 @@ typedef struct {
 #define RBP_TYPE_EXPRESSION               3
 #define RBP_TYPE_UNDEFINED_RETURN_ADDRESS 4
 #define RBP_TYPE_OFFSET_DID_NOT_FIT       5
+#define RBP_TYPE_RBP_OFFSET               6
+#define RBP_TYPE_RSP_OFFSET               7
+
+// The frame pointer type is stored in the low bits of the row's `rbp_type`
+// and the return address type, only used on arm64, in the high bits.
+#define RBP_TYPE_MASK                     0xF
+#define RA_TYPE_SHIFT                     4
+
+// Values for the unwind table's return address type.
+#define RA_TYPE_FRAME_RECORD              0
+#define RA_TYPE_LINK_REGISTER             1
+#define RA_TYPE_CFA_OFFSET                2
+#define RA_TYPE_UNSUPPORTED               3
 
 // Binary search error codes.
 #define BINARY_SEARCH_DEFAULT 0xFABADAFABADAULL
@@ -78,6 +111,11 @@ struct lightswitch_config_t {
   bool verbose_logging;
   bool use_ring_buffers;
   bool use_task_pt_regs_helper;
+  // Off-CPU periods shorter than this won't be reported.
+  u64 off_cpu_threshold_ns;
+  // Bits of arm64 code addresses that aren't used by pointer authentication
+  // codes or tags, which depends on the virtual address size.
+  u64 arm64_address_mask;
 };
 
 struct unwinder_stats_t {
@@ -103,12 +141,20 @@ struct unwinder_stats_t {
   u64 bp_non_zero_for_bottom_frame;
   u64 vdso_encountered;
   u64 jit_encountered;
+  u64 off_cpu_total;
+  u64 success_python;
+  u64 error_python_thread_not_found;
+  u64 success_frame_pointer;
+  u64 error_frame_pointer_read;
+  u64 error_unsupported_return_address;
 };
 
 const volatile struct lightswitch_config_t lightswitch_config = {
     .verbose_logging = false,
     .use_ring_buffers = false,
     .use_task_pt_regs_helper = false,
+    .off_cpu_threshold_ns = 0,
+    .arm64_address_mask = 0x0000FFFFFFFFFFFF,
 };
 
 #define LOG(fmt, ...)                                                          \
@@ -152,19 +198,41 @@ _Static_assert(sizeof(stack_unwind_row_t) == 8,
 
 
 // The addresses of a native stack trace.
+//
+// The user stack is stored first, followed by the Python frames, if any, and
+// the kernel stack. Each Python frame takes two entries, the address of its
+// code object and the offset of the instruction being executed, which might
+// be tagged with `PYTHON_FRAME_ENTRY`.
 typedef struct {
   u32 ulen;
   u32 klen;
+  u32 plen;
+  u32 _padding;
   // Needed as the verifier won't operate with dynamically computed offsets and
   // wants to ensure that any write won't be out of bounds. Note that only the
   // actual unwound stack will be sent to userspace.
-  u64 addresses[MAX_STACK_DEPTH * 2];
+  u64 addresses[MAX_STACK_ADDRESSES];
 } native_stack_t;
 
+// Set for Python frames that were the first ones to be evaluated by a call to
+// the interpreter loop.
+#define PYTHON_FRAME_ENTRY (1ULL << 32)
+
+enum sample_kind {
+  SAMPLE_KIND_ON_CPU = 0,
+  SAMPLE_KIND_OFF_CPU = 1,
+};
+
 typedef struct {
   int pid;
   int tid;
   u64 collected_at;
+  // Weight of the sample. It's 1 for on-CPU samples and the time the thread
+  // spent off-CPU in nanoseconds for off-CPU ones.
+  u64 value;
+  u32 kind;
+  // Index of the perf event that triggered an on-CPU sample.
+  u32 event;
   native_stack_t stack;
 } sample_t;
 
@@ -173,7 +241,13 @@ typedef struct {
   unsigned long long sp;
   unsigned long long bp;
   unsigned long long lr;
+  // Only known for the first frame. Used to find the CFA of code that realigns
+  // the stack on x86_64.
+  unsigned long long r10;
+  unsigned long long r13;
   u64 tail_calls;
+  // Number of frames unwound using frame pointers.
+  u64 frame_pointer_frames;
   sample_t sample;
 } unwind_state_t;
 
@@ -190,4 +264,46 @@ typedef struct {
 
 enum program {
   PROGRAM_NATIVE_UNWINDER = 0,
+  PROGRAM_PYTHON_UNWINDER = 1,
 };
+
+// Marks offsets of fields that don't exist in a given Python version.
+#define PYTHON_OFFSET_NONE 0xFFFF
+// `FRAME_OWNED_BY_CSTACK`, the owner of the shim frames pushed by the interpreter
+// loop on entry since Python 3.12.
+#define PYTHON_FRAME_OWNED_BY_CSTACK 3
+
+// How the last executed instruction is stored in a frame.
+enum python_lasti_kind {
+  // Offset in bytes, up to 3.9.
+  PYTHON_LASTI_BYTES = 0,
+  // Offset in code units, 3.10.
+  PYTHON_LASTI_CODE_UNITS = 1,
+  // Pointer to the instruction, since 3.11.
+  PYTHON_LASTI_INSTRUCTION_POINTER = 2,
+};
+
+// Offsets of the CPython internal structures needed to walk the Python stack.
+// They vary across Python versions.
+typedef struct {
+  u16 runtime_interpreters_head;
+  u16 interpreter_threads_head;
+  u16 thread_state_next;
+  u16 thread_state_thread_id;
+  u16 thread_state_native_thread_id;
+  u16 thread_state_frame;
+  u16 cframe_current_frame;
+  u16 frame_back;
+  u16 frame_code;
+  u16 frame_lasti;
+  u16 frame_is_entry;
+  u16 frame_owner;
+  u16 code_code_adaptive;
+  u16 lasti_kind;
+} python_offsets_t;
+
+typedef struct {
+  // Address of `_PyRuntime` in the process.
+  u64 runtime_address;
+  python_offsets_t offsets;
+} python_process_t;
@@ -15,6 +15,7 @@ unsafe impl Plain for exec_mappings_key {}
 unsafe impl Plain for mapping_t {}
 unsafe impl Plain for page_key_t {}
 unsafe impl Plain for page_value_t {}
+unsafe impl Plain for python_process_t {}
 
 impl exec_mappings_key {
     pub fn new(pid: u32, address: u64, prefix_len: u32) -> Self {
@@ -70,6 +71,15 @@ impl Add for unwinder_stats_t {
                 + other.bp_non_zero_for_bottom_frame,
             vdso_encountered: self.vdso_encountered + other.vdso_encountered,
             jit_encountered: self.jit_encountered + other.jit_encountered,
+            off_cpu_total: self.off_cpu_total + other.off_cpu_total,
+            success_python: self.success_python + other.success_python,
+            error_python_thread_not_found: self.error_python_thread_not_found
+                + other.error_python_thread_not_found,
+            success_frame_pointer: self.success_frame_pointer + other.success_frame_pointer,
+            error_frame_pointer_read: self.error_frame_pointer_read
+                + other.error_frame_pointer_read,
+            error_unsupported_return_address: self.error_unsupported_return_address
+                + other.error_unsupported_return_address,
         }
     }
 }
@@ -82,7 +92,8 @@ impl From<&CompactUnwindRow> for stack_unwind_row_t {
             pc_low: (row.pc & LOW_PC_MASK as u64) as u16,
             cfa_offset: row.cfa_offset,
             cfa_type: row.cfa_type as u8,
-            rbp_type: row.rbp_type as u8,
+            // The return address type, only used on arm64, is stored in the high bits.
+            rbp_type: row.rbp_type as u8 | (row.ra_type as u8) << RA_TYPE_SHIFT,
             rbp_offset: row.rbp_offset,
         }
     }
@@ -18,6 +18,14 @@ struct {
   __type(value, struct unwinder_stats_t);
 } percpu_stats SEC(".maps");
 
+// Time at which a thread was scheduled out, keyed by its pid_tgid.
+struct {
+  __uint(type, BPF_MAP_TYPE_LRU_HASH);
+  __uint(max_entries, MAX_OFF_CPU_THREADS);
+  __type(key, u64);
+  __type(value, u64);
+} off_cpu_start SEC(".maps");
+
 #define DEFINE_COUNTER(__func__name)                                           \
   static void bump_unwind_##__func__name() {                                   \
     u32 zero = 0;                                                              \
@@ -50,5 +58,11 @@ DEFINE_COUNTER(error_failure_sending_stack);
 DEFINE_COUNTER(bp_non_zero_for_bottom_frame);
 DEFINE_COUNTER(vdso_encountered);
 DEFINE_COUNTER(jit_encountered);
+DEFINE_COUNTER(off_cpu_total);
+DEFINE_COUNTER(success_python);
+DEFINE_COUNTER(error_python_thread_not_found);
+DEFINE_COUNTER(success_frame_pointer);
+DEFINE_COUNTER(error_frame_pointer_read);
+DEFINE_COUNTER(error_unsupported_return_address);
 
 #endif
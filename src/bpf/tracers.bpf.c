@@ -31,6 +31,15 @@ struct {
   __type(value, u64);
 } tracked_munmap SEC(".maps");
 
+// Processes whose children are followed, keyed by pid. Children are added once
+// they are forked, so all the descendants of a process are followed.
+struct {
+  __uint(type, BPF_MAP_TYPE_HASH);
+  __uint(max_entries, MAX_PROCESSES);
+  __type(key, int);
+  __type(value, bool);
+} followed_processes SEC(".maps");
+
 // Arguments from
 // /sys/kernel/debug/tracing/events/syscalls/sys_enter_munmap/format
 struct munmap_entry_args {
@@ -43,6 +52,12 @@ struct munmap_entry_args {
     size_t len;
 };
 
+static __always_inline int send_tracer_event(void *ctx, tracer_event_t *event) {
+    if (lightswitch_config.use_ring_buffers) {
+        return bpf_ringbuf_output(&tracer_events_rb, event, sizeof(tracer_event_t), 0);
+    }
+    return bpf_perf_event_output(ctx, &tracer_events, BPF_F_CURRENT_CPU, event, sizeof(tracer_event_t));
+}
 
 SEC("tracepoint/sched/sched_process_exit")
 int tracer_process_exit(void *ctx) {
@@ -52,12 +67,15 @@ int tracer_process_exit(void *ctx) {
     int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
     int per_thread_id = BPF_CORE_READ(task, thread_pid, numbers[level].nr);
 
-    if (!process_is_known(per_process_id)) {
+    // Only report main thread terminating.
+    if (per_process_id != per_thread_id) {
         return 0;
     }
 
-    // Only report main thread terminating.
-    if (per_process_id != per_thread_id) {
+    // Followed processes are reported even if they haven't been profiled yet, so
+    // they stop being followed.
+    bool is_followed = bpf_map_delete_elem(&followed_processes, &per_process_id) == 0;
+    if (!is_followed && !process_is_known(per_process_id)) {
         return 0;
     }
 
@@ -145,4 +163,83 @@ int tracer_exit_munmap(struct trace_event_raw_sys_exit *ctx) {
     return 0;
 }
 
+// Follows the new processes forked by the followed ones. Threads aren't new
+// processes, so they are skipped.
+SEC("tp_btf/sched_process_fork")
+int BPF_PROG(tracer_process_fork, struct task_struct *parent, struct task_struct *child) {
+    unsigned int level = BPF_CORE_READ(parent, nsproxy, pid_ns_for_children, level);
+    int per_process_id = BPF_CORE_READ(parent, group_leader, thread_pid, numbers[level].nr);
+
+    if (BPF_CORE_READ(child, pid) != BPF_CORE_READ(child, tgid)) {
+        return 0;
+    }
+
+    if (bpf_map_lookup_elem(&followed_processes, &per_process_id) == NULL) {
+        return 0;
+    }
+
+    int child_per_process_id = BPF_CORE_READ(child, thread_pid, numbers[level].nr);
+    bool followed = true;
+    if (bpf_map_update_elem(&followed_processes, &child_per_process_id, &followed, BPF_ANY) < 0) {
+        LOG("[error] failed to follow child process %d", child_per_process_id);
+        return 0;
+    }
+
+    tracer_event_t event = {
+        .type = TRACER_EVENT_TYPE_FORK,
+        .pid = child_per_process_id,
+        .start_address = 0,
+    };
+    if (send_tracer_event(ctx, &event) < 0) {
+        LOG("[error] failed to send fork tracer event");
+    }
+    return 0;
+}
+
+// Reports followed processes that execute a new program, as their memory
+// mappings are replaced.
+SEC("tp_btf/sched_process_exec")
+int BPF_PROG(tracer_process_exec, struct task_struct *p, pid_t old_pid, struct linux_binprm *bprm) {
+    unsigned int level = BPF_CORE_READ(p, nsproxy, pid_ns_for_children, level);
+    int per_process_id = BPF_CORE_READ(p, group_leader, thread_pid, numbers[level].nr);
+
+    if (bpf_map_lookup_elem(&followed_processes, &per_process_id) == NULL) {
+        return 0;
+    }
+
+    tracer_event_t event = {
+        .type = TRACER_EVENT_TYPE_EXEC,
+        .pid = per_process_id,
+        .start_address = 0,
+    };
+    if (send_tracer_event(ctx, &event) < 0) {
+        LOG("[error] failed to send exec tracer event");
+    }
+    return 0;
+}
+
+// Records when threads of the processes we profile are scheduled out. This
+// runs in the context of the task being switched out. The off-CPU unwinder
+// reports the stack once the thread runs again.
+SEC("tracepoint/sched/sched_switch")
+int tracer_sched_switch(void *ctx) {
+    struct task_struct *task = (struct task_struct *)bpf_get_current_task_btf();
+    unsigned int level = BPF_CORE_READ(task, nsproxy, pid_ns_for_children, level);
+    int per_process_id = BPF_CORE_READ(task, group_leader, thread_pid, numbers[level].nr);
+
+    if (per_process_id == 0) {
+        return 0;
+    }
+
+    if (!process_is_known(per_process_id)) {
+        return 0;
+    }
+
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    u64 now = bpf_ktime_get_ns();
+    bpf_map_update_elem(&off_cpu_start, &pid_tgid, &now, BPF_ANY);
+
+    return 0;
+}
+
 char LICENSE[] SEC("license") = "Dual MIT/GPL";
@@ -14,6 +14,8 @@ impl From<tracer_event_t> for TracerEvent {
             tracer_event_type_TRACER_EVENT_TYPE_MUNMAP => {
                 TracerEvent::Munmap(event.pid, event.start_address)
             }
+            tracer_event_type_TRACER_EVENT_TYPE_FORK => TracerEvent::Fork(event.pid),
+            tracer_event_type_TRACER_EVENT_TYPE_EXEC => TracerEvent::Exec(event.pid),
             _ => {
                 panic!("invalid event type {}, should never happen", event.type_);
             }
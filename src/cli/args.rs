@@ -1,11 +1,15 @@
 use clap::Parser;
 use clap::Subcommand;
+use regex::Regex;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::time::Duration;
 
+use lightswitch::perf_events::PerfEvent;
 use lightswitch::profiler::ProfilerConfig;
 
 use crate::validators::parse_duration;
+use crate::validators::parse_listen_address;
 use crate::validators::sample_freq_in_range;
 use crate::validators::value_is_power_of_two;
 
@@ -41,6 +45,8 @@ pub(crate) enum ProfileSender {
     #[default]
     LocalDisk,
     Remote,
+    /// Export to an OpenTelemetry collector with OTLP/HTTP.
+    Otlp,
 }
 
 #[derive(PartialEq, clap::ValueEnum, Debug, Clone, Default)]
@@ -60,9 +66,47 @@ pub(crate) enum DebugInfoBackend {
 
 #[derive(Subcommand, Debug)]
 pub(crate) enum Commands {
-    ObjectInfo { path: String },
-    ShowUnwind { path: String },
+    ObjectInfo {
+        path: String,
+    },
+    ShowUnwind {
+        path: String,
+    },
     SystemInfo,
+    /// Run a command and profile it until it exits
+    Run {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Keep the profiler running and control its sessions through a local API
+    Daemon(DaemonArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct DaemonArgs {
+    /// Unix socket the HTTP control API is served on
+    #[arg(long, default_value = "/run/lightswitch/control.sock")]
+    pub(crate) socket_path: PathBuf,
+    /// Also serve Go's pprof profile endpoint, /debug/pprof/profile, on this port of
+    /// localhost, such as 6060, or on this address. It's unauthenticated, so only bind
+    /// it to other interfaces on trusted networks
+    #[arg(long, value_name = "[ADDRESS:]PORT", value_parser = parse_listen_address)]
+    pub(crate) pprof_address: Option<SocketAddr>,
+    /// How long profiles are kept for, in seconds, so they can be captured after the fact
+    #[arg(long, default_value = "600", value_parser = parse_duration)]
+    pub(crate) retention: Duration,
+    /// Approximate max size in megabytes of the profiles that are kept. The oldest ones
+    /// are dropped earlier if needed
+    #[arg(long, default_value_t = 64)]
+    pub(crate) retention_memory_mb: usize,
+    /// Where captured profiles are written to. Defaults to a directory in the cache dir
+    #[arg(long)]
+    pub(crate) capture_dir: Option<PathBuf>,
+    /// Capture the kept profiles when this file is created. It can contain the start
+    /// and end of the span of time to capture, as Unix timestamps or local times such as
+    /// 14:02
+    #[arg(long)]
+    pub(crate) capture_trigger_path: Option<PathBuf>,
 }
 
 #[derive(Parser, Debug)]
@@ -70,6 +114,30 @@ pub(crate) struct CliArgs {
     /// Specific PIDs to profile
     #[arg(long)]
     pub(crate) pids: Vec<i32>,
+    /// Also profile the processes forked by the ones in --pids, or by the command that is
+    /// run, and their descendants
+    #[arg(long)]
+    pub(crate) follow_children: bool,
+    /// Profile the processes in this cgroup, such as /system.slice, and its descendants
+    #[arg(long)]
+    pub(crate) cgroup: Vec<PathBuf>,
+    /// Profile the processes of this systemd unit
+    #[arg(long)]
+    pub(crate) systemd_unit: Vec<String>,
+    /// Profile the processes running in the container with this ID, or ID prefix
+    #[arg(long)]
+    pub(crate) container_id: Vec<String>,
+    /// Profile the processes whose name matches this regular expression
+    #[arg(long, value_name = "REGEX")]
+    pub(crate) process_name: Vec<Regex>,
+    /// Label the samples of processes running in containers with their container ID
+    #[arg(long)]
+    pub(crate) container_metadata: bool,
+    /// CRI socket of the container runtime, such as /run/containerd/containerd.sock, to
+    /// label the samples of Kubernetes pods with their pod, namespace and container names.
+    /// Needs the cri feature
+    #[arg(long, value_name = "PATH", requires = "container_metadata")]
+    pub(crate) cri_socket: Option<PathBuf>,
     /// How long this agent will run in seconds
     #[arg(short='D', long, default_value = ProfilerConfig::default().duration.as_secs().to_string(),
         value_parser = parse_duration)]
@@ -90,6 +158,17 @@ pub(crate) struct CliArgs {
       value_parser = sample_freq_in_range,
     )]
     pub(crate) sample_freq: u64,
+    /// Events that trigger the samples: cpu-clock, cycles, instructions, cache-misses,
+    /// branch-misses, page-faults, context-switches or a raw PMU event code, such as r1c4.
+    /// Can be repeated or comma separated to sample several events in the same session.
+    /// Hardware events fall back to cpu-clock if there is no PMU
+    #[arg(long = "event", value_name = "EVENT", value_delimiter = ',',
+      default_values_t = [PerfEvent::CpuClock],
+    )]
+    pub(crate) events: Vec<PerfEvent>,
+    /// Take a sample every this many events rather than using the sampling frequency
+    #[arg(long, conflicts_with = "sample_freq")]
+    pub(crate) sample_period: Option<u64>,
     /// Output file for Flame Graph in SVG format
     #[arg(long, default_value_t, value_enum)]
     pub(crate) profile_format: ProfileFormat,
@@ -130,6 +209,14 @@ pub(crate) struct CliArgs {
     pub(crate) symbolizer: Symbolizer,
     #[arg(long, default_value_t, value_enum)]
     pub(crate) debug_info_backend: DebugInfoBackend,
+    /// Where the copy debug info backend stores debug information, by build id. Defaults
+    /// to a directory in the cache dir
+    #[arg(long)]
+    pub(crate) debug_info_store_path: Option<PathBuf>,
+    /// debuginfod servers to fetch the debug information of stripped binaries from when
+    /// symbolizing locally. Defaults to the ones in DEBUGINFOD_URLS
+    #[arg(long, value_name = "URL", value_delimiter = ',')]
+    pub(crate) debuginfod_urls: Vec<String>,
     #[arg(
         long,
         default_value_t = ProfilerConfig::default().max_native_unwind_info_size_mb,
@@ -152,6 +239,30 @@ pub(crate) struct CliArgs {
     pub(crate) unsafe_start: bool,
     #[arg(long, help = "force perf buffers even if ring buffers can be used")]
     pub(crate) force_perf_buffer: bool,
+    /// Also profile the time threads spend blocked, such as waiting on I/O or locks
+    #[arg(long)]
+    pub(crate) off_cpu: bool,
+    /// Only take samples while the CPU usage of this process is over
+    /// --cpu-trigger-threshold
+    #[arg(long, value_name = "PID", conflicts_with = "cpu_trigger_cgroup")]
+    pub(crate) cpu_trigger_pid: Option<i32>,
+    /// Only take samples while the CPU usage of this cgroup, such as
+    /// /system.slice/nginx.service, is over --cpu-trigger-threshold
+    #[arg(long, value_name = "CGROUP")]
+    pub(crate) cpu_trigger_cgroup: Option<PathBuf>,
+    /// CPU usage that starts the sampling, in percent of one CPU. It can be over 100 for
+    /// processes and cgroups that use several CPUs
+    #[arg(long, default_value_t = 80.0, value_name = "PERCENT")]
+    pub(crate) cpu_trigger_threshold: f64,
+    /// For how long, in seconds, the CPU usage has to stay below the threshold to stop
+    /// the sampling
+    #[arg(long, default_value = "60", value_parser = parse_duration)]
+    pub(crate) cpu_trigger_cooldown: Duration,
+    /// Lower the sampling frequency when lightswitch, including its BPF programs, uses
+    /// more than this percentage of the CPUs, and raise it back up to --sample-freq once
+    /// it uses less
+    #[arg(long, value_name = "PERCENT", conflicts_with = "sample_period")]
+    pub(crate) cpu_overhead_budget: Option<f64>,
     #[command(subcommand)]
     pub(crate) command: Option<Commands>,
 }
@@ -0,0 +1,168 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use nix::libc;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+/// Signal that requests a capture of every profile that is kept.
+pub const CAPTURE_SIGNAL: Signal = Signal::SIGUSR1;
+
+static CAPTURE_SIGNALED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_capture_signal(_: libc::c_int) {
+    CAPTURE_SIGNALED.store(true, Ordering::Relaxed);
+}
+
+/// Span of time to capture the profile of. Unset bounds are the oldest and newest
+/// samples that are kept.
+#[derive(Debug, Default, PartialEq)]
+pub struct CaptureRange {
+    pub start: Option<SystemTime>,
+    pub end: Option<SystemTime>,
+}
+
+impl CaptureRange {
+    /// Parses `[START [END]]`, with the formats supported by [`parse_time`].
+    pub fn parse(range: &str, now: SystemTime) -> Result<Self, String> {
+        let mut times = range
+            .split_whitespace()
+            .map(|time| parse_time(time, now).ok_or_else(|| format!("invalid time {time:?}")));
+        let range = Self {
+            start: times.next().transpose()?,
+            end: times.next().transpose()?,
+        };
+        if times.next().is_some() {
+            return Err("expected a start and an end time at most".into());
+        }
+        Ok(range)
+    }
+}
+
+/// Parses a point in time, either a Unix timestamp in seconds or a local time of the
+/// day, `HH:MM` or `HH:MM:SS`, within the 24 hours before `now`.
+pub fn parse_time(time: &str, now: SystemTime) -> Option<SystemTime> {
+    if let Ok(seconds) = time.parse() {
+        return Some(UNIX_EPOCH + Duration::from_secs(seconds));
+    }
+
+    let mut parts = time.split(':').map(|part| part.parse::<libc::c_int>().ok());
+    let hour = parts.next()??;
+    let minute = parts.next()??;
+    let second = parts.next().unwrap_or(Some(0))?;
+    if parts.next().is_some()
+        || !(0..24).contains(&hour)
+        || !(0..60).contains(&minute)
+        || !(0..60).contains(&second)
+    {
+        return None;
+    }
+
+    let now_seconds = now.duration_since(UNIX_EPOCH).ok()?.as_secs() as libc::time_t;
+    // SAFETY: `tm` is a plain C struct, which is filled in by `localtime_r`.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now_seconds, &mut tm) }.is_null() {
+        return None;
+    }
+    tm.tm_hour = hour;
+    tm.tm_min = minute;
+    tm.tm_sec = second;
+    // Let `mktime` figure out whether daylight saving time applies.
+    tm.tm_isdst = -1;
+    let seconds = unsafe { libc::mktime(&mut tm) };
+    if seconds < 0 {
+        return None;
+    }
+
+    let time = UNIX_EPOCH + Duration::from_secs(seconds as u64);
+    if time > now {
+        // It's a time of yesterday.
+        return time.checked_sub(Duration::from_secs(24 * 60 * 60));
+    }
+    Some(time)
+}
+
+/// Makes [`CAPTURE_SIGNAL`] request a capture, see [`take_signal_request`].
+pub fn install_signal_handler() -> nix::Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(handle_capture_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // SAFETY: the handler only stores to an atomic.
+    unsafe { sigaction(CAPTURE_SIGNAL, &action) }?;
+    Ok(())
+}
+
+/// Whether a capture was requested with [`CAPTURE_SIGNAL`] since the last call.
+pub fn take_signal_request() -> bool {
+    CAPTURE_SIGNALED.swap(false, Ordering::Relaxed)
+}
+
+/// Returns the capture requested by creating the file at `path`, if any, which can
+/// contain the range to capture. The file is removed so it only triggers one capture.
+pub fn take_file_request(path: &Path, now: SystemTime) -> Option<Result<CaptureRange, String>> {
+    let range = match fs::read_to_string(path) {
+        Ok(range) => range,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => return Some(Err(e.to_string())),
+    };
+    if let Err(e) = fs::remove_file(path) {
+        return Some(Err(e.to_string()));
+    }
+    Some(CaptureRange::parse(&range, now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_time() {
+        let now = SystemTime::now();
+        assert_eq!(
+            parse_time("1700000000", now),
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+        for time in ["00:00", "12:30", "23:59:59"] {
+            let parsed = parse_time(time, now).unwrap();
+            assert!(parsed <= now, "{time} should be in the past");
+            assert!(
+                now.duration_since(parsed).unwrap() < Duration::from_secs(24 * 60 * 60),
+                "{time} should be in the last day"
+            );
+        }
+        for time in ["24:00", "12:60", "12:00:00:00", "-1", "noon", ""] {
+            assert_eq!(parse_time(time, now), None, "{time}");
+        }
+    }
+
+    #[test]
+    fn test_take_file_request() {
+        let now = SystemTime::now();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capture");
+        assert!(take_file_request(&path, now).is_none());
+
+        fs::write(&path, "").unwrap();
+        assert_eq!(
+            take_file_request(&path, now),
+            Some(Ok(CaptureRange::default()))
+        );
+        assert!(!path.exists());
+
+        fs::write(&path, "1700000000 1700000060\n").unwrap();
+        assert_eq!(
+            take_file_request(&path, now),
+            Some(Ok(CaptureRange {
+                start: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+                end: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_060)),
+            }))
+        );
+
+        fs::write(&path, "1 2 3").unwrap();
+        assert!(matches!(take_file_request(&path, now), Some(Err(_))));
+    }
+}
@@ -0,0 +1,155 @@
+use std::ffi::{CString, NulError};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+
+use nix::sys::signal::{kill, raise, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{execvp, fork, ForkResult, Pid};
+use thiserror::Error;
+
+/// Exit code of commands that could not be executed, as used by shells.
+const EXEC_FAILED_EXIT_CODE: i32 = 127;
+
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("no command to run")]
+    Empty,
+    #[error("command arguments can't contain nul bytes")]
+    Nul(#[from] NulError),
+    #[error("command didn't stop before executing, status: {0:?}")]
+    NotStopped(WaitStatus),
+    #[error(transparent)]
+    Nix(#[from] nix::Error),
+}
+
+/// A command started by lightswitch, which is stopped before it executes so it can
+/// be profiled from the start.
+pub struct StoppedCommand {
+    pid: Pid,
+}
+
+/// A command that has been resumed.
+pub struct RunningCommand {
+    exit_code: Arc<OnceLock<i32>>,
+}
+
+impl StoppedCommand {
+    pub fn spawn(command: &[String]) -> Result<Self, CommandError> {
+        let args = command
+            .iter()
+            .map(|arg| CString::new(arg.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let Some(program) = args.first() else {
+            return Err(CommandError::Empty);
+        };
+
+        // SAFETY: the child only calls async-signal-safe functions until it executes
+        // the command.
+        match unsafe { fork() }? {
+            ForkResult::Child => {
+                let _ = raise(Signal::SIGSTOP);
+                let _ = execvp(program, &args);
+                unsafe { nix::libc::_exit(EXEC_FAILED_EXIT_CODE) }
+            }
+            ForkResult::Parent { child } => match waitpid(child, Some(WaitPidFlag::WUNTRACED))? {
+                WaitStatus::Stopped(_, Signal::SIGSTOP) => Ok(Self { pid: child }),
+                status => Err(CommandError::NotStopped(status)),
+            },
+        }
+    }
+
+    pub fn pid(&self) -> i32 {
+        self.pid.as_raw()
+    }
+
+    /// Lets the command execute. `on_exit` is called once it has exited.
+    pub fn resume(
+        self,
+        on_exit: impl FnOnce(i32) + Send + 'static,
+    ) -> Result<RunningCommand, CommandError> {
+        let exit_code = Arc::new(OnceLock::new());
+        let pid = self.pid;
+        kill(pid, Signal::SIGCONT)?;
+        // It's waited for below rather than killed once dropped.
+        std::mem::forget(self);
+
+        let exit_code_clone = exit_code.clone();
+        thread::Builder::new()
+            .name("command-wait-thread".to_string())
+            .spawn(move || {
+                let code = loop {
+                    match waitpid(pid, None) {
+                        Ok(WaitStatus::Exited(_, code)) => break code,
+                        // Like shells do, signals are reported after the highest exit code.
+                        Ok(WaitStatus::Signaled(_, signal, _)) => break 128 + signal as i32,
+                        Ok(_) | Err(nix::Error::EINTR) => continue,
+                        Err(_) => break EXEC_FAILED_EXIT_CODE,
+                    }
+                };
+                let _ = exit_code_clone.set(code);
+                on_exit(code);
+            })
+            .expect("spawn command wait thread");
+
+        Ok(RunningCommand { exit_code })
+    }
+}
+
+impl Drop for StoppedCommand {
+    /// Kills and reaps the command, so it isn't left stopped if lightswitch fails
+    /// before resuming it.
+    fn drop(&mut self) {
+        if kill(self.pid, Signal::SIGKILL).is_ok() {
+            let _ = waitpid(self.pid, None);
+        }
+    }
+}
+
+impl RunningCommand {
+    /// The exit code of the command, once it has exited.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code.get().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::bounded;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_command_exit_code() {
+        let command = StoppedCommand::spawn(&["sh".into(), "-c".into(), "exit 3".into()]).unwrap();
+        let stat = fs::read_to_string(format!("/proc/{}/stat", command.pid())).unwrap();
+        assert!(stat.contains(") T "), "command should be stopped: {stat}");
+
+        let (exited_send, exited_receive) = bounded(1);
+        let running = command
+            .resume(move |code| exited_send.send(code).unwrap())
+            .unwrap();
+        assert_eq!(exited_receive.recv().unwrap(), 3);
+        assert_eq!(running.exit_code(), Some(3));
+    }
+
+    #[test]
+    fn test_command_killed_when_dropped() {
+        let command = StoppedCommand::spawn(&["sleep".into(), "60".into()]).unwrap();
+        let proc_path = format!("/proc/{}", command.pid());
+        assert!(Path::new(&proc_path).exists());
+
+        drop(command);
+        assert!(!Path::new(&proc_path).exists());
+    }
+
+    #[test]
+    fn test_command_not_found() {
+        let command = StoppedCommand::spawn(&["/nonexistent".into()]).unwrap();
+        let (exited_send, exited_receive) = bounded(1);
+        command
+            .resume(move |code| exited_send.send(code).unwrap())
+            .unwrap();
+        assert_eq!(exited_receive.recv().unwrap(), EXEC_FAILED_EXIT_CODE);
+    }
+}
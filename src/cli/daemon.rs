@@ -0,0 +1,821 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crossbeam_channel::Sender;
+use lightswitch::collector::{RecentProfile, RecentProfilesCollector, ThreadSafeCollector};
+use lightswitch::debug_info::DebugInfoSources;
+use lightswitch::process::Pid;
+use lightswitch::process_selector::{process_is_selected, ProcessSelector};
+use lightswitch::profile::{
+    fold_profile, symbolize_profile, to_pprof, AggregatedProfile, SampleKind,
+};
+use lightswitch::profiler::{Profiler, ProfilerControl};
+use lightswitch_metadata::metadata_provider::ThreadSafeGlobalMetadataProvider;
+use prost::Message;
+use regex::Regex;
+use tracing::{error, info, warn};
+
+use crate::capture::{
+    install_signal_handler, parse_time, take_file_request, take_signal_request, CaptureRange,
+    CAPTURE_SIGNAL,
+};
+use crate::http::{handle_connection, ConnectionLimit, Request, Response, Stream, IO_TIMEOUT};
+
+/// Profile duration used when requests don't specify one, the same as Go's.
+const DEFAULT_PROFILE_SECONDS: u64 = 30;
+/// How long to wait for the profiler to collect the samples taken so far.
+const COLLECTION_TIMEOUT: Duration = Duration::from_secs(10);
+/// Path of the endpoint compatible with Go's `net/http/pprof`.
+const PPROF_PROFILE_PATH: &str = "/debug/pprof/profile";
+/// How often to check whether a capture was requested with a signal or a file.
+const CAPTURE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Connections that each API serves at the same time, the rest get a 503.
+const MAX_CONNECTIONS: usize = 8;
+
+pub struct DaemonConfig {
+    /// Unix socket the control API is served on.
+    pub socket_path: PathBuf,
+    /// Address Go's pprof profile endpoint is served on, if any.
+    pub pprof_address: Option<SocketAddr>,
+    /// How long profiles are kept for.
+    pub retention: Duration,
+    /// Bytes the samples that are kept can take.
+    pub memory_budget: usize,
+    /// Where captured profiles are written to.
+    pub capture_dir: PathBuf,
+    /// File whose creation requests a capture, if any.
+    pub capture_trigger_path: Option<PathBuf>,
+}
+
+/// Turns profiles into the formats the APIs serve.
+pub struct ProfileExporter {
+    pub symbolize: bool,
+    pub debug_info: DebugInfoSources,
+    pub metadata_provider: ThreadSafeGlobalMetadataProvider,
+}
+
+impl ProfileExporter {
+    fn symbolized(&self, recent: &RecentProfile) -> AggregatedProfile {
+        if self.symbolize {
+            symbolize_profile(
+                &recent.profile,
+                &recent.procs,
+                &recent.objs,
+                &self.debug_info,
+            )
+        } else {
+            recent.profile.clone()
+        }
+    }
+
+    /// Pprof profile with the duration and sampling periods the samples were taken with.
+    pub fn pprof(&self, recent: RecentProfile) -> Vec<u8> {
+        let profile = self.symbolized(&recent);
+        to_pprof(
+            profile,
+            &recent.procs,
+            &recent.objs,
+            &self.metadata_provider,
+            recent.duration,
+            &recent.event_configs,
+        )
+        .encode_to_vec()
+    }
+
+    /// Folded stacks of the on-CPU samples of the first event.
+    pub fn folded(&self, recent: RecentProfile) -> String {
+        let profile = self
+            .symbolized(&recent)
+            .into_iter()
+            .filter(|sample| sample.kind == SampleKind::OnCpu && sample.event == 0)
+            .collect();
+        fold_profile(profile, true)
+    }
+
+    /// Responds with the profile in the `format` query parameter, pprof by default.
+    pub fn response(&self, request: &Request, recent: RecentProfile) -> Response {
+        match request.query_value("format").unwrap_or("pprof") {
+            "pprof" => Response::new(200, "application/octet-stream", self.pprof(recent)),
+            "folded" => Response::new(200, "text/plain; charset=utf-8", self.folded(recent)),
+            format => Response::text(400, format!("unknown format {format:?}")),
+        }
+    }
+}
+
+/// Processes to profile. Every process is profiled if there are neither pids nor
+/// selectors.
+#[derive(Clone, Default)]
+pub struct Filters {
+    pub pids: Vec<Pid>,
+    pub selectors: Vec<ProcessSelector>,
+}
+
+impl Filters {
+    /// Processes selected by the query parameters `pid`, `cgroup`, `systemd_unit`,
+    /// `container_id`, `process_name`, a regular expression, and `comm`, an exact process
+    /// name. Each of them can be repeated.
+    pub fn from_query(request: &Request) -> Result<Self, String> {
+        let pids = request
+            .query_values("pid")
+            .map(|pid| pid.parse().map_err(|_| format!("invalid pid {pid:?}")))
+            .collect::<Result<Vec<Pid>, _>>()?;
+
+        let mut selectors: Vec<ProcessSelector> = request
+            .query_values("cgroup")
+            .map(|path| ProcessSelector::cgroup(Path::new(path)))
+            .collect();
+        selectors.extend(
+            request
+                .query_values("systemd_unit")
+                .map(ProcessSelector::systemd_unit),
+        );
+        selectors.extend(
+            request
+                .query_values("container_id")
+                .map(|id| ProcessSelector::ContainerId(id.to_string())),
+        );
+        for name in request.query_values("process_name") {
+            let regex = Regex::new(name).map_err(|e| format!("invalid process name regex: {e}"))?;
+            selectors.push(ProcessSelector::ProcessName(regex));
+        }
+        for comm in request.query_values("comm") {
+            let regex = Regex::new(&format!("^{}$", regex::escape(comm)))
+                .map_err(|e| format!("invalid comm: {e}"))?;
+            selectors.push(ProcessSelector::ProcessName(regex));
+        }
+
+        Ok(Self { pids, selectors })
+    }
+
+    fn control(&self) -> ProfilerControl {
+        ProfilerControl::SetFilters {
+            pids: self.pids.clone(),
+            selectors: self.selectors.clone(),
+        }
+    }
+
+    /// Why some of the processes selected by `requested` might not be selected by
+    /// these filters, if that's the case.
+    fn missing_from(&self, requested: &Filters) -> Option<String> {
+        if self.pids.is_empty() && self.selectors.is_empty() {
+            return None;
+        }
+        if requested.pids.is_empty() && requested.selectors.is_empty() {
+            return Some("the running session doesn't profile every process".into());
+        }
+        if let Some(pid) = requested.pids.iter().find(|pid| {
+            !self.pids.contains(pid)
+                && (self.selectors.is_empty() || !process_is_selected(&self.selectors, **pid))
+        }) {
+            return Some(format!("the running session doesn't profile pid {pid}"));
+        }
+        // Cgroups are covered by the selectors of any of their ancestors.
+        let covered = |selector: &ProcessSelector| match selector {
+            ProcessSelector::Cgroup(path) => self
+                .selectors
+                .iter()
+                .any(|running| running.matches_cgroup(path)),
+            _ => self.selectors.contains(selector),
+        };
+        if let Some(selector) = requested
+            .selectors
+            .iter()
+            .find(|selector| !covered(selector))
+        {
+            return Some(format!(
+                "the running session might not profile the processes selected by {selector:?}"
+            ));
+        }
+        None
+    }
+
+    /// Keeps the samples of the selected processes. As processes are selected by what
+    /// they run in now, the ones that have exited since they were sampled are dropped,
+    /// unless they are selected by their pid.
+    fn retain_selected(&self, profile: &mut AggregatedProfile) {
+        if self.pids.is_empty() && self.selectors.is_empty() {
+            return;
+        }
+        let mut selected = HashMap::new();
+        profile.retain(|sample| {
+            *selected.entry(sample.pid).or_insert_with(|| {
+                self.pids.contains(&sample.pid)
+                    || (!self.selectors.is_empty()
+                        && process_is_selected(&self.selectors, sample.pid))
+            })
+        });
+    }
+}
+
+struct DaemonState {
+    paused: bool,
+    /// Filters the profiler was last given.
+    filters: Filters,
+    /// Query of the last filters set through the API, if any.
+    filters_query: Option<String>,
+    /// Whether the profiler is sampling just for a pprof request.
+    on_demand_session: bool,
+}
+
+/// Local API to control a profiler that keeps running, served over HTTP.
+pub struct ControlApi {
+    control: Sender<ProfilerControl>,
+    recent_profiles: RecentProfilesCollector,
+    exporter: ProfileExporter,
+    capture_dir: PathBuf,
+    state: Mutex<DaemonState>,
+}
+
+impl ControlApi {
+    pub fn new(
+        control: Sender<ProfilerControl>,
+        recent_profiles: RecentProfilesCollector,
+        exporter: ProfileExporter,
+        filters: Filters,
+        capture_dir: PathBuf,
+        paused: bool,
+    ) -> Self {
+        Self {
+            control,
+            recent_profiles,
+            exporter,
+            capture_dir,
+            state: Mutex::new(DaemonState {
+                paused,
+                filters,
+                filters_query: None,
+                on_demand_session: false,
+            }),
+        }
+    }
+
+    fn send(&self, control: ProfilerControl) -> Result<(), Response> {
+        self.control
+            .send(control)
+            .map_err(|_| Response::text(503, "the profiler isn't running"))
+    }
+
+    /// Asks the profiler to collect the samples taken so far, returning when they were
+    /// collected.
+    fn collect(&self) -> Result<SystemTime, Response> {
+        let requested_at = SystemTime::now();
+        self.send(ProfilerControl::Collect)?;
+        self.recent_profiles
+            .wait_for_collection(requested_at, COLLECTION_TIMEOUT)
+            .ok_or_else(|| Response::text(503, "the profiler didn't collect the samples in time"))
+    }
+
+    /// Profile of the samples taken during the next `duration`.
+    fn take_profile(&self, duration: Duration) -> Result<RecentProfile, Response> {
+        let start = self.collect()?;
+        thread::sleep(duration);
+        let end = self.collect()?;
+        Ok(self.recent_profiles.profile_between(start, end))
+    }
+
+    /// Profile of the samples taken in `range`.
+    fn profile_in_range(&self, range: &CaptureRange) -> RecentProfile {
+        self.recent_profiles.profile_between(
+            range.start.unwrap_or(UNIX_EPOCH),
+            range.end.unwrap_or_else(SystemTime::now),
+        )
+    }
+
+    /// Writes the profile of the samples taken in `range` to the captures directory,
+    /// named after the span of time it covers. Returns where it was written to.
+    pub fn capture(&self, range: &CaptureRange) -> io::Result<PathBuf> {
+        let recent = self.profile_in_range(range);
+        let unix_seconds = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        };
+        let path = self.capture_dir.join(format!(
+            "capture-{}-{}.pb",
+            unix_seconds(recent.start),
+            unix_seconds(recent.end)
+        ));
+        fs::create_dir_all(&self.capture_dir)?;
+        fs::write(&path, self.exporter.pprof(recent))?;
+        Ok(path)
+    }
+
+    /// Profiles the processes in the query for `seconds`, like Go's `net/http/pprof`
+    /// does. If no session is running one is started for the request.
+    fn pprof_profile(&self, request: &Request) -> Response {
+        let duration = match self.seconds_from_query(request) {
+            Ok(duration) => duration,
+            Err(response) => return response,
+        };
+        let filters = match Filters::from_query(request) {
+            Ok(filters) => filters,
+            Err(e) => return Response::text(400, e),
+        };
+
+        let on_demand = {
+            let mut state = self.state.lock().unwrap();
+            if state.on_demand_session {
+                return Response::text(409, "another profile is being taken");
+            }
+            if !state.paused {
+                // Its samples would be missing from the profile rather than be taken.
+                if let Some(reason) = state.filters.missing_from(&filters) {
+                    return Response::text(
+                        409,
+                        format!("{reason}, change its filters with POST /filters or stop it"),
+                    );
+                }
+            }
+            if state.paused {
+                if let Err(response) = self
+                    .send(filters.control())
+                    .and_then(|_| self.send(ProfilerControl::Resume))
+                {
+                    return response;
+                }
+                state.on_demand_session = true;
+            }
+            state.on_demand_session
+        };
+
+        let recent = self.take_profile(duration);
+
+        if on_demand {
+            let mut state = self.state.lock().unwrap();
+            let _ = self
+                .send(ProfilerControl::Pause)
+                .and_then(|_| self.send(state.filters.control()));
+            state.on_demand_session = false;
+        }
+
+        match recent {
+            Ok(mut recent) => {
+                // The session that was already running might sample other processes.
+                if !on_demand {
+                    filters.retain_selected(&mut recent.profile);
+                }
+                Response::new(200, "application/octet-stream", self.exporter.pprof(recent))
+            }
+            Err(response) => response,
+        }
+    }
+
+    fn handle(&self, request: &Request) -> Response {
+        let expected_method = match request.path.as_str() {
+            "/status" | "/profile" | PPROF_PROFILE_PATH => "GET",
+            "/session/start" | "/session/stop" | "/filters" | "/capture" => "POST",
+            _ => return Response::text(404, "not found"),
+        };
+        if request.method != expected_method {
+            return Response::text(405, format!("use {expected_method}"));
+        }
+
+        match request.path.as_str() {
+            "/profile" => {
+                let recent = match range_from_query(request) {
+                    Ok(Some(range)) => self.profile_in_range(&range),
+                    Ok(None) => match self.seconds_from_query(request) {
+                        Ok(duration) => self.recent_profiles.profile(duration),
+                        Err(response) => return response,
+                    },
+                    Err(response) => return response,
+                };
+                return self.exporter.response(request, recent);
+            }
+            "/capture" => {
+                let range = match range_from_query(request) {
+                    Ok(range) => range.unwrap_or_default(),
+                    Err(response) => return response,
+                };
+                return match self.capture(&range) {
+                    Ok(path) => Response::text(200, path.display().to_string()),
+                    Err(e) => Response::text(500, format!("failed to write capture: {e}")),
+                };
+            }
+            PPROF_PROFILE_PATH => return self.pprof_profile(request),
+            _ => {}
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if request.path == "/status" {
+            return Response::text(
+                200,
+                format!(
+                    "sampling: {}\nfilters: {}",
+                    match (state.paused, state.on_demand_session) {
+                        (_, true) => "started for a pprof request",
+                        (true, false) => "stopped",
+                        (false, false) => "started",
+                    },
+                    match state.filters_query.as_deref() {
+                        None => "the ones given on the command line",
+                        Some("") => "none, every process is profiled",
+                        Some(filters) => filters,
+                    }
+                ),
+            );
+        }
+        if state.on_demand_session {
+            return Response::text(409, "a profile is being taken for a pprof request");
+        }
+
+        let result = match request.path.as_str() {
+            "/session/start" => self
+                .send(ProfilerControl::Resume)
+                .map(|_| state.paused = false),
+            "/session/stop" => self
+                .send(ProfilerControl::Pause)
+                .map(|_| state.paused = true),
+            _ => match Filters::from_query(request) {
+                Ok(filters) => self.send(filters.control()).map(|_| {
+                    let query = request
+                        .query
+                        .iter()
+                        .map(|(key, value)| format!("{key}={value}"))
+                        .collect::<Vec<_>>();
+                    state.filters = filters;
+                    state.filters_query = Some(query.join(" "));
+                }),
+                Err(e) => Err(Response::text(400, e)),
+            },
+        };
+
+        match result {
+            Ok(()) => Response::text(200, "ok"),
+            Err(response) => response,
+        }
+    }
+
+    /// Duration in the `seconds` query parameter, up to how long profiles are kept for.
+    fn seconds_from_query(&self, request: &Request) -> Result<Duration, Response> {
+        let seconds = match request.query_value("seconds").map(str::parse) {
+            None => DEFAULT_PROFILE_SECONDS,
+            Some(Ok(seconds)) => seconds,
+            Some(Err(_)) => return Err(Response::text(400, "invalid seconds")),
+        };
+        Ok(Duration::from_secs(seconds).min(self.recent_profiles.max_age()))
+    }
+
+    /// Only serves the endpoint compatible with Go's `net/http/pprof`.
+    fn handle_pprof(&self, request: &Request) -> Response {
+        if request.path != PPROF_PROFILE_PATH {
+            return Response::text(404, "not found");
+        }
+        self.handle(request)
+    }
+
+    /// Serves the requests of every connection with `handle`, on a thread per connection.
+    fn serve<S>(
+        self: Arc<Self>,
+        incoming: impl Iterator<Item = io::Result<S>>,
+        handle: fn(&Self, &Request) -> Response,
+    ) where
+        S: Stream + Send + 'static,
+    {
+        let limit = ConnectionLimit::new(MAX_CONNECTIONS);
+        for stream in incoming {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("failed to accept API connection: {:?}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = stream.set_timeouts(Some(IO_TIMEOUT)) {
+                error!("failed to set API connection timeouts: {:?}", e);
+                continue;
+            }
+            let Some(guard) = limit.acquire() else {
+                warn!("too many API connections, rejecting one");
+                let _ = Response::text(503, "too many connections").write_to(&mut stream);
+                continue;
+            };
+            let api = self.clone();
+            thread::spawn(move || {
+                handle_connection(stream, |request| handle(&api, request));
+                drop(guard);
+            });
+        }
+    }
+}
+
+/// Span of time in the `start` and `end` query parameters, if any is set, with the
+/// formats supported by [`parse_time`].
+fn range_from_query(request: &Request) -> Result<Option<CaptureRange>, Response> {
+    let now = SystemTime::now();
+    let time = |name| {
+        request
+            .query_value(name)
+            .map(|time| {
+                parse_time(time, now).ok_or_else(|| Response::text(400, format!("invalid {name}")))
+            })
+            .transpose()
+    };
+    let range = CaptureRange {
+        start: time("start")?,
+        end: time("end")?,
+    };
+    Ok((range != CaptureRange::default()).then_some(range))
+}
+
+/// Creates the control API socket, only accessible to the user lightswitch runs as.
+fn bind(socket_path: &Path) -> io::Result<UnixListener> {
+    if let Some(parent) = socket_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Left behind by a previous run.
+    if socket_path.exists() {
+        fs::remove_file(socket_path)?;
+    }
+    let listener = UnixListener::bind(socket_path)?;
+    fs::set_permissions(socket_path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Captures the profiles requested with [`CAPTURE_SIGNAL`] or by creating the trigger
+/// file, if any.
+fn poll_capture_requests(api: &ControlApi, trigger_path: Option<&Path>) {
+    loop {
+        thread::sleep(CAPTURE_POLL_INTERVAL);
+        let mut requests = Vec::new();
+        if take_signal_request() {
+            requests.push(Ok(CaptureRange::default()));
+        }
+        if let Some(request) =
+            trigger_path.and_then(|path| take_file_request(path, SystemTime::now()))
+        {
+            requests.push(request);
+        }
+
+        for request in requests {
+            match request.and_then(|range| api.capture(&range).map_err(|e| e.to_string())) {
+                Ok(path) => info!("captured profile to {}", path.display()),
+                Err(e) => error!("failed to capture profile: {}", e),
+            }
+        }
+    }
+}
+
+/// Runs the profiler until it's stopped, with its samples only being taken during the
+/// sessions started with the control API. The profiles of the last
+/// [`DaemonConfig::retention`] are kept, so they can be captured after the fact.
+pub fn run(
+    profiler: Profiler,
+    config: DaemonConfig,
+    filters: Filters,
+    exporter: ProfileExporter,
+) -> Result<(), Box<dyn Error>> {
+    let recent_profiles = RecentProfilesCollector::new(config.retention, config.memory_budget);
+    let listener = bind(&config.socket_path)?;
+    let api = Arc::new(ControlApi::new(
+        profiler.control_sender(),
+        recent_profiles.clone(),
+        exporter,
+        filters,
+        config.capture_dir,
+        true,
+    ));
+
+    if let Some(pprof_address) = config.pprof_address {
+        if !pprof_address.ip().is_loopback() {
+            warn!(
+                "the pprof endpoint is unauthenticated and can be reached from other hosts on {}",
+                pprof_address
+            );
+        }
+        let pprof_listener = TcpListener::bind(pprof_address)?;
+        let api = api.clone();
+        thread::Builder::new()
+            .name("pprof-api".to_string())
+            .spawn(move || api.serve(pprof_listener.incoming(), ControlApi::handle_pprof))?;
+        info!(
+            "pprof endpoint listening on http://{}{}",
+            pprof_address, PPROF_PROFILE_PATH
+        );
+    }
+
+    install_signal_handler()?;
+    let capture_api = api.clone();
+    let capture_trigger_path = config.capture_trigger_path;
+    thread::Builder::new()
+        .name("capture-poll-thread".to_string())
+        .spawn(move || poll_capture_requests(&capture_api, capture_trigger_path.as_deref()))?;
+    info!("profiles are captured on {}", CAPTURE_SIGNAL);
+
+    thread::Builder::new()
+        .name("control-api".to_string())
+        .spawn(move || api.serve(listener.incoming(), ControlApi::handle))?;
+    info!("control API listening on {}", config.socket_path.display());
+
+    let collector: ThreadSafeCollector = Arc::new(Mutex::new(Box::new(recent_profiles)));
+    profiler.run(collector);
+    let _ = fs::remove_file(&config.socket_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::{unbounded, Receiver};
+    use lightswitch::collector::Collector;
+    use lightswitch_metadata::metadata_provider::GlobalMetadataProvider;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    fn request(method: &str, target: &str) -> Request {
+        crate::http::read_request(format!("{method} {target} HTTP/1.1\r\n\r\n").as_bytes()).unwrap()
+    }
+
+    fn control_api(capture_dir: &Path) -> (ControlApi, Receiver<ProfilerControl>) {
+        let (control_send, control_receive) = unbounded();
+        let api = ControlApi::new(
+            control_send,
+            RecentProfilesCollector::new(Duration::from_secs(60), usize::MAX),
+            ProfileExporter {
+                symbolize: false,
+                debug_info: DebugInfoSources::default(),
+                metadata_provider: Arc::new(Mutex::new(GlobalMetadataProvider::default())),
+            },
+            Filters {
+                pids: vec![7],
+                selectors: Vec::new(),
+            },
+            capture_dir.to_path_buf(),
+            true,
+        );
+        (api, control_receive)
+    }
+
+    #[test]
+    fn test_control_api() {
+        let (api, control_receive) = control_api(Path::new("/nonexistent"));
+
+        let status = |api: &ControlApi| {
+            String::from_utf8(api.handle(&request("GET", "/status")).body).unwrap()
+        };
+        assert!(status(&api).starts_with("sampling: stopped\n"));
+
+        assert_eq!(api.handle(&request("POST", "/session/start")).status, 200);
+        assert!(matches!(
+            control_receive.try_recv(),
+            Ok(ProfilerControl::Resume)
+        ));
+        assert!(status(&api).starts_with("sampling: started\n"));
+
+        let response = api.handle(&request(
+            "POST",
+            "/filters?pid=1&systemd_unit=nginx&process_name=%5Epython",
+        ));
+        assert_eq!(response.status, 200);
+        match control_receive.try_recv() {
+            Ok(ProfilerControl::SetFilters { pids, selectors }) => {
+                assert_eq!(pids, vec![1]);
+                assert_eq!(selectors.len(), 2);
+            }
+            _ => panic!("filters should have been set"),
+        }
+        assert!(status(&api).ends_with("filters: pid=1 systemd_unit=nginx process_name=^python\n"));
+
+        assert_eq!(api.handle(&request("POST", "/filters?pid=abc")).status, 400);
+        assert_eq!(
+            api.handle(&request("POST", "/filters?process_name=("))
+                .status,
+            400
+        );
+        assert!(control_receive.try_recv().is_err());
+
+        let response = api.handle(&request("GET", "/profile?seconds=10&format=folded"));
+        assert_eq!(response.status, 200);
+        assert!(response.body.is_empty());
+        assert_eq!(
+            api.handle(&request("GET", "/profile?format=svg")).status,
+            400
+        );
+
+        // The running session doesn't profile it, and it isn't changed for the request.
+        let response = api.handle_pprof(&request(
+            "GET",
+            "/debug/pprof/profile?seconds=0&pid=2147483647",
+        ));
+        assert_eq!(response.status, 409);
+        assert!(control_receive.try_recv().is_err());
+
+        assert_eq!(api.handle(&request("GET", "/session/stop")).status, 405);
+        assert_eq!(api.handle(&request("GET", "/unknown")).status, 404);
+        assert_eq!(api.handle_pprof(&request("GET", "/status")).status, 404);
+    }
+
+    #[test]
+    fn test_pprof_profile_session() {
+        let (api, control_receive) = control_api(Path::new("/nonexistent"));
+
+        // Stands in for the profiler, collecting an empty profile when asked to.
+        let mut collector = api.recent_profiles.clone();
+        let profiler = thread::spawn(move || {
+            let mut controls = Vec::new();
+            for control in control_receive {
+                if matches!(control, ProfilerControl::Collect) {
+                    collector.collect(Vec::new(), &HashMap::new(), &HashMap::new());
+                }
+                controls.push(control);
+            }
+            controls
+        });
+
+        let response = api.handle_pprof(&request(
+            "GET",
+            "/debug/pprof/profile?seconds=0&pid=123&comm=nginx",
+        ));
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            api.handle_pprof(&request("GET", "/debug/pprof/profile?pid=abc"))
+                .status,
+            400
+        );
+
+        drop(api);
+        let controls = profiler.join().unwrap();
+        assert_eq!(controls.len(), 6);
+        // A session is started for the request, with its filters.
+        assert!(matches!(
+            &controls[0],
+            ProfilerControl::SetFilters { pids, selectors } if pids == &[123] && selectors.len() == 1
+        ));
+        assert!(matches!(controls[1], ProfilerControl::Resume));
+        assert!(matches!(controls[2], ProfilerControl::Collect));
+        assert!(matches!(controls[3], ProfilerControl::Collect));
+        // Once done, the profiler is paused and given its previous filters back.
+        assert!(matches!(controls[4], ProfilerControl::Pause));
+        assert!(matches!(
+            &controls[5],
+            ProfilerControl::SetFilters { pids, .. } if pids == &[7]
+        ));
+    }
+
+    #[test]
+    fn test_serve_connection_limit() {
+        let (api, _control_receive) = control_api(Path::new("/nonexistent"));
+        let (clients, servers): (Vec<_>, Vec<_>) = (0..=MAX_CONNECTIONS)
+            .map(|_| UnixStream::pair().unwrap())
+            .unzip();
+        // The connections being served wait for their requests, so the last one is rejected.
+        Arc::new(api).serve(servers.into_iter().map(Ok), ControlApi::handle);
+
+        let read_response = |mut client: &UnixStream| {
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+        assert!(read_response(&clients[MAX_CONNECTIONS]).starts_with("HTTP/1.1 503 "));
+
+        let mut client = &clients[0];
+        client.write_all(b"GET /status HTTP/1.1\r\n\r\n").unwrap();
+        assert!(read_response(client).starts_with("HTTP/1.1 200 "));
+    }
+
+    #[test]
+    fn test_filters_missing_from() {
+        let filters = |query: &str| {
+            Filters::from_query(&request("GET", &format!("/profile?{query}"))).unwrap()
+        };
+        let running = filters("pid=7&cgroup=/system.slice&systemd_unit=nginx");
+
+        assert!(running.missing_from(&filters("pid=7")).is_none());
+        assert!(running
+            .missing_from(&filters(
+                "cgroup=/system.slice/sshd.service&systemd_unit=nginx"
+            ))
+            .is_none());
+        assert!(running.missing_from(&filters("pid=2147483647")).is_some());
+        assert!(running.missing_from(&filters("comm=nginx")).is_some());
+        assert!(running.missing_from(&filters("")).is_some());
+        assert!(filters("").missing_from(&filters("pid=7")).is_none());
+    }
+
+    #[test]
+    fn test_capture() {
+        let capture_dir = tempfile::tempdir().unwrap();
+        let (api, _control_receive) = control_api(capture_dir.path());
+
+        let response = api.handle(&request("POST", "/capture?start=1700000000&end=1700000060"));
+        assert_eq!(response.status, 200);
+        let path = String::from_utf8(response.body).unwrap();
+        assert!(Path::new(path.trim_end()).exists());
+
+        assert_eq!(
+            api.handle(&request("POST", "/capture?start=noon")).status,
+            400
+        );
+        let response = api.handle(&request("GET", "/profile?start=12:00&format=folded"));
+        assert_eq!(response.status, 200);
+    }
+}
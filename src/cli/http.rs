@@ -0,0 +1,298 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Longest request line or header that is accepted.
+const MAX_LINE_BYTES: u64 = 8 * 1024;
+/// Largest number of headers that is accepted.
+const MAX_HEADERS: usize = 100;
+/// How long reading from or writing to a connection can block for.
+pub const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection whose reads and writes can time out.
+pub trait Stream: Read + Write {
+    fn set_timeouts(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn set_timeouts(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)?;
+        self.set_write_timeout(timeout)
+    }
+}
+
+impl Stream for UnixStream {
+    fn set_timeouts(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)?;
+        self.set_write_timeout(timeout)
+    }
+}
+
+/// Bounds how many connections are served at the same time.
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    active: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            active: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Takes one of the connections, which is given back when the returned guard is
+    /// dropped. Returns `None` if all of them are taken.
+    pub fn acquire(&self) -> Option<ConnectionGuard> {
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < self.max).then_some(active + 1)
+            })
+            .ok()?;
+        Some(ConnectionGuard(self.active.clone()))
+    }
+}
+
+pub struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// An HTTP/1.1 request. Only the parts needed by the APIs served by lightswitch are kept.
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+}
+
+impl Request {
+    /// All the values of a query parameter, in order.
+    pub fn query_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.query
+            .iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The first value of a query parameter.
+    pub fn query_value(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        let mut body = body.into();
+        body.push('\n');
+        Self::new(status, "text/plain; charset=utf-8", body)
+    }
+
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason_phrase(self.status),
+            self.content_type,
+            self.body.len()
+        )?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    reader.take(MAX_LINE_BYTES).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "line too long or connection closed",
+        ));
+    }
+    Ok(line.trim_end().to_string())
+}
+
+/// Decodes the percent-encoded characters of a query component, where `+` is a space.
+fn percent_decode(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let byte = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match byte {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+/// Reads a request. Its body, if any, is discarded.
+pub fn read_request(reader: impl Read) -> io::Result<Request> {
+    let mut reader = BufReader::new(reader);
+    let request_line = read_line(&mut reader)?;
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid request line {request_line:?}"),
+        ));
+    };
+
+    let mut content_length = 0;
+    for _ in 0..MAX_HEADERS {
+        let header = read_line(&mut reader)?;
+        if header.is_empty() {
+            io::copy(&mut reader.take(content_length), &mut io::sink())?;
+            let (path, query) = target.split_once('?').unwrap_or((target, ""));
+            return Ok(Request {
+                method: method.to_string(),
+                path: percent_decode(path),
+                query: parse_query(query),
+            });
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "too many headers",
+    ))
+}
+
+/// Serves a single request of a connection with `handler`.
+pub fn handle_connection<S>(mut stream: S, handler: impl FnOnce(&Request) -> Response)
+where
+    S: Read + Write,
+{
+    let response = match read_request(&mut stream) {
+        Ok(request) => handler(&request),
+        Err(e) => Response::text(400, e.to_string()),
+    };
+    let _ = response.write_to(&mut stream);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_request() {
+        let request = read_request(
+            &b"POST /filters?pid=1&pid=2&cgroup=%2Fsystem.slice&process_name=a+b HTTP/1.1\r\n\
+               Host: localhost\r\nContent-Length: 4\r\n\r\nbody"[..],
+        )
+        .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/filters");
+        assert_eq!(request.query_values("pid").collect::<Vec<_>>(), ["1", "2"]);
+        assert_eq!(request.query_value("cgroup"), Some("/system.slice"));
+        assert_eq!(request.query_value("process_name"), Some("a b"));
+        assert_eq!(request.query_value("seconds"), None);
+
+        assert!(read_request(&b"GET /\r\n\r\n"[..]).is_err());
+        assert!(read_request(&b"GET / HTTP/1.1\r\n"[..]).is_err());
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("%5Bpython%5D.%2A"), "[python].*");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let limit = ConnectionLimit::new(2);
+        let first = limit.acquire();
+        let second = limit.clone().acquire();
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(limit.acquire().is_none());
+
+        drop(first);
+        assert!(limit.acquire().is_some());
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut response = Vec::new();
+        Response::text(404, "not found")
+            .write_to(&mut response)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(response).unwrap(),
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: 10\r\nConnection: close\r\n\r\nnot found\n"
+        );
+    }
+}
@@ -1,10 +1,11 @@
 use core::str;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
 use std::io::IsTerminal;
 use std::io::Write;
 use std::panic;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
@@ -13,8 +14,12 @@ use clap::Parser;
 use crossbeam_channel::bounded;
 use crossbeam_channel::tick;
 use inferno::flamegraph;
-use lightswitch::collector::{AggregatorCollector, Collector, NullCollector, StreamingCollector};
+use lightswitch::collector::{
+    AggregatorCollector, Collector, NullCollector, OtlpCollector, StreamingCollector,
+};
+use lightswitch::cpu_trigger::{CpuTriggerConfig, CpuTriggerTarget};
 use lightswitch::debug_info::DebugInfoManager;
+use lightswitch::debuginfod::{DebuginfodCache, DebuginfodClient};
 use nix::unistd::Uid;
 use prost::Message;
 use tracing::{debug, error, info, Level};
@@ -22,16 +27,21 @@ use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::FmtSubscriber;
 
 use lightswitch_capabilities::system_info::SystemInfo;
+use lightswitch_metadata::container_metadata::ContainerMetadata;
+#[cfg(feature = "cri")]
+use lightswitch_metadata::cri::CriClient;
 use lightswitch_metadata::metadata_provider::{
     GlobalMetadataProvider, ThreadSafeGlobalMetadataProvider,
 };
 
 use lightswitch::debug_info::{
-    DebugInfoBackendFilesystem, DebugInfoBackendNull, DebugInfoBackendRemote,
+    DebugInfoBackendFilesystem, DebugInfoBackendNull, DebugInfoBackendRemote, DebugInfoSources,
 };
 use lightswitch::kernel::kernel_build_id;
+use lightswitch::overhead::OverheadConfig;
+use lightswitch::process_selector::ProcessSelector;
 use lightswitch::profile::symbolize_profile;
-use lightswitch::profile::{fold_profile, to_pprof};
+use lightswitch::profile::{fold_profile, to_pprof, AggregatedProfile, SampleKind};
 use lightswitch::profiler::{Profiler, ProfilerConfig};
 use lightswitch::unwind_info::compact_unwind_info;
 use lightswitch::unwind_info::CompactUnwindInfoBuilder;
@@ -39,6 +49,10 @@ use lightswitch_object::kernel::kaslr_offset;
 use lightswitch_object::ObjectFile;
 
 mod args;
+mod capture;
+mod command;
+mod daemon;
+mod http;
 mod killswitch;
 mod validators;
 
@@ -50,9 +64,25 @@ use crate::args::LoggingLevel;
 use crate::args::ProfileFormat;
 use crate::args::ProfileSender;
 use crate::args::Symbolizer;
+use crate::command::{RunningCommand, StoppedCommand};
+use crate::daemon::{DaemonConfig, Filters, ProfileExporter};
 use crate::killswitch::KillSwitch;
 
 const DEFAULT_SERVER_URL: &str = "http://localhost:4567";
+/// Default OTLP/HTTP endpoint of OpenTelemetry collectors.
+const DEFAULT_OTLP_ENDPOINT: &str = "http://localhost:4318";
+/// Bandwidth budget for debug information uploads.
+const DEBUG_INFO_UPLOAD_BYTES_PER_SECOND: u64 = 2 * 1024 * 1024;
+/// Size limit of the debug information stored by the copy debug info backend.
+const DEBUG_INFO_STORE_MAX_SIZE_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+/// Size limit of the debug information downloaded from debuginfod servers.
+const DEBUGINFOD_CACHE_MAX_SIZE_BYTES: u64 = 1024 * 1024 * 1024;
+/// How long to wait before looking up build ids that no debuginfod server had again.
+const DEBUGINFOD_NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+/// How often the CPU usage is checked when sampling is started by a CPU usage trigger.
+const CPU_TRIGGER_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the profiler's overhead is checked against --cpu-overhead-budget.
+const OVERHEAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
 static KILLSWITCH_POLL_INTERVAL: Duration = Duration::from_secs(5);
 
 /// Exit the main thread if any thread panics. We prefer this behaviour because pretty much every
@@ -102,8 +132,12 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
+    let mut command_to_run = None;
+    let mut daemon_args = None;
     match args.command {
         None => {} // record profiles by default
+        Some(Commands::Run { command }) => command_to_run = Some(command),
+        Some(Commands::Daemon(args)) => daemon_args = Some(args),
         Some(Commands::ObjectInfo { path }) => {
             show_object_file_info(&path);
             return Ok(());
@@ -149,61 +183,143 @@ fn main() -> Result<(), Box<dyn Error>> {
         return Ok(());
     }
 
+    if args.follow_children
+        && args.pids.is_empty()
+        && command_to_run.is_none()
+        && daemon_args.is_none()
+    {
+        error!("--follow-children requires --pids or a command to run");
+        std::process::exit(1);
+    }
+
+    let cpu_trigger_target = match (args.cpu_trigger_pid, &args.cpu_trigger_cgroup) {
+        (Some(pid), _) => Some(CpuTriggerTarget::Process(pid)),
+        (None, Some(cgroup)) => Some(CpuTriggerTarget::cgroup(cgroup)),
+        (None, None) => None,
+    };
+    if cpu_trigger_target.is_some() && daemon_args.is_some() {
+        error!("the daemon can't be combined with a CPU usage trigger");
+        std::process::exit(1);
+    }
+    if cfg!(not(feature = "cri")) && args.cri_socket.is_some() {
+        error!("--cri-socket requires lightswitch to be built with the cri feature");
+        std::process::exit(1);
+    }
+
+    // Started as soon as possible, but it will only execute once the profiler is ready.
+    // It's killed if lightswitch fails to start before then.
+    let stopped_command = match command_to_run {
+        Some(command) => Some(StoppedCommand::spawn(&command)?),
+        None => None,
+    };
+
+    let otlp_endpoint = args
+        .server_url
+        .clone()
+        .unwrap_or(DEFAULT_OTLP_ENDPOINT.into());
     let server_url = args.server_url.unwrap_or(DEFAULT_SERVER_URL.into());
     debug!("server url: {}, token: {:?}", server_url, args.token);
 
+    let mut global_metadata_provider = GlobalMetadataProvider::default();
+    if args.container_metadata {
+        let container_metadata = ContainerMetadata::new();
+        #[cfg(feature = "cri")]
+        let container_metadata = match &args.cri_socket {
+            Some(cri_socket) => container_metadata.with_cri_client(CriClient::new(cri_socket)?),
+            None => container_metadata,
+        };
+        global_metadata_provider
+            .register_task_metadata_providers(vec![Box::new(container_metadata)]);
+    }
     let metadata_provider: ThreadSafeGlobalMetadataProvider =
-        Arc::new(Mutex::new(GlobalMetadataProvider::default()));
-
-    let collector: Arc<Mutex<Box<dyn Collector + Send>>> =
-        Arc::new(Mutex::new(match args.sender {
-            ProfileSender::None => Box::new(NullCollector::new()),
-            ProfileSender::LocalDisk => Box::new(AggregatorCollector::new()),
-            ProfileSender::Remote => Box::new(StreamingCollector::new(
-                args.token.clone(),
-                args.symbolizer == Symbolizer::Local,
-                &server_url,
-                ProfilerConfig::default().session_duration,
-                args.sample_freq,
-                metadata_provider.clone(),
-            )),
-        }));
-
-    let debug_info_manager: Box<dyn DebugInfoManager> = match args.debug_info_backend {
-        DebugInfoBackend::None => Box::new(DebugInfoBackendNull {}),
-        DebugInfoBackend::Copy => Box::new(DebugInfoBackendFilesystem {
-            path: PathBuf::from("/tmp"),
-        }),
-        DebugInfoBackend::Remote => Box::new(DebugInfoBackendRemote::new(
-            args.token,
-            server_url,
+        Arc::new(Mutex::new(global_metadata_provider));
+
+    let debug_info_store_path = args
+        .debug_info_store_path
+        .unwrap_or(args.cache_dir_base.join("lightswitch").join("debuginfo"));
+    let debug_info_manager: Arc<dyn DebugInfoManager + Send + Sync> = match args.debug_info_backend
+    {
+        DebugInfoBackend::None => Arc::new(DebugInfoBackendNull {}),
+        DebugInfoBackend::Copy => Arc::new(DebugInfoBackendFilesystem::new(
+            &debug_info_store_path,
+            DEBUG_INFO_STORE_MAX_SIZE_BYTES,
+        )?),
+        DebugInfoBackend::Remote => Arc::new(DebugInfoBackendRemote::new(
+            args.token.clone(),
+            server_url.clone(),
             Duration::from_millis(500),
-            Duration::from_secs(15),
+            Duration::from_secs(5 * 60),
+            DEBUG_INFO_UPLOAD_BYTES_PER_SECOND,
+            &args.cache_dir_base.join("lightswitch"),
         )?),
     };
 
+    let debuginfod_urls = if args.debuginfod_urls.is_empty() {
+        DebuginfodClient::servers_from_env()
+    } else {
+        args.debuginfod_urls
+    };
+    let debuginfod = if args.symbolizer == Symbolizer::Local && !debuginfod_urls.is_empty() {
+        let cache = DebuginfodCache::new(
+            &args.cache_dir_base.join("lightswitch").join("debuginfod"),
+            DEBUGINFOD_CACHE_MAX_SIZE_BYTES,
+            DEBUGINFOD_NEGATIVE_CACHE_TTL,
+        )?;
+        Some(Arc::new(DebuginfodClient::new(debuginfod_urls, cache)?))
+    } else {
+        None
+    };
+    let debug_info_sources = DebugInfoSources {
+        store: (args.debug_info_backend == DebugInfoBackend::Copy)
+            .then(|| debug_info_manager.clone()),
+        debuginfod,
+    };
+
     let use_ring_buffers =
         !args.force_perf_buffer && system_info.available_bpf_features.has_ring_buf;
 
+    let spool_dir = args.cache_dir_base.join("lightswitch").join("spool");
+    let otlp_spool_dir = args.cache_dir_base.join("lightswitch").join("otlp-spool");
+    let capture_dir = args.cache_dir_base.join("lightswitch").join("captures");
     let profiler_config = ProfilerConfig {
         cache_dir_base: args.cache_dir_base,
         libbpf_debug: args.libbpf_debug,
         bpf_logging: args.bpf_logging,
         duration: args.duration,
         sample_freq: args.sample_freq,
+        events: args.events,
+        sample_period: args.sample_period,
         perf_buffer_bytes: args.perf_buffer_bytes,
         mapsize_info: args.mapsize_info,
         mapsize_rate_limits: args.mapsize_rate_limits,
         exclude_self: args.exclude_self,
+        follow_children: args.follow_children,
         debug_info_manager,
         max_native_unwind_info_size_mb: args.max_native_unwind_info_size_mb,
         use_ring_buffers,
         use_task_pt_regs_helper: system_info.available_bpf_features.has_task_pt_regs_helper,
+        off_cpu: args.off_cpu,
+        // The daemon only samples during the sessions started through its API.
+        start_paused: daemon_args.is_some(),
+        cpu_trigger: cpu_trigger_target.map(|target| CpuTriggerConfig {
+            target,
+            threshold_percent: args.cpu_trigger_threshold,
+            cooldown: args.cpu_trigger_cooldown,
+            interval: CPU_TRIGGER_INTERVAL,
+        }),
+        overhead_budget: args
+            .cpu_overhead_budget
+            .map(|budget_percent| OverheadConfig {
+                budget_percent,
+                max_sample_freq: args.sample_freq,
+                interval: OVERHEAD_CHECK_INTERVAL,
+            }),
         ..Default::default()
     };
 
     let (stop_signal_sender, stop_signal_receive) = bounded(1);
     let profiler_stop_signal_sender = stop_signal_sender.clone();
+    let command_stop_signal_sender = stop_signal_sender.clone();
     ctrlc::set_handler(move || {
         info!("received Ctrl+C, stopping...");
         let _ = profiler_stop_signal_sender.send(());
@@ -226,15 +342,109 @@ fn main() -> Result<(), Box<dyn Error>> {
         stop_signal_receive,
         metadata_provider.clone(),
     );
-    p.profile_pids(args.pids);
-    let profile_duration = p.run(collector.clone());
+    let mut pids = args.pids;
+    pids.extend(stopped_command.as_ref().map(StoppedCommand::pid));
+    let mut selectors: Vec<ProcessSelector> = args
+        .cgroup
+        .iter()
+        .map(|path| ProcessSelector::cgroup(path))
+        .collect();
+    selectors.extend(
+        args.systemd_unit
+            .iter()
+            .map(|unit| ProcessSelector::systemd_unit(unit)),
+    );
+    selectors.extend(
+        args.container_id
+            .into_iter()
+            .map(ProcessSelector::ContainerId),
+    );
+    selectors.extend(
+        args.process_name
+            .into_iter()
+            .map(ProcessSelector::ProcessName),
+    );
+    let filters = Filters {
+        pids: pids.clone(),
+        selectors: selectors.clone(),
+    };
+    p.profile_pids(pids);
+    p.profile_selectors(selectors);
+    let event_configs = p.event_configs();
+
+    if let Some(daemon_args) = daemon_args {
+        let config = DaemonConfig {
+            socket_path: daemon_args.socket_path,
+            pprof_address: daemon_args.pprof_address,
+            retention: daemon_args.retention,
+            memory_budget: daemon_args.retention_memory_mb * 1024 * 1024,
+            capture_dir: daemon_args.capture_dir.unwrap_or(capture_dir),
+            capture_trigger_path: daemon_args.capture_trigger_path,
+        };
+        let exporter = ProfileExporter {
+            symbolize: args.symbolizer == Symbolizer::Local,
+            debug_info: debug_info_sources,
+            metadata_provider,
+        };
+        return daemon::run(p, config, filters, exporter);
+    }
+
+    let collector: Arc<Mutex<Box<dyn Collector + Send>>> =
+        Arc::new(Mutex::new(match args.sender {
+            ProfileSender::None => Box::new(NullCollector::new()),
+            ProfileSender::LocalDisk => Box::new(AggregatorCollector::new()),
+            ProfileSender::Remote => Box::new(
+                StreamingCollector::new(
+                    args.token.clone(),
+                    args.symbolizer == Symbolizer::Local,
+                    &server_url,
+                    ProfilerConfig::default().session_duration,
+                    event_configs.clone(),
+                    metadata_provider.clone(),
+                    &spool_dir,
+                )?
+                .with_debug_info(debug_info_sources.clone()),
+            ),
+            ProfileSender::Otlp => Box::new(
+                OtlpCollector::new(
+                    args.token.clone(),
+                    args.symbolizer == Symbolizer::Local,
+                    &otlp_endpoint,
+                    ProfilerConfig::default().session_duration,
+                    event_configs.clone(),
+                    metadata_provider.clone(),
+                    &otlp_spool_dir,
+                )?
+                .with_debug_info(debug_info_sources.clone()),
+            ),
+        }));
+
+    // The command is only let go once the profiler is ready, so its first samples,
+    // forks and executions aren't missed.
+    let mut running_command = None;
+    p.run_with_ready_callback(collector.clone(), || {
+        running_command = stopped_command.map(|command| {
+            let exit_stop_signal_sender = command_stop_signal_sender.clone();
+            let running_command = command.resume(move |exit_code| {
+                info!("command exited with code {}, stopping...", exit_code);
+                let _ = exit_stop_signal_sender.send(());
+            });
+            if running_command.is_err() {
+                let _ = command_stop_signal_sender.send(());
+            }
+            running_command
+        });
+    });
+    let running_command = running_command.transpose()?;
 
     let collector = collector.lock().unwrap();
     let (mut profile, procs, objs) = collector.finish();
+    let session_summary = collector.session_summary().unwrap_or_default();
 
     // If we need to send the profile to the backend there's nothing else to do.
     match args.sender {
-        ProfileSender::Remote | ProfileSender::None => {
+        ProfileSender::Remote | ProfileSender::Otlp | ProfileSender::None => {
+            exit_with_command_exit_code(&running_command);
             return Ok(());
         }
         _ => {}
@@ -243,44 +453,76 @@ fn main() -> Result<(), Box<dyn Error>> {
     // Otherwise let's symbolize the profile and write it to disk.
     if args.symbolizer == Symbolizer::Local {
         info!("Symbolizing profile...");
-        profile = symbolize_profile(&profile, procs, objs);
+        profile = symbolize_profile(&profile, procs, objs, &debug_info_sources);
     }
 
     let profile_path = args.profile_path.unwrap_or(PathBuf::from(""));
 
     match args.profile_format {
         ProfileFormat::FlameGraph => {
-            let folded = fold_profile(
-                profile,
-                args.flamegraph_aggregation == FlamegraphAggregation::Function,
-            );
-            let mut options: flamegraph::Options<'_> = flamegraph::Options::default();
-            let data = folded.as_bytes();
+            let only_show_function_names =
+                args.flamegraph_aggregation == FlamegraphAggregation::Function;
             let profile_name = args.profile_name.unwrap_or_else(|| "flame.svg".into());
-            let profile_path = profile_path.join(profile_name);
-            let f = File::create(&profile_path).unwrap();
-            match flamegraph::from_reader(&mut options, data, f) {
-                Ok(_) => {
-                    eprintln!(
-                        "Flamegraph profile successfully written to {}",
-                        profile_path.to_string_lossy()
-                    );
-                }
-                Err(e) => {
-                    error!("Failed generate flamegraph: {:?}", e);
+            let (on_cpu_profile, off_cpu_profile): (Vec<_>, Vec<_>) = profile
+                .into_iter()
+                .partition(|sample| sample.kind == SampleKind::OnCpu);
+
+            let mut profile_per_event: HashMap<u32, AggregatedProfile> = HashMap::new();
+            for sample in on_cpu_profile {
+                profile_per_event
+                    .entry(sample.event)
+                    .or_default()
+                    .push(sample);
+            }
+
+            // Each event gets its own flamegraph, the first one is written to the
+            // requested path and the rest are prefixed with the event name.
+            for (event_idx, event_config) in event_configs.iter().enumerate() {
+                let event_profile = profile_per_event
+                    .remove(&(event_idx as u32))
+                    .unwrap_or_default();
+
+                let mut event_profile_name = PathBuf::new();
+                if event_idx > 0 {
+                    event_profile_name = PathBuf::from(format!("{}-", event_config.event));
                 }
+                event_profile_name.as_mut_os_string().push(&profile_name);
+                write_flamegraph(
+                    fold_profile(event_profile, only_show_function_names),
+                    &profile_path.join(event_profile_name),
+                );
+            }
+
+            // Off-CPU stacks are weighted by the time spent blocked rather than by the
+            // number of samples, so they are written to their own flamegraph.
+            if args.off_cpu {
+                let mut off_cpu_profile_name = PathBuf::from("off-cpu-");
+                off_cpu_profile_name.as_mut_os_string().push(&profile_name);
+                write_flamegraph(
+                    fold_profile(off_cpu_profile, only_show_function_names),
+                    &profile_path.join(off_cpu_profile_name),
+                );
             }
         }
         ProfileFormat::Pprof => {
             let mut buffer = Vec::new();
-            let pprof_profile = to_pprof(
+            // Nothing might have been collected if profiling stopped right away.
+            let event_configs = if session_summary.event_configs.is_empty() {
+                &event_configs
+            } else {
+                &session_summary.event_configs
+            };
+            let mut pprof_profile = to_pprof(
                 profile,
                 procs,
                 objs,
                 &metadata_provider,
-                profile_duration,
-                args.sample_freq,
+                session_summary.duration,
+                event_configs,
             );
+            for reason in &session_summary.trigger_reasons {
+                pprof_profile.add_comment(reason);
+            }
             pprof_profile.encode(&mut buffer).unwrap();
             let profile_name = args.profile_name.unwrap_or_else(|| "profile.pb".into());
             let profile_path = profile_path.join(profile_name);
@@ -303,9 +545,34 @@ fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
+    exit_with_command_exit_code(&running_command);
     Ok(())
 }
 
+/// Exits with the same exit code as the command that was run, if any.
+fn exit_with_command_exit_code(running_command: &Option<RunningCommand>) {
+    if let Some(exit_code) = running_command.as_ref().and_then(RunningCommand::exit_code) {
+        std::process::exit(exit_code);
+    }
+}
+
+fn write_flamegraph(folded: String, profile_path: &Path) {
+    let mut options: flamegraph::Options<'_> = flamegraph::Options::default();
+    let data = folded.as_bytes();
+    let f = File::create(profile_path).unwrap();
+    match flamegraph::from_reader(&mut options, data, f) {
+        Ok(_) => {
+            eprintln!(
+                "Flamegraph profile successfully written to {}",
+                profile_path.to_string_lossy()
+            );
+        }
+        Err(e) => {
+            error!("Failed generate flamegraph: {:?}", e);
+        }
+    }
+}
+
 fn show_unwind_info(path: &str) {
     let unwind_info = compact_unwind_info(path, None).unwrap();
     for compact_row in unwind_info {
@@ -314,9 +581,10 @@ fn show_unwind_info(path: &str) {
         let rbp_type = compact_row.rbp_type;
         let cfa_offset = compact_row.cfa_offset;
         let rbp_offset = compact_row.rbp_offset;
+        let ra_type = compact_row.ra_type;
         println!(
-            "pc: {:x} cfa_type: {:<2} rbp_type: {:<2} cfa_offset: {:<4} rbp_offset: {:<4}",
-            pc, cfa_type as u8, rbp_type as u8, cfa_offset, rbp_offset
+            "pc: {:x} cfa_type: {:<2} rbp_type: {:<2} cfa_offset: {:<4} rbp_offset: {:<4} ra_type: {:<2}",
+            pc, cfa_type as u8, rbp_type as u8, cfa_offset, rbp_offset, ra_type as u8
         );
     }
 }
@@ -355,7 +623,7 @@ mod tests {
         cmd.arg("--help");
         cmd.assert().success();
         let actual = String::from_utf8(cmd.unwrap().stdout).unwrap();
-        insta::assert_yaml_snapshot!(actual, @r#""Usage: lightswitch [OPTIONS] [COMMAND]\n\nCommands:\n  object-info  \n  show-unwind  \n  system-info  \n  help         Print this message or the help of the given subcommand(s)\n\nOptions:\n      --pids <PIDS>\n          Specific PIDs to profile\n\n  -D, --duration <DURATION>\n          How long this agent will run in seconds\n          \n          [default: 18446744073709551615]\n\n      --libbpf-debug\n          Enable libbpf logs. This includes the BPF verifier output\n\n      --bpf-logging\n          Enable BPF programs logging\n\n      --logging <LOGGING>\n          Set lightswitch's logging level\n          \n          [default: info]\n          [possible values: trace, debug, info, warn, error]\n\n      --sample-freq <SAMPLE_FREQ_IN_HZ>\n          Per-CPU Sampling Frequency in Hz\n          \n          [default: 19]\n\n      --profile-format <PROFILE_FORMAT>\n          Output file for Flame Graph in SVG format\n          \n          [default: flame-graph]\n          [possible values: none, flame-graph, pprof]\n\n      --flamegraph-aggregation <FLAMEGRAPH_AGGREGATION>\n          What information to show in the flamegraph. Won't do anything for other profile formats\n          \n          [default: function]\n          [possible values: function, all]\n\n      --profile-path <PROFILE_PATH>\n          Path for the generated profile\n\n      --profile-name <PROFILE_NAME>\n          Name for the generated profile\n\n      --sender <SENDER>\n          Where to write the profile\n\n          Possible values:\n          - none:       Discard the profile. Used for kernel tests\n          - local-disk\n          - remote\n          \n          [default: local-disk]\n\n      --server-url <SERVER_URL>\n          \n\n      --token <TOKEN>\n          \n\n      --perf-buffer-bytes <PERF_BUFFER_BYTES>\n          Size of each profiler perf buffer, in bytes (must be a power of 2)\n          \n          [default: 524288]\n\n      --mapsize-info\n          Print eBPF map sizes after creation\n\n      --mapsize-rate-limits <MAPSIZE_RATE_LIMITS>\n          max number of rate limit entries\n          \n          [default: 5000]\n\n      --exclude-self\n          Do not profile the profiler (myself)\n\n      --symbolizer <SYMBOLIZER>\n          [default: local]\n          [possible values: local, none]\n\n      --debug-info-backend <DEBUG_INFO_BACKEND>\n          [default: none]\n          [possible values: none, copy, remote]\n\n      --max-native-unwind-info-size-mb <MAX_NATIVE_UNWIND_INFO_SIZE_MB>\n          approximate max size in megabytes used for the BPF maps that hold unwind information\n          \n          [default: 2147483647]\n\n      --enable-deadlock-detector\n          enable parking_lot's deadlock detector\n\n      --cache-dir-base <CACHE_DIR_BASE>\n          [default: /tmp]\n\n      --killswitch-path-override <KILLSWITCH_PATH_OVERRIDE>\n          Override the default path to the killswitch file (/tmp/lighswitch/killswitch) which prevents the profiler from starting\n\n      --unsafe-start\n          Force the profiler to start even if the system killswitch is enabled\n\n      --force-perf-buffer\n          force perf buffers even if ring buffers can be used\n\n  -h, --help\n          Print help (see a summary with '-h')\n""#);
+        insta::assert_yaml_snapshot!(actual, @r#""Usage: lightswitch [OPTIONS] [COMMAND]\n\nCommands:\n  object-info  \n  show-unwind  \n  system-info  \n  run          Run a command and profile it until it exits\n  daemon       Keep the profiler running and control its sessions through a local API\n  help         Print this message or the help of the given subcommand(s)\n\nOptions:\n      --pids <PIDS>\n          Specific PIDs to profile\n\n      --follow-children\n          Also profile the processes forked by the ones in --pids, or by the command that is run, and their descendants\n\n      --cgroup <CGROUP>\n          Profile the processes in this cgroup, such as /system.slice, and its descendants\n\n      --systemd-unit <SYSTEMD_UNIT>\n          Profile the processes of this systemd unit\n\n      --container-id <CONTAINER_ID>\n          Profile the processes running in the container with this ID, or ID prefix\n\n      --process-name <REGEX>\n          Profile the processes whose name matches this regular expression\n\n      --container-metadata\n          Label the samples of processes running in containers with their container ID\n\n      --cri-socket <PATH>\n          CRI socket of the container runtime, such as /run/containerd/containerd.sock, to label the samples of Kubernetes pods with their pod, namespace and container names. Needs the cri feature\n\n  -D, --duration <DURATION>\n          How long this agent will run in seconds\n          \n          [default: 18446744073709551615]\n\n      --libbpf-debug\n          Enable libbpf logs. This includes the BPF verifier output\n\n      --bpf-logging\n          Enable BPF programs logging\n\n      --logging <LOGGING>\n          Set lightswitch's logging level\n          \n          [default: info]\n          [possible values: trace, debug, info, warn, error]\n\n      --sample-freq <SAMPLE_FREQ_IN_HZ>\n          Per-CPU Sampling Frequency in Hz\n          \n          [default: 19]\n\n      --event <EVENT>\n          Events that trigger the samples: cpu-clock, cycles, instructions, cache-misses, branch-misses, page-faults, context-switches or a raw PMU event code, such as r1c4. Can be repeated or comma separated to sample several events in the same session. Hardware events fall back to cpu-clock if there is no PMU\n          \n          [default: cpu-clock]\n\n      --sample-period <SAMPLE_PERIOD>\n          Take a sample every this many events rather than using the sampling frequency\n\n      --profile-format <PROFILE_FORMAT>\n          Output file for Flame Graph in SVG format\n          \n          [default: flame-graph]\n          [possible values: none, flame-graph, pprof]\n\n      --flamegraph-aggregation <FLAMEGRAPH_AGGREGATION>\n          What information to show in the flamegraph. Won't do anything for other profile formats\n          \n          [default: function]\n          [possible values: function, all]\n\n      --profile-path <PROFILE_PATH>\n          Path for the generated profile\n\n      --profile-name <PROFILE_NAME>\n          Name for the generated profile\n\n      --sender <SENDER>\n          Where to write the profile\n\n          Possible values:\n          - none:       Discard the profile. Used for kernel tests\n          - local-disk\n          - remote\n          - otlp:       Export to an OpenTelemetry collector with OTLP/HTTP\n          \n          [default: local-disk]\n\n      --server-url <SERVER_URL>\n          \n\n      --token <TOKEN>\n          \n\n      --perf-buffer-bytes <PERF_BUFFER_BYTES>\n          Size of each profiler perf buffer, in bytes (must be a power of 2)\n          \n          [default: 524288]\n\n      --mapsize-info\n          Print eBPF map sizes after creation\n\n      --mapsize-rate-limits <MAPSIZE_RATE_LIMITS>\n          max number of rate limit entries\n          \n          [default: 5000]\n\n      --exclude-self\n          Do not profile the profiler (myself)\n\n      --symbolizer <SYMBOLIZER>\n          [default: local]\n          [possible values: local, none]\n\n      --debug-info-backend <DEBUG_INFO_BACKEND>\n          [default: none]\n          [possible values: none, copy, remote]\n\n      --debug-info-store-path <DEBUG_INFO_STORE_PATH>\n          Where the copy debug info backend stores debug information, by build id. Defaults to a directory in the cache dir\n\n      --debuginfod-urls <URL>\n          debuginfod servers to fetch the debug information of stripped binaries from when symbolizing locally. Defaults to the ones in DEBUGINFOD_URLS\n\n      --max-native-unwind-info-size-mb <MAX_NATIVE_UNWIND_INFO_SIZE_MB>\n          approximate max size in megabytes used for the BPF maps that hold unwind information\n          \n          [default: 2147483647]\n\n      --enable-deadlock-detector\n          enable parking_lot's deadlock detector\n\n      --cache-dir-base <CACHE_DIR_BASE>\n          [default: /tmp]\n\n      --killswitch-path-override <KILLSWITCH_PATH_OVERRIDE>\n          Override the default path to the killswitch file (/tmp/lighswitch/killswitch) which prevents the profiler from starting\n\n      --unsafe-start\n          Force the profiler to start even if the system killswitch is enabled\n\n      --force-perf-buffer\n          force perf buffers even if ring buffers can be used\n\n      --off-cpu\n          Also profile the time threads spend blocked, such as waiting on I/O or locks\n\n      --cpu-trigger-pid <PID>\n          Only take samples while the CPU usage of this process is over --cpu-trigger-threshold\n\n      --cpu-trigger-cgroup <CGROUP>\n          Only take samples while the CPU usage of this cgroup, such as /system.slice/nginx.service, is over --cpu-trigger-threshold\n\n      --cpu-trigger-threshold <PERCENT>\n          CPU usage that starts the sampling, in percent of one CPU. It can be over 100 for processes and cgroups that use several CPUs\n          \n          [default: 80]\n\n      --cpu-trigger-cooldown <CPU_TRIGGER_COOLDOWN>\n          For how long, in seconds, the CPU usage has to stay below the threshold to stop the sampling\n          \n          [default: 60]\n\n      --cpu-overhead-budget <PERCENT>\n          Lower the sampling frequency when lightswitch, including its BPF programs, uses more than this percentage of the CPUs, and raise it back up to --sample-freq once it uses less\n\n  -h, --help\n          Print help (see a summary with '-h')\n""#);
     }
 
     #[rstest]
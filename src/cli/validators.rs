@@ -1,5 +1,6 @@
 use primal::is_prime;
 
+use std::net::{Ipv4Addr, SocketAddr};
 use std::ops::RangeInclusive;
 use std::time::Duration;
 
@@ -35,6 +36,15 @@ pub(crate) fn sample_freq_in_range(s: &str) -> Result<u64, String> {
     Ok(sample_freq)
 }
 
+/// Parses an address to listen on. A port on its own, such as 6060, is on localhost.
+pub(crate) fn parse_listen_address(arg: &str) -> Result<SocketAddr, String> {
+    if let Ok(port) = arg.parse::<u16>() {
+        return Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+    }
+    arg.parse()
+        .map_err(|_| format!("`{arg}' isn't a valid port or address"))
+}
+
 // Convert a &str into a usize, if possible, and return the result if it's a
 // power of 2
 pub(crate) fn value_is_power_of_two(s: &str) -> Result<usize, String> {
@@ -91,4 +101,22 @@ mod tests {
             }
         }
     }
+
+    #[rstest]
+    #[case("6060", "127.0.0.1:6060")]
+    #[case("127.0.0.1:6060", "127.0.0.1:6060")]
+    #[case("0.0.0.0:6060", "0.0.0.0:6060")]
+    #[case("[::1]:6060", "[::1]:6060")]
+    fn test_parse_listen_address(#[case] arg: &str, #[case] expected: &str) {
+        assert_eq!(
+            parse_listen_address(arg).unwrap(),
+            expected.parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_listen_address_invalid() {
+        assert!(parse_listen_address("localhost").is_err());
+        assert!(parse_listen_address("70000").is_err());
+    }
 }
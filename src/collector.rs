@@ -1,16 +1,27 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use prost::Message;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tracing::{debug, span, Level};
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, span, warn, Level};
 
+use crate::debug_info::DebugInfoSources;
+use crate::perf_events::EventConfig;
 use crate::process::ObjectFileInfo;
 use crate::process::ProcessInfo;
 use crate::profile::raw_to_processed;
 use crate::profile::AggregatedProfile;
 use crate::profile::AggregatedSample;
+use crate::profile::Frame;
 use crate::profile::RawAggregatedProfile;
-use crate::profile::{symbolize_profile, to_pprof};
+use crate::profile::SampleKind;
+use crate::profile::{symbolize_profile, to_otlp, to_pprof};
+use crate::spool::{SendOutcome, Spool, SpoolSender};
 use lightswitch_object::ExecutableId;
 
 use lightswitch_metadata::metadata_provider::ThreadSafeGlobalMetadataProvider;
@@ -29,6 +40,57 @@ pub trait Collector {
         &HashMap<i32, ProcessInfo>,
         &HashMap<ExecutableId, ObjectFileInfo>,
     );
+    /// Called before [`Collector::collect`] with how the profile was taken. Collectors
+    /// that export the profiles use it for their duration and sampling periods, and tag
+    /// them with the trigger reason, if any.
+    fn set_session_info(&mut self, _session_info: &SessionInfo) {}
+    /// How the samples returned by [`Collector::finish`] were taken, for the collectors
+    /// that keep them rather than exporting every profile.
+    fn session_summary(&self) -> Option<SessionSummary> {
+        None
+    }
+}
+
+/// How the samples of a profile were taken.
+#[derive(Debug, Clone, Default)]
+pub struct SessionInfo {
+    /// For how long samples were taken.
+    pub duration: Duration,
+    /// The events that were sampled and how often, as the sampling frequency might
+    /// change while profiling.
+    pub event_configs: Vec<EventConfig>,
+    /// Why profiling was started, if it was by a trigger such as a CPU usage threshold.
+    pub trigger_reason: Option<String>,
+}
+
+/// How the samples of several profiles were taken.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionSummary {
+    /// For how long samples were taken in total, which excludes the time profiling was
+    /// paused.
+    pub duration: Duration,
+    /// The events that were sampled, with the periods the counts correspond to.
+    pub event_configs: Vec<EventConfig>,
+    /// Why profiling was started, each time it was by a trigger.
+    pub trigger_reasons: Vec<String>,
+}
+
+impl SessionSummary {
+    fn new<'a>(sessions: impl IntoIterator<Item = &'a SessionInfo> + Clone) -> Self {
+        let mut summary = Self {
+            event_configs: shortest_periods(sessions.clone()),
+            ..Default::default()
+        };
+        for session in sessions {
+            summary.duration += session.duration;
+            if let Some(reason) = &session.trigger_reason {
+                if !summary.trigger_reasons.contains(reason) {
+                    summary.trigger_reasons.push(reason.clone());
+                }
+            }
+        }
+        summary
+    }
 }
 
 pub type ThreadSafeCollector = Arc<Mutex<Box<dyn Collector + Send>>>;
@@ -66,42 +128,109 @@ impl Collector for NullCollector {
     }
 }
 
-#[derive(Default)]
+/// Maximum size of the profiles waiting to be uploaded.
+const SPOOL_MAX_SIZE_BYTES: u64 = 64 * 1024 * 1024;
+/// Profiles older than this are dropped rather than uploaded.
+const SPOOL_MAX_AGE: Duration = Duration::from_secs(6 * 60 * 60);
+/// How long to wait for the spooled profiles to be uploaded when the collector finishes.
+const SPOOL_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether an upload should be retried after receiving the given status code. See
+/// <https://opentelemetry.io/docs/specs/otlp/#retryable-response-codes>.
+fn is_retryable(status: reqwest::StatusCode) -> bool {
+    matches!(status.as_u16(), 429 | 502 | 503 | 504)
+}
+
+/// Parses the number of seconds the server asked to wait for in the `Retry-After` header.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Classifies the response to an upload. Requests that failed without a response, and
+/// the status codes that mean the backend is overloaded or unavailable, are retried.
+fn send_outcome(response: reqwest::Result<reqwest::blocking::Response>) -> SendOutcome {
+    match response {
+        Ok(response) if response.status().is_success() => {
+            debug!("http response: {:?}", response);
+            SendOutcome::Sent
+        }
+        Ok(response) if is_retryable(response.status()) => SendOutcome::Retry(
+            response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after),
+        ),
+        Ok(response) => {
+            error!("upload rejected, dropping profile: {:?}", response);
+            SendOutcome::Rejected
+        }
+        Err(e) => {
+            debug!("upload failed: {:?}", e);
+            SendOutcome::Retry(None)
+        }
+    }
+}
+
 pub struct StreamingCollector {
-    token: Option<String>,
     local_symbolizer: bool,
-    pprof_ingest_url: String,
-    http_client_timeout: Duration,
+    debug_info: DebugInfoSources,
     profile_duration: Duration,
-    profile_frequency_hz: u64,
+    event_configs: Vec<EventConfig>,
     procs: HashMap<i32, ProcessInfo>,
     objs: HashMap<ExecutableId, ObjectFileInfo>,
     metadata_provider: ThreadSafeGlobalMetadataProvider,
+    sender: SpoolSender,
+    trigger_reason: Option<String>,
 }
 
 impl StreamingCollector {
+    /// Creates a collector which spools the profiles in `spool_dir` and uploads them in
+    /// the background. Profiles spooled by previous runs are uploaded too.
     pub fn new(
         token: Option<String>,
         local_symbolizer: bool,
         pprof_ingest_url: &str,
         profile_duration: Duration,
-        profile_frequency_hz: u64,
+        event_configs: Vec<EventConfig>,
         metadata_provider: ThreadSafeGlobalMetadataProvider,
-    ) -> Self {
-        Self {
-            token,
+        spool_dir: &Path,
+    ) -> anyhow::Result<Self> {
+        let pprof_ingest_url = format!("{pprof_ingest_url}/pprof/new");
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        let spool = Spool::new(spool_dir, SPOOL_MAX_SIZE_BYTES, SPOOL_MAX_AGE)?;
+        let sender = SpoolSender::start(spool, move |payload| {
+            let mut request = client.post(&pprof_ingest_url).body(payload);
+            if let Some(token) = &token {
+                request = request.bearer_auth(token);
+            }
+            send_outcome(request.send())
+        })?;
+
+        Ok(Self {
             local_symbolizer,
-            pprof_ingest_url: format!("{pprof_ingest_url}/pprof/new"),
-            http_client_timeout: Duration::from_secs(30),
+            debug_info: DebugInfoSources::default(),
             profile_duration,
-            profile_frequency_hz,
+            event_configs,
+            procs: HashMap::new(),
+            objs: HashMap::new(),
             metadata_provider,
-            ..Default::default()
-        }
+            sender,
+            trigger_reason: None,
+        })
+    }
+
+    /// Where to look for the debug information of objects when symbolizing locally.
+    pub fn with_debug_info(mut self, debug_info: DebugInfoSources) -> Self {
+        self.debug_info = debug_info;
+        self
     }
 }
 
-/// POSTs the pprof formatted profiles to the given url.
+/// POSTs the pprof formatted profiles to the given url. Profiles are spooled to disk and
+/// uploaded by a background thread, which retries them if the backend is unavailable.
 impl Collector for StreamingCollector {
     fn collect(
         &mut self,
@@ -109,32 +238,161 @@ impl Collector for StreamingCollector {
         procs: &HashMap<i32, ProcessInfo>,
         objs: &HashMap<ExecutableId, ObjectFileInfo>,
     ) {
-        let _span = span!(Level::DEBUG, "StreamingCollector.finish").entered();
+        let _span = span!(Level::DEBUG, "StreamingCollector.collect").entered();
+
+        let mut profile = raw_to_processed(&profile, procs, objs);
+        if self.local_symbolizer {
+            profile = symbolize_profile(&profile, procs, objs, &self.debug_info);
+        }
+
+        let mut pprof_profile = to_pprof(
+            profile,
+            procs,
+            objs,
+            &self.metadata_provider,
+            self.profile_duration,
+            &self.event_configs,
+        );
+        if let Some(reason) = &self.trigger_reason {
+            pprof_profile.add_comment(reason);
+        }
+
+        if let Err(e) = self.sender.push(&pprof_profile.encode_to_vec()) {
+            error!("failed to spool profile with {:?}", e);
+        }
+    }
+
+    fn finish(
+        &self,
+    ) -> (
+        AggregatedProfile,
+        &HashMap<i32, ProcessInfo>,
+        &HashMap<ExecutableId, ObjectFileInfo>,
+    ) {
+        if !self.sender.wait_until_empty(SPOOL_FLUSH_TIMEOUT) {
+            warn!("some profiles couldn't be sent, they will be retried on the next run");
+        }
+        let stats = self.sender.stats();
+        debug!(
+            "profiles sent: {}, failed attempts: {}, rejected: {}, dropped: {}",
+            stats.sent.load(Ordering::Relaxed),
+            stats.failed.load(Ordering::Relaxed),
+            stats.rejected.load(Ordering::Relaxed),
+            stats.dropped.load(Ordering::Relaxed)
+        );
+
+        (AggregatedProfile::new(), &self.procs, &self.objs)
+    }
+
+    fn set_session_info(&mut self, session_info: &SessionInfo) {
+        self.profile_duration = session_info.duration;
+        self.event_configs = session_info.event_configs.clone();
+        self.trigger_reason = session_info.trigger_reason.clone();
+    }
+}
+
+pub struct OtlpCollector {
+    local_symbolizer: bool,
+    debug_info: DebugInfoSources,
+    profile_duration: Duration,
+    event_configs: Vec<EventConfig>,
+    procs: HashMap<i32, ProcessInfo>,
+    objs: HashMap<ExecutableId, ObjectFileInfo>,
+    metadata_provider: ThreadSafeGlobalMetadataProvider,
+    sender: SpoolSender,
+    trigger_reason: Option<String>,
+}
+
+impl OtlpCollector {
+    /// Creates a collector which spools the export requests in `spool_dir` and sends them
+    /// in the background. Requests spooled by previous runs are sent too.
+    pub fn new(
+        token: Option<String>,
+        local_symbolizer: bool,
+        otlp_endpoint: &str,
+        profile_duration: Duration,
+        event_configs: Vec<EventConfig>,
+        metadata_provider: ThreadSafeGlobalMetadataProvider,
+        spool_dir: &Path,
+    ) -> anyhow::Result<Self> {
+        let otlp_profiles_url = format!("{otlp_endpoint}/v1development/profiles");
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        let spool = Spool::new(spool_dir, SPOOL_MAX_SIZE_BYTES, SPOOL_MAX_AGE)?;
+        let sender = SpoolSender::start(spool, move |body| {
+            let mut request = client
+                .post(&otlp_profiles_url)
+                .header(CONTENT_TYPE, "application/x-protobuf")
+                .header(CONTENT_ENCODING, "gzip")
+                .body(body);
+            if let Some(token) = &token {
+                request = request.bearer_auth(token);
+            }
+            send_outcome(request.send())
+        })?;
+
+        Ok(Self {
+            local_symbolizer,
+            debug_info: DebugInfoSources::default(),
+            profile_duration,
+            event_configs,
+            procs: HashMap::new(),
+            objs: HashMap::new(),
+            metadata_provider,
+            sender,
+            trigger_reason: None,
+        })
+    }
+
+    /// Where to look for the debug information of objects when symbolizing locally.
+    pub fn with_debug_info(mut self, debug_info: DebugInfoSources) -> Self {
+        self.debug_info = debug_info;
+        self
+    }
+}
+
+/// Exports the profiles to an OpenTelemetry collector with OTLP/HTTP, using gzip compressed
+/// protocol buffers.
+impl Collector for OtlpCollector {
+    fn collect(
+        &mut self,
+        profile: RawAggregatedProfile,
+        procs: &HashMap<i32, ProcessInfo>,
+        objs: &HashMap<ExecutableId, ObjectFileInfo>,
+    ) {
+        let _span = span!(Level::DEBUG, "OtlpCollector.collect").entered();
 
         let mut profile = raw_to_processed(&profile, procs, objs);
         if self.local_symbolizer {
-            profile = symbolize_profile(&profile, procs, objs);
+            profile = symbolize_profile(&profile, procs, objs, &self.debug_info);
         }
 
-        let pprof_profile = to_pprof(
+        let request = to_otlp(
             profile,
             procs,
             objs,
             &self.metadata_provider,
             self.profile_duration,
-            self.profile_frequency_hz,
+            &self.event_configs,
+            self.trigger_reason.as_slice(),
         );
 
-        let client_builder = reqwest::blocking::Client::builder().timeout(self.http_client_timeout);
-        let client = client_builder.build().unwrap();
-        let mut request = client
-            .post(self.pprof_ingest_url.clone())
-            .body(pprof_profile.encode_to_vec());
-        if let Some(token) = &self.token {
-            request = request.bearer_auth(token);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        let body = match encoder
+            .write_all(&request.encode_to_vec())
+            .and_then(|_| encoder.finish())
+        {
+            Ok(body) => body,
+            Err(e) => {
+                error!("failed to compress OTLP request: {:?}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.sender.push(&body) {
+            error!("failed to spool OTLP request with {:?}", e);
         }
-        let response = request.send();
-        tracing::debug!("http response: {:?}", response);
     }
 
     fn finish(
@@ -144,13 +402,33 @@ impl Collector for StreamingCollector {
         &HashMap<i32, ProcessInfo>,
         &HashMap<ExecutableId, ObjectFileInfo>,
     ) {
+        if !self.sender.wait_until_empty(SPOOL_FLUSH_TIMEOUT) {
+            warn!("some profiles couldn't be exported, they will be retried on the next run");
+        }
+        let stats = self.sender.stats();
+        debug!(
+            "OTLP requests sent: {}, failed attempts: {}, rejected: {}, dropped: {}",
+            stats.sent.load(Ordering::Relaxed),
+            stats.failed.load(Ordering::Relaxed),
+            stats.rejected.load(Ordering::Relaxed),
+            stats.dropped.load(Ordering::Relaxed)
+        );
+
         (AggregatedProfile::new(), &self.procs, &self.objs)
     }
+
+    fn set_session_info(&mut self, session_info: &SessionInfo) {
+        self.profile_duration = session_info.duration;
+        self.event_configs = session_info.event_configs.clone();
+        self.trigger_reason = session_info.trigger_reason.clone();
+    }
 }
 
 #[derive(Default)]
 pub struct AggregatorCollector {
-    profiles: Vec<AggregatedProfile>,
+    profiles: Vec<(AggregatedProfile, SessionInfo)>,
+    /// How the profile that is collected next was taken.
+    session_info: SessionInfo,
     procs: HashMap<i32, ProcessInfo>,
     objs: HashMap<ExecutableId, ObjectFileInfo>,
 }
@@ -169,8 +447,10 @@ impl Collector for AggregatorCollector {
         procs: &HashMap<i32, ProcessInfo>,
         objs: &HashMap<ExecutableId, ObjectFileInfo>,
     ) {
-        self.profiles
-            .push(raw_to_processed(&raw_profile, procs, objs));
+        self.profiles.push((
+            raw_to_processed(&raw_profile, procs, objs),
+            self.session_info.clone(),
+        ));
 
         for (k, v) in procs {
             self.procs.insert(*k, v.clone());
@@ -189,31 +469,554 @@ impl Collector for AggregatorCollector {
         &HashMap<ExecutableId, ObjectFileInfo>,
     ) {
         let _span = span!(Level::DEBUG, "AggregatorCollector.finish").entered();
+        let sessions = self.profiles.iter().map(|(_, session_info)| session_info);
+        let profiles = self
+            .profiles
+            .iter()
+            .map(|(profile, session_info)| (profile, session_info.event_configs.as_slice()));
+        (
+            aggregate(profiles, &shortest_periods(sessions)),
+            &self.procs,
+            &self.objs,
+        )
+    }
 
-        let mut samples_count = HashMap::new();
-        for profile in &self.profiles {
-            for sample in profile {
-                let sample_without_count = AggregatedSample {
-                    count: 0,
-                    ustack: sample.ustack.clone(),
-                    kstack: sample.kstack.clone(),
-                    ..*sample
-                };
-                *samples_count.entry(sample_without_count).or_insert(0) += sample.count
+    fn set_session_info(&mut self, session_info: &SessionInfo) {
+        self.session_info = session_info.clone();
+    }
+
+    fn session_summary(&self) -> Option<SessionSummary> {
+        Some(SessionSummary::new(
+            self.profiles.iter().map(|(_, session_info)| session_info),
+        ))
+    }
+}
+
+/// The event configs with the shortest period each event was sampled at, which can
+/// differ between sessions when the sampling frequency changes.
+fn shortest_periods<'a>(sessions: impl IntoIterator<Item = &'a SessionInfo>) -> Vec<EventConfig> {
+    let mut event_configs: Vec<EventConfig> = Vec::new();
+    for session in sessions {
+        for (event, event_config) in session.event_configs.iter().enumerate() {
+            let Some(shortest) = event_configs.get_mut(event) else {
+                event_configs.push(*event_config);
+                continue;
+            };
+            if let (Some(period), Some(shortest_period)) =
+                (event_config.period(), shortest.period())
+            {
+                if period < shortest_period {
+                    *shortest = *event_config;
+                }
             }
         }
+    }
+    event_configs
+}
 
-        debug!("found {} unique samples", samples_count.len());
-        let profile = samples_count
-            .iter()
-            .map(|(sample, count)| AggregatedSample {
-                count: *count,
+/// Number of samples an on-CPU sample taken with the periods of `from` is worth when
+/// sampling with the ones of `to`, rounded. Events without a known period are left as is.
+fn scaled_count(sample: &AggregatedSample, from: &[EventConfig], to: &[EventConfig]) -> u64 {
+    let period = |event_configs: &[EventConfig]| {
+        event_configs
+            .get(sample.event as usize)
+            .and_then(EventConfig::period)
+    };
+    match (sample.kind, period(from), period(to)) {
+        (SampleKind::OnCpu, Some(from), Some(to)) if from != to && to > 0 => {
+            let (count, from, to) = (sample.count as u128, from as u128, to as u128);
+            ((count * from + to / 2) / to) as u64
+        }
+        _ => sample.count,
+    }
+}
+
+/// Merges the samples with the same stacks of several profiles, each with the event
+/// configs it was sampled with. Their counts are scaled to the periods of
+/// `event_configs`, so samples taken at different frequencies are weighted correctly.
+fn aggregate<'a>(
+    profiles: impl IntoIterator<Item = (&'a AggregatedProfile, &'a [EventConfig])>,
+    event_configs: &[EventConfig],
+) -> AggregatedProfile {
+    let mut samples_count = HashMap::new();
+    for (profile, profile_event_configs) in profiles {
+        for sample in profile {
+            let sample_without_count = AggregatedSample {
+                count: 0,
                 ustack: sample.ustack.clone(),
                 kstack: sample.kstack.clone(),
                 ..*sample
-            })
+            };
+            *samples_count.entry(sample_without_count).or_insert(0) +=
+                scaled_count(sample, profile_event_configs, event_configs)
+        }
+    }
+
+    debug!("found {} unique samples", samples_count.len());
+    samples_count
+        .iter()
+        .map(|(sample, count)| AggregatedSample {
+            count: *count,
+            ustack: sample.ustack.clone(),
+            kstack: sample.kstack.clone(),
+            ..*sample
+        })
+        .collect()
+}
+
+/// Rough number of bytes used by the samples of a profile.
+fn profile_size(profile: &AggregatedProfile) -> usize {
+    profile
+        .iter()
+        .map(|sample| {
+            std::mem::size_of::<AggregatedSample>()
+                + (sample.ustack.len() + sample.kstack.len()) * std::mem::size_of::<Frame>()
+        })
+        .sum()
+}
+
+/// The samples collected in a span of time.
+struct ProfileBucket {
+    start: SystemTime,
+    end: SystemTime,
+    profile: AggregatedProfile,
+    session_info: SessionInfo,
+    size: usize,
+}
+
+/// Profiles collected recently, along with the processes and objects they refer to.
+#[derive(Default)]
+struct RecentProfiles {
+    buckets: VecDeque<ProfileBucket>,
+    /// Sum of the sizes of the buckets.
+    size: usize,
+    procs: HashMap<i32, ProcessInfo>,
+    objs: HashMap<ExecutableId, ObjectFileInfo>,
+    last_collected_at: Option<SystemTime>,
+}
+
+impl RecentProfiles {
+    fn push(
+        &mut self,
+        collected_at: SystemTime,
+        profile: AggregatedProfile,
+        session_info: SessionInfo,
+        max_age: Duration,
+        memory_budget: usize,
+    ) {
+        let size = profile_size(&profile);
+        self.buckets.push_back(ProfileBucket {
+            start: self.last_collected_at.unwrap_or(collected_at),
+            end: collected_at,
+            profile,
+            session_info,
+            size,
+        });
+        self.size += size;
+        self.last_collected_at = Some(collected_at);
+
+        let oldest = collected_at.checked_sub(max_age).unwrap_or(UNIX_EPOCH);
+        let mut evicted = false;
+        while let Some(bucket) = self.buckets.front() {
+            if bucket.end >= oldest && self.size <= memory_budget {
+                break;
+            }
+            self.size -= bucket.size;
+            self.buckets.pop_front();
+            evicted = true;
+        }
+
+        // Forget about the processes and objects that no profile refers to anymore.
+        if evicted {
+            let pids: HashSet<i32> = self
+                .buckets
+                .iter()
+                .flat_map(|bucket| bucket.profile.iter().map(|sample| sample.pid))
+                .collect();
+            self.procs.retain(|pid, _| pids.contains(pid));
+            let executable_ids: HashSet<ExecutableId> = self
+                .procs
+                .values()
+                .flat_map(|proc_info| proc_info.mappings.0.iter())
+                .map(|mapping| mapping.executable_id)
+                .collect();
+            self.objs
+                .retain(|executable_id, _| executable_ids.contains(executable_id));
+        }
+    }
+}
+
+/// The profile of a span of time, with the processes and objects it refers to.
+pub struct RecentProfile {
+    pub profile: AggregatedProfile,
+    pub procs: HashMap<i32, ProcessInfo>,
+    pub objs: HashMap<ExecutableId, ObjectFileInfo>,
+    /// Span of time the samples were taken in. Might be shorter than the one requested
+    /// if the oldest profiles were evicted or haven't been collected yet.
+    pub start: SystemTime,
+    pub end: SystemTime,
+    /// For how long samples were taken, which excludes the time profiling was paused.
+    pub duration: Duration,
+    /// The events that were sampled, with the periods the counts correspond to.
+    pub event_configs: Vec<EventConfig>,
+}
+
+/// Keeps the profiles collected in the last `max_age`, as a ring of buckets of the
+/// samples collected at each point in time, so the profile of any recent span of time
+/// can be requested after the fact. The oldest buckets are evicted earlier if the
+/// samples take more than `memory_budget` bytes. The processes and objects the samples
+/// refer to aren't accounted for.
+///
+/// It can be cloned to query the profiles while it's being used by the profiler.
+#[derive(Clone)]
+pub struct RecentProfilesCollector {
+    max_age: Duration,
+    memory_budget: usize,
+    recent: Arc<Mutex<RecentProfiles>>,
+    /// Notified every time a profile is collected.
+    collected: Arc<Condvar>,
+    /// How the profile that is collected next was taken.
+    session_info: SessionInfo,
+    // Only used by `finish`, which has to return references.
+    procs: HashMap<i32, ProcessInfo>,
+    objs: HashMap<ExecutableId, ObjectFileInfo>,
+}
+
+impl RecentProfilesCollector {
+    pub fn new(max_age: Duration, memory_budget: usize) -> Self {
+        Self {
+            max_age,
+            memory_budget,
+            recent: Arc::new(Mutex::new(RecentProfiles::default())),
+            collected: Arc::new(Condvar::new()),
+            session_info: SessionInfo::default(),
+            procs: HashMap::new(),
+            objs: HashMap::new(),
+        }
+    }
+
+    /// How long profiles are kept for.
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    /// Returns the samples of the profiles collected in the last `duration`.
+    pub fn profile(&self, duration: Duration) -> RecentProfile {
+        let now = SystemTime::now();
+        self.profile_between(now.checked_sub(duration).unwrap_or(UNIX_EPOCH), now)
+    }
+
+    /// Returns the samples of the buckets that overlap with the span from `start` to
+    /// `end`.
+    pub fn profile_between(&self, start: SystemTime, end: SystemTime) -> RecentProfile {
+        let recent = self.recent.lock().unwrap();
+        let buckets: Vec<&ProfileBucket> = recent
+            .buckets
+            .iter()
+            .filter(|bucket| bucket.end > start && bucket.start < end)
             .collect();
+        let (span_start, span_end) = match (buckets.first(), buckets.last()) {
+            (Some(first), Some(last)) => (first.start.max(start), last.end.min(end)),
+            _ => (start, start),
+        };
+        let event_configs = shortest_periods(buckets.iter().map(|bucket| &bucket.session_info));
+        let profiles = buckets.iter().map(|bucket| {
+            (
+                &bucket.profile,
+                bucket.session_info.event_configs.as_slice(),
+            )
+        });
+        RecentProfile {
+            profile: aggregate(profiles, &event_configs),
+            procs: recent.procs.clone(),
+            objs: recent.objs.clone(),
+            start: span_start,
+            end: span_end,
+            duration: buckets
+                .iter()
+                .map(|bucket| bucket.session_info.duration)
+                .sum(),
+            event_configs,
+        }
+    }
+
+    /// Waits until a profile is collected at or after `time`, for up to `timeout`.
+    /// Returns when it was collected, if it was.
+    pub fn wait_for_collection(&self, time: SystemTime, timeout: Duration) -> Option<SystemTime> {
+        let recent = self.recent.lock().unwrap();
+        let (recent, _) = self
+            .collected
+            .wait_timeout_while(recent, timeout, |recent| {
+                recent
+                    .last_collected_at
+                    .is_none_or(|collected_at| collected_at < time)
+            })
+            .unwrap();
+        recent
+            .last_collected_at
+            .filter(|collected_at| *collected_at >= time)
+    }
+}
+
+impl Collector for RecentProfilesCollector {
+    fn collect(
+        &mut self,
+        raw_profile: RawAggregatedProfile,
+        procs: &HashMap<i32, ProcessInfo>,
+        objs: &HashMap<ExecutableId, ObjectFileInfo>,
+    ) {
+        let profile = raw_to_processed(&raw_profile, procs, objs);
+        let mut recent = self.recent.lock().unwrap();
+        let pids: HashSet<i32> = profile.iter().map(|sample| sample.pid).collect();
+        for pid in pids {
+            let Some(proc_info) = procs.get(&pid) else {
+                continue;
+            };
+            for mapping in &proc_info.mappings.0 {
+                if let Some(object_file_info) = objs.get(&mapping.executable_id) {
+                    recent
+                        .objs
+                        .insert(mapping.executable_id, object_file_info.clone());
+                }
+            }
+            recent.procs.insert(pid, proc_info.clone());
+        }
+        recent.push(
+            SystemTime::now(),
+            profile,
+            self.session_info.clone(),
+            self.max_age,
+            self.memory_budget,
+        );
+        self.collected.notify_all();
+    }
+
+    fn finish(
+        &self,
+    ) -> (
+        AggregatedProfile,
+        &HashMap<i32, ProcessInfo>,
+        &HashMap<ExecutableId, ObjectFileInfo>,
+    ) {
+        (AggregatedProfile::new(), &self.procs, &self.objs)
+    }
+
+    fn set_session_info(&mut self, session_info: &SessionInfo) {
+        self.session_info = session_info.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::perf_events::{PerfEvent, Sampling};
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(is_retryable(reqwest::StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable(reqwest::StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!is_retryable(reqwest::StatusCode::BAD_REQUEST));
+        assert!(!is_retryable(reqwest::StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    #[test]
+    fn test_aggregator_session_summary() {
+        let event_configs = |sample_freq| {
+            vec![EventConfig {
+                event: PerfEvent::CpuClock,
+                sampling: Sampling::Frequency(sample_freq),
+            }]
+        };
+        let mut collector = AggregatorCollector::new();
+        for (seconds, sample_freq, trigger_reason) in [
+            (5, 10, Some("high CPU usage")),
+            (3, 20, None),
+            (2, 10, Some("high CPU usage")),
+        ] {
+            collector.set_session_info(&SessionInfo {
+                duration: Duration::from_secs(seconds),
+                event_configs: event_configs(sample_freq),
+                trigger_reason: trigger_reason.map(String::from),
+            });
+            collector.collect(Vec::new(), &HashMap::new(), &HashMap::new());
+        }
+        assert_eq!(
+            collector.session_summary(),
+            Some(SessionSummary {
+                duration: Duration::from_secs(10),
+                event_configs: event_configs(20),
+                trigger_reasons: vec!["high CPU usage".into()],
+            })
+        );
+
+        // The counts are scaled to the highest frequency.
+        let sample = |count| AggregatedSample {
+            pid: 1,
+            tid: 1,
+            ustack: Vec::new(),
+            kstack: Vec::new(),
+            count,
+            kind: SampleKind::OnCpu,
+            event: 0,
+        };
+        for ((profile, _), count) in collector.profiles.iter_mut().zip([1, 3, 2]) {
+            profile.push(sample(count));
+        }
+        let (profile, _, _) = collector.finish();
+        assert_eq!(profile, vec![sample(2 + 3 + 4)]);
+    }
+
+    #[test]
+    fn test_recent_profiles() {
+        let sample = |pid, count| AggregatedSample {
+            pid,
+            tid: pid,
+            ustack: Vec::new(),
+            kstack: Vec::new(),
+            count,
+            kind: SampleKind::OnCpu,
+            event: 0,
+        };
+        let collector = RecentProfilesCollector::new(Duration::from_secs(60), usize::MAX);
+        let now = SystemTime::now();
+        {
+            let mut recent = collector.recent.lock().unwrap();
+            for (age, profile) in [
+                (90, vec![sample(1, 1)]),
+                (20, vec![sample(2, 2)]),
+                (0, vec![sample(2, 3)]),
+            ] {
+                recent.push(
+                    now - Duration::from_secs(age),
+                    profile,
+                    SessionInfo {
+                        duration: Duration::from_secs(10),
+                        ..Default::default()
+                    },
+                    collector.max_age,
+                    collector.memory_budget,
+                );
+            }
+            assert_eq!(recent.buckets.len(), 2, "the oldest profile is evicted");
+            assert_eq!(recent.size, 2 * profile_size(&vec![sample(2, 2)]));
+        }
+
+        assert_eq!(
+            collector.profile(Duration::from_secs(10)).profile,
+            vec![sample(2, 3)]
+        );
+        assert_eq!(
+            collector.profile(Duration::from_secs(30)).profile,
+            vec![sample(2, 5)]
+        );
+        assert_eq!(
+            collector.profile(Duration::from_secs(120)).profile,
+            vec![sample(2, 5)]
+        );
+
+        // The second bucket has the samples collected from 90 to 20 seconds ago.
+        let recent =
+            collector.profile_between(now - Duration::from_secs(60), now - Duration::from_secs(20));
+        assert_eq!(recent.profile, vec![sample(2, 2)]);
+        assert_eq!(recent.start, now - Duration::from_secs(60));
+        assert_eq!(recent.end, now - Duration::from_secs(20));
+        assert_eq!(recent.duration, Duration::from_secs(10));
+        let recent = collector.profile_between(now + Duration::from_secs(1), SystemTime::now());
+        assert!(recent.profile.is_empty());
+
+        assert_eq!(
+            collector.wait_for_collection(now, Duration::ZERO),
+            Some(now)
+        );
+        assert_eq!(
+            collector.wait_for_collection(now + Duration::from_secs(1), Duration::from_millis(10)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_recent_profiles_memory_budget() {
+        let profile = vec![AggregatedSample {
+            pid: 1,
+            tid: 1,
+            ustack: vec![Frame::default(); 4],
+            kstack: Vec::new(),
+            count: 1,
+            kind: SampleKind::OnCpu,
+            event: 0,
+        }];
+        let memory_budget = 2 * profile_size(&profile);
+        let collector = RecentProfilesCollector::new(Duration::from_secs(60), memory_budget);
+        let now = SystemTime::now();
+
+        let mut recent = collector.recent.lock().unwrap();
+        for age in [3, 2, 1] {
+            recent.push(
+                now - Duration::from_secs(age),
+                profile.clone(),
+                SessionInfo::default(),
+                collector.max_age,
+                collector.memory_budget,
+            );
+        }
+        assert_eq!(recent.buckets.len(), 2);
+        assert_eq!(recent.size, memory_budget);
+        assert_eq!(
+            recent.buckets.front().unwrap().start,
+            now - Duration::from_secs(3)
+        );
+    }
+
+    #[test]
+    fn test_recent_profiles_sampling_frequency() {
+        let sample = |count| AggregatedSample {
+            pid: 1,
+            tid: 1,
+            ustack: Vec::new(),
+            kstack: Vec::new(),
+            count,
+            kind: SampleKind::OnCpu,
+            event: 0,
+        };
+        let session_info = |sample_freq| SessionInfo {
+            duration: Duration::from_secs(10),
+            event_configs: vec![EventConfig {
+                event: PerfEvent::CpuClock,
+                sampling: Sampling::Frequency(sample_freq),
+            }],
+            trigger_reason: None,
+        };
+        let collector = RecentProfilesCollector::new(Duration::from_secs(60), usize::MAX);
+        let now = SystemTime::now();
+        {
+            let mut recent = collector.recent.lock().unwrap();
+            for (age, count, sample_freq) in [(20, 2, 10), (10, 3, 20), (0, 1, 5)] {
+                recent.push(
+                    now - Duration::from_secs(age),
+                    vec![sample(count)],
+                    session_info(sample_freq),
+                    collector.max_age,
+                    collector.memory_budget,
+                );
+            }
+        }
+
+        // The counts are scaled to the highest frequency.
+        let recent = collector.profile(Duration::from_secs(30));
+        assert_eq!(recent.profile, vec![sample(2 * 2 + 3 + 4)]);
+        assert_eq!(recent.event_configs, session_info(20).event_configs);
+        assert_eq!(recent.duration, Duration::from_secs(30));
+
+        let recent = collector.profile(Duration::from_secs(5));
+        assert_eq!(recent.profile, vec![sample(1)]);
+        assert_eq!(recent.event_configs, session_info(5).event_configs);
+    }
 
-        (profile, &self.procs, &self.objs)
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("2"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_retry_after(" 30 "), Some(Duration::from_secs(30)));
+        // HTTP dates aren't supported.
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
     }
 }
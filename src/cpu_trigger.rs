@@ -0,0 +1,247 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::process::Pid;
+use crate::process_selector::CGROUP_ROOT;
+use crate::util::process_cpu_time;
+
+/// Whose CPU usage is watched to decide when to profile.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CpuTriggerTarget {
+    Process(Pid),
+    /// A cgroup, relative to the root of the hierarchy.
+    Cgroup(PathBuf),
+}
+
+impl CpuTriggerTarget {
+    /// Watches a cgroup, either relative to the root of the hierarchy or with the path
+    /// it has under [`CGROUP_ROOT`].
+    pub fn cgroup(path: &Path) -> Self {
+        let path = path.strip_prefix(CGROUP_ROOT).unwrap_or(path);
+        Self::Cgroup(Path::new("/").join(path))
+    }
+
+    /// CPU time used so far, from `/proc/<pid>/stat` for processes and from `cpu.stat`
+    /// for cgroups under `cgroup_root`.
+    fn cpu_time(&self, cgroup_root: &Path) -> io::Result<Duration> {
+        match self {
+            Self::Process(pid) => process_cpu_time(*pid),
+            Self::Cgroup(path) => {
+                let path = cgroup_root
+                    .join(path.strip_prefix("/").unwrap_or(path))
+                    .join("cpu.stat");
+                parse_cgroup_cpu_usage(&fs::read_to_string(path)?).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "no usage_usec in cpu.stat")
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for CpuTriggerTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Process(pid) => write!(f, "process {pid}"),
+            Self::Cgroup(path) => write!(f, "cgroup {}", path.display()),
+        }
+    }
+}
+
+/// Reads the CPU time used by a cgroup out of its `cpu.stat`.
+fn parse_cgroup_cpu_usage(cpu_stat: &str) -> Option<Duration> {
+    cpu_stat
+        .lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .and_then(|usec| usec.trim().parse().ok())
+        .map(Duration::from_micros)
+}
+
+#[derive(Debug, Clone)]
+pub struct CpuTriggerConfig {
+    pub target: CpuTriggerTarget,
+    /// CPU usage that starts profiling, in percent of one CPU, so it can be over 100
+    /// for multithreaded processes and cgroups.
+    pub threshold_percent: f64,
+    /// For how long the CPU usage has to stay below the threshold to stop profiling.
+    pub cooldown: Duration,
+    /// How often the CPU usage is checked.
+    pub interval: Duration,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CpuTriggerTransition {
+    /// Start profiling, for the given reason.
+    Start(String),
+    Stop,
+}
+
+/// Decides when to profile by periodically checking the CPU usage of a process or a
+/// cgroup. Profiling starts as soon as the usage crosses the threshold and stops once
+/// it has been below it for the cooldown.
+pub struct CpuTrigger {
+    config: CpuTriggerConfig,
+    cgroup_root: PathBuf,
+    /// When the CPU time was last read, and its value.
+    last_reading: Option<(Instant, Duration)>,
+    triggered: bool,
+    below_threshold_since: Option<Instant>,
+}
+
+impl CpuTrigger {
+    pub fn new(config: CpuTriggerConfig) -> Self {
+        Self {
+            config,
+            cgroup_root: PathBuf::from(CGROUP_ROOT),
+            last_reading: None,
+            triggered: false,
+            below_threshold_since: None,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.config.interval
+    }
+
+    /// Reads the CPU usage and returns whether profiling should start or stop.
+    pub fn check(&mut self) -> Option<CpuTriggerTransition> {
+        let cpu_time = match self.config.target.cpu_time(&self.cgroup_root) {
+            Ok(cpu_time) => Some(cpu_time),
+            Err(e) => {
+                debug!(
+                    "could not read the CPU usage of {} due to {:?}",
+                    self.config.target, e
+                );
+                None
+            }
+        };
+        self.update(Instant::now(), cpu_time)
+    }
+
+    /// Handles a reading of the CPU time used by the target. Targets that can't be read,
+    /// such as processes that exited, count as being below the threshold.
+    fn update(&mut self, now: Instant, cpu_time: Option<Duration>) -> Option<CpuTriggerTransition> {
+        let previous_reading = self.last_reading.take();
+        self.last_reading = cpu_time.map(|cpu_time| (now, cpu_time));
+        let usage_percent = match (previous_reading, cpu_time) {
+            (Some((then, previous_cpu_time)), Some(cpu_time)) if now > then => Some(
+                100.0 * cpu_time.saturating_sub(previous_cpu_time).as_secs_f64()
+                    / (now - then).as_secs_f64(),
+            ),
+            _ => None,
+        };
+
+        match usage_percent {
+            Some(usage_percent) if usage_percent >= self.config.threshold_percent => {
+                self.below_threshold_since = None;
+                if self.triggered {
+                    return None;
+                }
+                self.triggered = true;
+                Some(CpuTriggerTransition::Start(format!(
+                    "CPU usage of {} was {:.1}%, over the {}% threshold",
+                    self.config.target, usage_percent, self.config.threshold_percent
+                )))
+            }
+            _ if self.triggered => {
+                let below_threshold_since = *self.below_threshold_since.get_or_insert(now);
+                if now - below_threshold_since < self.config.cooldown {
+                    return None;
+                }
+                self.triggered = false;
+                self.below_threshold_since = None;
+                Some(CpuTriggerTransition::Stop)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cgroup_cpu_usage() {
+        let cpu_stat = "usage_usec 2500000\nuser_usec 2000000\nsystem_usec 500000\n";
+        assert_eq!(
+            parse_cgroup_cpu_usage(cpu_stat),
+            Some(Duration::from_millis(2500))
+        );
+        assert_eq!(parse_cgroup_cpu_usage("user_usec 1\n"), None);
+    }
+
+    #[test]
+    fn test_cgroup_cpu_time() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("system.slice/app.service")).unwrap();
+        fs::write(
+            root.path().join("system.slice/app.service/cpu.stat"),
+            "usage_usec 1000\n",
+        )
+        .unwrap();
+
+        let target = CpuTriggerTarget::cgroup(Path::new("/sys/fs/cgroup/system.slice/app.service"));
+        assert_eq!(target.to_string(), "cgroup /system.slice/app.service");
+        assert_eq!(
+            target.cpu_time(root.path()).unwrap(),
+            Duration::from_millis(1)
+        );
+        assert!(CpuTriggerTarget::cgroup(Path::new("/missing"))
+            .cpu_time(root.path())
+            .is_err());
+    }
+
+    #[test]
+    fn test_trigger() {
+        let mut trigger = CpuTrigger::new(CpuTriggerConfig {
+            target: CpuTriggerTarget::Process(1234),
+            threshold_percent: 80.0,
+            cooldown: Duration::from_secs(10),
+            interval: Duration::from_secs(1),
+        });
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let cpu = |millis| Some(Duration::from_millis(millis));
+
+        // The first reading is only used as a baseline.
+        assert_eq!(trigger.update(at(0), cpu(0)), None);
+        // 50% of a CPU.
+        assert_eq!(trigger.update(at(1), cpu(500)), None);
+        // 150% of a CPU.
+        assert_eq!(
+            trigger.update(at(2), cpu(2000)),
+            Some(CpuTriggerTransition::Start(
+                "CPU usage of process 1234 was 150.0%, over the 80% threshold".into()
+            ))
+        );
+        assert_eq!(trigger.update(at(3), cpu(3000)), None);
+
+        // Going back over the threshold during the cooldown restarts it.
+        assert_eq!(trigger.update(at(4), cpu(3000)), None);
+        assert_eq!(trigger.update(at(13), cpu(3000)), None);
+        assert_eq!(trigger.update(at(14), cpu(4000)), None);
+        assert_eq!(trigger.update(at(15), cpu(4000)), None);
+        assert_eq!(trigger.update(at(24), cpu(4000)), None);
+        assert_eq!(
+            trigger.update(at(25), cpu(4000)),
+            Some(CpuTriggerTransition::Stop)
+        );
+
+        // A process that can't be read anymore is below the threshold.
+        assert_eq!(trigger.update(at(26), cpu(4000)), None);
+        assert!(matches!(
+            trigger.update(at(27), cpu(5000)),
+            Some(CpuTriggerTransition::Start(_))
+        ));
+        assert_eq!(trigger.update(at(28), None), None);
+        assert_eq!(
+            trigger.update(at(38), None),
+            Some(CpuTriggerTransition::Stop)
+        );
+    }
+}
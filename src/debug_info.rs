@@ -1,29 +1,98 @@
-use std::fs::File;
-use std::io::BufReader;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
+use crossbeam_channel::{bounded, Receiver, Sender};
+use memmap2::Mmap;
+use object::Object;
+use reqwest::blocking::Body;
 use reqwest::StatusCode;
-use tracing::instrument;
+use tracing::{debug, error, instrument};
 
-use lightswitch_object::BuildId;
+use lightswitch_object::{write_debug_sections_elf, BuildId, ObjectFile};
+
+use crate::debuginfod::DebuginfodClient;
+use crate::unwind_info::find_debug_file;
+
+/// How long symbolizing a profile can wait for debug information to be downloaded, in
+/// total. The rest is used for later profiles once it's downloaded.
+pub const DEBUG_INFO_DOWNLOAD_WAIT_PER_PROFILE: Duration = Duration::from_secs(2);
 
 /// Handles with debug information.
 ///
-/// This currently experimental, not feature-complete and not used yet during
-/// symbolization. The end goal would be to keep track of every debug info
-/// that's either present locally or remotely (depending on configuration), while
-/// minimizing the number of open FDs, file copies, and race condition windows.
+/// This currently experimental and not feature-complete. The end goal would be to
+/// keep track of every debug info that's either present locally or remotely
+/// (depending on configuration), while minimizing the number of open FDs, file
+/// copies, and race condition windows. Local debug information is used during
+/// symbolization, see [`DebugInfoSources`].
 pub trait DebugInfoManager {
+    /// Adds the debug information of an object. It's read from `file`, the object as it
+    /// was opened when it was profiled, as `debug_info` might refer to a different file
+    /// by then. `debug_info` is used to find the object's separate debug file, if any.
     fn add_if_not_present(
         &self,
         name: &str,
         build_id: &BuildId,
         debug_info: &Path,
+        file: &File,
     ) -> anyhow::Result<()>;
-    fn debug_info_path(&self) -> Option<PathBuf>;
+    /// Returns the local path to the debug information for a build id, if any.
+    fn debug_info_path(&self, build_id: &BuildId) -> Option<PathBuf>;
+}
+
+/// Where to look for the debug information of objects when symbolizing, for objects
+/// that were stripped, deleted or replaced by a different version since they were
+/// profiled.
+#[derive(Clone, Default)]
+pub struct DebugInfoSources {
+    pub store: Option<Arc<dyn DebugInfoManager + Send + Sync>>,
+    pub debuginfod: Option<Arc<DebuginfodClient>>,
+}
+
+impl DebugInfoSources {
+    /// Returns the file to symbolize the object at `path`, which had the given build id
+    /// when it was profiled. Debug information that has to be downloaded is waited for
+    /// until `deadline` at most.
+    pub fn symbolization_path(
+        &self,
+        path: &Path,
+        build_id: Option<&BuildId>,
+        deadline: Instant,
+    ) -> PathBuf {
+        if self.store.is_none() && self.debuginfod.is_none() {
+            return path.to_path_buf();
+        }
+
+        let object = ObjectFile::from_path(path).ok();
+        if let Some(object) = &object {
+            if object.has_debug_info() && build_id.is_none_or(|id| id == object.build_id()) {
+                return path.to_path_buf();
+            }
+        }
+
+        let Some(build_id) = build_id.or(object.as_ref().map(|object| object.build_id())) else {
+            return path.to_path_buf();
+        };
+        self.store
+            .as_ref()
+            .and_then(|store| store.debug_info_path(build_id))
+            .or_else(|| {
+                self.debuginfod
+                    .as_ref()
+                    .and_then(|debuginfod| debuginfod.debuginfo(build_id, deadline))
+            })
+            .unwrap_or_else(|| {
+                debug!("no debug information found for {}", path.display());
+                path.to_path_buf()
+            })
+    }
 }
 
 pub struct DebugInfoBackendNull {}
@@ -33,62 +102,351 @@ impl DebugInfoManager for DebugInfoBackendNull {
         _name: &str,
         _build_id: &BuildId,
         _debug_info: &Path,
+        _file: &File,
     ) -> anyhow::Result<()> {
         Ok(())
     }
 
-    fn debug_info_path(&self) -> Option<PathBuf> {
+    fn debug_info_path(&self, _build_id: &BuildId) -> Option<PathBuf> {
         None
     }
 }
 
+/// Directory with the debug information, laid out like `/usr/lib/debug/.build-id`.
+const BUILD_ID_DIRECTORY: &str = ".build-id";
+
+#[derive(Debug)]
+struct StoreEntry {
+    size: u64,
+    last_used: u64,
+}
+
+/// Entries of the filesystem store in least recently used order.
+#[derive(Debug, Default)]
+struct StoreEntries {
+    entries: HashMap<PathBuf, StoreEntry>,
+    uses: u64,
+}
+
+impl StoreEntries {
+    fn touch(&mut self, path: &Path, size: u64) {
+        self.uses += 1;
+        self.entries.insert(
+            path.to_path_buf(),
+            StoreEntry {
+                size,
+                last_used: self.uses,
+            },
+        );
+    }
+
+    fn size(&self) -> u64 {
+        self.entries.values().map(|entry| entry.size).sum()
+    }
+
+    fn least_recently_used(&self) -> Option<PathBuf> {
+        self.entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(path, _)| path.clone())
+    }
+}
+
+/// Number of objects that can be waiting to be added to the filesystem store.
+const STORE_QUEUE_SIZE: usize = 64;
+
+/// Local store of debug information by build id, with the same layout as
+/// `/usr/lib/debug/.build-id` so it can be used by gdb or served with debuginfod too.
+/// Objects are hardlinked if possible, so their debug information is still around after
+/// they are deleted or upgraded, and copied otherwise. The least recently used entries
+/// are evicted once the store grows over its size limit.
+///
+/// Objects are added in a background thread, as copying them can take a while.
 #[derive(Debug)]
 pub struct DebugInfoBackendFilesystem {
-    pub path: PathBuf,
+    store: Arc<FilesystemStore>,
+    /// Build ids queued or being added.
+    pending: Arc<Mutex<HashSet<BuildId>>>,
+    additions: Sender<StoreRequest>,
 }
+
+#[derive(Debug)]
+struct StoreRequest {
+    build_id: BuildId,
+    debug_info: PathBuf,
+    /// A duplicate of the descriptor the object was opened with, see [`UploadRequest`].
+    file: File,
+}
+
 impl DebugInfoManager for DebugInfoBackendFilesystem {
-    #[instrument]
+    #[instrument(skip(self))]
     fn add_if_not_present(
         &self,
         _name: &str,
         build_id: &BuildId,
         debug_info: &Path,
+        file: &File,
     ) -> anyhow::Result<()> {
         // try to find, else extract
-        if self.find_in_fs(build_id) {
+        if self.store.find_in_fs(build_id) {
+            return Ok(());
+        }
+        let file = file.try_clone()?;
+        if !self.pending.lock().unwrap().insert(build_id.clone()) {
             return Ok(());
         }
 
-        self.add_to_fs(build_id, debug_info)
+        let request = StoreRequest {
+            build_id: build_id.clone(),
+            debug_info: debug_info.to_path_buf(),
+            file,
+        };
+        if let Err(e) = self.additions.try_send(request) {
+            self.pending.lock().unwrap().remove(build_id);
+            return Err(anyhow!("could not queue debuginfo addition: {}", e));
+        }
+        Ok(())
     }
 
-    fn debug_info_path(&self) -> Option<PathBuf> {
-        todo!()
+    fn debug_info_path(&self, build_id: &BuildId) -> Option<PathBuf> {
+        self.store.debug_info_path(build_id)
     }
 }
 
 impl DebugInfoBackendFilesystem {
+    /// Opens the store at `path`, picking up the debug information added by previous
+    /// runs.
+    pub fn new(path: &Path, max_size_bytes: u64) -> anyhow::Result<Self> {
+        let store = Arc::new(FilesystemStore::open(path, max_size_bytes)?);
+        let pending = Arc::new(Mutex::new(HashSet::new()));
+        let (additions, requests) = bounded::<StoreRequest>(STORE_QUEUE_SIZE);
+
+        let thread_store = store.clone();
+        let thread_pending = pending.clone();
+        thread::Builder::new()
+            .name("debuginfo-store".to_string())
+            .spawn(move || {
+                for request in requests {
+                    if let Err(e) = thread_store.add_to_fs(
+                        &request.build_id,
+                        &request.debug_info,
+                        &request.file,
+                    ) {
+                        error!(
+                            "adding debuginfo for {} failed with: {}",
+                            request.build_id,
+                            e.root_cause()
+                        );
+                    }
+                    thread_pending.lock().unwrap().remove(&request.build_id);
+                }
+            })?;
+
+        Ok(Self {
+            store,
+            pending,
+            additions,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct FilesystemStore {
+    path: PathBuf,
+    max_size_bytes: u64,
+    entries: Mutex<StoreEntries>,
+}
+
+impl FilesystemStore {
+    fn open(path: &Path, max_size_bytes: u64) -> anyhow::Result<Self> {
+        let build_id_dir = path.join(BUILD_ID_DIRECTORY);
+        fs::create_dir_all(&build_id_dir)?;
+
+        let mut existing = Vec::new();
+        for prefix_dir in fs::read_dir(&build_id_dir)? {
+            for entry in fs::read_dir(prefix_dir?.path())? {
+                let path = entry?.path();
+                if path
+                    .extension()
+                    .is_some_and(|extension| extension == "debug")
+                {
+                    let metadata = fs::metadata(&path)?;
+                    existing.push((metadata.modified()?, metadata.len(), path));
+                }
+            }
+        }
+        existing.sort();
+
+        let mut entries = StoreEntries::default();
+        for (_, size, path) in existing {
+            entries.touch(&path, size);
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size_bytes,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    fn debug_info_path(&self, build_id: &BuildId) -> Option<PathBuf> {
+        let path = self.entry_path(build_id);
+        let mut entries = self.entries.lock().unwrap();
+        let size = entries.entries.get(&path)?.size;
+        entries.touch(&path, size);
+        Some(path)
+    }
+
+    /// Path of the debug information for a build id, `.build-id/xx/rest.debug`.
+    fn entry_path(&self, build_id: &BuildId) -> PathBuf {
+        let hex = build_id
+            .data
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        let (prefix, rest) = hex.split_at(2);
+        self.path
+            .join(BUILD_ID_DIRECTORY)
+            .join(prefix)
+            .join(format!("{rest}.debug"))
+    }
+
     fn find_in_fs(&self, build_id: &BuildId) -> bool {
-        self.path.join(build_id.to_string()).exists()
+        self.entries
+            .lock()
+            .unwrap()
+            .entries
+            .contains_key(&self.entry_path(build_id))
+    }
+
+    fn add_to_fs(&self, build_id: &BuildId, debug_info: &Path, file: &File) -> anyhow::Result<()> {
+        let path = self.entry_path(build_id);
+        fs::create_dir_all(path.parent().expect("entries are in a subdirectory"))?;
+
+        // Stripped objects might have a separate debug file, which is stored instead.
+        let mmap = unsafe { Mmap::map(file) }?;
+        let object = object::File::parse(&*mmap)?;
+        let debug_file = if object.has_debug_symbols() {
+            None
+        } else {
+            find_debug_file(debug_info, &object)
+        };
+
+        match debug_file {
+            Some(debug_file) => write_atomically(&path, &debug_file)?,
+            // Hardlinks only work within the same filesystem, copy otherwise. The path
+            // might refer to a different file than the one profiled by now, in which case
+            // the link is replaced by a copy too.
+            None => {
+                if fs::hard_link(debug_info, &path).is_err() || !same_file(&path, file)? {
+                    write_atomically(&path, &mmap)?;
+                }
+            }
+        }
+
+        let size = fs::metadata(&path)?.len();
+        let mut entries = self.entries.lock().unwrap();
+        entries.touch(&path, size);
+        self.evict(&mut entries, &path)
+    }
+
+    /// Evicts the least recently used entries, other than the one just added, until the
+    /// store is under its size limit.
+    fn evict(&self, entries: &mut StoreEntries, keep: &Path) -> anyhow::Result<()> {
+        while entries.size() > self.max_size_bytes {
+            let Some(path) = entries.least_recently_used() else {
+                break;
+            };
+            if path == keep {
+                break;
+            }
+            debug!("evicting {} from the debug info store", path.display());
+            entries.entries.remove(&path);
+            fs::remove_file(&path)?;
+        }
+        Ok(())
     }
+}
+
+/// Whether `path` is the given opened file.
+fn same_file(path: &Path, file: &File) -> io::Result<bool> {
+    let (linked, opened) = (fs::metadata(path)?, file.metadata()?);
+    Ok(linked.dev() == opened.dev() && linked.ino() == opened.ino())
+}
 
-    fn add_to_fs(&self, build_id: &BuildId, debug_info: &Path) -> anyhow::Result<()> {
-        // TODO: add support for other methods beyond copying. For example
-        // hardlinks could be used and only fall back to copying if the src
-        // and dst filesystems differ.
-        let mut reader = BufReader::new(File::open(debug_info)?);
-        let mut writer = std::fs::File::create(self.path.join(build_id.to_string()))?;
-        std::io::copy(&mut reader, &mut writer)?;
+/// Writes a file under a temporary name first, so partially written files are never
+/// used.
+fn write_atomically(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let temporary_path = path.with_extension("tmp");
+    let mut file = File::create(&temporary_path)?;
+    file.write_all(contents)?;
+    fs::rename(&temporary_path, path)?;
+    Ok(())
+}
+
+/// Number of debug information uploads that can be waiting to be processed.
+const UPLOAD_QUEUE_SIZE: usize = 64;
+/// Where the build ids the backend is known to have are recorded, one per line.
+const KNOWN_BUILD_IDS_FILE: &str = "debuginfo-known-build-ids";
+
+#[derive(Debug)]
+struct UploadRequest {
+    name: String,
+    build_id: BuildId,
+    debug_info: PathBuf,
+    /// A duplicate of the descriptor the object was opened with, so what's uploaded is the
+    /// object that was profiled, even if `debug_info` refers to a different file by then.
+    file: File,
+}
+
+/// Persistent record of the build ids the backend already has, so it isn't queried
+/// about them again, even after restarts.
+#[derive(Debug)]
+struct KnownBuildIds {
+    path: PathBuf,
+    build_ids: Mutex<HashSet<String>>,
+}
+
+impl KnownBuildIds {
+    fn load(path: &Path) -> Self {
+        let build_ids = fs::read_to_string(path)
+            .map(|contents| contents.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+        KnownBuildIds {
+            path: path.to_path_buf(),
+            build_ids: Mutex::new(build_ids),
+        }
+    }
+
+    fn contains(&self, build_id: &BuildId) -> bool {
+        self.build_ids
+            .lock()
+            .unwrap()
+            .contains(&build_id.to_string())
+    }
+
+    fn insert(&self, build_id: &BuildId) -> anyhow::Result<()> {
+        let build_id = build_id.to_string();
+        if self.build_ids.lock().unwrap().insert(build_id.clone()) {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            writeln!(file, "{build_id}")?;
+        }
         Ok(())
     }
 }
 
+/// Uploads debug information to the backend in a background thread, so adding it never
+/// blocks. Requests for the same build id are merged while they are queued or being
+/// uploaded, and uploads are compressed and rate limited.
 #[derive(Debug)]
 pub struct DebugInfoBackendRemote {
-    pub token: Option<String>,
-    pub server_url: String,
-    pub query_client: reqwest::blocking::Client,
-    pub upload_client: reqwest::blocking::Client,
+    known_build_ids: Arc<KnownBuildIds>,
+    /// Build ids queued or being uploaded.
+    pending: Arc<Mutex<HashSet<BuildId>>>,
+    uploads: Sender<UploadRequest>,
 }
 
 impl DebugInfoBackendRemote {
@@ -97,8 +455,15 @@ impl DebugInfoBackendRemote {
         server_url: String,
         query_timeout: Duration,
         upload_timeout: Duration,
+        upload_bytes_per_second: u64,
+        cache_dir: &Path,
     ) -> anyhow::Result<Self> {
-        Ok(DebugInfoBackendRemote {
+        fs::create_dir_all(cache_dir)?;
+        let known_build_ids = Arc::new(KnownBuildIds::load(&cache_dir.join(KNOWN_BUILD_IDS_FILE)));
+        let pending = Arc::new(Mutex::new(HashSet::new()));
+        let (uploads, requests) = bounded(UPLOAD_QUEUE_SIZE);
+
+        let uploader = RemoteUploader {
             token,
             server_url,
             query_client: reqwest::blocking::Client::builder()
@@ -107,6 +472,19 @@ impl DebugInfoBackendRemote {
             upload_client: reqwest::blocking::Client::builder()
                 .timeout(upload_timeout)
                 .build()?,
+            upload_bytes_per_second,
+            cache_dir: cache_dir.to_path_buf(),
+            known_build_ids: known_build_ids.clone(),
+            pending: pending.clone(),
+        };
+        thread::Builder::new()
+            .name("debuginfo-uploader".to_string())
+            .spawn(move || uploader.run(requests))?;
+
+        Ok(DebugInfoBackendRemote {
+            known_build_ids,
+            pending,
+            uploads,
         })
     }
 }
@@ -118,56 +496,113 @@ impl DebugInfoManager for DebugInfoBackendRemote {
         name: &str,
         build_id: &BuildId,
         debug_info: &Path,
+        file: &File,
     ) -> anyhow::Result<()> {
-        // TODO: add a local cache to not have to reach to the backend
-        // unnecessarily.
-        if self.find_in_backend(build_id)? {
+        if self.known_build_ids.contains(build_id) {
+            return Ok(());
+        }
+        let file = file.try_clone()?;
+        if !self.pending.lock().unwrap().insert(build_id.clone()) {
             return Ok(());
         }
 
-        // TODO: do this in another thread.
-        self.upload_to_backend(name, build_id, debug_info)?;
+        let request = UploadRequest {
+            name: name.to_string(),
+            build_id: build_id.clone(),
+            debug_info: debug_info.to_path_buf(),
+            file,
+        };
+        if let Err(e) = self.uploads.try_send(request) {
+            self.pending.lock().unwrap().remove(build_id);
+            return Err(anyhow!("could not queue debuginfo upload: {}", e));
+        }
         Ok(())
     }
 
-    fn debug_info_path(&self) -> Option<PathBuf> {
+    fn debug_info_path(&self, _build_id: &BuildId) -> Option<PathBuf> {
         None
     }
 }
 
-impl DebugInfoBackendRemote {
+#[derive(Debug)]
+struct RemoteUploader {
+    token: Option<String>,
+    server_url: String,
+    query_client: reqwest::blocking::Client,
+    upload_client: reqwest::blocking::Client,
+    upload_bytes_per_second: u64,
+    /// Where the artifacts are written to before being uploaded.
+    cache_dir: PathBuf,
+    known_build_ids: Arc<KnownBuildIds>,
+    pending: Arc<Mutex<HashSet<BuildId>>>,
+}
+
+impl RemoteUploader {
+    /// Processes the upload requests until the backend is dropped.
+    fn run(self, requests: Receiver<UploadRequest>) {
+        for request in requests {
+            if let Err(e) = self.process(&request) {
+                error!(
+                    "debuginfo upload for {} failed with: {}",
+                    request.build_id,
+                    e.root_cause()
+                );
+            }
+            self.pending.lock().unwrap().remove(&request.build_id);
+        }
+    }
+
+    fn process(&self, request: &UploadRequest) -> anyhow::Result<()> {
+        if !self.find_in_backend(&request.build_id)? {
+            self.upload_to_backend(request)?;
+        }
+        self.known_build_ids.insert(&request.build_id)
+    }
+
     /// Whether the backend knows about some debug information.
     #[instrument(level = "debug")]
     fn find_in_backend(&self, build_id: &BuildId) -> anyhow::Result<bool> {
-        let response = self
-            .query_client
-            .get(format!(
-                "{}/debuginfo/{}",
-                self.server_url.clone(),
-                build_id
-            ))
-            .send();
+        let mut request = self.query_client.get(format!(
+            "{}/debuginfo/{}",
+            self.server_url.clone(),
+            build_id
+        ));
+
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
 
-        Ok(response?.status() == StatusCode::OK)
+        Ok(request.send()?.status() == StatusCode::OK)
     }
 
-    /// Send the debug information to the backend.
-    #[instrument]
-    fn upload_to_backend(
-        &self,
-        name: &str,
-        build_id: &BuildId,
-        debug_info: &Path,
-    ) -> anyhow::Result<()> {
+    /// Send the debug information to the backend. Only the sections needed for
+    /// symbolization are sent, compressed. They are written to an unnamed temporary file
+    /// first, so large objects are never held in memory.
+    #[instrument(skip_all, fields(build_id = %request.build_id))]
+    fn upload_to_backend(&self, request: &UploadRequest) -> anyhow::Result<()> {
+        let mut artifact = tempfile::tempfile_in(&self.cache_dir)?;
+        {
+            let mut out = BufWriter::new(&mut artifact);
+            write_debug_sections_artifact(
+                &request.file,
+                &request.debug_info,
+                &request.build_id,
+                &mut out,
+            )?;
+            out.flush()?;
+        }
+        artifact.rewind()?;
+
+        let body = ThrottledReader::new(artifact, self.upload_bytes_per_second);
         let mut request = self
             .upload_client
             .post(format!(
                 "{}/debuginfo/new/{}/{}",
                 self.server_url.clone(),
-                name,
-                build_id
+                request.name,
+                request.build_id
             ))
-            .body(File::open(debug_info)?);
+            .body(Body::new(body));
 
         if let Some(token) = &self.token {
             request = request.bearer_auth(token);
@@ -181,3 +616,248 @@ impl DebugInfoBackendRemote {
         Ok(())
     }
 }
+
+/// Writes what is uploaded for an object, a minimal ELF file with its symbol tables and
+/// debug sections. These are taken from its separate debug file if it has one. Fails if
+/// the object doesn't have the expected build id.
+fn write_debug_sections_artifact<W: Write + Seek>(
+    file: &File,
+    path: &Path,
+    build_id: &BuildId,
+    out: &mut W,
+) -> anyhow::Result<()> {
+    let mmap = unsafe { Mmap::map(file) }?;
+    let object = object::File::parse(&*mmap)?;
+    let found_build_id = ObjectFile::read_build_id(&object)?;
+    if &found_build_id != build_id {
+        return Err(anyhow!(
+            "expected build id {} but {} has {}",
+            build_id,
+            path.display(),
+            found_build_id
+        ));
+    }
+
+    if !object.has_debug_symbols() {
+        if let Some(debug_file) = find_debug_file(path, &object) {
+            return write_debug_sections_elf(&object::File::parse(&*debug_file)?, out);
+        }
+    }
+    write_debug_sections_elf(&object, out)
+}
+
+/// Limits the rate at which a reader is read.
+struct ThrottledReader<R> {
+    inner: R,
+    bytes_per_second: u64,
+    start: Instant,
+    bytes_read: u64,
+}
+
+impl<R> ThrottledReader<R> {
+    fn new(inner: R, bytes_per_second: u64) -> Self {
+        Self {
+            inner,
+            bytes_per_second,
+            start: Instant::now(),
+            bytes_read: 0,
+        }
+    }
+}
+
+/// How long to wait before reading more so the average rate stays under the limit.
+fn throttle_delay(bytes_read: u64, bytes_per_second: u64, elapsed: Duration) -> Duration {
+    Duration::from_secs_f64(bytes_read as f64 / bytes_per_second.max(1) as f64)
+        .saturating_sub(elapsed)
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        thread::sleep(throttle_delay(
+            self.bytes_read,
+            self.bytes_per_second,
+            self.start.elapsed(),
+        ));
+        // Read at most a second's worth of data at once.
+        let len = buf.len().min(self.bytes_per_second as usize).max(1);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.bytes_read += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    fn build_id(byte: u8) -> BuildId {
+        BuildId::gnu_from_bytes(&[byte; 20]).unwrap()
+    }
+
+    #[test]
+    fn test_known_build_ids() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(KNOWN_BUILD_IDS_FILE);
+
+        let known_build_ids = KnownBuildIds::load(&path);
+        assert!(!known_build_ids.contains(&build_id(1)));
+        known_build_ids.insert(&build_id(1)).unwrap();
+        known_build_ids.insert(&build_id(1)).unwrap();
+        known_build_ids.insert(&build_id(2)).unwrap();
+
+        let known_build_ids = KnownBuildIds::load(&path);
+        assert!(known_build_ids.contains(&build_id(1)));
+        assert!(known_build_ids.contains(&build_id(2)));
+        assert!(!known_build_ids.contains(&build_id(3)));
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+    }
+
+    /// Copies an object with debug information into `dir`.
+    fn object_with_debug_info(dir: &Path) -> PathBuf {
+        let path = dir.join("main");
+        fs::copy("tests/testdata/main_cpp_clang_03_with_inlined_3s", &path).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_filesystem_store() {
+        let dir = TempDir::new().unwrap();
+        let object = object_with_debug_info(dir.path());
+        let store_path = dir.path().join("store");
+        let store = DebugInfoBackendFilesystem::new(&store_path, u64::MAX).unwrap();
+
+        assert_eq!(store.debug_info_path(&build_id(1)), None);
+        store
+            .add_if_not_present("main", &build_id(1), &object, &File::open(&object).unwrap())
+            .unwrap();
+        wait_for_additions(&store);
+        let path = store.debug_info_path(&build_id(1)).unwrap();
+        assert_eq!(
+            path,
+            store_path.join(".build-id/01/01010101010101010101010101010101010101.debug")
+        );
+        assert_eq!(fs::read(&path).unwrap(), fs::read(&object).unwrap());
+
+        // The debug information is kept after the object is deleted, and across restarts.
+        fs::remove_file(&object).unwrap();
+        let store = DebugInfoBackendFilesystem::new(&store_path, u64::MAX).unwrap();
+        assert_eq!(store.debug_info_path(&build_id(1)), Some(path));
+    }
+
+    /// Waits for the objects queued to be added to the filesystem store.
+    fn wait_for_additions(store: &DebugInfoBackendFilesystem) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !store.pending.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "objects weren't added in time");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_filesystem_store_eviction() {
+        let dir = TempDir::new().unwrap();
+        let object = object_with_debug_info(dir.path());
+        let file = File::open(&object).unwrap();
+        let size = fs::metadata(&object).unwrap().len();
+        let store = FilesystemStore::open(&dir.path().join("store"), 2 * size).unwrap();
+
+        store.add_to_fs(&build_id(1), &object, &file).unwrap();
+        store.add_to_fs(&build_id(2), &object, &file).unwrap();
+        // Makes the second entry the least recently used one.
+        store.debug_info_path(&build_id(1)).unwrap();
+        store.add_to_fs(&build_id(3), &object, &file).unwrap();
+
+        assert!(store.debug_info_path(&build_id(1)).unwrap().exists());
+        assert_eq!(store.debug_info_path(&build_id(2)), None);
+        assert!(store.debug_info_path(&build_id(3)).unwrap().exists());
+    }
+
+    #[test]
+    fn test_filesystem_store_replaced_object() {
+        let dir = TempDir::new().unwrap();
+        let object = object_with_debug_info(dir.path());
+        let file = File::open(&object).unwrap();
+        let contents = fs::read(&object).unwrap();
+        let store = FilesystemStore::open(&dir.path().join("store"), u64::MAX).unwrap();
+
+        // The object is replaced after being opened, so the opened one is copied.
+        fs::remove_file(&object).unwrap();
+        fs::write(&object, b"new version").unwrap();
+        store.add_to_fs(&build_id(1), &object, &file).unwrap();
+
+        let path = store.debug_info_path(&build_id(1)).unwrap();
+        assert_eq!(fs::read(path).unwrap(), contents);
+    }
+
+    #[test]
+    fn test_uploads_are_merged() {
+        let dir = TempDir::new().unwrap();
+        let (uploads, requests) = bounded(1);
+        let backend = DebugInfoBackendRemote {
+            known_build_ids: Arc::new(KnownBuildIds::load(&dir.path().join(KNOWN_BUILD_IDS_FILE))),
+            pending: Arc::new(Mutex::new(HashSet::new())),
+            uploads,
+        };
+        let debug_info = Path::new("/usr/bin/app");
+        let file = tempfile::tempfile().unwrap();
+
+        backend
+            .add_if_not_present("app", &build_id(1), debug_info, &file)
+            .unwrap();
+        backend
+            .add_if_not_present("app", &build_id(1), debug_info, &file)
+            .unwrap();
+        assert_eq!(requests.len(), 1);
+
+        // The queue is full.
+        assert!(backend
+            .add_if_not_present("other", &build_id(2), debug_info, &file)
+            .is_err());
+        assert!(!backend.pending.lock().unwrap().contains(&build_id(2)));
+
+        // Build ids the backend has are not uploaded again.
+        requests.recv().unwrap();
+        backend.known_build_ids.insert(&build_id(2)).unwrap();
+        backend
+            .add_if_not_present("other", &build_id(2), debug_info, &file)
+            .unwrap();
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn test_debug_sections_artifact_build_id() {
+        let dir = TempDir::new().unwrap();
+        let object = object_with_debug_info(dir.path());
+        let file = File::open(&object).unwrap();
+        let object_build_id = ObjectFile::from_path(&object).unwrap().build_id().clone();
+        let mut out = Cursor::new(Vec::new());
+
+        assert!(write_debug_sections_artifact(&file, &object, &object_build_id, &mut out).is_ok());
+        // The object at the path was replaced by a different one.
+        assert!(write_debug_sections_artifact(&file, &object, &build_id(1), &mut out).is_err());
+    }
+
+    #[test]
+    fn test_throttle_delay() {
+        assert_eq!(throttle_delay(0, 100, Duration::ZERO), Duration::ZERO);
+        assert_eq!(
+            throttle_delay(200, 100, Duration::from_millis(500)),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(
+            throttle_delay(100, 100, Duration::from_secs(2)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn test_throttled_reader() {
+        let data = vec![1; 64];
+        let mut reader = ThrottledReader::new(&data[..], u64::MAX);
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+    }
+}
@@ -0,0 +1,463 @@
+//! Client for debuginfod servers, which serve the debug information of binaries by build
+//! id, see <https://sourceware.org/elfutils/Debuginfod.html>. Used to symbolize binaries
+//! that were stripped, such as the ones in most distribution packages.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::anyhow;
+use crossbeam_channel::{bounded, Receiver, Sender};
+use reqwest::StatusCode;
+use tracing::{debug, warn};
+
+use lightswitch_object::{BuildId, BuildIdFlavour};
+
+/// Environment variable with the space separated list of debuginfod servers, as used by
+/// other debuginfod clients.
+pub const DEBUGINFOD_URLS_ENV: &str = "DEBUGINFOD_URLS";
+
+const DEBUGINFO_FILE: &str = "debuginfo";
+/// Number of downloads that can be waiting to start.
+const DOWNLOAD_QUEUE_SIZE: usize = 64;
+/// Marks build ids no server had, which aren't looked up again for a while.
+const NOT_FOUND_FILE: &str = "not-found";
+
+/// Result of looking up a build id in a server.
+enum Lookup {
+    Found,
+    NotFound,
+}
+
+/// Downloaded debug information, stored by build id with the same layout as the
+/// `debuginfod-find` cache. The least recently used entries are evicted once it grows over
+/// its size limit.
+#[derive(Debug)]
+pub struct DebuginfodCache {
+    dir: PathBuf,
+    max_size_bytes: u64,
+    negative_ttl: Duration,
+    sequence: AtomicU64,
+}
+
+impl DebuginfodCache {
+    pub fn new(dir: &Path, max_size_bytes: u64, negative_ttl: Duration) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_size_bytes,
+            negative_ttl,
+            sequence: AtomicU64::new(0),
+        })
+    }
+
+    fn entry_dir(&self, build_id: &str) -> PathBuf {
+        self.dir.join(build_id)
+    }
+
+    /// Returns the cached debug information for a build id. `Some(None)` means that it
+    /// recently wasn't found in any server.
+    fn get(&self, build_id: &str) -> Option<Option<PathBuf>> {
+        let entry_dir = self.entry_dir(build_id);
+        let path = entry_dir.join(DEBUGINFO_FILE);
+        if path.exists() {
+            // Mark the entry as recently used.
+            let _ = File::options()
+                .append(true)
+                .open(&path)
+                .and_then(|file| file.set_modified(SystemTime::now()));
+            return Some(Some(path));
+        }
+
+        let not_found_at = fs::metadata(entry_dir.join(NOT_FOUND_FILE))
+            .and_then(|metadata| metadata.modified())
+            .ok()?;
+        let expired = SystemTime::now()
+            .duration_since(not_found_at)
+            .is_ok_and(|age| age > self.negative_ttl);
+        if expired {
+            return None;
+        }
+        Some(None)
+    }
+
+    /// Stores the debug information for a build id, written by `write` to the given path.
+    fn insert(
+        &self,
+        build_id: &str,
+        write: impl FnOnce(&Path) -> anyhow::Result<()>,
+    ) -> anyhow::Result<PathBuf> {
+        let entry_dir = self.entry_dir(build_id);
+        fs::create_dir_all(&entry_dir)?;
+
+        // Written under a temporary name first so partially downloaded files are never used.
+        let temporary_path = entry_dir.join(format!(
+            "{}.{}.tmp",
+            DEBUGINFO_FILE,
+            self.sequence.fetch_add(1, Ordering::Relaxed)
+        ));
+        if let Err(e) = write(&temporary_path) {
+            let _ = fs::remove_file(&temporary_path);
+            return Err(e);
+        }
+        let path = entry_dir.join(DEBUGINFO_FILE);
+        fs::rename(&temporary_path, &path)?;
+        let _ = fs::remove_file(entry_dir.join(NOT_FOUND_FILE));
+
+        self.evict(&path)?;
+        Ok(path)
+    }
+
+    fn insert_not_found(&self, build_id: &str) -> io::Result<()> {
+        let entry_dir = self.entry_dir(build_id);
+        fs::create_dir_all(&entry_dir)?;
+        File::create(entry_dir.join(NOT_FOUND_FILE))?;
+        Ok(())
+    }
+
+    /// Evicts the least recently used entries, other than the one just added, until the
+    /// cache is under its size limit.
+    fn evict(&self, keep: &Path) -> io::Result<()> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path().join(DEBUGINFO_FILE);
+            let Ok(metadata) = fs::metadata(&path) else {
+                continue;
+            };
+            entries.push((metadata.modified()?, metadata.len(), path));
+        }
+        entries.sort();
+
+        let mut total_size: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        for (_, size, path) in entries {
+            if total_size <= self.max_size_bytes {
+                break;
+            }
+            if path == keep {
+                continue;
+            }
+            debug!("evicting {} from the debuginfod cache", path.display());
+            fs::remove_file(&path)?;
+            total_size -= size;
+        }
+        Ok(())
+    }
+}
+
+/// Downloads debug information in a background thread, so looking it up never blocks on
+/// the servers for longer than the caller is willing to wait.
+#[derive(Debug)]
+pub struct DebuginfodClient {
+    downloader: Arc<Downloader>,
+    downloads: Sender<String>,
+}
+
+impl DebuginfodClient {
+    /// Creates a client for the given servers, which are tried in order. Besides HTTP
+    /// servers, local directories with the same layout, such as `file:///srv/debuginfod`,
+    /// are supported.
+    pub fn new(servers: Vec<String>, cache: DebuginfodCache) -> anyhow::Result<Self> {
+        let downloader = Arc::new(Downloader {
+            servers: servers
+                .into_iter()
+                .map(|server| server.trim_end_matches('/').to_string())
+                .collect(),
+            cache,
+            http_client: reqwest::blocking::Client::builder()
+                .timeout(Duration::from_secs(2 * 60))
+                .build()?,
+            pending: Mutex::new(HashSet::new()),
+            done: Condvar::new(),
+        });
+        let (downloads, requests) = bounded(DOWNLOAD_QUEUE_SIZE);
+        let thread_downloader = downloader.clone();
+        thread::Builder::new()
+            .name("debuginfod".to_string())
+            .spawn(move || thread_downloader.run(requests))?;
+
+        Ok(Self {
+            downloader,
+            downloads,
+        })
+    }
+
+    /// Returns the servers in `DEBUGINFOD_URLS`.
+    pub fn servers_from_env() -> Vec<String> {
+        std::env::var(DEBUGINFOD_URLS_ENV)
+            .map(|urls| urls.split_whitespace().map(str::to_string).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the path to the debug information for a build id. If it isn't cached, it's
+    /// downloaded in the background, waiting for it up to `deadline`. Downloads that take
+    /// longer carry on and are picked up by later lookups.
+    pub fn debuginfo(&self, build_id: &BuildId, deadline: Instant) -> Option<PathBuf> {
+        // Servers index binaries by their GNU build id.
+        if build_id.flavour != BuildIdFlavour::Gnu {
+            return None;
+        }
+        let build_id = build_id.short();
+        if let Some(cached) = self.downloader.cache.get(&build_id) {
+            return cached;
+        }
+
+        let mut pending = self.downloader.pending.lock().unwrap();
+        if pending.insert(build_id.clone()) && self.downloads.try_send(build_id.clone()).is_err() {
+            debug!("debuginfod download queue is full, skipping {}", build_id);
+            pending.remove(&build_id);
+            return None;
+        }
+        while pending.contains(&build_id) {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return None;
+            }
+            pending = self
+                .downloader
+                .done
+                .wait_timeout(pending, timeout)
+                .unwrap()
+                .0;
+        }
+        drop(pending);
+
+        self.downloader.cache.get(&build_id).flatten()
+    }
+}
+
+#[derive(Debug)]
+struct Downloader {
+    servers: Vec<String>,
+    cache: DebuginfodCache,
+    http_client: reqwest::blocking::Client,
+    /// Build ids queued or being downloaded.
+    pending: Mutex<HashSet<String>>,
+    /// Notified every time a download finishes.
+    done: Condvar,
+}
+
+impl Downloader {
+    /// Processes the downloads until the client is dropped.
+    fn run(&self, requests: Receiver<String>) {
+        for build_id in requests {
+            self.download(&build_id);
+            self.pending.lock().unwrap().remove(&build_id);
+            self.done.notify_all();
+        }
+    }
+
+    /// Looks a build id up in every server until one has it, caching the result.
+    fn download(&self, build_id: &str) {
+        let mut all_not_found = true;
+        for server in &self.servers {
+            match self.lookup(server, build_id) {
+                Ok(Lookup::Found) => return,
+                Ok(Lookup::NotFound) => {}
+                Err(e) => {
+                    warn!(
+                        "debuginfod lookup of {} in {} failed: {}",
+                        build_id, server, e
+                    );
+                    all_not_found = false;
+                }
+            }
+        }
+
+        // Errors might be transient so only build ids no server has are remembered.
+        if all_not_found {
+            if let Err(e) = self.cache.insert_not_found(build_id) {
+                warn!("could not cache debuginfod miss for {}: {}", build_id, e);
+            }
+        }
+    }
+
+    fn lookup(&self, server: &str, build_id: &str) -> anyhow::Result<Lookup> {
+        let resource = format!("buildid/{build_id}/debuginfo");
+
+        if let Some(dir) = server.strip_prefix("file://") {
+            let source = Path::new(dir).join(resource);
+            if !source.exists() {
+                return Ok(Lookup::NotFound);
+            }
+            self.cache.insert(build_id, |path| {
+                fs::copy(&source, path)?;
+                Ok(())
+            })?;
+            return Ok(Lookup::Found);
+        }
+
+        let mut response = self
+            .http_client
+            .get(format!("{server}/{resource}"))
+            .send()?;
+        match response.status() {
+            StatusCode::OK => {
+                self.cache.insert(build_id, |path| {
+                    response.copy_to(&mut File::create(path)?)?;
+                    Ok(())
+                })?;
+                Ok(Lookup::Found)
+            }
+            StatusCode::NOT_FOUND => Ok(Lookup::NotFound),
+            status => Err(anyhow!("unexpected status {}", status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tempfile::TempDir;
+
+    const BUILD_ID: [u8; 20] = [0xab; 20];
+
+    /// Creates a directory with the layout debuginfod servers have.
+    fn server_dir(debuginfo: &[u8]) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        let build_id = BuildId::gnu_from_bytes(&BUILD_ID).unwrap().short();
+        let debuginfo_dir = dir.path().join("buildid").join(build_id);
+        fs::create_dir_all(&debuginfo_dir).unwrap();
+        fs::write(debuginfo_dir.join("debuginfo"), debuginfo).unwrap();
+        dir
+    }
+
+    /// Serves a directory over HTTP, for the given number of requests.
+    fn serve(dir: &Path, requests: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let dir = dir.to_path_buf();
+
+        thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut request_line = String::new();
+                BufReader::new(&stream)
+                    .read_line(&mut request_line)
+                    .unwrap();
+                let path = request_line.split_whitespace().nth(1).unwrap();
+                let response = match fs::read(dir.join(path.trim_start_matches('/'))) {
+                    Ok(body) => {
+                        let mut response = format!(
+                            "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                            body.len()
+                        )
+                        .into_bytes();
+                        response.extend(body);
+                        response
+                    }
+                    Err(_) => {
+                        b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                            .to_vec()
+                    }
+                };
+                stream.write_all(&response).unwrap();
+            }
+        });
+
+        url
+    }
+
+    fn deadline() -> Instant {
+        Instant::now() + Duration::from_secs(10)
+    }
+
+    fn client(servers: Vec<String>, cache_dir: &Path) -> DebuginfodClient {
+        let cache = DebuginfodCache::new(cache_dir, 1024, Duration::from_secs(60)).unwrap();
+        DebuginfodClient::new(servers, cache).unwrap()
+    }
+
+    #[test]
+    fn test_debuginfod_http() {
+        let server_dir = server_dir(b"debuginfo");
+        let cache_dir = TempDir::new().unwrap();
+        // One request for the first lookup, cached afterwards.
+        let url = serve(server_dir.path(), 1);
+        let client = client(vec![format!("{url}/")], cache_dir.path());
+        let build_id = BuildId::gnu_from_bytes(&BUILD_ID).unwrap();
+
+        let path = client.debuginfo(&build_id, deadline()).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"debuginfo");
+        assert!(path.starts_with(cache_dir.path()));
+        assert_eq!(client.debuginfo(&build_id, deadline()), Some(path));
+    }
+
+    #[test]
+    fn test_debuginfod_directory() {
+        let server_dir = server_dir(b"debuginfo");
+        let cache_dir = TempDir::new().unwrap();
+        let client = client(
+            vec![format!("file://{}", server_dir.path().display())],
+            cache_dir.path(),
+        );
+
+        let path = client
+            .debuginfo(&BuildId::gnu_from_bytes(&BUILD_ID).unwrap(), deadline())
+            .unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"debuginfo");
+    }
+
+    #[test]
+    fn test_debuginfod_not_found() {
+        let server_dir = server_dir(b"debuginfo");
+        let cache_dir = TempDir::new().unwrap();
+        // Only one request, as misses are cached too.
+        let url = serve(server_dir.path(), 1);
+        let client = client(vec![url], cache_dir.path());
+        let build_id = BuildId::gnu_from_bytes(&[0xcd; 20]).unwrap();
+
+        assert_eq!(client.debuginfo(&build_id, deadline()), None);
+        assert_eq!(client.debuginfo(&build_id, deadline()), None);
+        assert_eq!(client.downloader.cache.get(&build_id.short()), Some(None));
+    }
+
+    #[test]
+    fn test_debuginfod_deadline() {
+        // A server that accepts connections but never responds.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let cache_dir = TempDir::new().unwrap();
+        let client = client(vec![url], cache_dir.path());
+        let build_id = BuildId::gnu_from_bytes(&BUILD_ID).unwrap();
+
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(100);
+        assert_eq!(client.debuginfo(&build_id, deadline), None);
+        assert_eq!(client.debuginfo(&build_id, deadline), None);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_debuginfod_cache_eviction() {
+        let cache_dir = TempDir::new().unwrap();
+        let cache = DebuginfodCache::new(cache_dir.path(), 10, Duration::from_secs(60)).unwrap();
+        let write = |data: &'static [u8]| {
+            move |path: &Path| -> anyhow::Result<()> {
+                fs::write(path, data)?;
+                Ok(())
+            }
+        };
+
+        let first = cache.insert("01", write(b"123456")).unwrap();
+        let old = SystemTime::now() - Duration::from_secs(60);
+        File::options()
+            .append(true)
+            .open(&first)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        let second = cache.insert("02", write(b"123456")).unwrap();
+
+        assert!(!first.exists());
+        assert!(second.exists());
+        assert_eq!(cache.get("01"), None);
+        assert_eq!(cache.get("02"), Some(Some(second)));
+    }
+}
@@ -0,0 +1,509 @@
+//! Symbols for JIT compiled code, which isn't backed by any object file. Runtimes such as
+//! the JVM, .NET or Node (with `--perf-basic-prof`) can write the address ranges of the code
+//! they generate to `/tmp/perf-<pid>.map` and / or to a `jit-<pid>.dump` file, the formats
+//! that `perf` understands.
+//!
+//! Both files are append only, so they are read incrementally as they grow.
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use tracing::debug;
+
+use crate::process::Pid;
+
+/// 'JiTD', written in the native endianness of the process.
+const JITDUMP_MAGIC: u32 = 0x4A695444;
+const JITDUMP_HEADER_MIN_SIZE: usize = 40;
+const JITDUMP_RECORD_HEADER_SIZE: usize = 16;
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_MOVE: u32 = 1;
+/// Most bytes read from each JIT symbol file on every refresh. The rest is read on the
+/// following ones.
+const MAX_READ_BYTES_PER_REFRESH: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JitSymbol {
+    pub start_addr: u64,
+    pub size: u64,
+    pub name: String,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum JitDumpError {
+    #[error("jitdump header is too short")]
+    HeaderTooShort,
+    #[error("unexpected jitdump magic {0:#x}")]
+    BadMagic(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JitDumpRecord {
+    /// Newly generated code.
+    CodeLoad(JitSymbol),
+    /// Code that was moved to a different address.
+    CodeMove {
+        old_addr: u64,
+        new_addr: u64,
+        size: u64,
+    },
+}
+
+/// Parses the complete lines of a perf map, `START SIZE symbol name`, with the start
+/// address and size in hexadecimal. Returns the symbols and the number of bytes consumed.
+pub fn parse_perf_map(data: &[u8]) -> (Vec<JitSymbol>, usize) {
+    let mut symbols = Vec::new();
+    let mut consumed = 0;
+
+    // The last line might still be being written.
+    while let Some(line_len) = data[consumed..].iter().position(|&b| b == b'\n') {
+        let line = String::from_utf8_lossy(&data[consumed..consumed + line_len]);
+        consumed += line_len + 1;
+
+        let mut parts = line.trim().splitn(3, ' ');
+        let (Some(start_addr), Some(size), Some(name)) = (parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        let parse_hex = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16);
+        let (Ok(start_addr), Ok(size)) = (parse_hex(start_addr), parse_hex(size)) else {
+            continue;
+        };
+
+        symbols.push(JitSymbol {
+            start_addr,
+            size,
+            name: name.to_string(),
+        });
+    }
+
+    (symbols, consumed)
+}
+
+/// Parses the jitdump file header, returning its size.
+pub fn parse_jitdump_header(data: &[u8]) -> Result<usize, JitDumpError> {
+    if data.len() < JITDUMP_HEADER_MIN_SIZE {
+        return Err(JitDumpError::HeaderTooShort);
+    }
+
+    let magic = read_u32(data, 0);
+    if magic != JITDUMP_MAGIC {
+        return Err(JitDumpError::BadMagic(magic));
+    }
+
+    Ok(read_u32(data, 8) as usize)
+}
+
+/// Parses the complete jitdump records after the header. Returns the records we are
+/// interested in and the number of bytes consumed.
+pub fn parse_jitdump_records(data: &[u8]) -> (Vec<JitDumpRecord>, usize) {
+    let mut records = Vec::new();
+    let mut consumed = 0;
+
+    while data.len() - consumed >= JITDUMP_RECORD_HEADER_SIZE {
+        let record = &data[consumed..];
+        let id = read_u32(record, 0);
+        let total_size = read_u32(record, 4) as usize;
+        if total_size < JITDUMP_RECORD_HEADER_SIZE || record.len() < total_size {
+            break;
+        }
+        let body = &record[JITDUMP_RECORD_HEADER_SIZE..total_size];
+        consumed += total_size;
+
+        match id {
+            // pid, tid, vma, code_addr, code_size, code_index and the function name.
+            JIT_CODE_LOAD if body.len() > 40 => {
+                let name = &body[40..];
+                let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                records.push(JitDumpRecord::CodeLoad(JitSymbol {
+                    start_addr: read_u64(body, 16),
+                    size: read_u64(body, 24),
+                    name: String::from_utf8_lossy(&name[..name_len]).to_string(),
+                }));
+            }
+            // pid, tid, vma, old_code_addr, new_code_addr, code_size and code_index.
+            JIT_CODE_MOVE if body.len() >= 48 => {
+                records.push(JitDumpRecord::CodeMove {
+                    old_addr: read_u64(body, 16),
+                    new_addr: read_u64(body, 24),
+                    size: read_u64(body, 32),
+                });
+            }
+            // Debug and unwind information as well as the close record are not used.
+            _ => {}
+        }
+    }
+
+    (records, consumed)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_ne_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JitFileKind {
+    PerfMap,
+    JitDump,
+}
+
+#[derive(Debug)]
+struct JitFile {
+    path: PathBuf,
+    kind: JitFileKind,
+    /// How much of the file has been read so far.
+    offset: u64,
+}
+
+/// JIT symbols for a process.
+#[derive(Debug)]
+pub struct JitSymbols {
+    files: Vec<JitFile>,
+    /// User the process runs as, which the files have to be owned by.
+    uid: Option<u32>,
+    symbols: BTreeMap<u64, JitSymbol>,
+}
+
+impl JitSymbols {
+    /// Looks for the JIT symbol files written by a process. They are named after the pid
+    /// in the process' pid namespace and read through its mount namespace.
+    pub fn new(pid: Pid) -> Self {
+        let status = procfs::process::Process::new(pid)
+            .and_then(|process| process.status())
+            .ok();
+        let namespaced_pid = status
+            .as_ref()
+            .and_then(|status| status.nspid.as_ref())
+            .and_then(|nspid| nspid.last().copied())
+            .unwrap_or(pid);
+
+        let root = format!("/proc/{pid}/root");
+        Self {
+            files: vec![
+                JitFile {
+                    path: PathBuf::from(format!("{root}/tmp/perf-{namespaced_pid}.map")),
+                    kind: JitFileKind::PerfMap,
+                    offset: 0,
+                },
+                JitFile {
+                    path: PathBuf::from(format!("{root}/tmp/jit-{namespaced_pid}.dump")),
+                    kind: JitFileKind::JitDump,
+                    offset: 0,
+                },
+            ],
+            uid: status.map(|status| status.euid),
+            symbols: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a jitdump file the process has mapped, which is how `perf` finds them too.
+    pub fn add_jitdump(&mut self, path: &Path) {
+        if self.files.iter().any(|file| file.path == path) {
+            return;
+        }
+        self.files.push(JitFile {
+            path: path.to_path_buf(),
+            kind: JitFileKind::JitDump,
+            offset: 0,
+        });
+    }
+
+    /// Whether a mapped file is a jitdump file, named `jit-<pid>.dump`.
+    pub fn is_jitdump(path: &Path) -> bool {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("jit-"))
+            .and_then(|name| name.strip_suffix(".dump"))
+            .is_some_and(|pid| pid.parse::<Pid>().is_ok())
+    }
+
+    /// Reads the data appended to the JIT symbol files since the last refresh.
+    pub fn refresh(&mut self) {
+        let mut files = std::mem::take(&mut self.files);
+
+        for file in &mut files {
+            let data = match Self::read_new_data(file, self.uid) {
+                Ok(data) => data,
+                Err(e) => {
+                    // The files are created whenever the runtime decides to, if at all.
+                    debug!("could not read {}: {}", file.path.display(), e);
+                    continue;
+                }
+            };
+
+            let consumed = match file.kind {
+                JitFileKind::PerfMap => {
+                    let (symbols, consumed) = parse_perf_map(&data);
+                    for symbol in symbols {
+                        self.symbols.insert(symbol.start_addr, symbol);
+                    }
+                    consumed
+                }
+                JitFileKind::JitDump => {
+                    let mut header_size = 0;
+                    if file.offset == 0 {
+                        header_size = match parse_jitdump_header(&data) {
+                            Ok(header_size) => header_size,
+                            Err(e) => {
+                                debug!("invalid jitdump {}: {}", file.path.display(), e);
+                                continue;
+                            }
+                        };
+                    }
+                    let Some(records) = data.get(header_size..) else {
+                        continue;
+                    };
+                    let (records, consumed) = parse_jitdump_records(records);
+                    for record in records {
+                        self.apply(record);
+                    }
+                    header_size + consumed
+                }
+            };
+
+            file.offset += consumed as u64;
+        }
+
+        self.files = files;
+    }
+
+    /// Reads what was appended to a file since the last time, up to
+    /// [`MAX_READ_BYTES_PER_REFRESH`]. The process controls the paths, so, like `perf`
+    /// does, symlinks aren't followed and only regular files owned by the process' user
+    /// are read, without blocking on FIFOs.
+    fn read_new_data(file: &mut JitFile, uid: Option<u32>) -> io::Result<Vec<u8>> {
+        let mut f = OpenOptions::new()
+            .read(true)
+            .custom_flags(nix::libc::O_NOFOLLOW | nix::libc::O_NONBLOCK)
+            .open(&file.path)?;
+        let metadata = f.metadata()?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a regular file",
+            ));
+        }
+        if Some(metadata.uid()) != uid {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "owned by uid {} rather than the process' user",
+                    metadata.uid()
+                ),
+            ));
+        }
+        // The file was truncated or replaced, start over.
+        if metadata.len() < file.offset {
+            file.offset = 0;
+        }
+        f.seek(SeekFrom::Start(file.offset))?;
+        let mut data = Vec::new();
+        f.take(MAX_READ_BYTES_PER_REFRESH).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn apply(&mut self, record: JitDumpRecord) {
+        match record {
+            JitDumpRecord::CodeLoad(symbol) => {
+                self.symbols.insert(symbol.start_addr, symbol);
+            }
+            JitDumpRecord::CodeMove {
+                old_addr,
+                new_addr,
+                size,
+            } => {
+                if let Some(symbol) = self.symbols.remove(&old_addr) {
+                    self.symbols.insert(
+                        new_addr,
+                        JitSymbol {
+                            start_addr: new_addr,
+                            size,
+                            name: symbol.name,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns the name of the JIT compiled function containing the given address.
+    pub fn symbolize(&self, virtual_address: u64) -> Option<&str> {
+        let (_, symbol) = self.symbols.range(..=virtual_address).next_back()?;
+        // The size comes from a file the process writes, so it can be anything.
+        if virtual_address < symbol.start_addr.saturating_add(symbol.size) {
+            Some(&symbol.name)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jitdump_record(id: u32, body: &[u8]) -> Vec<u8> {
+        let mut record = Vec::new();
+        record.extend(id.to_ne_bytes());
+        record.extend(((JITDUMP_RECORD_HEADER_SIZE + body.len()) as u32).to_ne_bytes());
+        record.extend(0u64.to_ne_bytes());
+        record.extend(body);
+        record
+    }
+
+    fn code_load(start_addr: u64, size: u64, name: &str) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend(1u32.to_ne_bytes());
+        body.extend(1u32.to_ne_bytes());
+        body.extend(start_addr.to_ne_bytes());
+        body.extend(start_addr.to_ne_bytes());
+        body.extend(size.to_ne_bytes());
+        body.extend(0u64.to_ne_bytes());
+        body.extend(name.as_bytes());
+        body.push(0);
+        jitdump_record(JIT_CODE_LOAD, &body)
+    }
+
+    #[test]
+    fn test_parse_perf_map() {
+        let data = b"7f0000001000 40 LazyCompile:~main /app/index.js:1\n0x7f0000002000 0x10 Interpreter\nnot a symbol\n7f0000003000 20 incompl";
+        let (symbols, consumed) = parse_perf_map(data);
+        assert_eq!(
+            symbols,
+            vec![
+                JitSymbol {
+                    start_addr: 0x7f0000001000,
+                    size: 0x40,
+                    name: "LazyCompile:~main /app/index.js:1".to_string()
+                },
+                JitSymbol {
+                    start_addr: 0x7f0000002000,
+                    size: 0x10,
+                    name: "Interpreter".to_string()
+                },
+            ]
+        );
+        // The incomplete line is left for the next read.
+        assert_eq!(&data[consumed..], b"7f0000003000 20 incompl");
+    }
+
+    #[test]
+    fn test_parse_jitdump() {
+        let mut header = Vec::new();
+        header.extend(JITDUMP_MAGIC.to_ne_bytes());
+        header.extend(1u32.to_ne_bytes());
+        header.extend((JITDUMP_HEADER_MIN_SIZE as u32).to_ne_bytes());
+        header.resize(JITDUMP_HEADER_MIN_SIZE, 0);
+        assert_eq!(parse_jitdump_header(&header), Ok(JITDUMP_HEADER_MIN_SIZE));
+        assert_eq!(
+            parse_jitdump_header(&[0; 8]),
+            Err(JitDumpError::HeaderTooShort)
+        );
+
+        let mut move_body = Vec::new();
+        move_body.extend([0; 16]);
+        move_body.extend(0x1000u64.to_ne_bytes());
+        move_body.extend(0x5000u64.to_ne_bytes());
+        move_body.extend(0x80u64.to_ne_bytes());
+        move_body.extend(0u64.to_ne_bytes());
+
+        let mut records = code_load(0x1000, 0x80, "java.lang.String::hashCode");
+        records.extend(jitdump_record(2, &[0; 24]));
+        records.extend(jitdump_record(JIT_CODE_MOVE, &move_body));
+        let complete_len = records.len();
+        // Partially written record.
+        records.extend(&code_load(0x2000, 0x10, "Foo::bar")[..20]);
+
+        let (parsed, consumed) = parse_jitdump_records(&records);
+        assert_eq!(consumed, complete_len);
+        assert_eq!(
+            parsed,
+            vec![
+                JitDumpRecord::CodeLoad(JitSymbol {
+                    start_addr: 0x1000,
+                    size: 0x80,
+                    name: "java.lang.String::hashCode".to_string()
+                }),
+                JitDumpRecord::CodeMove {
+                    old_addr: 0x1000,
+                    new_addr: 0x5000,
+                    size: 0x80
+                },
+            ]
+        );
+
+        let mut jit_symbols = JitSymbols {
+            files: vec![],
+            uid: None,
+            symbols: BTreeMap::new(),
+        };
+        for record in parsed {
+            jit_symbols.apply(record);
+        }
+        assert_eq!(jit_symbols.symbolize(0x1010), None);
+        assert_eq!(
+            jit_symbols.symbolize(0x5010),
+            Some("java.lang.String::hashCode")
+        );
+        assert_eq!(jit_symbols.symbolize(0x5080), None);
+    }
+
+    #[test]
+    fn test_symbolize_overflowing_size() {
+        let (symbols, _) = parse_perf_map(b"7f0000001000 ffffffffffffffff huge\n");
+        let mut jit_symbols = JitSymbols {
+            files: vec![],
+            uid: None,
+            symbols: BTreeMap::new(),
+        };
+        for symbol in symbols {
+            jit_symbols.symbols.insert(symbol.start_addr, symbol);
+        }
+        assert_eq!(jit_symbols.symbolize(0x7f0000000fff), None);
+        assert_eq!(jit_symbols.symbolize(0x7f0000001000), Some("huge"));
+        assert_eq!(jit_symbols.symbolize(u64::MAX - 1), Some("huge"));
+    }
+
+    #[test]
+    fn test_read_new_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("perf-1234.map");
+        std::fs::write(&path, b"1000 10 foo\n").unwrap();
+        let uid = Some(nix::unistd::geteuid().as_raw());
+        let jit_file = |path: &Path| JitFile {
+            path: path.to_path_buf(),
+            kind: JitFileKind::PerfMap,
+            offset: 0,
+        };
+
+        let mut file = jit_file(&path);
+        assert_eq!(
+            JitSymbols::read_new_data(&mut file, uid).unwrap(),
+            b"1000 10 foo\n"
+        );
+        // Files owned by other users are ignored.
+        assert!(JitSymbols::read_new_data(&mut file, uid.map(|uid| uid + 1)).is_err());
+
+        // As well as symlinks and anything but regular files.
+        let symlink = dir.path().join("perf-5678.map");
+        std::os::unix::fs::symlink(&path, &symlink).unwrap();
+        assert!(JitSymbols::read_new_data(&mut jit_file(&symlink), uid).is_err());
+        assert!(JitSymbols::read_new_data(&mut jit_file(dir.path()), uid).is_err());
+    }
+
+    #[test]
+    fn test_is_jitdump() {
+        assert!(JitSymbols::is_jitdump(Path::new(
+            "/root/.debug/jit/java-jit-20240101.abc/jit-1234.dump"
+        )));
+        assert!(!JitSymbols::is_jitdump(Path::new("/tmp/perf-1234.map")));
+        assert!(!JitSymbols::is_jitdump(Path::new("/tmp/jit-abc.dump")));
+    }
+}
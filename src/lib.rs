@@ -1,13 +1,20 @@
 pub mod aggregator;
 pub mod bpf;
 pub mod collector;
+pub mod cpu_trigger;
 pub mod debug_info;
+pub mod debuginfod;
+pub mod jit;
 pub mod kernel;
 pub mod ksym;
+pub mod overhead;
 pub mod perf_events;
 pub mod process;
+pub mod process_selector;
 pub mod profile;
 pub mod profiler;
+pub mod python;
+pub mod spool;
 pub mod unwind_info;
 pub mod usym;
 pub mod util;
@@ -0,0 +1,110 @@
+use std::time::{Duration, Instant};
+
+/// Lowest sampling frequency the overhead controller goes down to, in Hz.
+pub const MIN_SAMPLE_FREQ: u64 = 5;
+/// Fraction of the budget that is aimed for when the frequency changes, so small
+/// variations in the overhead don't make it go back and forth.
+const TARGET_BUDGET_FRACTION: f64 = 0.8;
+/// The frequency is at most doubled on every change, as the overhead measured at low
+/// frequencies doesn't say much about the one at high frequencies.
+const MAX_SCALE_UP: f64 = 2.0;
+
+#[derive(Debug, Clone)]
+pub struct OverheadConfig {
+    /// CPU time the profiler can use, including its BPF programs, in percent of all the
+    /// online CPUs.
+    pub budget_percent: f64,
+    /// Sampling starts at this frequency, in Hz, and it's never raised above it.
+    pub max_sample_freq: u64,
+    /// How often the overhead is measured.
+    pub interval: Duration,
+}
+
+/// Keeps the CPU used by the profiler within a budget by scaling the sampling frequency,
+/// as most of the work it does is per sample.
+pub struct OverheadController {
+    config: OverheadConfig,
+    sample_freq: u64,
+    /// When the CPU time was last read, and its value.
+    last_reading: Option<(Instant, Duration)>,
+}
+
+impl OverheadController {
+    pub fn new(config: OverheadConfig) -> Self {
+        Self {
+            sample_freq: config.max_sample_freq,
+            config,
+            last_reading: None,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.config.interval
+    }
+
+    /// Takes the CPU time used by the profiler so far, and returns the sampling frequency
+    /// to use from now on if it has to change.
+    pub fn update(&mut self, now: Instant, cpu_time: Duration, online_cpus: usize) -> Option<u64> {
+        let (then, previous_cpu_time) = self.last_reading.replace((now, cpu_time))?;
+        if now <= then || online_cpus == 0 {
+            return None;
+        }
+        let overhead_percent = 100.0 * cpu_time.saturating_sub(previous_cpu_time).as_secs_f64()
+            / ((now - then).as_secs_f64() * online_cpus as f64);
+
+        let target_percent = self.config.budget_percent * TARGET_BUDGET_FRACTION;
+        let scale = if overhead_percent > self.config.budget_percent {
+            target_percent / overhead_percent
+        } else if overhead_percent < target_percent / 2.0 {
+            (target_percent / overhead_percent).min(MAX_SCALE_UP)
+        } else {
+            return None;
+        };
+
+        let min_sample_freq = MIN_SAMPLE_FREQ.min(self.config.max_sample_freq);
+        let sample_freq = ((self.sample_freq as f64 * scale) as u64)
+            .clamp(min_sample_freq, self.config.max_sample_freq);
+        // Prime frequencies avoid sampling in lockstep with periodic activity.
+        let sample_freq = (min_sample_freq..=sample_freq)
+            .rev()
+            .find(|freq| primal::is_prime(*freq))
+            .unwrap_or(sample_freq);
+        if sample_freq == self.sample_freq {
+            return None;
+        }
+        self.sample_freq = sample_freq;
+        Some(sample_freq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overhead_controller() {
+        let mut controller = OverheadController::new(OverheadConfig {
+            budget_percent: 1.0,
+            max_sample_freq: 97,
+            interval: Duration::from_secs(10),
+        });
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let cpu = |millis| Duration::from_millis(millis);
+
+        // The first reading is only used as a baseline.
+        assert_eq!(controller.update(at(0), cpu(0), 4), None);
+        // 0.9% of 4 CPUs, within the budget.
+        assert_eq!(controller.update(at(10), cpu(360), 4), None);
+        // 2% of 4 CPUs, aims for 0.8%.
+        assert_eq!(controller.update(at(20), cpu(1160), 4), Some(37));
+        // 0.2% of 4 CPUs, doubled at most.
+        assert_eq!(controller.update(at(30), cpu(1240), 4), Some(73));
+        // Never above the configured frequency.
+        assert_eq!(controller.update(at(40), cpu(1240), 4), Some(97));
+        assert_eq!(controller.update(at(50), cpu(1240), 4), None);
+        // Nor below the minimum.
+        assert_eq!(controller.update(at(60), cpu(41240), 4), Some(5));
+        assert_eq!(controller.update(at(70), cpu(81240), 4), None);
+    }
+}
@@ -1,20 +1,218 @@
+use std::fmt;
 use std::io;
+use std::os::fd::{FromRawFd, OwnedFd};
 use std::os::raw::c_int;
+use std::str::FromStr;
 
 use perf_event_open_sys as sys;
 use perf_event_open_sys::bindings::perf_event_attr;
+use tracing::warn;
+
+use crate::util::get_online_cpus;
+
+/// Events that can be used to drive the profiler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum PerfEvent {
+    /// Software CPU clock, which is always available.
+    #[default]
+    CpuClock,
+    Cycles,
+    Instructions,
+    /// Usually last level cache misses, depending on the PMU.
+    CacheMisses,
+    BranchMisses,
+    /// Raw, model specific, PMU event code.
+    Raw(u64),
+    PageFaults,
+    ContextSwitches,
+}
+
+#[derive(Debug, thiserror::Error, Eq, PartialEq)]
+pub enum ParsePerfEventError {
+    #[error("unknown event {0}, expected one of cpu-clock, cycles, instructions, cache-misses, branch-misses, page-faults, context-switches or r<hex code>")]
+    Unknown(String),
+    #[error("invalid raw event code {0}")]
+    InvalidRawCode(String),
+}
+
+impl FromStr for PerfEvent {
+    type Err = ParsePerfEventError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cpu-clock" => Ok(PerfEvent::CpuClock),
+            "cycles" => Ok(PerfEvent::Cycles),
+            "instructions" => Ok(PerfEvent::Instructions),
+            "cache-misses" => Ok(PerfEvent::CacheMisses),
+            "branch-misses" => Ok(PerfEvent::BranchMisses),
+            "page-faults" => Ok(PerfEvent::PageFaults),
+            "context-switches" => Ok(PerfEvent::ContextSwitches),
+            _ => match s.strip_prefix('r') {
+                // Same syntax as `perf record -e`.
+                Some(code) => u64::from_str_radix(code, 16)
+                    .map(PerfEvent::Raw)
+                    .map_err(|_| ParsePerfEventError::InvalidRawCode(s.to_string())),
+                None => Err(ParsePerfEventError::Unknown(s.to_string())),
+            },
+        }
+    }
+}
+
+impl fmt::Display for PerfEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PerfEvent::CpuClock => write!(f, "cpu-clock"),
+            PerfEvent::Cycles => write!(f, "cycles"),
+            PerfEvent::Instructions => write!(f, "instructions"),
+            PerfEvent::CacheMisses => write!(f, "cache-misses"),
+            PerfEvent::BranchMisses => write!(f, "branch-misses"),
+            PerfEvent::Raw(code) => write!(f, "r{code:x}"),
+            PerfEvent::PageFaults => write!(f, "page-faults"),
+            PerfEvent::ContextSwitches => write!(f, "context-switches"),
+        }
+    }
+}
+
+impl PerfEvent {
+    /// Software events don't need a PMU.
+    pub fn is_software(&self) -> bool {
+        matches!(
+            self,
+            PerfEvent::CpuClock | PerfEvent::PageFaults | PerfEvent::ContextSwitches
+        )
+    }
+
+    /// The type and config fields for `perf_event_open`.
+    fn type_and_config(&self) -> (u32, u64) {
+        match self {
+            PerfEvent::CpuClock => (
+                sys::bindings::PERF_TYPE_SOFTWARE,
+                sys::bindings::PERF_COUNT_SW_CPU_CLOCK as u64,
+            ),
+            PerfEvent::Cycles => (
+                sys::bindings::PERF_TYPE_HARDWARE,
+                sys::bindings::PERF_COUNT_HW_CPU_CYCLES as u64,
+            ),
+            PerfEvent::Instructions => (
+                sys::bindings::PERF_TYPE_HARDWARE,
+                sys::bindings::PERF_COUNT_HW_INSTRUCTIONS as u64,
+            ),
+            PerfEvent::CacheMisses => (
+                sys::bindings::PERF_TYPE_HARDWARE,
+                sys::bindings::PERF_COUNT_HW_CACHE_MISSES as u64,
+            ),
+            PerfEvent::BranchMisses => (
+                sys::bindings::PERF_TYPE_HARDWARE,
+                sys::bindings::PERF_COUNT_HW_BRANCH_MISSES as u64,
+            ),
+            PerfEvent::Raw(code) => (sys::bindings::PERF_TYPE_RAW, *code),
+            PerfEvent::PageFaults => (
+                sys::bindings::PERF_TYPE_SOFTWARE,
+                sys::bindings::PERF_COUNT_SW_PAGE_FAULTS as u64,
+            ),
+            PerfEvent::ContextSwitches => (
+                sys::bindings::PERF_TYPE_SOFTWARE,
+                sys::bindings::PERF_COUNT_SW_CONTEXT_SWITCHES as u64,
+            ),
+        }
+    }
+
+    /// The pprof sample type and unit for this event.
+    pub fn sample_type(&self) -> (String, String) {
+        match self {
+            PerfEvent::CpuClock => ("cpu".into(), "nanoseconds".into()),
+            other => (other.to_string(), "count".into()),
+        }
+    }
+}
+
+/// How often samples are taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Sampling {
+    /// Samples per second, per CPU. The kernel adjusts the period dynamically.
+    Frequency(u64),
+    /// Number of events between samples.
+    Period(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventConfig {
+    pub event: PerfEvent,
+    pub sampling: Sampling,
+}
+
+impl Default for EventConfig {
+    fn default() -> Self {
+        Self {
+            event: PerfEvent::CpuClock,
+            sampling: Sampling::Frequency(19),
+        }
+    }
+}
+
+impl EventConfig {
+    /// Number of events each sample represents, if known. For the CPU clock this is
+    /// in nanoseconds. When sampling by frequency other events don't have a fixed period.
+    pub fn period(&self) -> Option<u64> {
+        match (self.event, self.sampling) {
+            (_, Sampling::Period(period)) => Some(period),
+            (PerfEvent::CpuClock, Sampling::Frequency(freq)) => Some(1_000_000_000 / freq),
+            (_, Sampling::Frequency(_)) => None,
+        }
+    }
+
+    /// Returns this configuration if the event can be opened, otherwise falls back to the
+    /// software CPU clock. Hardware events are not available without a PMU, which is the case
+    /// for many virtual machines.
+    pub fn or_cpu_clock_fallback(self, sample_freq: u64) -> Self {
+        if self.event.is_software() {
+            return self;
+        }
+
+        let cpu = get_online_cpus()
+            .ok()
+            .and_then(|cpus| cpus.first().copied())
+            .unwrap_or(0);
+
+        match unsafe { setup_perf_event(cpu as i32, &self) } {
+            Ok(fd) => {
+                // SAFETY: the file descriptor was just opened and it's not used anywhere else.
+                drop(unsafe { OwnedFd::from_raw_fd(fd) });
+                self
+            }
+            Err(e) => {
+                warn!(
+                    "could not open perf event {} ({:?}), falling back to cpu-clock",
+                    self.event, e
+                );
+                Self {
+                    event: PerfEvent::CpuClock,
+                    sampling: Sampling::Frequency(sample_freq),
+                }
+            }
+        }
+    }
+}
 
 /// # Safety
-pub unsafe fn setup_perf_event(cpu: i32, sample_freq: u64) -> Result<c_int, io::Error> {
+pub unsafe fn setup_perf_event(cpu: i32, event_config: &EventConfig) -> Result<c_int, io::Error> {
+    let (type_, config) = event_config.event.type_and_config();
     let mut attrs: perf_event_attr = perf_event_open_sys::bindings::perf_event_attr {
         size: std::mem::size_of::<sys::bindings::perf_event_attr>() as u32,
-        type_: sys::bindings::PERF_TYPE_SOFTWARE,
-        config: sys::bindings::PERF_COUNT_SW_CPU_CLOCK as u64,
+        type_,
+        config,
         ..Default::default()
     };
-    attrs.__bindgen_anon_1.sample_freq = sample_freq;
+    match event_config.sampling {
+        Sampling::Frequency(sample_freq) => {
+            attrs.__bindgen_anon_1.sample_freq = sample_freq;
+            attrs.set_freq(1);
+        }
+        Sampling::Period(sample_period) => {
+            attrs.__bindgen_anon_1.sample_period = sample_period;
+        }
+    }
     attrs.set_disabled(1);
-    attrs.set_freq(1);
 
     let ret = sys::perf_event_open(
         &mut attrs, -1, /* pid */
@@ -28,3 +226,47 @@ pub unsafe fn setup_perf_event(cpu: i32, sample_freq: u64) -> Result<c_int, io::
 
     Ok(ret)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_perf_event() {
+        assert_eq!("cpu-clock".parse(), Ok(PerfEvent::CpuClock));
+        assert_eq!("cycles".parse(), Ok(PerfEvent::Cycles));
+        assert_eq!("branch-misses".parse(), Ok(PerfEvent::BranchMisses));
+        assert_eq!("page-faults".parse(), Ok(PerfEvent::PageFaults));
+        assert_eq!("r1c4".parse(), Ok(PerfEvent::Raw(0x1c4)));
+        assert_eq!(
+            "rxyz".parse::<PerfEvent>(),
+            Err(ParsePerfEventError::InvalidRawCode("rxyz".into()))
+        );
+        assert_eq!(
+            "bogus".parse::<PerfEvent>(),
+            Err(ParsePerfEventError::Unknown("bogus".into()))
+        );
+        assert_eq!(PerfEvent::Raw(0x1c4).to_string(), "r1c4");
+    }
+
+    #[test]
+    fn test_event_period() {
+        let config = EventConfig {
+            event: PerfEvent::CpuClock,
+            sampling: Sampling::Frequency(100),
+        };
+        assert_eq!(config.period(), Some(10_000_000));
+
+        let config = EventConfig {
+            event: PerfEvent::Cycles,
+            sampling: Sampling::Period(100_000),
+        };
+        assert_eq!(config.period(), Some(100_000));
+
+        let config = EventConfig {
+            event: PerfEvent::Cycles,
+            sampling: Sampling::Frequency(100),
+        };
+        assert_eq!(config.period(), None);
+    }
+}
@@ -1,5 +1,9 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fs::File;
+use std::ops::Deref;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
 use tracing::debug;
@@ -9,6 +13,9 @@ use lightswitch_object::ElfLoad;
 use lightswitch_object::ExecutableId;
 use lightswitch_object::Runtime;
 
+use crate::jit::JitSymbols;
+use crate::python::PythonProcess;
+
 pub type Pid = i32;
 
 /// What type of mapping we are dealing with.
@@ -34,6 +41,10 @@ pub struct ProcessInfo {
     pub status: ProcessStatus,
     pub mappings: ExecutableMappings,
     pub last_used: Instant,
+    /// Present for processes running the CPython interpreter.
+    pub python: Option<Arc<PythonProcess>>,
+    /// Present for processes with JIT compiled code.
+    pub jit_symbols: Option<Arc<Mutex<JitSymbols>>>,
 }
 
 /// Stores information for a executable mapping with all
@@ -92,6 +103,7 @@ impl ExecutableMapping {
                     "object file with path {} can be deleted",
                     object_file.path.display()
                 );
+                object_file.file = None;
                 return true;
             }
 
@@ -108,6 +120,9 @@ impl ExecutableMapping {
 
 pub struct ObjectFileInfo {
     pub path: PathBuf,
+    /// The opened file, for objects that were deleted or replaced by the time they were
+    /// seen, which can't be read from their path. Closed once no process maps it.
+    pub file: Option<Arc<File>>,
     pub elf_load_segments: Vec<ElfLoad>,
     pub is_dyn: bool,
     pub references: i64,
@@ -120,6 +135,7 @@ impl Clone for ObjectFileInfo {
     fn clone(&self) -> Self {
         ObjectFileInfo {
             path: self.path.clone(),
+            file: self.file.clone(),
             elf_load_segments: self.elf_load_segments.clone(),
             is_dyn: self.is_dyn,
             references: self.references,
@@ -130,7 +146,37 @@ impl Clone for ObjectFileInfo {
     }
 }
 
+/// Path to read an object file from. Paths to opened files are only valid while they
+/// are open, so the file is kept open for as long as the path is around.
+pub struct OpenPath {
+    path: PathBuf,
+    _file: Option<Arc<File>>,
+}
+
+impl Deref for OpenPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
 impl ObjectFileInfo {
+    /// Path to read the object file from, which refers to the opened file if any, as the
+    /// file at `path` was deleted or replaced by a different version.
+    pub fn open_path(&self) -> OpenPath {
+        match &self.file {
+            Some(file) => OpenPath {
+                path: PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd())),
+                _file: Some(file.clone()),
+            },
+            None => OpenPath {
+                path: self.path.clone(),
+                _file: None,
+            },
+        }
+    }
+
     /// For a virtual address return the offset within the object file. This is
     /// necessary for off-host symbolization. In order to do this we must check every
     /// `PT_LOAD` segment.
@@ -164,6 +210,7 @@ mod tests {
     fn test_address_normalization() {
         let mut object_file_info = ObjectFileInfo {
             path: "/".into(),
+            file: None,
             elf_load_segments: vec![],
             is_dyn: false,
             references: 0,
@@ -0,0 +1,273 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use lightswitch_metadata::container_metadata::container_from_cgroup;
+use regex::Regex;
+
+use crate::process::Pid;
+
+/// Where the unified cgroup hierarchy is mounted.
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// For how long whether a process is selected is remembered. Processes can join a
+/// selected cgroup later on, which is noticed once the decision expires.
+const SELECTION_TTL: Duration = Duration::from_secs(5);
+
+/// Selects processes to profile by what they run in, rather than by their pid.
+#[derive(Debug, Clone)]
+pub enum ProcessSelector {
+    /// Processes in this cgroup, relative to the root of the hierarchy, or in any of
+    /// its descendants.
+    Cgroup(PathBuf),
+    /// Processes started by a systemd unit, such as `nginx.service`.
+    SystemdUnit(String),
+    /// Processes running in a container, by its id or a prefix of it.
+    ContainerId(String),
+    /// Processes whose name, as in `/proc/<pid>/comm`, matches.
+    ProcessName(Regex),
+}
+
+impl PartialEq for ProcessSelector {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Cgroup(a), Self::Cgroup(b)) => a == b,
+            (Self::SystemdUnit(a), Self::SystemdUnit(b)) => a == b,
+            (Self::ContainerId(a), Self::ContainerId(b)) => a == b,
+            (Self::ProcessName(a), Self::ProcessName(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+impl ProcessSelector {
+    /// Selects a cgroup, either relative to the root of the hierarchy or with the path
+    /// it has under [`CGROUP_ROOT`].
+    pub fn cgroup(path: &Path) -> Self {
+        let path = path.strip_prefix(CGROUP_ROOT).unwrap_or(path);
+        Self::Cgroup(Path::new("/").join(path))
+    }
+
+    /// Selects a systemd unit. Units without a type, such as `nginx`, are services.
+    pub fn systemd_unit(unit: &str) -> Self {
+        if unit.contains('.') {
+            Self::SystemdUnit(unit.to_string())
+        } else {
+            Self::SystemdUnit(format!("{unit}.service"))
+        }
+    }
+
+    /// Whether processes can be selected by just looking at their cgroup, and hence
+    /// filtered in BPF.
+    pub fn is_cgroup_based(&self) -> bool {
+        !matches!(self, Self::ProcessName(_))
+    }
+
+    /// Whether the processes in a cgroup, given by its path relative to the root of the
+    /// hierarchy, are selected.
+    pub fn matches_cgroup(&self, cgroup: &Path) -> bool {
+        match self {
+            Self::Cgroup(path) => cgroup.starts_with(path),
+            Self::SystemdUnit(unit) => cgroup
+                .iter()
+                .any(|segment| segment.to_str() == Some(unit.as_str())),
+            Self::ContainerId(id) => cgroup.iter().any(|segment| {
+                container_from_cgroup(&format!("0::/{}", segment.to_string_lossy()))
+                    .is_some_and(|container| container.id.starts_with(id.as_str()))
+            }),
+            Self::ProcessName(_) => false,
+        }
+    }
+
+    fn matches_process(&self, cgroups: &str, name: &str) -> bool {
+        match self {
+            Self::ProcessName(regex) => regex.is_match(name),
+            _ => cgroup_paths(cgroups).any(|cgroup| self.matches_cgroup(cgroup)),
+        }
+    }
+}
+
+/// Paths of the cgroups in the contents of `/proc/<pid>/cgroup`, one per hierarchy.
+fn cgroup_paths(cgroups: &str) -> impl Iterator<Item = &Path> {
+    cgroups
+        .lines()
+        .filter_map(|line| line.splitn(3, ':').nth(2))
+        .map(Path::new)
+}
+
+/// Whether a process is selected by any of the selectors.
+pub fn process_is_selected(selectors: &[ProcessSelector], pid: Pid) -> bool {
+    let Ok(cgroups) = fs::read_to_string(format!("/proc/{pid}/cgroup")) else {
+        return false;
+    };
+    let name = if selectors.iter().all(ProcessSelector::is_cgroup_based) {
+        String::new()
+    } else {
+        fs::read_to_string(format!("/proc/{pid}/comm")).unwrap_or_default()
+    };
+
+    selectors
+        .iter()
+        .any(|selector| selector.matches_process(&cgroups, name.trim_end()))
+}
+
+/// Remembers whether processes are selected, so `/proc` isn't read again for every
+/// event of the processes that aren't.
+#[derive(Debug, Default)]
+pub struct SelectionCache {
+    /// Whether each process is selected, and since when.
+    decisions: HashMap<Pid, (bool, Instant)>,
+}
+
+impl SelectionCache {
+    pub fn is_selected(&mut self, selectors: &[ProcessSelector], pid: Pid, now: Instant) -> bool {
+        if let Some((selected, since)) = self.decisions.get(&pid) {
+            if now.duration_since(*since) < SELECTION_TTL {
+                return *selected;
+            }
+        }
+        let selected = process_is_selected(selectors, pid);
+        self.decisions.insert(pid, (selected, now));
+        selected
+    }
+
+    /// Forgets a process, such as when it exits or runs a different program.
+    pub fn remove(&mut self, pid: Pid) {
+        self.decisions.remove(&pid);
+    }
+
+    /// Forgets every process, such as when the selectors change.
+    pub fn clear(&mut self) {
+        self.decisions.clear();
+    }
+
+    /// Forgets the decisions that expired.
+    pub fn expire(&mut self, now: Instant) {
+        self.decisions
+            .retain(|_, (_, since)| now.duration_since(*since) < SELECTION_TTL);
+    }
+}
+
+/// Whether `root` is the root of a cgroup v2 hierarchy, where cgroup ids are the inode
+/// numbers of their directories.
+pub fn is_cgroup_v2(root: &Path) -> bool {
+    root.join("cgroup.controllers").exists()
+}
+
+/// Ids of the cgroups under `root` that are selected by any of the selectors.
+pub fn selected_cgroup_ids(selectors: &[ProcessSelector], root: &Path) -> io::Result<HashSet<u64>> {
+    let mut ids = HashSet::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let cgroup = Path::new("/").join(dir.strip_prefix(root).unwrap_or(&dir));
+        if selectors
+            .iter()
+            .any(|selector| selector.matches_cgroup(&cgroup))
+        {
+            ids.insert(fs::metadata(&dir)?.ino());
+        }
+
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            // The cgroup might have been removed while walking the hierarchy.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                pending.push(entry.path());
+            }
+        }
+    }
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "3f1b8e0c5d2a4f6e9b7c1d0e2f3a4b5c6d7e8f9a0b1c2d3e4f5a6b7c8d9e0f1a";
+
+    #[test]
+    fn test_matches_cgroup() {
+        let container = format!("/system.slice/docker-{ID}.scope");
+        let cgroup = ProcessSelector::cgroup(Path::new("/sys/fs/cgroup/system.slice"));
+        assert!(cgroup.matches_cgroup(Path::new("/system.slice/nginx.service")));
+        assert!(cgroup.matches_cgroup(Path::new(&container)));
+        assert!(!cgroup.matches_cgroup(Path::new("/system.slice2")));
+        assert!(!cgroup.matches_cgroup(Path::new("/user.slice")));
+
+        let unit = ProcessSelector::systemd_unit("nginx");
+        assert!(unit.matches_cgroup(Path::new("/system.slice/nginx.service")));
+        assert!(unit.matches_cgroup(Path::new("/system.slice/nginx.service/worker")));
+        assert!(!unit.matches_cgroup(Path::new("/system.slice/nginx-exporter.service")));
+
+        let container_id = ProcessSelector::ContainerId(ID[..12].to_string());
+        assert!(container_id.matches_cgroup(Path::new(&container)));
+        assert!(container_id.matches_cgroup(Path::new(&format!("/docker/{ID}"))));
+        assert!(!container_id.matches_cgroup(Path::new("/system.slice/nginx.service")));
+    }
+
+    #[test]
+    fn test_process_is_selected() {
+        let pid = std::process::id() as Pid;
+        let cgroups = fs::read_to_string("/proc/self/cgroup").unwrap();
+        let own_cgroup = cgroup_paths(&cgroups).next().unwrap();
+
+        assert!(process_is_selected(
+            &[ProcessSelector::cgroup(own_cgroup)],
+            pid
+        ));
+        assert!(process_is_selected(
+            &[ProcessSelector::ProcessName(Regex::new(".").unwrap())],
+            pid
+        ));
+        assert!(!process_is_selected(
+            &[
+                ProcessSelector::ProcessName(Regex::new("^$").unwrap()),
+                ProcessSelector::ContainerId(ID.to_string()),
+            ],
+            pid
+        ));
+    }
+
+    #[test]
+    fn test_selection_cache() {
+        let pid = std::process::id() as Pid;
+        let all = [ProcessSelector::ProcessName(Regex::new(".").unwrap())];
+        let none = [ProcessSelector::ProcessName(Regex::new("^$").unwrap())];
+        let start = Instant::now();
+        let mut cache = SelectionCache::default();
+
+        assert!(cache.is_selected(&all, pid, start));
+        // The decision is remembered until it expires.
+        assert!(cache.is_selected(&none, pid, start + Duration::from_secs(1)));
+        assert!(!cache.is_selected(&none, pid, start + SELECTION_TTL));
+
+        cache.remove(pid);
+        assert!(cache.is_selected(&all, pid, start + SELECTION_TTL));
+        cache.expire(start + SELECTION_TTL * 2);
+        assert!(cache.decisions.is_empty());
+    }
+
+    #[test]
+    fn test_selected_cgroup_ids() {
+        let root = tempfile::tempdir().unwrap();
+        let service = root.path().join("system.slice/nginx.service");
+        fs::create_dir_all(service.join("worker")).unwrap();
+        fs::create_dir_all(root.path().join("user.slice")).unwrap();
+
+        let ino = |path: &Path| fs::metadata(path).unwrap().ino();
+        assert_eq!(
+            selected_cgroup_ids(&[ProcessSelector::systemd_unit("nginx")], root.path()).unwrap(),
+            HashSet::from([ino(&service), ino(&service.join("worker"))])
+        );
+        assert!(
+            selected_cgroup_ids(&[ProcessSelector::systemd_unit("sshd")], root.path())
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
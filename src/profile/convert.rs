@@ -2,26 +2,38 @@ use lightswitch_metadata::metadata_provider::ThreadSafeGlobalMetadataProvider;
 use lightswitch_metadata::taskname::TaskName;
 use lightswitch_metadata::types::{MetadataLabelValue, TaskKey};
 
+use lightswitch_proto::otlp::opentelemetry::proto::collector::profiles::v1development::ExportProfilesServiceRequest;
+use lightswitch_proto::otlp::OtlpBuilder;
 use lightswitch_proto::profile::pprof::Label;
-use lightswitch_proto::profile::{pprof, LabelStringOrNumber, PprofBuilder};
-use std::collections::HashMap;
+use lightswitch_proto::profile::{pprof, LabelStringOrNumber, PprofBuilder, SampleType};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Write;
-use std::path::PathBuf;
 use std::time::Duration;
+use std::time::Instant;
 use std::time::SystemTime;
 use tracing::{debug, error, span, Level};
 
+use crate::debug_info::{DebugInfoSources, DEBUG_INFO_DOWNLOAD_WAIT_PER_PROFILE};
 use crate::kernel::KERNEL_PID;
 use crate::ksym::Ksym;
 use crate::ksym::KsymIter;
+use crate::perf_events::EventConfig;
+use crate::process::ExecutableMappingType;
 use crate::process::ObjectFileInfo;
 use crate::process::ProcessInfo;
 use crate::profile::{
-    AggregatedProfile, AggregatedSample, Frame, FrameAddress, RawAggregatedProfile, SymbolizedFrame,
+    AggregatedProfile, AggregatedSample, Frame, FrameAddress, RawAggregatedProfile, SampleKind,
+    SymbolizedFrame,
 };
 use crate::usym::symbolize_native_stack_blaze;
 use lightswitch_object::ExecutableId;
 
+/// Mapping for frames of interpreted code, such as Python's, which don't belong to any
+/// executable mapping.
+const INTERPRETED_CODE_MAPPING_ID: u64 = u64::MAX;
+/// Mapping for frames of JIT compiled code, which isn't backed by any object file.
+const JIT_MAPPING_ID: u64 = u64::MAX - 1;
+
 struct ProfileLabel {
     value: MetadataLabelValue,
 }
@@ -35,6 +47,38 @@ impl From<ProfileLabel> for LabelStringOrNumber {
     }
 }
 
+/// Returns the values of a sample, one per sample type. The first one is the number of
+/// on-CPU samples, followed by one value per event, and the time spent off-CPU if there
+/// are off-CPU samples. Event values are weighted by the event period when it's known,
+/// otherwise they are the number of samples for that event.
+fn sample_values(sample: &AggregatedSample, periods: &[Option<u64>], off_cpu: bool) -> Vec<i64> {
+    let count = sample.count as i64;
+    let mut values = Vec::new();
+
+    match sample.kind {
+        SampleKind::OnCpu => {
+            values.push(count);
+            for (event, period) in periods.iter().enumerate() {
+                if event != sample.event as usize {
+                    values.push(0);
+                    continue;
+                }
+                values.push(count * period.unwrap_or(1) as i64);
+            }
+            if off_cpu {
+                values.push(0);
+            }
+        }
+        SampleKind::OffCpu => {
+            values.push(0);
+            values.extend(periods.iter().map(|_| 0));
+            values.push(count);
+        }
+    }
+
+    values
+}
+
 /// Converts a given symbolized profile to Google's pprof.
 pub fn to_pprof(
     profile: AggregatedProfile,
@@ -42,15 +86,45 @@ pub fn to_pprof(
     objs: &HashMap<ExecutableId, ObjectFileInfo>,
     metadata_provider: &ThreadSafeGlobalMetadataProvider,
     profile_duration: Duration,
-    profile_frequency_hz: u64,
+    event_configs: &[EventConfig],
 ) -> pprof::Profile {
     // Not exactly when the profile session really started but works for now.
     let profile_start = SystemTime::now();
 
-    let mut pprof = PprofBuilder::new(profile_start, profile_duration, profile_frequency_hz);
+    let periods: Vec<_> = event_configs
+        .iter()
+        .map(|event_config| event_config.period())
+        .collect();
+    let off_cpu = profile
+        .iter()
+        .any(|sample| sample.kind == SampleKind::OffCpu);
+
+    let mut sample_types = vec![SampleType::new("samples", "count")];
+    for event_config in event_configs {
+        let (event_type, event_unit) = event_config.event.sample_type();
+        match event_config.period() {
+            Some(_) => sample_types.push(SampleType::new(&event_type, &event_unit)),
+            None => sample_types.push(SampleType::new(&event_type, "samples")),
+        }
+    }
+    if off_cpu {
+        sample_types.push(SampleType::new("off-cpu", "nanoseconds"));
+    }
+
+    // The period type is the first event's, which drives the profile.
+    let first_event = event_configs.first().copied().unwrap_or_default();
+    let (period_type, period_unit) = first_event.event.sample_type();
+    let mut pprof = PprofBuilder::with_sample_types(
+        profile_start,
+        profile_duration,
+        sample_types,
+        SampleType::new(&period_type, &period_unit),
+        first_event.period().unwrap_or(0) as i64,
+    );
     let mut task_to_labels: HashMap<i32, Vec<Label>> = HashMap::new();
 
     for sample in profile {
+        let values = sample_values(&sample, &periods, off_cpu);
         let ustack = sample.ustack;
         let kstack = sample.kstack;
         let mut location_ids = Vec::new();
@@ -126,7 +200,28 @@ pub fn to_pprof(
             };
 
             let Some(mapping) = info.mappings.for_address(&virtual_address) else {
-                // todo: maybe append an error frame for debugging?
+                // Frames for interpreted code don't belong to any mapping. Their address is
+                // derived from the function and line so different lines get different locations.
+                if let Some(Ok(SymbolizedFrame {
+                    name,
+                    filename,
+                    line,
+                    ..
+                })) = uframe.symbolization_result
+                {
+                    let mapping_id = pprof.add_mapping(
+                        INTERPRETED_CODE_MAPPING_ID,
+                        0x0,
+                        0x0,
+                        0x0,
+                        "[interpreted]",
+                        "",
+                    );
+                    let (line, function_id) = pprof.add_line(&name, filename, line);
+                    let address = (function_id << 32) | line.line as u64;
+                    let location = pprof.add_location(address, mapping_id, vec![line]);
+                    location_ids.push(location);
+                }
                 continue;
             };
 
@@ -184,6 +279,15 @@ pub fn to_pprof(
                     let location = pprof.add_location(normalized_addr, mapping_id, lines);
                     location_ids.push(location);
                 }
+                None if mapping.kind == ExecutableMappingType::Anonymous => {
+                    if let Some(Ok(SymbolizedFrame { name, .. })) = uframe.symbolization_result {
+                        let mapping_id =
+                            pprof.add_mapping(JIT_MAPPING_ID, 0x0, 0x0, 0x0, "[jit]", "");
+                        let (line, _) = pprof.add_line(&name, None, None);
+                        let location = pprof.add_location(virtual_address, mapping_id, vec![line]);
+                        location_ids.push(location);
+                    }
+                }
                 None => {
                     debug!("executable with id 0x{} not found", mapping.executable_id);
                 }
@@ -202,12 +306,51 @@ pub fn to_pprof(
                 })
                 .collect()
         });
-        pprof.add_sample(location_ids, sample.count as i64, labels);
+        pprof.add_sample_with_values(location_ids, values, labels);
     }
 
     pprof.build()
 }
 
+/// Converts a given symbolized profile to an OpenTelemetry profiles export request, with one
+/// resource per process. Each process is converted to pprof first and the labels shared by
+/// all of its samples, such as the process name, become resource attributes. The
+/// `comments` are added to every profile.
+pub fn to_otlp(
+    profile: AggregatedProfile,
+    procs: &HashMap<i32, ProcessInfo>,
+    objs: &HashMap<ExecutableId, ObjectFileInfo>,
+    metadata_provider: &ThreadSafeGlobalMetadataProvider,
+    profile_duration: Duration,
+    event_configs: &[EventConfig],
+    comments: &[String],
+) -> ExportProfilesServiceRequest {
+    let mut profile_per_process: BTreeMap<i32, AggregatedProfile> = BTreeMap::new();
+    for sample in profile {
+        profile_per_process
+            .entry(sample.pid)
+            .or_default()
+            .push(sample);
+    }
+
+    let mut otlp = OtlpBuilder::new("lightswitch", env!("CARGO_PKG_VERSION"));
+    for process_profile in profile_per_process.into_values() {
+        let mut pprof = to_pprof(
+            process_profile,
+            procs,
+            objs,
+            metadata_provider,
+            profile_duration,
+            event_configs,
+        );
+        for comment in comments {
+            pprof.add_comment(comment);
+        }
+        otlp.add_profile(&pprof);
+    }
+    otlp.build()
+}
+
 /// Converts a collection of symbolized aggregated profiles to their folded representation that most flamegraph renderers use.
 /// Folded stacks look like this:
 ///
@@ -286,18 +429,29 @@ pub fn symbolize_profile(
     profile: &AggregatedProfile,
     procs: &HashMap<i32, ProcessInfo>,
     objs: &HashMap<ExecutableId, ObjectFileInfo>,
+    debug_info: &DebugInfoSources,
 ) -> AggregatedProfile {
     let _span = span!(Level::DEBUG, "symbolize_profile").entered();
     let mut r = AggregatedProfile::new();
 
-    let addresses_per_sample = fetch_symbols_for_profile(profile, procs, objs);
+    let addresses_per_sample = fetch_symbols_for_profile(profile, procs, objs, debug_info);
     let ksyms = KsymIter::from_kallsyms().collect::<Vec<_>>();
 
+    // Pick up the JIT symbols written since the last profile.
+    let pids: HashSet<_> = profile.iter().map(|sample| sample.pid).collect();
+    for pid in pids {
+        if let Some(jit_symbols) = procs.get(&pid).and_then(|info| info.jit_symbols.as_ref()) {
+            jit_symbols.lock().unwrap().refresh();
+        }
+    }
+
     for sample in profile {
         let symbolized_sample = AggregatedSample {
             pid: sample.pid,
             tid: sample.tid,
             count: sample.count,
+            kind: sample.kind,
+            event: sample.event,
             ustack: symbolize_user_stack(
                 &addresses_per_sample,
                 procs,
@@ -317,9 +471,11 @@ pub fn fetch_symbols_for_profile(
     profile: &AggregatedProfile,
     procs: &HashMap<i32, ProcessInfo>,
     objs: &HashMap<ExecutableId, ObjectFileInfo>,
-) -> HashMap<PathBuf, HashMap<FrameAddress, Vec<Frame>>> {
-    let mut addresses_per_sample: HashMap<PathBuf, HashMap<FrameAddress, Vec<Frame>>> =
+    debug_info: &DebugInfoSources,
+) -> HashMap<ExecutableId, HashMap<FrameAddress, Vec<Frame>>> {
+    let mut addresses_per_sample: HashMap<ExecutableId, HashMap<FrameAddress, Vec<Frame>>> =
         HashMap::new();
+    let mut build_ids = HashMap::new();
 
     for sample in profile {
         if sample.ustack.is_empty() {
@@ -340,10 +496,12 @@ pub fn fetch_symbols_for_profile(
             };
 
             match objs.get(&mapping.executable_id) {
-                Some(obj) => {
+                Some(_) => {
+                    build_ids
+                        .entry(mapping.executable_id)
+                        .or_insert(mapping.build_id.as_ref());
                     addresses_per_sample
-                        // todo: use open object file path
-                        .entry(obj.path.clone())
+                        .entry(mapping.executable_id)
                         .or_default()
                         .insert(
                             FrameAddress {
@@ -361,9 +519,18 @@ pub fn fetch_symbols_for_profile(
     }
 
     // second pass, symbolize
-    for (path, addr_to_symbol_mapping) in addresses_per_sample.iter_mut() {
+    let deadline = Instant::now() + DEBUG_INFO_DOWNLOAD_WAIT_PER_PROFILE;
+    for (executable_id, addr_to_symbol_mapping) in addresses_per_sample.iter_mut() {
+        let Some(obj) = objs.get(executable_id) else {
+            continue;
+        };
         let frame_addresses = addr_to_symbol_mapping.keys().copied().collect();
-        let symbolized_frames = symbolize_native_stack_blaze(frame_addresses, path);
+        let build_id = build_ids.get(executable_id).copied().flatten();
+        // Objects are read from the opened file, as the one at their path might have been
+        // deleted or replaced.
+        let open_path = obj.open_path();
+        let symbolization_path = debug_info.symbolization_path(&open_path, build_id, deadline);
+        let symbolized_frames = symbolize_native_stack_blaze(frame_addresses, &symbolization_path);
         for ((frame_address, _), symbolized_frame) in addr_to_symbol_mapping
             .clone()
             .iter_mut()
@@ -410,7 +577,7 @@ fn symbolize_kernel_stack(kernel_stack: &[Frame], ksyms: &[Ksym]) -> Vec<Frame>
 }
 
 fn symbolize_user_stack(
-    addresses_per_sample: &HashMap<PathBuf, HashMap<FrameAddress, Vec<Frame>>>,
+    addresses_per_sample: &HashMap<ExecutableId, HashMap<FrameAddress, Vec<Frame>>>,
     procs: &HashMap<i32, ProcessInfo>,
     objs: &HashMap<ExecutableId, ObjectFileInfo>,
     pid: i32,
@@ -419,6 +586,13 @@ fn symbolize_user_stack(
     let mut result = Vec::new();
 
     for frame in native_stack.iter() {
+        // Frames for interpreted code, such as Python's, are symbolized while processing
+        // the samples.
+        if frame.symbolization_result.is_some() {
+            result.push(frame.clone());
+            continue;
+        }
+
         let Some(info) = procs.get(&pid) else {
             result.push(Frame::with_error(
                 frame.virtual_address,
@@ -435,6 +609,11 @@ fn symbolize_user_stack(
             continue;
         };
 
+        if mapping.kind == ExecutableMappingType::Anonymous {
+            result.push(symbolize_jit_frame(info, frame));
+            continue;
+        }
+
         // We need the file offsets to symbolize.
         let Some(file_offset) = frame.file_offset else {
             continue;
@@ -456,7 +635,7 @@ fn symbolize_user_stack(
                     virtual_address: frame.virtual_address,
                     file_offset,
                 };
-                let frames_for_address = match addresses_per_sample.get(&obj.path) {
+                let frames_for_address = match addresses_per_sample.get(&mapping.executable_id) {
                     Some(value) => match value.get(&frame_address) {
                         Some(frames) => frames,
                         None => &failed_to_fetch_symbol,
@@ -476,3 +655,23 @@ fn symbolize_user_stack(
 
     result
 }
+
+/// Symbolizes a frame of JIT compiled code with the symbols the runtime wrote.
+fn symbolize_jit_frame(info: &ProcessInfo, frame: &Frame) -> Frame {
+    let name = info.jit_symbols.as_ref().and_then(|jit_symbols| {
+        jit_symbols
+            .lock()
+            .unwrap()
+            .symbolize(frame.virtual_address)
+            .map(|name| name.to_string())
+    });
+
+    match name {
+        Some(name) => Frame {
+            virtual_address: frame.virtual_address,
+            file_offset: None,
+            symbolization_result: Some(Ok(SymbolizedFrame::new(name, false, None, None))),
+        },
+        None => Frame::with_error(frame.virtual_address, "<unknown jit frame>".to_string()),
+    }
+}
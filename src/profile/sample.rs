@@ -6,11 +6,37 @@ use anyhow::anyhow;
 use lightswitch_object::ExecutableId;
 use tracing::error;
 
+use crate::bpf::profiler_bindings::sample_kind_SAMPLE_KIND_OFF_CPU;
+use crate::bpf::profiler_bindings::MAX_STACK_ADDRESSES;
 use crate::kernel::KERNEL_PID;
+use crate::process::ExecutableMappingType;
 use crate::process::ObjectFileInfo;
 use crate::process::Pid;
 use crate::process::ProcessInfo;
 use crate::profile::Frame;
+use crate::python::RawPythonFrame;
+
+/// Size of the fields of [`sample_t`] that precede the stack addresses.
+const SAMPLE_HEADER_SIZE: usize = 48;
+
+/// Whether a sample was taken while the thread was running or when it was
+/// scheduled back in after being blocked.
+#[derive(Debug, Default, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum SampleKind {
+    #[default]
+    OnCpu,
+    OffCpu,
+}
+
+impl From<u32> for SampleKind {
+    fn from(kind: u32) -> Self {
+        if kind == sample_kind_SAMPLE_KIND_OFF_CPU {
+            SampleKind::OffCpu
+        } else {
+            SampleKind::OnCpu
+        }
+    }
+}
 
 /// This *must* be in sync with the C struct `sample_t`.
 #[derive(Debug, Clone, PartialEq)]
@@ -18,8 +44,16 @@ pub struct RawSample {
     pub pid: Pid,
     pub tid: Pid,
     pub collected_at: u64,
+    /// 1 for on-CPU samples, time spent off-CPU in nanoseconds for off-CPU samples.
+    pub value: u64,
+    pub kind: SampleKind,
+    /// Index of the event that triggered the sample, in the order the events were configured.
+    /// Always 0 for off-CPU samples.
+    pub event: u32,
     pub ustack: Vec<u64>,
     pub kstack: Vec<u64>,
+    /// Python frames, from the innermost one, for processes running CPython.
+    pub python_stack: Vec<RawPythonFrame>,
 }
 
 #[derive(Debug, thiserror::Error, Eq, PartialEq)]
@@ -39,28 +73,44 @@ pub enum RawSampleParsingError {
 impl RawSample {
     pub fn from_bytes(data: &[u8]) -> Result<Self, RawSampleParsingError> {
         let sample_len = data.len();
-        if sample_len < 24 {
+        if sample_len < SAMPLE_HEADER_SIZE {
             return Err(RawSampleParsingError::BeforeStackTooSmall);
         }
-        if sample_len > 24 + 127 * 2 * 8 {
+        if sample_len > SAMPLE_HEADER_SIZE + MAX_STACK_ADDRESSES as usize * 8 {
             return Err(RawSampleParsingError::SampleTooLarge);
         }
 
         let pid = i32::from_ne_bytes(data[0..4].try_into().unwrap());
         let tid = i32::from_ne_bytes(data[4..8].try_into().unwrap());
         let collected_at = u64::from_ne_bytes(data[8..16].try_into().unwrap());
-        let ulen = u32::from_ne_bytes(data[16..20].try_into().unwrap()) as usize;
-        let klen = u32::from_ne_bytes(data[20..24].try_into().unwrap()) as usize;
-
-        if sample_len < 24 + (ulen + klen) * 8 {
+        let value = u64::from_ne_bytes(data[16..24].try_into().unwrap());
+        let kind = u32::from_ne_bytes(data[24..28].try_into().unwrap());
+        let event = u32::from_ne_bytes(data[28..32].try_into().unwrap());
+        let ulen = u32::from_ne_bytes(data[32..36].try_into().unwrap()) as usize;
+        let klen = u32::from_ne_bytes(data[36..40].try_into().unwrap()) as usize;
+        let plen = u32::from_ne_bytes(data[40..44].try_into().unwrap()) as usize;
+
+        if sample_len < SAMPLE_HEADER_SIZE + (ulen + klen + 2 * plen) * 8 {
             return Err(RawSampleParsingError::StackTooSmall);
         }
 
-        let ustack = data[24..(24 + ulen * 8)]
+        let ustack_end = SAMPLE_HEADER_SIZE + ulen * 8;
+        let ustack = data[SAMPLE_HEADER_SIZE..ustack_end]
             .chunks_exact(8)
             .map(|chunk| u64::from_ne_bytes(chunk.try_into().unwrap()))
             .collect::<Vec<_>>();
-        let kstack = data[(24 + ulen * 8)..(24 + ulen * 8 + klen * 8)]
+        // Each Python frame is made of the code object address and the instruction offset.
+        let python_stack_end = ustack_end + plen * 2 * 8;
+        let python_stack = data[ustack_end..python_stack_end]
+            .chunks_exact(16)
+            .map(|chunk| {
+                RawPythonFrame::from_raw(
+                    u64::from_ne_bytes(chunk[0..8].try_into().unwrap()),
+                    u64::from_ne_bytes(chunk[8..16].try_into().unwrap()),
+                )
+            })
+            .collect::<Vec<_>>();
+        let kstack = data[python_stack_end..(python_stack_end + klen * 8)]
             .chunks_exact(8)
             .map(|chunk| u64::from_ne_bytes(chunk.try_into().unwrap()))
             .collect::<Vec<_>>();
@@ -69,8 +119,12 @@ impl RawSample {
             pid,
             tid,
             collected_at,
+            value,
+            kind: SampleKind::from(kind),
+            event,
             ustack,
             kstack,
+            python_stack,
         })
     }
 }
@@ -79,10 +133,13 @@ impl std::hash::Hash for RawSample {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.pid.hash(state);
         self.kstack.hash(state);
-        // The collected_at field is excluded when hashing
+        // The collected_at and value fields are excluded when hashing
         // the samples for aggregation.
         self.tid.hash(state);
+        self.kind.hash(state);
+        self.event.hash(state);
         self.ustack.hash(state);
+        self.python_stack.hash(state);
     }
 }
 
@@ -109,6 +166,8 @@ impl fmt::Display for RawSample {
 #[derive(Debug, PartialEq)]
 pub struct RawAggregatedSample {
     pub sample: RawSample,
+    /// Sum of the values of the aggregated samples. For on-CPU samples this is the
+    /// number of samples, for off-CPU ones the total time spent off-CPU in nanoseconds.
     pub count: u64,
 }
 
@@ -128,12 +187,15 @@ impl RawAggregatedSample {
             ustack: Vec::new(),
             kstack: Vec::new(),
             count: self.count,
+            kind: self.sample.kind,
+            event: self.sample.event,
         };
 
         let Some(info) = procs.get(&self.sample.pid) else {
             return Err(anyhow!("process not found"));
         };
 
+        let mut native_stack = Vec::new();
         for virtual_address in &self.sample.ustack {
             let Some(mapping) = info.mappings.for_address(virtual_address) else {
                 continue;
@@ -141,19 +203,41 @@ impl RawAggregatedSample {
 
             let file_offset = match objs.get(&mapping.executable_id) {
                 Some(obj) => obj.normalized_address(*virtual_address, mapping),
+                // JIT compiled code is not backed by any object file.
+                None if mapping.kind == ExecutableMappingType::Anonymous => None,
                 None => {
                     error!("executable with id 0x{} not found", mapping.executable_id);
                     None
                 }
             };
 
-            processed_sample.ustack.push(Frame {
-                virtual_address: *virtual_address,
-                file_offset,
-                symbolization_result: None,
-            });
+            let in_interpreter_loop = info
+                .python
+                .as_ref()
+                .is_some_and(|python| python.is_eval_frame(mapping.executable_id, file_offset));
+            native_stack.push((
+                Frame {
+                    virtual_address: *virtual_address,
+                    file_offset,
+                    symbolization_result: None,
+                },
+                in_interpreter_loop,
+            ));
         }
 
+        processed_sample.ustack = match &info.python {
+            Some(python) if !self.sample.python_stack.is_empty() => {
+                let python_stack = self
+                    .sample
+                    .python_stack
+                    .iter()
+                    .map(|raw_frame| (python.symbolize(raw_frame), raw_frame.is_entry))
+                    .collect();
+                merge_python_stack(native_stack, python_stack)
+            }
+            _ => native_stack.into_iter().map(|(frame, _)| frame).collect(),
+        };
+
         let Some(info) = procs.get(&KERNEL_PID) else {
             return Err(anyhow!("kernel process not found"));
         };
@@ -188,6 +272,44 @@ impl RawAggregatedSample {
     }
 }
 
+/// Replaces the native frames of the interpreter loop with the Python frames it was
+/// evaluating. Each call to the interpreter loop evaluates Python frames up to and
+/// including an entry frame. Both stacks start at the innermost frame, and the Python
+/// frames are tagged with whether they are entry frames.
+fn merge_python_stack(
+    native_stack: Vec<(Frame, bool)>,
+    python_stack: Vec<(Frame, bool)>,
+) -> Vec<Frame> {
+    let mut merged = Vec::new();
+    let mut python_stack = python_stack.into_iter();
+
+    for (native_frame, in_interpreter_loop) in native_stack {
+        if !in_interpreter_loop {
+            merged.push(native_frame);
+            continue;
+        }
+
+        let mut replaced = false;
+        for (python_frame, is_entry) in python_stack.by_ref() {
+            merged.push(python_frame);
+            replaced = true;
+            if is_entry {
+                break;
+            }
+        }
+
+        // Keep the native frame if there are no Python frames left for it.
+        if !replaced {
+            merged.push(native_frame);
+        }
+    }
+
+    // Python frames for which we didn't find the native interpreter loop frame, for example
+    // if the native stack was truncated.
+    merged.extend(python_stack.map(|(python_frame, _)| python_frame));
+    merged
+}
+
 impl fmt::Display for RawAggregatedSample {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("RawAggregatedSample")
@@ -213,6 +335,8 @@ pub struct AggregatedSample {
     pub ustack: Vec<Frame>,
     pub kstack: Vec<Frame>,
     pub count: u64,
+    pub kind: SampleKind,
+    pub event: u32,
 }
 
 impl fmt::Display for AggregatedSample {
@@ -249,6 +373,7 @@ pub type AggregatedProfile = Vec<AggregatedSample>;
 mod tests {
     use crate::bpf::profiler_bindings::native_stack_t;
     use crate::bpf::profiler_bindings::sample_t;
+    use crate::bpf::profiler_bindings::PYTHON_FRAME_ENTRY;
     use crate::profile::SymbolizedFrame;
 
     use super::*;
@@ -264,14 +389,19 @@ mod tests {
             pid: 234,
             tid: 987,
             collected_at: 0xDEADBEEF,
+            value: 1,
+            kind: 0,
+            event: 0,
             stack: native_stack_t {
                 ulen: 2,
                 klen: 1,
-                addresses: [0; 254],
+                plen: 0,
+                _padding: 0,
+                addresses: [0; MAX_STACK_ADDRESSES as usize],
             },
         };
         assert_eq!(
-            RawSample::from_bytes(&unsafe { plain::as_bytes(&c_sample) }[..30]),
+            RawSample::from_bytes(&unsafe { plain::as_bytes(&c_sample) }[..54]),
             Err(RawSampleParsingError::StackTooSmall)
         );
     }
@@ -282,10 +412,15 @@ mod tests {
             pid: 234,
             tid: 987,
             collected_at: 0xDEADBEEF,
+            value: 1,
+            kind: 0,
+            event: 0,
             stack: native_stack_t {
                 ulen: 2,
                 klen: 1,
-                addresses: [0; 254],
+                plen: 0,
+                _padding: 0,
+                addresses: [0; MAX_STACK_ADDRESSES as usize],
             },
         };
         let bytes = unsafe { plain::as_bytes(&c_sample) };
@@ -304,10 +439,15 @@ mod tests {
             pid: 234,
             tid: 987,
             collected_at: 0xDEADBEEF,
+            value: 1,
+            kind: 0,
+            event: 2,
             stack: native_stack_t {
                 ulen: 2,
                 klen: 1,
-                addresses: [0; 254],
+                plen: 0,
+                _padding: 0,
+                addresses: [0; MAX_STACK_ADDRESSES as usize],
             },
         };
 
@@ -323,12 +463,149 @@ mod tests {
                 pid: 234,
                 tid: 987,
                 collected_at: 0xDEADBEEF,
+                value: 1,
+                kind: SampleKind::OnCpu,
+                event: 2,
                 ustack: vec![0xFFFBBBDDD, 0x113355770],
-                kstack: vec![0xBBBAAADDD]
+                kstack: vec![0xBBBAAADDD],
+                python_stack: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn test_off_cpu_sample_parsing() {
+        let mut c_sample = sample_t {
+            pid: 234,
+            tid: 987,
+            collected_at: 0xDEADBEEF,
+            value: 1_500_000,
+            kind: sample_kind_SAMPLE_KIND_OFF_CPU,
+            event: 0,
+            stack: native_stack_t {
+                ulen: 1,
+                klen: 0,
+                plen: 0,
+                _padding: 0,
+                addresses: [0; MAX_STACK_ADDRESSES as usize],
+            },
+        };
+        c_sample.stack.addresses[0] = 0xFFFBBBDDD;
+
+        assert_eq!(
+            RawSample::from_bytes(unsafe { plain::as_bytes(&c_sample) }),
+            Ok(RawSample {
+                pid: 234,
+                tid: 987,
+                collected_at: 0xDEADBEEF,
+                value: 1_500_000,
+                kind: SampleKind::OffCpu,
+                event: 0,
+                ustack: vec![0xFFFBBBDDD],
+                kstack: vec![],
+                python_stack: vec![],
             })
         );
     }
 
+    #[test]
+    fn test_python_sample_parsing() {
+        let mut c_sample = sample_t {
+            pid: 234,
+            tid: 987,
+            collected_at: 0xDEADBEEF,
+            value: 1,
+            kind: 0,
+            event: 0,
+            stack: native_stack_t {
+                ulen: 1,
+                klen: 1,
+                plen: 2,
+                _padding: 0,
+                addresses: [0; MAX_STACK_ADDRESSES as usize],
+            },
+        };
+        c_sample.stack.addresses[0] = 0xFFFBBBDDD;
+        c_sample.stack.addresses[1] = 0x7F0000001000;
+        c_sample.stack.addresses[2] = 0x10;
+        c_sample.stack.addresses[3] = 0x7F0000002000;
+        c_sample.stack.addresses[4] = PYTHON_FRAME_ENTRY | 0x4;
+        c_sample.stack.addresses[5] = 0xBBBAAADDD;
+
+        assert_eq!(
+            RawSample::from_bytes(unsafe { plain::as_bytes(&c_sample) }),
+            Ok(RawSample {
+                pid: 234,
+                tid: 987,
+                collected_at: 0xDEADBEEF,
+                value: 1,
+                kind: SampleKind::OnCpu,
+                event: 0,
+                ustack: vec![0xFFFBBBDDD],
+                kstack: vec![0xBBBAAADDD],
+                python_stack: vec![
+                    RawPythonFrame {
+                        code_address: 0x7F0000001000,
+                        instruction_offset: 0x10,
+                        is_entry: false,
+                    },
+                    RawPythonFrame {
+                        code_address: 0x7F0000002000,
+                        instruction_offset: 0x4,
+                        is_entry: true,
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_merge_python_stack() {
+        let frame = |name: &str| Frame {
+            virtual_address: 0x0,
+            file_offset: None,
+            symbolization_result: Some(Ok(SymbolizedFrame::new(
+                name.to_string(),
+                false,
+                None,
+                None,
+            ))),
+        };
+        let native_stack = vec![
+            (frame("native_leaf"), false),
+            (frame("eval_1"), true),
+            (frame("native_call"), false),
+            (frame("eval_2"), true),
+            (frame("eval_3"), true),
+            (frame("main"), false),
+        ];
+        let python_stack = vec![
+            (frame("py_1"), false),
+            (frame("py_2"), true),
+            (frame("py_3"), true),
+            (frame("py_4"), true),
+            (frame("py_5"), true),
+        ];
+
+        let merged = merge_python_stack(native_stack, python_stack)
+            .iter()
+            .map(|frame| frame.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            merged,
+            vec![
+                "native_leaf",
+                "py_1",
+                "py_2",
+                "native_call",
+                "py_3",
+                "py_4",
+                "main",
+                "py_5"
+            ]
+        );
+    }
+
     #[test]
     fn display_raw_aggregated_sample() {
         // User stack but no kernel stack
@@ -337,8 +614,12 @@ mod tests {
                 pid: 1234,
                 tid: 1235,
                 collected_at: 1748865070,
+                value: 1,
+                kind: SampleKind::OnCpu,
+                event: 0,
                 ustack: vec![0xffff, 0xdeadbeef],
                 kstack: vec![],
+                python_stack: vec![],
             },
             count: 1,
         };
@@ -350,8 +631,12 @@ mod tests {
                 pid: 1234,
                 tid: 1235,
                 collected_at: 1748865170,
+                value: 1,
+                kind: SampleKind::OnCpu,
+                event: 0,
                 ustack: vec![],
                 kstack: vec![],
+                python_stack: vec![],
             },
             count: 1,
         };
@@ -393,6 +678,8 @@ mod tests {
             ustack: ustack_data,
             kstack: kstack_data.clone(),
             count: 128,
+            kind: SampleKind::OnCpu,
+            event: 0,
         };
         insta::assert_yaml_snapshot!(format!("{}", sample), @r#""SymbolizedAggregatedSample { pid: 1234567, tid: 1234568, ustack: \"[  0: ufunc3,  1: ufunc2,  2: ufunc1]\", kstack: \"[  0: kfunc2,  1: kfunc1]\", count: 128 }""#);
 
@@ -404,6 +691,8 @@ mod tests {
             ustack: ustack_data,
             kstack: kstack_data.clone(),
             count: 1001,
+            kind: SampleKind::OnCpu,
+            event: 0,
         };
         insta::assert_yaml_snapshot!(format!("{}", sample), @r#""SymbolizedAggregatedSample { pid: 98765, tid: 98766, ustack: \"[NONE]\", kstack: \"[  0: kfunc2,  1: kfunc1]\", count: 1001 }""#);
     }
@@ -6,6 +6,7 @@ use parking_lot::RwLock;
 use std::collections::hash_map::Entry;
 use std::collections::hash_map::OccupiedEntry;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::env::temp_dir;
 use std::fs;
 use std::fs::File;
@@ -14,7 +15,7 @@ use std::iter;
 use std::mem::size_of;
 use std::mem::ManuallyDrop;
 use std::mem::MaybeUninit;
-use std::os::fd::{AsFd, AsRawFd};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
 use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
@@ -22,7 +23,7 @@ use std::thread;
 use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use crossbeam_channel::{bounded, select, tick, unbounded, Receiver, Sender};
+use crossbeam_channel::{bounded, never, select, tick, unbounded, Receiver, Sender};
 use itertools::Itertools;
 use libbpf_rs::num_possible_cpus;
 use libbpf_rs::skel::SkelBuilder;
@@ -30,7 +31,7 @@ use libbpf_rs::skel::{OpenSkel, Skel};
 use libbpf_rs::MapCore;
 use libbpf_rs::MapHandle;
 use libbpf_rs::MapType;
-use libbpf_rs::{Link, MapFlags, PerfBufferBuilder};
+use libbpf_rs::{Link, MapFlags, PerfBufferBuilder, PerfEventOpts};
 use memmap2::MmapOptions;
 use procfs;
 use tracing::{debug, error, info, span, warn, Level};
@@ -42,23 +43,34 @@ use crate::bpf::tracers_bindings::*;
 use crate::bpf::tracers_skel::OpenTracersSkel;
 use crate::bpf::tracers_skel::{TracersSkel, TracersSkelBuilder};
 use crate::collector::*;
+use crate::cpu_trigger::{CpuTrigger, CpuTriggerConfig, CpuTriggerTransition};
 use crate::debug_info::DebugInfoBackendNull;
 use crate::debug_info::DebugInfoManager;
+use crate::jit::JitSymbols;
 use crate::kernel::get_all_kernel_modules;
 use crate::kernel::KERNEL_PID;
-use crate::perf_events::setup_perf_event;
+use crate::overhead::{OverheadConfig, OverheadController};
+use crate::perf_events::{setup_perf_event, EventConfig, PerfEvent, Sampling};
 use crate::process::{
     ExecutableMapping, ExecutableMappingType, ExecutableMappings, ObjectFileInfo, Pid, ProcessInfo,
     ProcessStatus,
 };
+use crate::process_selector::{
+    is_cgroup_v2, selected_cgroup_ids, ProcessSelector, SelectionCache, CGROUP_ROOT,
+};
 use crate::profile::*;
-use crate::unwind_info::manager::UnwindInfoManager;
+use crate::python::PythonProcess;
+use crate::unwind_info::manager::{FetchUnwindInfoError, UnwindInfoManager};
+use crate::unwind_info::persist::WriterError;
 use crate::unwind_info::types::CompactUnwindRow;
-use crate::util::executable_path;
+use crate::unwind_info::UnwindInfoError;
 use crate::util::page_size;
 use crate::util::roundup_page;
 use crate::util::Architecture;
-use crate::util::{architecture, get_online_cpus, summarize_address_range};
+use crate::util::{
+    architecture, arm64_address_mask, get_online_cpus, process_cpu_time, summarize_address_range,
+};
+use crate::util::{open_mapped_file, MappedFile};
 use lightswitch_metadata::metadata_provider::{
     GlobalMetadataProvider, ThreadSafeGlobalMetadataProvider,
 };
@@ -70,6 +82,28 @@ const MAX_UNWIND_INFO_SIZE: usize = 7_000_000;
 pub enum TracerEvent {
     ProcessExit(Pid),
     Munmap(Pid, u64),
+    /// A followed process forked this new process.
+    Fork(Pid),
+    /// A followed process executed a new program.
+    Exec(Pid),
+}
+
+/// Changes to a running profiler, sent through [`Profiler::control_sender`].
+pub enum ProfilerControl {
+    /// Stops taking samples. The BPF programs and the process and unwind information
+    /// are kept, so profiling can be resumed cheaply.
+    Pause,
+    /// Starts taking samples again.
+    Resume,
+    /// Replaces which processes are profiled. Every process is profiled if both are
+    /// empty.
+    SetFilters {
+        pids: Vec<Pid>,
+        selectors: Vec<ProcessSelector>,
+    },
+    /// Sends the samples taken so far to the collector, rather than waiting for the
+    /// session to end.
+    Collect,
 }
 
 pub struct KnownExecutableInfo {
@@ -114,6 +148,7 @@ pub struct Profiler {
     cache_dir: PathBuf,
     // Prevent the links from being removed.
     _links: Vec<Link>,
+    _off_cpu_links: Vec<Link>,
     native_unwinder_open_object: ManuallyDrop<Box<MaybeUninit<OpenObject>>>,
     native_unwinder: ManuallyDrop<ProfilerSkel<'static>>,
     tracers_open_object: ManuallyDrop<Box<MaybeUninit<OpenObject>>>,
@@ -128,12 +163,38 @@ pub struct Profiler {
     tracers_chan_receive: Arc<Receiver<TracerEvent>>,
     /// Profiler stop channel. Used to receive signals from users to stop profiling.
     stop_chan_receive: Receiver<()>,
+    control_chan_send: Sender<ProfilerControl>,
+    control_chan_receive: Receiver<ProfilerControl>,
+    /// Whether samples are not being taken.
+    paused: bool,
+    /// Starts and stops taking samples depending on the CPU usage of a process or cgroup.
+    cpu_trigger: Option<CpuTrigger>,
+    /// Why samples are being taken, if it was because of `cpu_trigger`.
+    trigger_reason: Option<String>,
+    /// Scales the sampling frequency to keep the profiler's overhead within a budget.
+    overhead_controller: Option<OverheadController>,
+    /// Keeps the run time stats of BPF programs enabled while it's open.
+    _bpf_stats: Option<OwnedFd>,
+    /// When the samples of the current session started being taken, or were last
+    /// resumed.
+    session_start: Instant,
+    /// For how long samples were taken in the current session before it was last paused.
+    sampled_before_pause: Duration,
     pub(crate) native_unwind_state: NativeUnwindState,
     /// Pids excluded from profiling.
     filter_pids: HashMap<Pid, bool>,
-    // Profile channel
-    profile_send: Arc<Sender<RawAggregatedProfile>>,
-    profile_receive: Arc<Receiver<RawAggregatedProfile>>,
+    /// Whether only some pids are profiled. Followed processes are removed from
+    /// `filter_pids` once they exit, so it might be empty.
+    filtering_pids: bool,
+    /// Processes selected for profiling, in addition to the ones in `filter_pids`.
+    selectors: Vec<ProcessSelector>,
+    selection_cache: SelectionCache,
+    /// Ids of the cgroups in the BPF cgroup filter, if processes are filtered by cgroup
+    /// in BPF.
+    cgroup_filter: Option<HashSet<u64>>,
+    // Profile channel, along with how each profile was taken.
+    profile_send: Arc<Sender<(RawAggregatedProfile, SessionInfo)>>,
+    profile_receive: Arc<Receiver<(RawAggregatedProfile, SessionInfo)>>,
     // A vector of raw samples received from bpf in the current profiling session
     raw_samples: Vec<RawSample>,
     // Raw samples channel. Used for receiving raw samples from the ringbuf/perfbuf poll thread
@@ -141,22 +202,27 @@ pub struct Profiler {
     raw_sample_receive: Arc<Receiver<RawSample>>,
     /// For how long to profile.
     duration: Duration,
-    /// Per-CPU sampling frequency in Hz.
-    sample_freq: u64,
+    /// Events used to take samples and how often. The index of each event is
+    /// stored in the samples it triggers.
+    event_configs: Vec<EventConfig>,
     /// Size of the perf buffer.
     perf_buffer_bytes: usize,
     /// For how long to profile until the aggregated in-kernel profiles are read.
     session_duration: Duration,
     /// Whether the profiler itself should be excluded from profiling.
     exclude_self: bool,
+    /// Whether the processes forked by the ones in `filter_pids` are profiled too.
+    follow_children: bool,
     /// Deals with debug information
-    debug_info_manager: Box<dyn DebugInfoManager>,
+    debug_info_manager: Arc<dyn DebugInfoManager + Send + Sync>,
     /// Maximum size of BPF unwind information maps. A higher value will result in
     /// evictions which might reduce the quality of the profiles and in more work
     /// for the profiler.
     max_native_unwind_info_size_mb: i32,
     unwind_info_manager: UnwindInfoManager,
     use_ring_buffers: bool,
+    /// Whether stacks are also collected when threads are scheduled back in after being blocked.
+    off_cpu: bool,
     aggregator: Aggregator,
     metadata_provider: ThreadSafeGlobalMetadataProvider,
     // Baseline for calculating raw_sample collection wall clock time
@@ -170,15 +236,31 @@ pub struct ProfilerConfig {
     pub bpf_logging: bool,
     pub duration: Duration,
     pub sample_freq: u64,
+    /// Events that trigger the samples. All of them are sampled in the same session.
+    pub events: Vec<PerfEvent>,
+    /// Sample every this many events rather than at `sample_freq`.
+    pub sample_period: Option<u64>,
     pub perf_buffer_bytes: usize,
     pub session_duration: Duration,
     pub mapsize_info: bool,
     pub mapsize_rate_limits: u32,
     pub exclude_self: bool,
-    pub debug_info_manager: Box<dyn DebugInfoManager>,
+    /// Also profile the descendants of the pids given to [`Profiler::profile_pids`].
+    pub follow_children: bool,
+    pub debug_info_manager: Arc<dyn DebugInfoManager + Send + Sync>,
     pub max_native_unwind_info_size_mb: i32,
     pub use_ring_buffers: bool,
     pub use_task_pt_regs_helper: bool,
+    pub off_cpu: bool,
+    /// Off-CPU periods shorter than this won't be reported.
+    pub off_cpu_threshold: Duration,
+    /// Don't take samples until [`ProfilerControl::Resume`] is sent.
+    pub start_paused: bool,
+    /// Only take samples while the CPU usage of a process or cgroup is over a threshold.
+    pub cpu_trigger: Option<CpuTriggerConfig>,
+    /// Lower the sampling frequency when the profiler uses more CPU than a budget. Only
+    /// applies to the events sampled by frequency.
+    pub overhead_budget: Option<OverheadConfig>,
 }
 
 impl Default for ProfilerConfig {
@@ -189,15 +271,23 @@ impl Default for ProfilerConfig {
             bpf_logging: false,
             duration: Duration::MAX,
             sample_freq: 19,
+            events: vec![PerfEvent::CpuClock],
+            sample_period: None,
             perf_buffer_bytes: 512 * 1024,
             session_duration: Duration::from_secs(5),
             mapsize_info: false,
             mapsize_rate_limits: 5000,
             exclude_self: false,
-            debug_info_manager: Box::new(DebugInfoBackendNull {}),
+            follow_children: false,
+            debug_info_manager: Arc::new(DebugInfoBackendNull {}),
             max_native_unwind_info_size_mb: i32::MAX,
             use_ring_buffers: true,
             use_task_pt_regs_helper: true,
+            off_cpu: false,
+            off_cpu_threshold: Duration::from_millis(1),
+            start_paused: false,
+            cpu_trigger: None,
+            overhead_budget: None,
         }
     }
 }
@@ -274,6 +364,8 @@ enum AddUnwindInformationError {
     NoUnwindInfoKnownNaughty,
     #[error("no unwind information: {0} for executable: {1}")]
     NoUnwindInfo(String, String),
+    #[error("no .eh_frame section for executable: {0}")]
+    NoEhFrameSection(String),
     #[error("unwind information contains no entries")]
     Empty,
     #[error("failed to write to BPF map that stores unwind information: {0}")]
@@ -343,6 +435,24 @@ impl Profiler {
             .lightswitch_config
             .use_ring_buffers
             .write(profiler_config.use_ring_buffers);
+        open_skel
+            .maps
+            .rodata_data
+            .lightswitch_config
+            .off_cpu_threshold_ns = profiler_config.off_cpu_threshold.as_nanos() as u64;
+        if architecture() == Architecture::Arm64 {
+            open_skel
+                .maps
+                .rodata_data
+                .lightswitch_config
+                .arm64_address_mask = arm64_address_mask();
+        }
+
+        if !profiler_config.off_cpu {
+            // Skip loading, and verifying, the off-CPU programs when they aren't used.
+            open_skel.progs.on_finish_task_switch.set_autoload(false);
+            open_skel.progs.dwarf_unwind_off_cpu.set_autoload(false);
+        }
 
         if profiler_config.use_ring_buffers {
             // Set sample collecting ringbuf size based sampling frequency
@@ -467,6 +577,7 @@ impl Profiler {
         info!("native unwinder BPF program loaded");
         let native_unwinder_maps = &native_unwinder.maps;
         let exec_mappings_fd = native_unwinder_maps.exec_mappings.as_fd();
+        let off_cpu_start_fd = native_unwinder_maps.off_cpu_start.as_fd();
 
         // BPF map sizes can be overriden, this is a debugging option to print the actual size once
         // the maps are created and the BPF program is loaded.
@@ -486,6 +597,18 @@ impl Profiler {
             .exec_mappings
             .reuse_fd(exec_mappings_fd)
             .expect("reuse exec_mappings");
+        open_tracers
+            .maps
+            .off_cpu_start
+            .reuse_fd(off_cpu_start_fd)
+            .expect("reuse off_cpu_start");
+        if !profiler_config.off_cpu {
+            open_tracers.progs.tracer_sched_switch.set_autoload(false);
+        }
+        if !profiler_config.follow_children {
+            open_tracers.progs.tracer_process_fork.set_autoload(false);
+            open_tracers.progs.tracer_process_exec.set_autoload(false);
+        }
         open_tracers
             .maps
             .rodata_data
@@ -528,12 +651,38 @@ impl Profiler {
         let raw_sample_sender = Arc::new(sender);
         let raw_sample_receiver = Arc::new(receiver);
 
+        let (control_chan_send, control_chan_receive) = unbounded();
+
         let walltime_at_system_boot =
             procfs::boot_time().unwrap().timestamp_nanos_opt().unwrap() as u64;
 
+        let sampling = match profiler_config.sample_period {
+            Some(period) => Sampling::Period(period),
+            None => Sampling::Frequency(profiler_config.sample_freq),
+        };
+        let mut event_configs: Vec<EventConfig> = Vec::new();
+        for event in profiler_config.events {
+            let event_config =
+                EventConfig { event, sampling }.or_cpu_clock_fallback(profiler_config.sample_freq);
+            // Several unsupported events might have fallen back to the CPU clock.
+            if !event_configs
+                .iter()
+                .any(|other| other.event == event_config.event)
+            {
+                event_configs.push(event_config);
+            }
+        }
+        if event_configs.is_empty() {
+            event_configs.push(EventConfig {
+                event: PerfEvent::CpuClock,
+                sampling: Sampling::Frequency(profiler_config.sample_freq),
+            });
+        }
+
         Profiler {
             cache_dir,
             _links: Vec::new(),
+            _off_cpu_links: Vec::new(),
             native_unwinder_open_object,
             native_unwinder,
             tracers_open_object,
@@ -545,36 +694,347 @@ impl Profiler {
             tracers_chan_send,
             tracers_chan_receive,
             stop_chan_receive: stop_signal_receive,
+            control_chan_send,
+            control_chan_receive,
+            paused: profiler_config.start_paused || profiler_config.cpu_trigger.is_some(),
+            cpu_trigger: profiler_config.cpu_trigger.map(CpuTrigger::new),
+            trigger_reason: None,
+            overhead_controller: profiler_config.overhead_budget.map(OverheadController::new),
+            _bpf_stats: None,
+            session_start: Instant::now(),
+            sampled_before_pause: Duration::ZERO,
             native_unwind_state,
             filter_pids: HashMap::new(),
+            filtering_pids: false,
+            selectors: Vec::new(),
+            selection_cache: SelectionCache::default(),
+            cgroup_filter: None,
             profile_send,
             profile_receive,
             raw_samples: Vec::new(),
             raw_sample_send: raw_sample_sender,
             raw_sample_receive: raw_sample_receiver,
             duration: profiler_config.duration,
-            sample_freq: profiler_config.sample_freq,
+            event_configs,
             perf_buffer_bytes: profiler_config.perf_buffer_bytes,
             session_duration: profiler_config.session_duration,
             exclude_self: profiler_config.exclude_self,
+            follow_children: profiler_config.follow_children,
             debug_info_manager: profiler_config.debug_info_manager,
             max_native_unwind_info_size_mb: profiler_config.max_native_unwind_info_size_mb,
             unwind_info_manager: UnwindInfoManager::new(&unwind_cache_dir, None),
             use_ring_buffers: profiler_config.use_ring_buffers,
+            off_cpu: profiler_config.off_cpu,
             aggregator: Aggregator::default(),
             metadata_provider,
             walltime_at_system_boot,
         }
     }
 
+    /// The events samples are taken on, in the same order as the event indices in
+    /// the samples. They might differ from the configured ones if some aren't supported
+    /// by the hardware.
+    pub fn event_configs(&self) -> Vec<EventConfig> {
+        self.event_configs.clone()
+    }
+
+    /// Sends changes to the profiler while it runs.
+    pub fn control_sender(&self) -> Sender<ProfilerControl> {
+        self.control_chan_send.clone()
+    }
+
     pub fn profile_pids(&mut self, pids: Vec<Pid>) {
         for pid in pids {
+            self.filtering_pids = true;
             self.filter_pids.insert(pid, true);
+            if self.follow_children {
+                self.follow_process(pid);
+            }
+        }
+    }
+
+    /// Profiles the processes a process forks, and their descendants, as they are forked.
+    fn follow_process(&self, pid: Pid) {
+        if let Err(e) =
+            self.tracers
+                .maps
+                .followed_processes
+                .update(&pid.to_ne_bytes(), &[1], MapFlags::ANY)
+        {
+            error!("failed to follow the children of {} due to {:?}", pid, e);
+        }
+    }
+
+    /// Also profile the processes selected by any of the selectors. Processes that are
+    /// selected later on, such as when they join a selected cgroup, will be profiled
+    /// as well.
+    pub fn profile_selectors(&mut self, selectors: Vec<ProcessSelector>) {
+        self.selectors.extend(selectors);
+    }
+
+    /// Whether samples can be discarded in BPF for the tasks that aren't in any of the
+    /// selected cgroups, rather than once they reach userspace.
+    fn can_filter_cgroups_in_bpf(&self) -> bool {
+        !self.filtering_pids
+            && !self.selectors.is_empty()
+            && self.selectors.iter().all(ProcessSelector::is_cgroup_based)
+    }
+
+    fn setup_cgroup_filter(&mut self) {
+        if !self.can_filter_cgroups_in_bpf() {
+            return;
+        }
+        if !is_cgroup_v2(Path::new(CGROUP_ROOT)) {
+            warn!(
+                "cgroup v2 isn't mounted in {CGROUP_ROOT}, processes will be filtered in userspace"
+            );
+            return;
+        }
+
+        self.cgroup_filter = Some(HashSet::new());
+        self.update_cgroup_filter();
+        if let Some(cgroup_filter) = &self.cgroup_filter {
+            debug!("filtering {} cgroups in BPF", cgroup_filter.len());
+        }
+    }
+
+    /// Updates the BPF cgroup filter with the cgroups that are currently selected, as
+    /// cgroups come and go. The filter is only enabled while it has all of them, and
+    /// the ones that couldn't be added are retried on the next update.
+    fn update_cgroup_filter(&mut self) {
+        let Some(mut filtered_ids) = self.cgroup_filter.take() else {
+            return;
+        };
+
+        let ids = match selected_cgroup_ids(&self.selectors, Path::new(CGROUP_ROOT)) {
+            Ok(ids) => ids,
+            Err(e) => {
+                warn!("failed to read the cgroup hierarchy due to {:?}", e);
+                self.cgroup_filter = Some(filtered_ids);
+                return;
+            }
+        };
+
+        let cgroup_filter = &self.native_unwinder.maps.cgroup_filter;
+        for id in filtered_ids.difference(&ids) {
+            let _ = cgroup_filter.delete(&id.to_ne_bytes());
+        }
+        filtered_ids.retain(|id| ids.contains(id));
+        let mut complete = true;
+        for id in &ids {
+            if filtered_ids.contains(id) {
+                continue;
+            }
+            match cgroup_filter.update(&id.to_ne_bytes(), &[1], MapFlags::ANY) {
+                Ok(()) => {
+                    filtered_ids.insert(*id);
+                }
+                Err(e) => {
+                    warn!(
+                        "failed to add cgroup {} to the BPF filter due to {:?}",
+                        id, e
+                    );
+                    complete = false;
+                }
+            }
+        }
+        // Processes are still filtered in userspace, so they aren't filtered in BPF
+        // while some of the cgroups are missing rather than missing their samples.
+        self.set_bpf_cgroup_filter_enabled(complete);
+        self.cgroup_filter = Some(filtered_ids);
+    }
+
+    fn handle_control(&mut self, control: ProfilerControl) {
+        match control {
+            ProfilerControl::Pause if !self.paused => {
+                info!("pausing profiling");
+                self.teardown_perf_events();
+                self.sampled_before_pause += self.session_start.elapsed();
+                self.paused = true;
+            }
+            ProfilerControl::Resume if self.paused => {
+                info!("resuming profiling");
+                self.setup_perf_events();
+                self.paused = false;
+                self.session_start = Instant::now();
+            }
+            ProfilerControl::Pause | ProfilerControl::Resume => {}
+            ProfilerControl::SetFilters { pids, selectors } => {
+                self.set_filters(pids, selectors);
+            }
+            ProfilerControl::Collect => {
+                let profile = self.collect_profile();
+                self.send_profile(profile);
+            }
+        }
+    }
+
+    /// Replaces which processes are profiled.
+    fn set_filters(&mut self, pids: Vec<Pid>, selectors: Vec<ProcessSelector>) {
+        self.filtering_pids = !pids.is_empty();
+        self.filter_pids = pids.into_iter().map(|pid| (pid, true)).collect();
+        self.selectors = selectors;
+        self.selection_cache.clear();
+        if self.follow_children {
+            let followed_processes = &self.tracers.maps.followed_processes;
+            let followed_pids: Vec<Vec<u8>> = followed_processes.keys().collect();
+            for pid in followed_pids {
+                let _ = followed_processes.delete(&pid);
+            }
+            for pid in self.filter_pids.keys() {
+                self.follow_process(*pid);
+            }
+        }
+
+        if self.cgroup_filter.take().is_some() {
+            self.set_bpf_cgroup_filter_enabled(false);
+            let cgroup_filter = &self.native_unwinder.maps.cgroup_filter;
+            let ids: Vec<Vec<u8>> = cgroup_filter.keys().collect();
+            for id in ids {
+                let _ = cgroup_filter.delete(&id);
+            }
+        }
+        self.setup_cgroup_filter();
+
+        // Processes that aren't selected anymore keep their process and unwind
+        // information, as they might be selected again, but their samples are
+        // dropped when they are received.
+    }
+
+    fn set_bpf_cgroup_filter_enabled(&self, enabled: bool) {
+        let zero = 0_u32;
+        if let Err(e) = self.native_unwinder.maps.cgroup_filter_enabled.update(
+            &zero.to_ne_bytes(),
+            &[enabled.into()],
+            MapFlags::ANY,
+        ) {
+            error!("failed to set cgroup_filter_enabled due to {:?}", e);
         }
     }
 
     pub fn send_profile(&mut self, profile: RawAggregatedProfile) {
-        self.profile_send.send(profile).expect("handle send");
+        let now = Instant::now();
+        let mut duration = self.sampled_before_pause;
+        if !self.paused {
+            duration += now - self.session_start;
+        }
+        let session_info = SessionInfo {
+            duration,
+            event_configs: self.event_configs.clone(),
+            trigger_reason: self.trigger_reason.clone(),
+        };
+        self.session_start = now;
+        self.sampled_before_pause = Duration::ZERO;
+        self.profile_send
+            .send((profile, session_info))
+            .expect("handle send");
+    }
+
+    /// Starts taking samples when the CPU usage crosses the trigger's threshold, and
+    /// stops once it has been below it for the cooldown.
+    fn check_cpu_trigger(&mut self) {
+        let Some(transition) = self.cpu_trigger.as_mut().and_then(CpuTrigger::check) else {
+            return;
+        };
+        match transition {
+            CpuTriggerTransition::Start(reason) => {
+                info!("starting profiling: {}", reason);
+                self.trigger_reason = Some(reason);
+                if self.paused {
+                    self.setup_perf_events();
+                    self.paused = false;
+                    self.session_start = Instant::now();
+                }
+            }
+            CpuTriggerTransition::Stop => {
+                info!("CPU usage is back below the threshold, stopping profiling");
+                if !self.paused {
+                    self.teardown_perf_events();
+                    self.sampled_before_pause += self.session_start.elapsed();
+                    self.paused = true;
+                }
+                let profile = self.collect_profile();
+                self.send_profile(profile);
+                self.trigger_reason = None;
+            }
+        }
+    }
+
+    /// Makes the kernel keep track of how long BPF programs run for, which is counted
+    /// towards the overhead budget.
+    fn enable_bpf_stats(&mut self) {
+        let fd = unsafe { libbpf_sys::bpf_enable_stats(libbpf_sys::BPF_STATS_RUN_TIME) };
+        if fd < 0 {
+            warn!(
+                "could not enable BPF stats due to {:?}, only the profiler's CPU time counts towards the overhead budget",
+                std::io::Error::last_os_error()
+            );
+            return;
+        }
+        // SAFETY: the file descriptor was just opened and it's not used anywhere else.
+        self._bpf_stats = Some(unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+
+    /// Time spent running the profiler's BPF programs, if BPF stats are enabled.
+    fn bpf_run_time(&self) -> Duration {
+        let mut run_time_ns = 0;
+        let progs = self
+            .native_unwinder
+            .object()
+            .progs()
+            .chain(self.tracers.object().progs());
+        for prog in progs {
+            let mut info = libbpf_sys::bpf_prog_info::default();
+            let mut info_len = size_of::<libbpf_sys::bpf_prog_info>() as u32;
+            let ret = unsafe {
+                libbpf_sys::bpf_prog_get_info_by_fd(
+                    prog.as_fd().as_raw_fd(),
+                    &mut info,
+                    &mut info_len,
+                )
+            };
+            if ret == 0 {
+                run_time_ns += info.run_time_ns;
+            }
+        }
+        Duration::from_nanos(run_time_ns)
+    }
+
+    /// Scales the sampling frequency if the CPU used by the profiler, including its BPF
+    /// programs, is over or well under the overhead budget.
+    fn check_overhead(&mut self) {
+        let cpu_time = match process_cpu_time(std::process::id() as Pid) {
+            Ok(cpu_time) => cpu_time + self.bpf_run_time(),
+            Err(e) => {
+                warn!("could not read the profiler's CPU time due to {:?}", e);
+                return;
+            }
+        };
+        let online_cpus = get_online_cpus().map_or(1, |cpus| cpus.len());
+        let Some(sample_freq) = self
+            .overhead_controller
+            .as_mut()
+            .and_then(|controller| controller.update(Instant::now(), cpu_time, online_cpus))
+        else {
+            return;
+        };
+
+        info!(
+            "sampling at {} Hz to stay within the overhead budget",
+            sample_freq
+        );
+        // The samples taken so far are sent along with the frequency they were taken at.
+        let profile = self.collect_profile();
+        self.send_profile(profile);
+        for event_config in &mut self.event_configs {
+            if let Sampling::Frequency(_) = event_config.sampling {
+                event_config.sampling = Sampling::Frequency(sample_freq);
+            }
+        }
+        if !self.paused {
+            self.teardown_perf_events();
+            self.setup_perf_events();
+        }
     }
 
     /// Starts a thread that polls the given ring or perf buffer, depending on the
@@ -685,6 +1145,8 @@ impl Profiler {
                                 .collect(),
                         ),
                         last_used: Instant::now(),
+                        python: None,
+                        jit_symbols: None,
                     },
                 );
 
@@ -696,6 +1158,7 @@ impl Profiler {
                             .expect("should never happen"),
                         ObjectFileInfo {
                             path: PathBuf::from(kernel_code_range.name),
+                            file: None,
                             elf_load_segments: vec![],
                             is_dyn: false,
                             references: 1,
@@ -712,9 +1175,25 @@ impl Profiler {
         }
     }
 
-    pub fn run(mut self, collector: ThreadSafeCollector) -> Duration {
-        self.setup_perf_events();
+    pub fn run(self, collector: ThreadSafeCollector) -> Duration {
+        self.run_with_ready_callback(collector, || {})
+    }
+
+    /// Like [`Profiler::run`], calling `on_ready` once the perf events are set up and
+    /// the tracers attached, so everything processes do from then on is seen.
+    pub fn run_with_ready_callback(
+        mut self,
+        collector: ThreadSafeCollector,
+        on_ready: impl FnOnce(),
+    ) -> Duration {
+        self.setup_cgroup_filter();
+        if !self.paused {
+            self.setup_perf_events();
+        }
         self.set_bpf_map_info();
+        if self.off_cpu {
+            self.setup_off_cpu_probe();
+        }
         self.add_kernel_modules();
 
         self.tracers.attach().expect("attach tracers");
@@ -768,11 +1247,10 @@ impl Profiler {
 
         thread::spawn(move || loop {
             match profile_receive.recv() {
-                Ok(profile) => {
-                    collector
-                        .lock()
-                        .unwrap()
-                        .collect(profile, &procs.read(), &object_files.read());
+                Ok((profile, session_info)) => {
+                    let mut collector = collector.lock().unwrap();
+                    collector.set_session_info(&session_info);
+                    collector.collect(profile, &procs.read(), &object_files.read());
                 }
                 Err(_e) => {
                     // println!("failed to receive event {:?}", e);
@@ -780,9 +1258,27 @@ impl Profiler {
             }
         });
 
+        on_ready();
+
         let start = Instant::now();
         let total_duration_tick = tick(self.duration);
         let session_tick = tick(self.session_duration);
+        let cpu_trigger_tick = match &self.cpu_trigger {
+            Some(cpu_trigger) => tick(cpu_trigger.interval()),
+            None => never(),
+        };
+        let overhead_tick = match self
+            .overhead_controller
+            .as_ref()
+            .map(OverheadController::interval)
+        {
+            Some(interval) => {
+                self.enable_bpf_stats();
+                tick(interval)
+            }
+            None => never(),
+        };
+        self.session_start = Instant::now();
 
         loop {
             select! {
@@ -801,11 +1297,31 @@ impl Profiler {
                 recv(session_tick) -> _ => {
                     debug!("collecting profiles on schedule");
                     let profile = self.collect_profile();
-                    self.send_profile(profile);
+                    // Don't send empty profiles while waiting for the CPU trigger.
+                    if !(self.paused && self.cpu_trigger.is_some()) {
+                        self.send_profile(profile);
+                    }
+                    self.update_cgroup_filter();
+                    self.selection_cache.expire(Instant::now());
+                },
+                recv(cpu_trigger_tick) -> _ => {
+                    self.check_cpu_trigger();
+                },
+                recv(overhead_tick) -> _ => {
+                    self.check_overhead();
+                },
+                recv(self.control_chan_receive) -> control => {
+                    if let Ok(control) = control {
+                        self.handle_control(control);
+                    }
                 },
                 recv(self.raw_sample_receive) -> raw_sample => {
                     if let Ok(raw_sample) = raw_sample {
-                        self.raw_samples.push(raw_sample);
+                        // Processes that were unselected might still be sampled.
+                        let pid = raw_sample.pid;
+                        if pid == KERNEL_PID || self.should_profile(pid) {
+                            self.raw_samples.push(raw_sample);
+                        }
                     }
                     else {
                         warn!("Failed to receive raw sample, err={:?}", raw_sample.err());
@@ -818,6 +1334,23 @@ impl Profiler {
                         },
                         Ok(TracerEvent::ProcessExit(pid)) => {
                                 self.handle_process_exit(pid, false);
+                                self.selection_cache.remove(pid);
+                                if self.follow_children {
+                                    self.filter_pids.remove(&pid);
+                                }
+                        },
+                        Ok(TracerEvent::Fork(pid)) => {
+                                debug!("following child process {}", pid);
+                                self.filter_pids.insert(pid, true);
+                                self.event_new_proc(pid);
+                        },
+                        Ok(TracerEvent::Exec(pid)) => {
+                                // The libraries of the new program haven't been loaded yet, the
+                                // process will be added again once it's sampled.
+                                debug!("process {} executed a new program", pid);
+                                self.handle_process_exit(pid, false);
+                                // Its name changed, which might select it.
+                                self.selection_cache.remove(pid);
                         },
                         Err(_) => {}
                     }
@@ -860,6 +1393,13 @@ impl Profiler {
                     debug!("could not remove bpf process due to {:?}", e);
                 }
 
+                if proc_info.python.is_some() {
+                    let err = Self::delete_bpf_python_process(&self.native_unwinder, pid);
+                    if let Err(e) = err {
+                        debug!("could not remove bpf Python process due to {:?}", e);
+                    }
+                }
+
                 for mapping in &mut proc_info.mappings.0 {
                     let mut object_files = self.object_files.write();
                     if mapping.mark_as_deleted(&mut object_files) {
@@ -1084,6 +1624,14 @@ impl Profiler {
         self.procs.read().get(&pid).is_some()
     }
 
+    /// Whether the process is known and hasn't exited, or executed another program, since.
+    fn process_is_running(&self, pid: Pid) -> bool {
+        self.procs
+            .read()
+            .get(&pid)
+            .is_some_and(|proc_info| proc_info.status == ProcessStatus::Running)
+    }
+
     fn add_bpf_unwind_info(
         inner: &MapHandle,
         unwind_info: &[CompactUnwindRow],
@@ -1264,6 +1812,22 @@ impl Profiler {
             .delete(unsafe { plain::as_bytes(&key) }) // improve error handling
     }
 
+    fn add_bpf_python_process(
+        bpf: &ProfilerSkel,
+        pid: Pid,
+        python: &PythonProcess,
+    ) -> Result<(), libbpf_rs::Error> {
+        bpf.maps.python_processes.update(
+            &pid.to_ne_bytes(),
+            unsafe { plain::as_bytes(&python.bpf_process()) },
+            MapFlags::ANY,
+        )
+    }
+
+    fn delete_bpf_python_process(bpf: &ProfilerSkel, pid: Pid) -> Result<(), libbpf_rs::Error> {
+        bpf.maps.python_processes.delete(&pid.to_ne_bytes())
+    }
+
     fn delete_bpf_unwind_info_map(
         bpf: &mut ProfilerSkel,
         executable_id: u64,
@@ -1373,8 +1937,8 @@ impl Profiler {
             .0
             .iter()
         {
-            // There is no unwind information for anonymous (JIT) mappings, so let's skip them.
-            // In the future we could either try to synthetise the unwind information.
+            // There is no unwind information for anonymous (JIT) mappings, so they are
+            // unwound using frame pointers.
             if mapping.kind == ExecutableMappingType::Anonymous {
                 bpf_mappings.push(mapping_t {
                     load_address: 0,
@@ -1421,6 +1985,16 @@ impl Profiler {
                 if e == AddUnwindInformationError::NoUnwindInfoKnownNaughty {
                     return;
                 }
+                // Objects without unwind tables, such as some hand written assembly or
+                // binaries built with `-fno-asynchronous-unwind-tables`, are walked using
+                // frame pointers.
+                if let AddUnwindInformationError::NoEhFrameSection(path) = e {
+                    debug!("no .eh_frame for {}, unwinding with frame pointers", path);
+                    if let Some(bpf_mapping) = bpf_mappings.last_mut() {
+                        bpf_mapping.type_ = MAPPING_TYPE_FRAME_POINTER;
+                    }
+                    continue;
+                }
                 warn!(
                     "error adding unwind information for executable 0x{} due to {:?}",
                     mapping.executable_id, e
@@ -1441,6 +2015,17 @@ impl Profiler {
             errored = true;
             debug!("failed to add BPF process due to {:?}", e);
         }
+        let python = self
+            .procs
+            .read()
+            .get(&pid)
+            .and_then(|proc_info| proc_info.python.clone());
+        if let Some(python) = python {
+            if let Err(e) = Self::add_bpf_python_process(&self.native_unwinder, pid, &python) {
+                errored = true;
+                debug!("failed to add BPF Python process due to {:?}", e);
+            }
+        }
 
         if errored {
             // Remove partially written data.
@@ -1469,6 +2054,7 @@ impl Profiler {
         let object_files = self.object_files.read();
         let executable_info = object_files.get(&executable_id).unwrap();
         let executable_path = executable_info.path.clone();
+        let open_path = executable_info.open_path();
         let needs_synthesis = executable_info.is_vdso && architecture() == Architecture::Arm64;
         let runtime = executable_info.runtime.clone();
         std::mem::drop(object_files);
@@ -1504,13 +2090,13 @@ impl Profiler {
                 )
                 .entered();
                 self.unwind_info_manager.fetch_unwind_info(
-                    &executable_path,
+                    &open_path,
                     executable_id,
                     Some((start_low_address, start_high_address)),
                     false,
                 )
             }
-            Runtime::CLike => {
+            Runtime::CLike | Runtime::CPython(_) => {
                 if needs_synthesis {
                     debug!("synthetising arm64 unwind information using frame pointers for vDSO");
                     Ok(vec![
@@ -1526,7 +2112,7 @@ impl Profiler {
                     )
                     .entered();
                     self.unwind_info_manager.fetch_unwind_info(
-                        &executable_path,
+                        &open_path,
                         executable_id,
                         None,
                         false,
@@ -1544,8 +2130,18 @@ impl Profiler {
             Err(e) => {
                 let known_naughty = executable_path.to_string_lossy().contains("libicudata.so")
                     || executable_path.to_string_lossy().contains("libnss_dns.so");
+                let no_eh_frame = matches!(
+                    e,
+                    FetchUnwindInfoError::Write(WriterError::UnwindInfo(
+                        UnwindInfoError::NoEhFrameSection
+                    ))
+                );
                 if known_naughty {
                     return Err(AddUnwindInformationError::NoUnwindInfoKnownNaughty);
+                } else if no_eh_frame {
+                    return Err(AddUnwindInformationError::NoEhFrameSection(
+                        executable_path.to_string_lossy().to_string(),
+                    ));
                 } else {
                     return Err(AddUnwindInformationError::NoUnwindInfo(
                         e.to_string(),
@@ -1683,16 +2279,19 @@ impl Profiler {
         true
     }
 
-    fn should_profile(&self, pid: Pid) -> bool {
+    fn should_profile(&mut self, pid: Pid) -> bool {
         if self.exclude_self && pid == std::process::id() as i32 {
             return false;
         }
 
-        if self.filter_pids.is_empty() {
+        if !self.filtering_pids && self.selectors.is_empty() {
             return true;
         }
 
         self.filter_pids.contains_key(&pid)
+            || self
+                .selection_cache
+                .is_selected(&self.selectors, pid, Instant::now())
     }
 
     fn event_new_proc(&mut self, pid: Pid) {
@@ -1700,7 +2299,7 @@ impl Profiler {
             return;
         }
 
-        if self.process_is_known(pid) {
+        if self.process_is_running(pid) {
             // We hit this when we had to reset the state of the BPF maps but we know about this process.
             self.add_unwind_info_for_process(pid);
             return;
@@ -1792,6 +2391,7 @@ impl Profiler {
         }
 
         let mut mappings = vec![];
+        let mut jitdump_paths = vec![];
         let object_files_clone = self.object_files.clone();
 
         for map in maps.iter() {
@@ -1800,36 +2400,40 @@ impl Profiler {
             }
             match &map.pathname {
                 procfs::process::MMapPath::Path(path) => {
-                    let Ok(exe_path) = executable_path(pid, path) else {
-                        // Can fail due to race-conditions
-                        continue;
-                    };
-
-                    // We've seen debug info executables that get deleted in Rust applications.
-                    if exe_path.to_string_lossy().contains("(deleted)") {
+                    // We want to open the file as quickly as possible to minimise the chances of races
+                    // if the file is deleted.
+                    let mapped_file =
+                        match open_mapped_file(pid, path, map.address.0, map.address.1) {
+                            Ok(mapped_file) => mapped_file,
+                            Err(e) => {
+                                // Can fail due to race-conditions
+                                debug!("failed to open file {} due to {:?}", path.display(), e);
+                                // Rather than returning here, we prefer to be able to profile some
+                                // parts of the binary
+                                continue;
+                            }
+                        };
+                    let deleted = mapped_file.is_deleted();
+                    let MappedFile {
+                        path: exe_path,
+                        open_path,
+                        file,
+                    } = mapped_file;
+
+                    // Runtimes writing jitdump files map them as executable so they can be found.
+                    if JitSymbols::is_jitdump(&exe_path) {
+                        jitdump_paths.push(exe_path);
                         continue;
                     }
 
                     // There are probably other cases, but we'll handle them as we bump into them.
-                    if exe_path.to_string_lossy().contains("(") {
+                    if exe_path.to_string_lossy().contains("(") && exe_path == open_path {
                         warn!(
                             "absolute path ({}) contains '(', it might be special",
                             exe_path.display()
                         );
                     }
 
-                    // We want to open the file as quickly as possible to minimise the chances of races
-                    // if the file is deleted.
-                    let file = match File::open(&exe_path) {
-                        Ok(f) => f,
-                        Err(e) => {
-                            debug!("failed to open file {} due to {:?}", exe_path.display(), e);
-                            // Rather than returning here, we prefer to be able to profile some
-                            // parts of the binary
-                            continue;
-                        }
-                    };
-
                     let object_file = match ObjectFile::new(&file) {
                         Ok(f) => f,
                         Err(e) => {
@@ -1881,15 +2485,16 @@ impl Profiler {
                         soft_delete: false,
                     });
 
-                    // If the object file has debug info, add it to our store.
-                    if object_file.has_debug_info() {
+                    // If the object file has debug info, or a separate debug file, add it to
+                    // our store.
+                    if object_file.has_debug_info() || object_file.has_gnu_debuglink() {
                         let name = match exe_path.file_name() {
                             Some(os_name) => os_name.to_string_lossy().to_string(),
                             None => "error".to_string(),
                         };
                         let res = self
                             .debug_info_manager
-                            .add_if_not_present(&name, build_id, &exe_path);
+                            .add_if_not_present(&name, build_id, &open_path, &file);
                         match res {
                             Ok(_) => {
                                 debug!("debuginfo add_if_not_present succeded {:?}", res);
@@ -1912,6 +2517,10 @@ impl Profiler {
                         Entry::Vacant(entry) => {
                             entry.insert(ObjectFileInfo {
                                 path: exe_path,
+                                // Objects are only kept open if they can't be read from
+                                // their path, so there's an open file per deployed version
+                                // rather than per object.
+                                file: deleted.then(|| Arc::new(file)),
                                 elf_load_segments: elf_loads,
                                 is_dyn: object_file.is_dynamic(),
                                 references: 1,
@@ -1921,7 +2530,11 @@ impl Profiler {
                             });
                         }
                         Entry::Occupied(mut entry) => {
-                            entry.get_mut().references += 1;
+                            let object_file_info = entry.get_mut();
+                            object_file_info.references += 1;
+                            if deleted {
+                                object_file_info.file.get_or_insert(Arc::new(file));
+                            }
                         }
                     }
                 }
@@ -1964,6 +2577,7 @@ impl Profiler {
                             executable_id,
                             ObjectFileInfo {
                                 path: vdso_path.clone(),
+                                file: None,
                                 elf_load_segments,
                                 is_dyn: object_file.is_dynamic(),
                                 references: 1,
@@ -1990,10 +2604,25 @@ impl Profiler {
         }
 
         mappings.sort_by_key(|k| k.start_addr.cmp(&k.start_addr));
+        let python = self.python_process(pid, &mappings);
+        let has_jit_code = mappings
+            .iter()
+            .any(|mapping| mapping.kind == ExecutableMappingType::Anonymous);
+        let jit_symbols = if has_jit_code || !jitdump_paths.is_empty() {
+            let mut jit_symbols = JitSymbols::new(pid);
+            for path in &jitdump_paths {
+                jit_symbols.add_jitdump(path);
+            }
+            Some(Arc::new(Mutex::new(jit_symbols)))
+        } else {
+            None
+        };
         let proc_info = ProcessInfo {
             status: ProcessStatus::Running,
             mappings: ExecutableMappings(mappings),
             last_used: Instant::now(),
+            python,
+            jit_symbols,
         };
         self.procs.clone().write().insert(pid, proc_info);
 
@@ -2017,6 +2646,44 @@ impl Profiler {
         Ok(())
     }
 
+    /// Returns the information needed to walk the Python stacks if the process is running
+    /// the CPython interpreter, either from the executable or `libpython`.
+    fn python_process(
+        &self,
+        pid: Pid,
+        mappings: &[ExecutableMapping],
+    ) -> Option<Arc<PythonProcess>> {
+        let object_files = self.object_files.read();
+
+        for mapping in mappings {
+            let Some(object_file) = object_files.get(&mapping.executable_id) else {
+                continue;
+            };
+            let Runtime::CPython(cpython_info) = &object_file.runtime else {
+                continue;
+            };
+
+            return match PythonProcess::new(
+                pid,
+                cpython_info,
+                mapping.executable_id,
+                &object_file.path,
+                mapping.load_address,
+            ) {
+                Ok(python) => {
+                    debug!("pid {} is running Python {}", pid, python.version);
+                    Some(Arc::new(python))
+                }
+                Err(e) => {
+                    warn!("Python frames won't be unwound for pid {}: {}", pid, e);
+                    None
+                }
+            };
+        }
+
+        None
+    }
+
     fn handle_sample(
         sample_send: &Arc<Sender<RawSample>>,
         data: &[u8],
@@ -2052,6 +2719,8 @@ impl Profiler {
     pub fn set_bpf_map_info(&mut self) {
         let native_unwinder_prog_id = program_PROGRAM_NATIVE_UNWINDER;
         let native_unwinder_prog_fd = self.native_unwinder.progs.dwarf_unwind.as_fd().as_raw_fd();
+        let python_unwinder_prog_id = program_PROGRAM_PYTHON_UNWINDER;
+        let python_unwinder_prog_fd = self.native_unwinder.progs.python_unwind.as_fd().as_raw_fd();
         let maps = &self.native_unwinder.maps;
         let programs = &maps.programs;
         programs
@@ -2061,24 +2730,94 @@ impl Profiler {
                 MapFlags::ANY,
             )
             .expect("update map");
+        programs
+            .update(
+                &python_unwinder_prog_id.to_le_bytes(),
+                &python_unwinder_prog_fd.to_le_bytes(),
+                MapFlags::ANY,
+            )
+            .expect("update map");
+
+        if self.off_cpu {
+            let off_cpu_unwinder_prog_fd = self
+                .native_unwinder
+                .progs
+                .dwarf_unwind_off_cpu
+                .as_fd()
+                .as_raw_fd();
+            maps.off_cpu_programs
+                .update(
+                    &native_unwinder_prog_id.to_le_bytes(),
+                    &off_cpu_unwinder_prog_fd.to_le_bytes(),
+                    MapFlags::ANY,
+                )
+                .expect("update map");
+
+            let off_cpu_python_unwinder_prog_fd = self
+                .native_unwinder
+                .progs
+                .python_unwind_off_cpu
+                .as_fd()
+                .as_raw_fd();
+            maps.off_cpu_programs
+                .update(
+                    &python_unwinder_prog_id.to_le_bytes(),
+                    &off_cpu_python_unwinder_prog_fd.to_le_bytes(),
+                    MapFlags::ANY,
+                )
+                .expect("update map");
+        }
+    }
+
+    /// Attaches the off-CPU unwinder to `finish_task_switch`, which runs in the context of
+    /// the thread that has just been scheduled in. Depending on how the kernel was compiled
+    /// the symbol might have a suffix.
+    pub fn setup_off_cpu_probe(&mut self) {
+        for symbol in ["finish_task_switch", "finish_task_switch.isra.0"] {
+            let prog = self
+                .native_unwinder
+                .object_mut()
+                .progs_mut()
+                .find(|prog| prog.name() == "on_finish_task_switch")
+                .expect("get prog");
+            match prog.attach_kprobe(false, symbol) {
+                Ok(link) => {
+                    debug!("off-CPU probe attached to {}", symbol);
+                    self._off_cpu_links.push(link);
+                    return;
+                }
+                Err(e) => {
+                    debug!("attaching off-CPU probe to {} failed with {:?}", symbol, e);
+                }
+            }
+        }
+
+        error!("could not attach the off-CPU probe, no off-CPU samples will be collected");
     }
 
     pub fn setup_perf_events(&mut self) {
         let mut perf_fds = Vec::new();
-        for i in get_online_cpus().expect("get online CPUs") {
-            let perf_fd = unsafe { setup_perf_event(i.try_into().unwrap(), self.sample_freq) }
-                .expect("setup perf event");
-            perf_fds.push(perf_fd);
+        for (event_idx, event_config) in self.event_configs.iter().enumerate() {
+            for i in get_online_cpus().expect("get online CPUs") {
+                let perf_fd = unsafe { setup_perf_event(i.try_into().unwrap(), event_config) }
+                    .expect("setup perf event");
+                perf_fds.push((event_idx, perf_fd));
+            }
         }
 
-        for perf_fd in perf_fds {
+        for (event_idx, perf_fd) in perf_fds {
             let prog = self
                 .native_unwinder
                 .object_mut()
                 .progs_mut()
                 .find(|prog| prog.name() == "on_event")
                 .expect("get prog");
-            let link = prog.attach_perf_event(perf_fd);
+            // The BPF program reads the event index from the attach cookie.
+            let opts = PerfEventOpts {
+                cookie: event_idx as u64,
+                ..Default::default()
+            };
+            let link = prog.attach_perf_event_with_opts(perf_fd, opts);
             self._links.push(link.expect("bpf link is present"));
         }
     }
@@ -0,0 +1,196 @@
+//! Decoders for the tables CPython uses to map bytecode offsets to line numbers. Their
+//! format changed in 3.10 and again in 3.11.
+
+/// Decodes `co_lnotab`, used up to 3.9. It's made of pairs of unsigned bytecode offset
+/// and signed line increments.
+pub fn lnotab_line(lnotab: &[u8], first_line: u32, byte_offset: u32) -> Option<u32> {
+    let mut line = first_line as i64;
+    let mut address = 0;
+
+    for entry in lnotab.chunks_exact(2) {
+        address += entry[0] as u32;
+        if address > byte_offset {
+            break;
+        }
+        line += entry[1] as i8 as i64;
+    }
+
+    u32::try_from(line).ok()
+}
+
+/// Decodes `co_linetable` in 3.10. It's made of pairs of unsigned bytecode range lengths
+/// and signed line increments. A line increment of -128 means that the range has no line.
+pub fn linetable_310_line(linetable: &[u8], first_line: u32, byte_offset: u32) -> Option<u32> {
+    let mut line = first_line as i64;
+    let mut start = 0;
+
+    for entry in linetable.chunks_exact(2) {
+        let end = start + entry[0] as u32;
+        let line_delta = entry[1] as i8;
+        let range_line = if line_delta == -128 {
+            None
+        } else {
+            line += line_delta as i64;
+            u32::try_from(line).ok()
+        };
+
+        if (start..end).contains(&byte_offset) {
+            return range_line;
+        }
+        start = end;
+    }
+
+    None
+}
+
+/// Decodes the location table, `co_linetable`, used since 3.11. Each entry covers a range
+/// of code units, which are two bytes long, and its first byte encodes the entry kind and
+/// the length of the range.
+pub fn location_table_line(linetable: &[u8], first_line: u32, byte_offset: u32) -> Option<u32> {
+    let code_unit = byte_offset / 2;
+    let mut bytes = linetable.iter().copied();
+    let mut line = first_line as i64;
+    let mut start = 0;
+
+    while let Some(first_byte) = bytes.next() {
+        // Entries start with the most significant bit set.
+        if first_byte & 0x80 == 0 {
+            return None;
+        }
+        let code = (first_byte >> 3) & 0xF;
+        let end = start + (first_byte & 0x7) as u32 + 1;
+
+        let range_line = match code {
+            // No location.
+            15 => None,
+            // Long form, followed by the end line, start and end columns.
+            14 => {
+                line += read_signed_varint(&mut bytes)?;
+                read_varint(&mut bytes)?;
+                read_varint(&mut bytes)?;
+                read_varint(&mut bytes)?;
+                Some(line)
+            }
+            // No column information.
+            13 => {
+                line += read_signed_varint(&mut bytes)?;
+                Some(line)
+            }
+            // One line forms, followed by the start and end columns.
+            10..=12 => {
+                line += (code - 10) as i64;
+                bytes.next()?;
+                bytes.next()?;
+                Some(line)
+            }
+            // Short forms, same line as the previous entry, followed by the column.
+            _ => {
+                bytes.next()?;
+                Some(line)
+            }
+        };
+
+        if (start..end).contains(&code_unit) {
+            return range_line.and_then(|line| u32::try_from(line).ok());
+        }
+        start = end;
+    }
+
+    None
+}
+
+/// Varints are stored in chunks of 6 bits, least significant first, with the 7th bit
+/// signaling that more chunks follow.
+fn read_varint(bytes: &mut impl Iterator<Item = u8>) -> Option<u64> {
+    let mut byte = bytes.next()?;
+    let mut value = (byte & 0x3F) as u64;
+    let mut shift = 0;
+
+    while byte & 0x40 != 0 {
+        byte = bytes.next()?;
+        shift += 6;
+        if shift >= 64 {
+            return None;
+        }
+        value |= ((byte & 0x3F) as u64) << shift;
+    }
+
+    Some(value)
+}
+
+/// Signed varints store the sign in the least significant bit.
+fn read_signed_varint(bytes: &mut impl Iterator<Item = u8>) -> Option<i64> {
+    let value = read_varint(bytes)?;
+    if value & 1 != 0 {
+        Some(-((value >> 1) as i64))
+    } else {
+        Some((value >> 1) as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lnotab_line() {
+        // Bytes 0..6 are on the first line, 6..10 two lines later and the rest one line
+        // before that.
+        let lnotab = [6, 2, 4, 0xFF];
+        assert_eq!(lnotab_line(&lnotab, 10, 0), Some(10));
+        assert_eq!(lnotab_line(&lnotab, 10, 5), Some(10));
+        assert_eq!(lnotab_line(&lnotab, 10, 6), Some(12));
+        assert_eq!(lnotab_line(&lnotab, 10, 10), Some(11));
+        assert_eq!(lnotab_line(&lnotab, 10, 100), Some(11));
+    }
+
+    #[test]
+    fn test_linetable_310_line() {
+        let linetable = [4, 0, 6, 3, 2, 0x80];
+        assert_eq!(linetable_310_line(&linetable, 10, 0), Some(10));
+        assert_eq!(linetable_310_line(&linetable, 10, 4), Some(13));
+        assert_eq!(linetable_310_line(&linetable, 10, 9), Some(13));
+        assert_eq!(linetable_310_line(&linetable, 10, 10), None);
+        assert_eq!(linetable_310_line(&linetable, 10, 12), None);
+    }
+
+    #[test]
+    fn test_location_table_line() {
+        let linetable = [
+            // No column info, one code unit, line + 1.
+            0x80 | (13 << 3),
+            0x02,
+            // One line form, two code units, line + 2.
+            0x80 | (12 << 3) | 1,
+            0x00,
+            0x05,
+            // Short form, one code unit, same line.
+            0x80,
+            0x04,
+            // Long form, one code unit, line - 3.
+            0x80 | (14 << 3),
+            0x07,
+            0x00,
+            0x01,
+            0x02,
+            // No location, one code unit.
+            0x80 | (15 << 3),
+        ];
+        assert_eq!(location_table_line(&linetable, 10, 0), Some(11));
+        assert_eq!(location_table_line(&linetable, 10, 2), Some(13));
+        assert_eq!(location_table_line(&linetable, 10, 4), Some(13));
+        assert_eq!(location_table_line(&linetable, 10, 6), Some(13));
+        assert_eq!(location_table_line(&linetable, 10, 8), Some(10));
+        assert_eq!(location_table_line(&linetable, 10, 10), None);
+        assert_eq!(location_table_line(&linetable, 10, 12), None);
+    }
+
+    #[test]
+    fn test_read_varint() {
+        assert_eq!(read_varint(&mut [0x05].into_iter()), Some(5));
+        assert_eq!(read_varint(&mut [0x41, 0x01].into_iter()), Some(65));
+        assert_eq!(read_varint(&mut [0x41].into_iter()), None);
+        assert_eq!(read_signed_varint(&mut [0x07].into_iter()), Some(-3));
+        assert_eq!(read_signed_varint(&mut [0x06].into_iter()), Some(3));
+    }
+}
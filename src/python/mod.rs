@@ -0,0 +1,471 @@
+//! Support for profiling CPython. The BPF unwinder walks the interpreter frames of the
+//! current thread and sends the code objects being executed, which are read and turned
+//! into `module.function (file:line)` frames here.
+
+mod linetable;
+mod offsets;
+
+use std::fmt;
+use std::fs::File;
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use lightswitch_object::{CPythonInfo, ExecutableId};
+use lru::LruCache;
+
+use crate::bpf::profiler_bindings::*;
+use crate::process::Pid;
+use crate::profile::{Frame, SymbolizedFrame};
+
+pub use offsets::PythonOffsets;
+
+/// Code objects can be large, but we only read their names and line tables which are
+/// not expected to be bigger than this.
+const MAX_READ_SIZE: u64 = 64 * 1024;
+/// Code objects that are kept for each process, the least recently used ones are read
+/// again when they are needed.
+const CODE_OBJECTS_CACHE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PythonVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl PythonVersion {
+    /// Guesses the version from the interpreter or `libpython` path, such as
+    /// `/usr/bin/python3.11` or `/usr/lib/libpython3.11.so.1.0`. Needed for versions
+    /// before 3.11, which don't export `Py_Version`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let file_name = path.file_name()?.to_str()?;
+        let version = file_name
+            .strip_prefix("libpython")
+            .or_else(|| file_name.strip_prefix("python"))?;
+        let mut parts = version.split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts
+            .next()?
+            .trim_end_matches(|c: char| !c.is_ascii_digit())
+            .parse()
+            .ok()?;
+        Some(Self { major, minor })
+    }
+}
+
+impl fmt::Display for PythonVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum PythonProcessError {
+    #[error("could not detect the Python version")]
+    UnknownVersion,
+    #[error("Python {0} is not supported")]
+    UnsupportedVersion(PythonVersion),
+}
+
+/// A Python frame, as sent by the BPF unwinder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RawPythonFrame {
+    pub code_address: u64,
+    /// Offset in bytes of the instruction being executed.
+    pub instruction_offset: u32,
+    /// Whether this was the first frame evaluated by a call to the interpreter loop.
+    pub is_entry: bool,
+}
+
+impl RawPythonFrame {
+    pub fn from_raw(code_address: u64, raw_instruction_offset: u64) -> Self {
+        Self {
+            code_address,
+            instruction_offset: raw_instruction_offset as u32,
+            is_entry: raw_instruction_offset & PYTHON_FRAME_ENTRY != 0,
+        }
+    }
+}
+
+/// The parts of a code object needed to symbolize a frame.
+#[derive(Debug)]
+struct PythonCode {
+    /// Tells apart code objects that were allocated at the same address.
+    identity: CodeIdentity,
+    filename: String,
+    name: String,
+    linetable: Vec<u8>,
+}
+
+/// Addresses of the filename and name objects of a code object, and its first line.
+#[derive(Debug, PartialEq, Eq)]
+struct CodeIdentity {
+    filename: u64,
+    name: u64,
+    first_line: u32,
+}
+
+#[derive(Debug)]
+pub struct PythonProcess {
+    pid: Pid,
+    pub version: PythonVersion,
+    /// The object file containing the interpreter, either the executable or `libpython`.
+    pub executable_id: ExecutableId,
+    /// Object relative addresses of `_PyEval_EvalFrameDefault`.
+    pub eval_frame: Range<u64>,
+    /// Address of `_PyRuntime` in the process.
+    runtime_address: u64,
+    offsets: PythonOffsets,
+    /// Code objects by address. Once freed, a code object's address can be reused by
+    /// another one, so the entries are checked against the process memory when used.
+    code_objects: Mutex<LruCache<u64, Arc<PythonCode>>>,
+}
+
+impl PythonProcess {
+    pub fn new(
+        pid: Pid,
+        cpython_info: &CPythonInfo,
+        executable_id: ExecutableId,
+        executable_path: &Path,
+        load_address: u64,
+    ) -> Result<Self, PythonProcessError> {
+        let version = match cpython_info.version {
+            Some((major, minor)) => PythonVersion { major, minor },
+            None => PythonVersion::from_path(executable_path)
+                .ok_or(PythonProcessError::UnknownVersion)?,
+        };
+        let offsets = PythonOffsets::for_version(version)
+            .ok_or(PythonProcessError::UnsupportedVersion(version))?;
+
+        Ok(Self {
+            pid,
+            version,
+            executable_id,
+            eval_frame: cpython_info.eval_frame_start_address..cpython_info.eval_frame_end_address,
+            runtime_address: load_address + cpython_info.runtime_address,
+            offsets,
+            code_objects: Mutex::new(LruCache::new(
+                NonZeroUsize::new(CODE_OBJECTS_CACHE_SIZE).unwrap(),
+            )),
+        })
+    }
+
+    /// The information the BPF unwinder needs for this process.
+    pub fn bpf_process(&self) -> python_process_t {
+        python_process_t {
+            runtime_address: self.runtime_address,
+            offsets: (&self.offsets).into(),
+        }
+    }
+
+    /// Whether an object relative address belongs to the interpreter loop.
+    pub fn is_eval_frame(&self, executable_id: ExecutableId, file_offset: Option<u64>) -> bool {
+        executable_id == self.executable_id
+            && file_offset.is_some_and(|file_offset| self.eval_frame.contains(&file_offset))
+    }
+
+    /// Reads the code object of a frame from the process memory, which is cached, and
+    /// returns the symbolized frame.
+    pub fn symbolize(&self, raw_frame: &RawPythonFrame) -> Frame {
+        let Some(code) = self.code(raw_frame.code_address) else {
+            return Frame::with_error(
+                raw_frame.code_address,
+                "<could not read python code object>".to_string(),
+            );
+        };
+
+        let line = match (self.version.major, self.version.minor) {
+            (3, 0..=9) => linetable::lnotab_line(
+                &code.linetable,
+                code.identity.first_line,
+                raw_frame.instruction_offset,
+            ),
+            (3, 10) => linetable::linetable_310_line(
+                &code.linetable,
+                code.identity.first_line,
+                raw_frame.instruction_offset,
+            ),
+            _ => linetable::location_table_line(
+                &code.linetable,
+                code.identity.first_line,
+                raw_frame.instruction_offset,
+            ),
+        };
+        let module = Path::new(&code.filename)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        Frame {
+            virtual_address: raw_frame.code_address,
+            file_offset: None,
+            symbolization_result: Some(Ok(SymbolizedFrame::new(
+                format!("{}.{}", module, code.name),
+                false,
+                Some(code.filename.clone()),
+                line,
+            ))),
+        }
+    }
+
+    /// The code object at an address, from the cache if it's still the same object.
+    fn code(&self, code_address: u64) -> Option<Arc<PythonCode>> {
+        let mem = File::open(format!("/proc/{}/mem", self.pid)).ok()?;
+        let cached = self
+            .code_objects
+            .lock()
+            .unwrap()
+            .get(&code_address)
+            .cloned();
+        if let Some(code) = cached {
+            if self.read_code_identity(&mem, code_address).as_ref() == Some(&code.identity) {
+                return Some(code);
+            }
+        }
+
+        // Failures aren't cached, the object might be read while it's being set up.
+        let code = Arc::new(self.read_code(&mem, code_address)?);
+        self.code_objects
+            .lock()
+            .unwrap()
+            .put(code_address, code.clone());
+        Some(code)
+    }
+
+    fn read_code_identity(&self, mem: &File, code_address: u64) -> Option<CodeIdentity> {
+        let offsets = &self.offsets;
+        let name_field = offsets.code_qualname.unwrap_or(offsets.code_name);
+        Some(CodeIdentity {
+            filename: read_u64(mem, code_address + offsets.code_filename)?,
+            name: read_u64(mem, code_address + name_field)?,
+            first_line: read_u32(mem, code_address + offsets.code_firstlineno)?,
+        })
+    }
+
+    fn read_code(&self, mem: &File, code_address: u64) -> Option<PythonCode> {
+        let identity = self.read_code_identity(mem, code_address)?;
+        let linetable = read_u64(mem, code_address + self.offsets.code_linetable)?;
+
+        Some(PythonCode {
+            filename: self.read_unicode(mem, identity.filename)?,
+            name: self.read_unicode(mem, identity.name)?,
+            linetable: read_bytes_object(mem, linetable)?,
+            identity,
+        })
+    }
+
+    /// Reads a compact `PyUnicodeObject`, which is how strings coming from source
+    /// code are stored.
+    fn read_unicode(&self, mem: &File, address: u64) -> Option<String> {
+        let length = read_u64(mem, address + 16)?;
+        let state = read_u32(mem, address + 32)?;
+        let kind = (state >> 2) & 0x7;
+        let compact = state & (1 << 5) != 0;
+        let ascii = state & (1 << 6) != 0;
+        if !compact {
+            return None;
+        }
+
+        let data_address = if ascii {
+            address + self.offsets.unicode_ascii_data
+        } else {
+            address + self.offsets.unicode_compact_data
+        };
+        let size = length.checked_mul(kind as u64)?;
+        if size > MAX_READ_SIZE {
+            return None;
+        }
+        let mut data = vec![0; size as usize];
+        mem.read_exact_at(&mut data, data_address).ok()?;
+
+        match kind {
+            1 => Some(data.iter().map(|&c| c as char).collect()),
+            2 => char::decode_utf16(
+                data.chunks_exact(2)
+                    .map(|c| u16::from_ne_bytes(c.try_into().unwrap())),
+            )
+            .collect::<Result<String, _>>()
+            .ok(),
+            4 => data
+                .chunks_exact(4)
+                .map(|c| char::from_u32(u32::from_ne_bytes(c.try_into().unwrap())))
+                .collect(),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the contents of a `PyBytesObject`.
+fn read_bytes_object(mem: &File, address: u64) -> Option<Vec<u8>> {
+    let size = read_u64(mem, address + 16)?;
+    if size > MAX_READ_SIZE {
+        return None;
+    }
+    let mut data = vec![0; size as usize];
+    mem.read_exact_at(&mut data, address + 32).ok()?;
+    Some(data)
+}
+
+fn read_u64(mem: &File, address: u64) -> Option<u64> {
+    let mut buf = [0; 8];
+    mem.read_exact_at(&mut buf, address).ok()?;
+    Some(u64::from_ne_bytes(buf))
+}
+
+fn read_u32(mem: &File, address: u64) -> Option<u32> {
+    let mut buf = [0; 4];
+    mem.read_exact_at(&mut buf, address).ok()?;
+    Some(u32::from_ne_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_python_version_from_path() {
+        assert_eq!(
+            PythonVersion::from_path(Path::new("/usr/bin/python3.11")),
+            Some(PythonVersion {
+                major: 3,
+                minor: 11
+            })
+        );
+        assert_eq!(
+            PythonVersion::from_path(Path::new("/usr/lib/libpython3.9.so.1.0")),
+            Some(PythonVersion { major: 3, minor: 9 })
+        );
+        assert_eq!(
+            PythonVersion::from_path(Path::new("/usr/bin/python3.10d")),
+            Some(PythonVersion {
+                major: 3,
+                minor: 10
+            })
+        );
+        assert_eq!(
+            PythonVersion::from_path(Path::new("/usr/bin/python3")),
+            None
+        );
+        assert_eq!(PythonVersion::from_path(Path::new("/usr/bin/ls")), None);
+    }
+
+    #[test]
+    fn test_raw_python_frame() {
+        assert_eq!(
+            RawPythonFrame::from_raw(0x1000, PYTHON_FRAME_ENTRY | 0x10),
+            RawPythonFrame {
+                code_address: 0x1000,
+                instruction_offset: 0x10,
+                is_entry: true,
+            }
+        );
+        assert!(!RawPythonFrame::from_raw(0x1000, 0x10).is_entry);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let cpython_info = CPythonInfo {
+            version: Some((3, 7)),
+            runtime_address: 0x1000,
+            eval_frame_start_address: 0x2000,
+            eval_frame_end_address: 0x3000,
+        };
+        assert_eq!(
+            PythonProcess::new(
+                1,
+                &cpython_info,
+                ExecutableId(0),
+                Path::new("/usr/bin/python3.7"),
+                0
+            )
+            .err(),
+            Some(PythonProcessError::UnsupportedVersion(PythonVersion {
+                major: 3,
+                minor: 7
+            }))
+        );
+    }
+
+    /// An object of `size` bytes with the given bytes at some offsets, laid out in this
+    /// process' memory to be read like the ones of a Python process.
+    fn object(size: usize, fields: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut object = vec![0; size];
+        for (offset, bytes) in fields {
+            object[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+        }
+        object
+    }
+
+    /// A compact ASCII string, as laid out by Python 3.11.
+    fn unicode_object(text: &str) -> Vec<u8> {
+        let state: u32 = (1 << 2) | (1 << 5) | (1 << 6);
+        object(
+            48 + text.len(),
+            &[
+                (16, &(text.len() as u64).to_ne_bytes()),
+                (32, &state.to_ne_bytes()),
+                (48, text.as_bytes()),
+            ],
+        )
+    }
+
+    /// A Python 3.11 code object, which points to the given objects.
+    fn code_object(filename: &[u8], name: &[u8], first_line: u32, linetable: u64) -> Vec<u8> {
+        object(
+            144,
+            &[
+                (72, &first_line.to_ne_bytes()),
+                (112, &(filename.as_ptr() as u64).to_ne_bytes()),
+                (128, &(name.as_ptr() as u64).to_ne_bytes()),
+                (136, &linetable.to_ne_bytes()),
+            ],
+        )
+    }
+
+    fn symbol_name(frame: Frame) -> Option<String> {
+        frame.symbolization_result?.ok().map(|symbol| symbol.name)
+    }
+
+    #[test]
+    fn test_code_objects_cache() {
+        let cpython_info = CPythonInfo {
+            version: Some((3, 11)),
+            runtime_address: 0x1000,
+            eval_frame_start_address: 0x2000,
+            eval_frame_end_address: 0x3000,
+        };
+        let process = PythonProcess::new(
+            std::process::id() as Pid,
+            &cpython_info,
+            ExecutableId(0),
+            Path::new("/usr/bin/python3.11"),
+            0,
+        )
+        .unwrap();
+
+        let filename = unicode_object("/app/main.py");
+        let first_name = unicode_object("first");
+        let second_name = unicode_object("second");
+        let linetable = object(32, &[]);
+        let linetable_address = linetable.as_ptr() as u64;
+
+        // Not cached when it can't be read, as its line table isn't set yet.
+        let mut code = code_object(&filename, &first_name, 1, 0);
+        let frame = RawPythonFrame::from_raw(code.as_ptr() as u64, 0);
+        assert_eq!(symbol_name(process.symbolize(&frame)), None);
+        code.copy_from_slice(&code_object(&filename, &first_name, 1, linetable_address));
+        assert_eq!(
+            symbol_name(process.symbolize(&frame)),
+            Some("main.first".to_string())
+        );
+
+        // Another code object at the same address.
+        code.copy_from_slice(&code_object(&filename, &second_name, 10, linetable_address));
+        assert_eq!(
+            symbol_name(process.symbolize(&frame)),
+            Some("main.second".to_string())
+        );
+    }
+}
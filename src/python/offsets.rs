@@ -0,0 +1,138 @@
+use crate::bpf::profiler_bindings::*;
+use crate::python::PythonVersion;
+
+const NONE: u16 = PYTHON_OFFSET_NONE as u16;
+
+/// Offsets of the CPython internal structures, which are not part of the stable ABI
+/// and change across minor versions. The first group of fields is used by the BPF
+/// unwinder to walk the frames and the second one to read code objects in userspace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PythonOffsets {
+    pub runtime_interpreters_head: u16,
+    pub interpreter_threads_head: u16,
+    pub thread_state_next: u16,
+    pub thread_state_thread_id: u16,
+    pub thread_state_native_thread_id: u16,
+    pub thread_state_frame: u16,
+    pub cframe_current_frame: u16,
+    pub frame_back: u16,
+    pub frame_code: u16,
+    pub frame_lasti: u16,
+    pub frame_is_entry: u16,
+    pub frame_owner: u16,
+    pub code_code_adaptive: u16,
+    pub lasti_kind: u16,
+
+    pub code_filename: u64,
+    pub code_name: u64,
+    /// Not present before 3.11.
+    pub code_qualname: Option<u64>,
+    pub code_firstlineno: u64,
+    /// `co_lnotab` before 3.10, `co_linetable` afterwards.
+    pub code_linetable: u64,
+    pub unicode_ascii_data: u64,
+    pub unicode_compact_data: u64,
+}
+
+impl PythonOffsets {
+    /// Returns the offsets for the given Python version, if supported.
+    pub fn for_version(version: PythonVersion) -> Option<Self> {
+        match (version.major, version.minor) {
+            (3, 8..=10) => Some(Self {
+                runtime_interpreters_head: 32,
+                interpreter_threads_head: 8,
+                thread_state_next: 8,
+                thread_state_thread_id: 176,
+                thread_state_native_thread_id: NONE,
+                thread_state_frame: 24,
+                cframe_current_frame: NONE,
+                frame_back: 24,
+                frame_code: 32,
+                frame_lasti: if version.minor == 10 { 96 } else { 104 },
+                frame_is_entry: NONE,
+                frame_owner: NONE,
+                code_code_adaptive: NONE,
+                lasti_kind: if version.minor == 10 {
+                    python_lasti_kind_PYTHON_LASTI_CODE_UNITS as u16
+                } else {
+                    python_lasti_kind_PYTHON_LASTI_BYTES as u16
+                },
+                code_filename: 104,
+                code_name: 112,
+                code_qualname: None,
+                code_firstlineno: 40,
+                code_linetable: 120,
+                unicode_ascii_data: 48,
+                unicode_compact_data: 72,
+            }),
+            (3, 11) => Some(Self {
+                runtime_interpreters_head: 40,
+                interpreter_threads_head: 16,
+                thread_state_next: 8,
+                thread_state_thread_id: 152,
+                thread_state_native_thread_id: 160,
+                thread_state_frame: 56,
+                cframe_current_frame: 8,
+                frame_back: 48,
+                frame_code: 32,
+                frame_lasti: 56,
+                frame_is_entry: 68,
+                frame_owner: NONE,
+                code_code_adaptive: 184,
+                lasti_kind: python_lasti_kind_PYTHON_LASTI_INSTRUCTION_POINTER as u16,
+                code_filename: 112,
+                code_name: 120,
+                code_qualname: Some(128),
+                code_firstlineno: 72,
+                code_linetable: 136,
+                unicode_ascii_data: 48,
+                unicode_compact_data: 72,
+            }),
+            (3, 12) => Some(Self {
+                runtime_interpreters_head: 48,
+                interpreter_threads_head: 648,
+                thread_state_next: 8,
+                thread_state_thread_id: 136,
+                thread_state_native_thread_id: 144,
+                thread_state_frame: 56,
+                cframe_current_frame: 0,
+                frame_back: 8,
+                frame_code: 0,
+                frame_lasti: 56,
+                frame_is_entry: NONE,
+                frame_owner: 70,
+                code_code_adaptive: 192,
+                lasti_kind: python_lasti_kind_PYTHON_LASTI_INSTRUCTION_POINTER as u16,
+                code_filename: 112,
+                code_name: 120,
+                code_qualname: Some(128),
+                code_firstlineno: 68,
+                code_linetable: 136,
+                unicode_ascii_data: 40,
+                unicode_compact_data: 56,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl From<&PythonOffsets> for python_offsets_t {
+    fn from(offsets: &PythonOffsets) -> Self {
+        python_offsets_t {
+            runtime_interpreters_head: offsets.runtime_interpreters_head,
+            interpreter_threads_head: offsets.interpreter_threads_head,
+            thread_state_next: offsets.thread_state_next,
+            thread_state_thread_id: offsets.thread_state_thread_id,
+            thread_state_native_thread_id: offsets.thread_state_native_thread_id,
+            thread_state_frame: offsets.thread_state_frame,
+            cframe_current_frame: offsets.cframe_current_frame,
+            frame_back: offsets.frame_back,
+            frame_code: offsets.frame_code,
+            frame_lasti: offsets.frame_lasti,
+            frame_is_entry: offsets.frame_is_entry,
+            frame_owner: offsets.frame_owner,
+            code_code_adaptive: offsets.code_code_adaptive,
+            lasti_kind: offsets.lasti_kind,
+        }
+    }
+}
@@ -0,0 +1,444 @@
+//! Durable storage for profiles that haven't been uploaded yet. Profiles are written to
+//! disk before being sent by a background thread, so they survive backend outages and
+//! restarts, and the profiler never waits on the network.
+
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+use tracing::{debug, error, warn};
+
+const SPOOL_EXTENSION: &str = "pprof";
+const TEMPORARY_EXTENSION: &str = "tmp";
+
+/// Delay before retrying the first failed upload, doubled after every failure.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Default)]
+pub struct SpoolStats {
+    /// Profiles uploaded successfully.
+    pub sent: AtomicU64,
+    /// Failed upload attempts.
+    pub failed: AtomicU64,
+    /// Profiles the backend refused, which are never retried.
+    pub rejected: AtomicU64,
+    /// Profiles deleted before they could be uploaded because the spool was full or they
+    /// were too old.
+    pub dropped: AtomicU64,
+}
+
+/// A directory of pending payloads, bounded in size and age. Entries are named after the
+/// time they were added at, so they are uploaded and dropped oldest first.
+#[derive(Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    max_size_bytes: u64,
+    max_age: Duration,
+    sequence: AtomicU64,
+    stats: Arc<SpoolStats>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SpoolEntry {
+    path: PathBuf,
+    created_at: SystemTime,
+    size: u64,
+}
+
+impl Spool {
+    pub fn new(dir: &Path, max_size_bytes: u64, max_age: Duration) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_size_bytes,
+            max_age,
+            sequence: AtomicU64::new(0),
+            stats: Arc::new(SpoolStats::default()),
+        })
+    }
+
+    pub fn stats(&self) -> Arc<SpoolStats> {
+        self.stats.clone()
+    }
+
+    /// Writes a payload to the spool, dropping the oldest entries if it grows over its
+    /// size limit.
+    pub fn push(&self, payload: &[u8]) -> io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let name = format!(
+            "{:020}-{:010}",
+            now.as_nanos(),
+            self.sequence.fetch_add(1, Ordering::Relaxed)
+        );
+
+        // Entries are written under a temporary name first so partially written ones are
+        // never uploaded.
+        let temporary_path = self.dir.join(&name).with_extension(TEMPORARY_EXTENSION);
+        fs::write(&temporary_path, payload)?;
+        fs::rename(
+            &temporary_path,
+            self.dir.join(name).with_extension(SPOOL_EXTENSION),
+        )?;
+
+        self.trim()
+    }
+
+    /// Returns the path of the oldest payload, if any.
+    pub fn oldest(&self) -> io::Result<Option<PathBuf>> {
+        Ok(self.entries()?.into_iter().next().map(|entry| entry.path))
+    }
+
+    pub fn remove(&self, path: &Path) -> io::Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.entries()?.is_empty())
+    }
+
+    /// Drops the entries that are too old, and then the oldest ones until the spool is
+    /// under its size limit.
+    fn trim(&self) -> io::Result<()> {
+        let entries = self.entries()?;
+        let now = SystemTime::now();
+        let mut total_size: u64 = entries.iter().map(|entry| entry.size).sum();
+
+        for entry in entries {
+            let expired = now
+                .duration_since(entry.created_at)
+                .is_ok_and(|age| age > self.max_age);
+            if !expired && total_size <= self.max_size_bytes {
+                break;
+            }
+
+            debug!("dropping spooled profile {}", entry.path.display());
+            self.remove(&entry.path)?;
+            total_size -= entry.size;
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the spooled entries, oldest first.
+    fn entries(&self) -> io::Result<Vec<SpoolEntry>> {
+        let mut entries = Vec::new();
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some(SPOOL_EXTENSION) {
+                continue;
+            }
+            let Some(created_at) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.split('-').next())
+                .and_then(|nanos| nanos.parse().ok())
+                .map(|nanos| SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos))
+            else {
+                continue;
+            };
+            let Ok(metadata) = fs::metadata(&path) else {
+                continue;
+            };
+            entries.push(SpoolEntry {
+                path,
+                created_at,
+                size: metadata.len(),
+            });
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+}
+
+/// What happened when uploading a payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendOutcome {
+    Sent,
+    /// The upload failed but might succeed later, after the given delay if the backend
+    /// asked for one.
+    Retry(Option<Duration>),
+    /// The backend won't ever accept the payload, so it's dropped.
+    Rejected,
+}
+
+/// Returns the exponential backoff delay after some number of consecutive failures, with
+/// jitter so agents don't retry in lockstep after a backend outage.
+fn backoff_with_jitter(failures: u32) -> Duration {
+    let backoff = INITIAL_BACKOFF
+        .saturating_mul(2_u32.saturating_pow(failures))
+        .min(MAX_BACKOFF);
+    let jitter = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+    backoff.mul_f64(0.5 + jitter / 2.0)
+}
+
+/// Uploads the spooled payloads in a background thread, oldest first, retrying them until
+/// they are sent or dropped from the spool.
+pub struct SpoolSender {
+    spool: Arc<Spool>,
+    wake_up: Sender<()>,
+}
+
+impl SpoolSender {
+    /// Starts the sender thread, which uploads the payloads with `send`.
+    pub fn start<F>(spool: Spool, send: F) -> io::Result<Self>
+    where
+        F: Fn(Vec<u8>) -> SendOutcome + Send + 'static,
+    {
+        let spool = Arc::new(spool);
+        let (wake_up, woken_up) = bounded(1);
+        let thread_spool = spool.clone();
+        thread::Builder::new()
+            .name("spool-sender".to_string())
+            .spawn(move || Self::run(&thread_spool, woken_up, send))?;
+
+        Ok(Self { spool, wake_up })
+    }
+
+    /// Spools a payload to be uploaded. Never blocks on the upload.
+    pub fn push(&self, payload: &[u8]) -> io::Result<()> {
+        self.spool.push(payload)?;
+        let _ = self.wake_up.try_send(());
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Arc<SpoolStats> {
+        self.spool.stats()
+    }
+
+    /// Waits for the spooled payloads to be uploaded, up to some time. Returns whether
+    /// the spool is empty.
+    pub fn wait_until_empty(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.spool.is_empty().unwrap_or(true) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    fn run<F>(spool: &Spool, woken_up: Receiver<()>, send: F)
+    where
+        F: Fn(Vec<u8>) -> SendOutcome,
+    {
+        let stats = spool.stats();
+        let mut failures = 0;
+
+        loop {
+            // Profiles can expire while waiting to be retried, and they are dropped
+            // before being sent rather than only when new ones are spooled.
+            if let Err(e) = spool.trim() {
+                error!("failed to trim the spool with {:?}", e);
+            }
+            let path = match spool.oldest() {
+                Ok(Some(path)) => path,
+                Ok(None) => {
+                    // Wait for new payloads, checking every now and then in case they
+                    // were added by something else.
+                    let _ = woken_up.recv_timeout(MAX_BACKOFF);
+                    continue;
+                }
+                Err(e) => {
+                    error!("failed to read the spool with {:?}", e);
+                    thread::sleep(MAX_BACKOFF);
+                    continue;
+                }
+            };
+
+            let payload = match fs::read(&path) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    // The entry might have been dropped from the spool in the meantime,
+                    // otherwise it's removed so it's not retried forever.
+                    debug!("failed to read spooled profile with {:?}", e);
+                    let _ = spool.remove(&path);
+                    continue;
+                }
+            };
+
+            match send(payload) {
+                SendOutcome::Sent => {
+                    failures = 0;
+                    stats.sent.fetch_add(1, Ordering::Relaxed);
+                    if let Err(e) = spool.remove(&path) {
+                        error!("failed to remove spooled profile with {:?}", e);
+                    }
+                }
+                SendOutcome::Rejected => {
+                    failures = 0;
+                    stats.rejected.fetch_add(1, Ordering::Relaxed);
+                    if let Err(e) = spool.remove(&path) {
+                        error!("failed to remove spooled profile with {:?}", e);
+                    }
+                }
+                SendOutcome::Retry(delay) => {
+                    stats.failed.fetch_add(1, Ordering::Relaxed);
+                    let backoff = delay
+                        .map(|delay| delay.min(MAX_BACKOFF))
+                        .unwrap_or_else(|| backoff_with_jitter(failures));
+                    failures = failures.saturating_add(1);
+                    warn!("failed to send profile, retrying in {:?}", backoff);
+                    thread::sleep(backoff);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_spool_order() {
+        let dir = TempDir::new().unwrap();
+        let spool = Spool::new(dir.path(), 1024, Duration::from_secs(60)).unwrap();
+        assert!(spool.is_empty().unwrap());
+
+        spool.push(b"first").unwrap();
+        spool.push(b"second").unwrap();
+
+        let oldest = spool.oldest().unwrap().unwrap();
+        assert_eq!(fs::read(&oldest).unwrap(), b"first");
+        spool.remove(&oldest).unwrap();
+
+        let oldest = spool.oldest().unwrap().unwrap();
+        assert_eq!(fs::read(&oldest).unwrap(), b"second");
+        spool.remove(&oldest).unwrap();
+
+        assert!(spool.is_empty().unwrap());
+        assert_eq!(spool.stats().dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_spool_size_limit() {
+        let dir = TempDir::new().unwrap();
+        let spool = Spool::new(dir.path(), 10, Duration::from_secs(60)).unwrap();
+
+        spool.push(b"12345").unwrap();
+        spool.push(b"67890").unwrap();
+        spool.push(b"abcde").unwrap();
+
+        assert_eq!(spool.entries().unwrap().len(), 2);
+        assert_eq!(
+            fs::read(spool.oldest().unwrap().unwrap()).unwrap(),
+            b"67890"
+        );
+        assert_eq!(spool.stats().dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_spool_age_limit() {
+        let dir = TempDir::new().unwrap();
+        let spool = Spool::new(dir.path(), 1024, Duration::from_secs(60)).unwrap();
+
+        // An entry written an hour ago by a previous run.
+        let created_at = SystemTime::now() - Duration::from_secs(60 * 60);
+        let nanos = created_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        fs::write(
+            dir.path().join(format!("{nanos:020}-0000000000.pprof")),
+            b"old",
+        )
+        .unwrap();
+        // Partially written entries are ignored.
+        fs::write(dir.path().join("partial.tmp"), b"partial").unwrap();
+
+        spool.push(b"new").unwrap();
+
+        assert_eq!(spool.entries().unwrap().len(), 1);
+        assert_eq!(fs::read(spool.oldest().unwrap().unwrap()).unwrap(), b"new");
+        assert_eq!(spool.stats().dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_backoff_with_jitter() {
+        for failures in 0..4 {
+            let backoff = backoff_with_jitter(failures);
+            let max = INITIAL_BACKOFF * 2_u32.pow(failures);
+            assert!(backoff >= max / 2 && backoff <= max);
+        }
+        assert!(backoff_with_jitter(100) <= MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_spool_sender() {
+        let dir = TempDir::new().unwrap();
+        let spool = Spool::new(dir.path(), 1024, Duration::from_secs(60)).unwrap();
+        let (sent, received) = crossbeam_channel::unbounded();
+        let sender = SpoolSender::start(spool, move |payload| {
+            sent.send(payload).unwrap();
+            SendOutcome::Sent
+        })
+        .unwrap();
+
+        sender.push(b"profile").unwrap();
+
+        assert_eq!(
+            received.recv_timeout(Duration::from_secs(5)).unwrap(),
+            b"profile"
+        );
+        assert!(sender.wait_until_empty(Duration::from_secs(5)));
+        assert_eq!(sender.stats().sent.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_spool_sender_rejected() {
+        let dir = TempDir::new().unwrap();
+        let spool = Spool::new(dir.path(), 1024, Duration::from_secs(60)).unwrap();
+        let sender = SpoolSender::start(spool, |_| SendOutcome::Rejected).unwrap();
+
+        sender.push(b"profile").unwrap();
+
+        assert!(sender.wait_until_empty(Duration::from_secs(5)));
+        assert_eq!(sender.stats().sent.load(Ordering::Relaxed), 0);
+        assert_eq!(sender.stats().rejected.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_spool_sender_age_limit() {
+        let dir = TempDir::new().unwrap();
+        let created_at = SystemTime::now() - Duration::from_secs(60 * 60);
+        let nanos = created_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        fs::write(
+            dir.path().join(format!("{nanos:020}-0000000000.pprof")),
+            b"old",
+        )
+        .unwrap();
+
+        let spool = Spool::new(dir.path(), 1024, Duration::from_secs(60)).unwrap();
+        let (sent, received) = crossbeam_channel::unbounded();
+        let sender = SpoolSender::start(spool, move |payload| {
+            sent.send(payload).unwrap();
+            SendOutcome::Sent
+        })
+        .unwrap();
+
+        assert!(sender.wait_until_empty(Duration::from_secs(5)));
+        assert!(received.try_recv().is_err());
+        assert_eq!(sender.stats().dropped.load(Ordering::Relaxed), 1);
+    }
+}
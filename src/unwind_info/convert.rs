@@ -1,10 +1,12 @@
 use std::fs::File;
+use std::path::PathBuf;
 
 use anyhow::Result;
 use gimli::{
-    CfaRule, CieOrFde, EhFrame, Encoding, Format,
+    BaseAddresses, CallFrameInstruction, CallFrameInstructionIter, CfaRule, CieOrFde, DebugFrame,
+    EhFrame, Encoding, EndianSlice, Expression, Format,
     Operation::{Deref, PlusConstant, RegisterOffset},
-    UnwindContext, UnwindSection,
+    Reader, Register, RunTimeEndian, UnwindContext, UnwindOffset, UnwindSection,
 };
 use memmap2::Mmap;
 use object::Architecture;
@@ -12,13 +14,14 @@ use object::{Object, ObjectSection};
 use thiserror::Error;
 use tracing::{debug, error, span, Level};
 
+use crate::unwind_info::debug_file::find_debug_file;
 use crate::unwind_info::optimize::remove_redundant;
 use crate::unwind_info::optimize::remove_unnecesary_markers;
 use crate::unwind_info::types::*;
 
 #[derive(Debug, Error)]
 pub enum UnwindInfoError {
-    #[error("no eh_frame section found")]
+    #[error("no eh_frame or debug_frame section found")]
     NoEhFrameSection,
     #[error("object file could not be parsed due to {0}")]
     ParsingObjectFile(String),
@@ -34,9 +37,32 @@ pub enum UnwindData {
     Instruction(CompactUnwindRow),
 }
 
+const EXPRESSION_ENCODING: Encoding = Encoding {
+    format: Format::Dwarf64,
+    version: 4,
+    address_size: 8,
+};
+
+/// The section a frame description entry was found in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameSection {
+    EhFrame,
+    DebugFrame,
+}
+
+/// Address range covered by a frame description entry and its offset within its section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FdeRange {
+    start: u64,
+    end: u64,
+    section: FrameSection,
+    offset: usize,
+}
+
 // Ideally this interface should do most of the preparatory work in the
 // constructor but this is complicated by the various lifetimes.
 pub struct CompactUnwindInfoBuilder<'a> {
+    path: PathBuf,
     mmap: Mmap,
     callback: Box<dyn FnMut(&UnwindData) + 'a>,
     first_frame_override: Option<(u64, u64)>,
@@ -52,217 +78,528 @@ impl<'a> CompactUnwindInfoBuilder<'a> {
         let mmap = unsafe { memmap2::Mmap::map(&in_file)? };
 
         Ok(Self {
+            path: PathBuf::from(path),
             mmap,
             callback: Box::new(callback),
             first_frame_override,
         })
     }
 
+    /// Reads the unwind information from `.eh_frame` as well as from `.debug_frame`,
+    /// which might be in a separate debug file. When both are present, `.debug_frame`
+    /// is only used for the code `.eh_frame` doesn't cover.
     pub fn process(mut self) -> Result<(), anyhow::Error> {
         let _span = span!(Level::DEBUG, "processing unwind info").entered();
 
         let object_file = object::File::parse(&self.mmap[..])
             .map_err(|e| UnwindInfoError::ParsingObjectFile(e.to_string()))?;
 
-        let eh_frame_section = object_file
-            .section_by_name(".eh_frame")
-            .ok_or(UnwindInfoError::NoEhFrameSection)?;
+        let eh_frame_section = object_file.section_by_name(".eh_frame");
+        let debug_frame_section = object_file.section_by_name(".debug_frame");
+
+        // Stripped binaries might have their `.debug_frame` in a separate debug file.
+        let debug_file = if debug_frame_section.is_none() {
+            find_debug_file(&self.path, &object_file)
+        } else {
+            None
+        };
+        let debug_object_file = debug_file
+            .as_ref()
+            .and_then(|debug_file| object::File::parse(&debug_file[..]).ok());
+
+        let eh_frame_data = match &eh_frame_section {
+            Some(section) => section.uncompressed_data()?,
+            None => Default::default(),
+        };
+        let debug_frame_data = match (&debug_frame_section, &debug_object_file) {
+            (Some(section), _) => section.uncompressed_data()?,
+            (None, Some(debug_object_file)) => {
+                match debug_object_file.section_by_name(".debug_frame") {
+                    Some(section) => section.uncompressed_data()?,
+                    None => Default::default(),
+                }
+            }
+            (None, None) => Default::default(),
+        };
+
+        if eh_frame_section.is_none() && debug_frame_data.is_empty() {
+            return Err(UnwindInfoError::NoEhFrameSection.into());
+        }
 
         let text = object_file
             .section_by_name(".text")
             .ok_or(UnwindInfoError::NoTextSection)?;
 
-        let bases = gimli::BaseAddresses::default()
-            .set_eh_frame(eh_frame_section.address())
-            .set_text(text.address());
+        let mut bases = BaseAddresses::default().set_text(text.address());
+        if let Some(eh_frame_section) = &eh_frame_section {
+            bases = bases.set_eh_frame(eh_frame_section.address());
+        }
 
         let endian = if object_file.is_little_endian() {
-            gimli::RunTimeEndian::Little
+            RunTimeEndian::Little
         } else {
-            gimli::RunTimeEndian::Big
+            RunTimeEndian::Big
         };
 
-        let eh_frame_data = &eh_frame_section.uncompressed_data()?;
-
-        let mut eh_frame = EhFrame::new(eh_frame_data, endian);
+        let mut eh_frame = EhFrame::new(&eh_frame_data, endian);
+        let mut debug_frame = DebugFrame::new(&debug_frame_data, endian);
+        debug_frame.set_address_size(8);
         if object_file.architecture() == Architecture::Aarch64 {
             eh_frame.set_vendor(gimli::Vendor::AArch64);
+            debug_frame.set_vendor(gimli::Vendor::AArch64);
         }
-        let mut entries_iter = eh_frame.entries(&bases);
 
-        let mut cur_cie = None;
-        let mut pc_and_fde_offset = Vec::new();
-
-        let frame_pointer = if object_file.architecture() == Architecture::Aarch64 {
-            ARM64_FP
-        } else {
-            X86_FP
+        let fde_ranges = {
+            let _span = span!(Level::DEBUG, "sort pc and fdes").entered();
+            merge_fde_ranges(
+                fde_ranges(&eh_frame, &bases, FrameSection::EhFrame),
+                fde_ranges(&debug_frame, &bases, FrameSection::DebugFrame),
+            )
         };
-        let stack_pointer = if object_file.architecture() == Architecture::Aarch64 {
-            ARM64_SP
+
+        let row_converter = if object_file.architecture() == Architecture::Aarch64 {
+            RowConverter {
+                frame_pointer: ARM64_FP,
+                stack_pointer: ARM64_SP,
+                link_register: Some(ARM64_LR),
+                cfa_registers: &[],
+                first_frame_override: self.first_frame_override,
+            }
         } else {
-            X86_SP
+            RowConverter {
+                frame_pointer: X86_FP,
+                stack_pointer: X86_SP,
+                link_register: None,
+                cfa_registers: &[X86_R10, X86_R13],
+                first_frame_override: self.first_frame_override,
+            }
         };
 
-        while let Ok(Some(entry)) = entries_iter.next() {
-            match entry {
-                CieOrFde::Cie(cie) => {
-                    cur_cie = Some(cie);
-                }
-                CieOrFde::Fde(partial_fde) => {
-                    let fde = partial_fde.parse(|eh_frame, bases, cie_offset| {
-                        if let Some(cie) = &cur_cie {
-                            if cie.offset() == cie_offset.0 {
-                                return Ok(cie.clone());
-                            }
-                        }
-                        let cie = eh_frame.cie_from_offset(bases, cie_offset);
-                        if let Ok(cie) = &cie {
-                            cur_cie = Some(cie.clone());
-                        }
-                        cie
-                    });
+        let mut ctx = Box::new(UnwindContext::new());
+        for fde_range in fde_ranges {
+            match fde_range.section {
+                FrameSection::EhFrame => row_converter.process_fde(
+                    &eh_frame,
+                    &bases,
+                    fde_range.offset,
+                    &mut ctx,
+                    &mut self.callback,
+                )?,
+                FrameSection::DebugFrame => row_converter.process_fde(
+                    &debug_frame,
+                    &bases,
+                    fde_range.offset,
+                    &mut ctx,
+                    &mut self.callback,
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
 
-                    if let Ok(fde) = fde {
-                        pc_and_fde_offset.push((fde.initial_address(), fde.offset()));
+/// Returns the address ranges of all the frame description entries in a section.
+fn fde_ranges<'d, S: UnwindSection<EndianSlice<'d, RunTimeEndian>>>(
+    section: &S,
+    bases: &BaseAddresses,
+    frame_section: FrameSection,
+) -> Vec<FdeRange> {
+    let mut entries_iter = section.entries(bases);
+    let mut cur_cie = None;
+    let mut fde_ranges = Vec::new();
+
+    while let Ok(Some(entry)) = entries_iter.next() {
+        match entry {
+            CieOrFde::Cie(cie) => {
+                cur_cie = Some(cie);
+            }
+            CieOrFde::Fde(partial_fde) => {
+                let fde = partial_fde.parse(|section, bases, cie_offset| {
+                    if let Some(cie) = &cur_cie {
+                        if cie.offset() == UnwindOffset::into(cie_offset) {
+                            return Ok(cie.clone());
+                        }
+                    }
+                    let cie = section.cie_from_offset(bases, cie_offset);
+                    if let Ok(cie) = &cie {
+                        cur_cie = Some(cie.clone());
                     }
+                    cie
+                });
+
+                if let Ok(fde) = fde {
+                    fde_ranges.push(FdeRange {
+                        start: fde.initial_address(),
+                        end: fde.initial_address() + fde.len(),
+                        section: frame_section,
+                        offset: fde.offset(),
+                    });
                 }
             }
         }
+    }
 
-        {
-            let _span = span!(Level::DEBUG, "sort pc and fdes").entered();
-            pc_and_fde_offset.sort_by_key(|(pc, _)| *pc);
+    fde_ranges
+}
+
+/// Merges the `.eh_frame` and `.debug_frame` entries, sorted by address. `.debug_frame`
+/// entries are only kept if they don't overlap with any `.eh_frame` one.
+fn merge_fde_ranges(mut eh_frame: Vec<FdeRange>, debug_frame: Vec<FdeRange>) -> Vec<FdeRange> {
+    eh_frame.sort_by_key(|fde_range| fde_range.start);
+
+    let mut merged = eh_frame.clone();
+    for fde_range in debug_frame {
+        let preceding =
+            eh_frame.partition_point(|eh_frame_range| eh_frame_range.start < fde_range.end);
+        let overlaps = preceding > 0 && eh_frame[preceding - 1].end > fde_range.start;
+        if !overlaps {
+            merged.push(fde_range);
         }
+    }
 
-        let mut ctx = Box::new(UnwindContext::new());
-        for (_, fde_offset) in pc_and_fde_offset {
-            let fde = eh_frame.fde_from_offset(
-                &bases,
-                gimli::EhFrameOffset(fde_offset),
-                EhFrame::cie_from_offset,
-            )?;
-
-            (self.callback)(&UnwindData::Function(
-                fde.initial_address(),
-                fde.initial_address() + fde.len(),
-            ));
-
-            let mut table = fde.rows(&eh_frame, &bases, &mut ctx)?;
-
-            loop {
-                let mut compact_row = CompactUnwindRow::default();
-
-                match table.next_row() {
-                    Ok(None) => break,
-                    Ok(Some(row)) => {
-                        compact_row.pc = row.start_address();
-                        match row.cfa() {
-                            CfaRule::RegisterAndOffset { register, offset } => {
-                                if register == &frame_pointer {
-                                    compact_row.cfa_type = CfaType::FramePointerOffset;
-                                } else if register == &stack_pointer {
-                                    compact_row.cfa_type = CfaType::StackPointerOffset;
-                                } else {
-                                    compact_row.cfa_type = CfaType::UnsupportedRegisterOffset;
+    merged.sort_by_key(|fde_range| fde_range.start);
+    merged
+}
+
+/// Converts the unwind rows of frame description entries to our compact format.
+struct RowConverter {
+    frame_pointer: Register,
+    stack_pointer: Register,
+    /// Register holding the return address on function entry, on architectures that
+    /// don't push it to the stack.
+    link_register: Option<Register>,
+    /// Registers other than the frame and stack pointers the CFA can be based on, which
+    /// the BPF unwinder knows for the first frame. Used by code realigning the stack.
+    cfa_registers: &'static [Register],
+    first_frame_override: Option<(u64, u64)>,
+}
+
+impl RowConverter {
+    fn process_fde<'d, S: UnwindSection<EndianSlice<'d, RunTimeEndian>>>(
+        &self,
+        section: &S,
+        bases: &BaseAddresses,
+        fde_offset: usize,
+        ctx: &mut UnwindContext<usize>,
+        callback: &mut dyn FnMut(&UnwindData),
+    ) -> Result<()> {
+        let frame_pointer = self.frame_pointer;
+        let stack_pointer = self.stack_pointer;
+
+        let fde =
+            section.fde_from_offset(bases, S::Offset::from(fde_offset), S::cie_from_offset)?;
+
+        callback(&UnwindData::Function(
+            fde.initial_address(),
+            fde.initial_address() + fde.len(),
+        ));
+
+        // Rows without a rule for the link register have the return address in it,
+        // unless it's explicitly undefined, which is how the outermost frame is marked.
+        // Both look the same once the rows are evaluated.
+        let undefined_return_address = self.link_register.is_some_and(|link_register| {
+            undefines_register(fde.cie().instructions(section, bases), link_register)
+                || undefines_register(fde.instructions(section, bases), link_register)
+        });
+
+        let mut table = fde.rows(section, bases, ctx)?;
+
+        loop {
+            let mut compact_row = CompactUnwindRow::default();
+
+            match table.next_row() {
+                Ok(None) => break,
+                Ok(Some(row)) => {
+                    compact_row.pc = row.start_address();
+                    let return_address_expression =
+                        match row.register(fde.cie().return_address_register()) {
+                            gimli::RegisterRule::Expression(exp) => exp.get(section).ok(),
+                            _ => None,
+                        };
+
+                    match row.cfa() {
+                        CfaRule::RegisterAndOffset { register, offset } => {
+                            if register == &frame_pointer {
+                                compact_row.cfa_type = CfaType::FramePointerOffset;
+                            } else if register == &stack_pointer {
+                                compact_row.cfa_type = CfaType::StackPointerOffset;
+                            } else {
+                                compact_row.cfa_type = CfaType::UnsupportedRegisterOffset;
+                            }
+
+                            match u16::try_from(*offset) {
+                                Ok(off) => {
+                                    compact_row.cfa_offset = off;
+                                }
+                                Err(_) => {
+                                    compact_row.cfa_type = CfaType::OffsetDidNotFit;
                                 }
+                            }
 
-                                match u16::try_from(*offset) {
+                            if self.cfa_registers.contains(register) {
+                                match u8::try_from(*offset) {
                                     Ok(off) => {
-                                        compact_row.cfa_offset = off;
+                                        compact_row.cfa_type = CfaType::RegisterOffset;
+                                        compact_row.cfa_offset = (register.0 << 8) | off as u16;
                                     }
                                     Err(_) => {
                                         compact_row.cfa_type = CfaType::OffsetDidNotFit;
                                     }
                                 }
                             }
-                            CfaRule::Expression(exp) => {
-                                compact_row.cfa_type = CfaType::UnsupportedExpression;
-
-                                if let Ok(expression) = exp.get(&eh_frame) {
-                                    let expression_data = expression.0.slice();
-                                    if expression_data == *PLT1 {
-                                        compact_row.cfa_type = CfaType::Plt1;
-                                    } else if expression_data == *PLT2 {
-                                        compact_row.cfa_type = CfaType::Plt2;
-                                    } else {
-                                        let mut ops = expression.operations(Encoding {
-                                            format: Format::Dwarf64,
-                                            version: 4,
-                                            address_size: 8,
-                                        });
-
-                                        match (ops.next(), ops.next(), ops.next(), ops.next()) {
-                                            (
-                                                Ok(Some(RegisterOffset {
-                                                    register, offset, ..
-                                                })),
-                                                Ok(Some(Deref { .. })),
-                                                Ok(Some(PlusConstant { value: addition })),
-                                                Ok(None),
-                                            ) if register == stack_pointer => {
-                                                debug!("*(rsp+{offset})+{addition}");
-                                                compact_row.cfa_type = CfaType::DerefAndAdd;
-                                                // Assumes that both the offset and addition will fit in 2 bytes,
-                                                // which seems to be the case for many binaries I've tried but
-                                                // would be good to test against larger ones.
-                                                compact_row.cfa_offset =
-                                                    ((offset as u16) << 8) | (addition as u16);
-                                            }
-                                            _ => {}
-                                        }
-                                    }
+                        }
+                        CfaRule::Expression(exp) => {
+                            compact_row.cfa_type = CfaType::UnsupportedExpression;
+
+                            if let Ok(expression) = exp.get(section) {
+                                let expression_data = expression.0.slice();
+                                if expression_data == *PLT1 {
+                                    compact_row.cfa_type = CfaType::Plt1;
+                                } else if expression_data == *PLT2 {
+                                    compact_row.cfa_type = CfaType::Plt2;
+                                } else {
+                                    (compact_row.cfa_type, compact_row.cfa_offset) = self
+                                        .convert_cfa_expression(
+                                            expression,
+                                            return_address_expression,
+                                        );
                                 }
                             }
-                        };
+                        }
+                    };
 
-                        match row.register(frame_pointer) {
-                            gimli::RegisterRule::Undefined => {}
-                            gimli::RegisterRule::Offset(offset) => {
-                                compact_row.rbp_type = RbpType::CfaOffset;
+                    match row.register(frame_pointer) {
+                        gimli::RegisterRule::Undefined => {}
+                        gimli::RegisterRule::Offset(offset) => {
+                            compact_row.rbp_type = RbpType::CfaOffset;
 
-                                match i16::try_from(offset) {
-                                    Ok(off) => {
-                                        compact_row.rbp_offset = off;
-                                    }
-                                    Err(_) => {
-                                        compact_row.rbp_type = RbpType::OffsetDidNotFit;
-                                    }
+                            match i16::try_from(offset) {
+                                Ok(off) => {
+                                    compact_row.rbp_offset = off;
+                                }
+                                Err(_) => {
+                                    compact_row.rbp_type = RbpType::OffsetDidNotFit;
                                 }
                             }
-                            gimli::RegisterRule::Register(_reg) => {
-                                compact_row.rbp_type = RbpType::Register;
-                            }
-                            gimli::RegisterRule::Expression(_) => {
-                                compact_row.rbp_type = RbpType::Expression;
-                            }
-                            _ => {
-                                // print!(", rbp unsupported {:?}", rbp);
+                        }
+                        gimli::RegisterRule::Register(_reg) => {
+                            compact_row.rbp_type = RbpType::Register;
+                        }
+                        gimli::RegisterRule::Expression(exp) => {
+                            compact_row.rbp_type = RbpType::Expression;
+
+                            if let Ok(expression) = exp.get(section) {
+                                (compact_row.rbp_type, compact_row.rbp_offset) =
+                                    self.convert_rbp_expression(expression);
                             }
                         }
+                        _ => {
+                            // print!(", rbp unsupported {:?}", rbp);
+                        }
+                    }
 
-                        if row.register(fde.cie().return_address_register())
-                            == gimli::RegisterRule::Undefined
-                        {
-                            compact_row.rbp_type = RbpType::UndefinedReturnAddress;
+                    match self.link_register {
+                        Some(link_register) => {
+                            compact_row.ra_type = self.convert_link_register_rule(
+                                row.register(link_register),
+                                &mut compact_row,
+                            );
+                            if compact_row.ra_type == RaType::LinkRegister
+                                && undefined_return_address
+                            {
+                                compact_row.rbp_type = RbpType::UndefinedReturnAddress;
+                                compact_row.ra_type = RaType::FrameRecord;
+                            }
+                        }
+                        None => {
+                            if row.register(fde.cie().return_address_register())
+                                == gimli::RegisterRule::Undefined
+                            {
+                                compact_row.rbp_type = RbpType::UndefinedReturnAddress;
+                            }
                         }
                     }
-                    _ => continue,
                 }
+                _ => continue,
+            }
+
+            if let Some(first_frame_override) = self.first_frame_override {
+                if compact_row.pc == first_frame_override.0 {
+                    compact_row = CompactUnwindRow::stop_unwinding(compact_row.pc);
+                }
+            }
 
-                if let Some(first_frame_override) = self.first_frame_override {
-                    if compact_row.pc == first_frame_override.0 {
-                        compact_row = CompactUnwindRow::stop_unwinding(compact_row.pc);
+            callback(&UnwindData::Instruction(compact_row));
+        }
+        Ok(())
+    }
+
+    /// Returns where the return address is given the rule for the link register. The
+    /// BPF unwinder finds it next to the saved frame pointer, or, if the frame pointer
+    /// wasn't saved, at the offset from the CFA stored in the row's RBP offset.
+    fn convert_link_register_rule(
+        &self,
+        rule: gimli::RegisterRule<usize>,
+        compact_row: &mut CompactUnwindRow,
+    ) -> RaType {
+        match rule {
+            gimli::RegisterRule::Undefined | gimli::RegisterRule::SameValue => RaType::LinkRegister,
+            gimli::RegisterRule::Offset(offset)
+                if compact_row.rbp_type == RbpType::CfaOffset
+                    && offset == compact_row.rbp_offset as i64 + 8 =>
+            {
+                RaType::FrameRecord
+            }
+            gimli::RegisterRule::Offset(offset) if compact_row.rbp_type == RbpType::Unchanged => {
+                match i16::try_from(offset) {
+                    Ok(offset) => {
+                        compact_row.rbp_offset = offset;
+                        RaType::CfaOffset
+                    }
+                    Err(_) => {
+                        compact_row.rbp_type = RbpType::OffsetDidNotFit;
+                        RaType::FrameRecord
                     }
                 }
+            }
+            _ => RaType::Unsupported,
+        }
+    }
 
-                (self.callback)(&UnwindData::Instruction(compact_row));
+    /// Encodes the CFA expressions the BPF unwinder can evaluate, returning the CFA type
+    /// and offset of the row.
+    fn convert_cfa_expression<R: Reader>(
+        &self,
+        expression: Expression<R>,
+        return_address_expression: Option<Expression<R>>,
+    ) -> (CfaType, u16) {
+        let mut ops = expression.operations(EXPRESSION_ENCODING);
+
+        match (ops.next(), ops.next(), ops.next(), ops.next()) {
+            (
+                Ok(Some(RegisterOffset {
+                    register, offset, ..
+                })),
+                Ok(Some(Deref { .. })),
+                Ok(Some(PlusConstant { value: addition })),
+                Ok(None),
+            ) if register == self.stack_pointer => {
+                debug!("*(rsp+{offset})+{addition}");
+                // Assumes that both the offset and addition will fit in 2 bytes,
+                // which seems to be the case for many binaries I've tried but
+                // would be good to test against larger ones.
+                (
+                    CfaType::DerefAndAdd,
+                    ((offset as u16) << 8) | (addition as u16),
+                )
+            }
+            // Signal trampolines, such as glibc's `__restore_rt`. The registers of the
+            // interrupted code are stored in the `ucontext` the kernel pushed to the stack,
+            // with the program counter right after the stack pointer.
+            (
+                Ok(Some(RegisterOffset {
+                    register, offset, ..
+                })),
+                Ok(Some(Deref { .. })),
+                Ok(None),
+                _,
+            ) if register == self.stack_pointer
+                && return_address_expression
+                    .and_then(|expression| self.register_offset(expression))
+                    == Some((self.stack_pointer, offset + 8)) =>
+            {
+                match u16::try_from(offset) {
+                    Ok(offset) => (CfaType::SignalFrame, offset),
+                    Err(_) => (CfaType::OffsetDidNotFit, 0),
+                }
             }
+            (
+                Ok(Some(RegisterOffset {
+                    register, offset, ..
+                })),
+                Ok(Some(Deref { .. })),
+                Ok(None),
+                _,
+            ) if register == self.stack_pointer => match u8::try_from(offset) {
+                Ok(offset) => (CfaType::DerefAndAdd, (offset as u16) << 8),
+                Err(_) => (CfaType::OffsetDidNotFit, 0),
+            },
+            // Code realigning the stack saves the CFA, which is usually computed from
+            // another register, in its frame, e.g. `*($rbp - 8)`.
+            (
+                Ok(Some(RegisterOffset {
+                    register, offset, ..
+                })),
+                Ok(Some(Deref { .. })),
+                addition,
+                end,
+            ) if register == self.frame_pointer => {
+                let addition = match (addition, end) {
+                    (Ok(None), _) => Some(0),
+                    (Ok(Some(PlusConstant { value })), Ok(None)) => Some(value),
+                    _ => None,
+                };
+                match (i8::try_from(offset), addition.map(u8::try_from)) {
+                    (Ok(offset), Some(Ok(addition))) => (
+                        CfaType::FramePointerDerefAndAdd,
+                        ((offset as u8 as u16) << 8) | addition as u16,
+                    ),
+                    (_, Some(_)) => (CfaType::OffsetDidNotFit, 0),
+                    (_, None) => (CfaType::UnsupportedExpression, 0),
+                }
+            }
+            _ => (CfaType::UnsupportedExpression, 0),
+        }
+    }
+
+    /// Encodes expressions for the address the frame pointer is saved at, returning the
+    /// RBP type and offset of the row.
+    fn convert_rbp_expression<R: Reader>(&self, expression: Expression<R>) -> (RbpType, i16) {
+        let (rbp_type, offset) = match self.register_offset(expression) {
+            Some((register, offset)) if register == self.frame_pointer => {
+                (RbpType::FramePointerOffset, offset)
+            }
+            Some((register, offset)) if register == self.stack_pointer => {
+                (RbpType::StackPointerOffset, offset)
+            }
+            _ => return (RbpType::Expression, 0),
+        };
+
+        match i16::try_from(offset) {
+            Ok(offset) => (rbp_type, offset),
+            Err(_) => (RbpType::OffsetDidNotFit, 0),
+        }
+    }
+
+    /// Matches expressions made of a single `DW_OP_breg`.
+    fn register_offset<R: Reader>(&self, expression: Expression<R>) -> Option<(Register, i64)> {
+        let mut ops = expression.operations(EXPRESSION_ENCODING);
+        match (ops.next(), ops.next()) {
+            (
+                Ok(Some(RegisterOffset {
+                    register, offset, ..
+                })),
+                Ok(None),
+            ) => Some((register, offset)),
+            _ => None,
         }
-        Ok(())
     }
 }
 
+/// Whether any of the instructions sets the rule of a register to undefined.
+fn undefines_register<R: Reader>(
+    mut instructions: CallFrameInstructionIter<R>,
+    register: Register,
+) -> bool {
+    while let Ok(Some(instruction)) = instructions.next() {
+        if matches!(instruction, CallFrameInstruction::Undefined { register: undefined } if undefined == register)
+        {
+            return true;
+        }
+    }
+    false
+}
+
 pub fn compact_unwind_info(
     path: &str,
     first_frame_override: Option<(u64, u64)>,
@@ -294,6 +631,7 @@ pub fn compact_unwind_info(
                         cfa_type: compact_row.cfa_type,
                         rbp_type: compact_row.rbp_type,
                         rbp_offset: compact_row.rbp_offset,
+                        ra_type: compact_row.ra_type,
                     };
                     unwind_info.push(row);
                     last_row = Some(*compact_row)
@@ -325,3 +663,166 @@ pub fn compact_unwind_info(
 
     Ok(unwind_info)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fde_range(start: u64, end: u64, section: FrameSection) -> FdeRange {
+        FdeRange {
+            start,
+            end,
+            section,
+            offset: start as usize,
+        }
+    }
+
+    fn expression(data: &[u8]) -> Expression<EndianSlice<'_, RunTimeEndian>> {
+        Expression(EndianSlice::new(data, RunTimeEndian::Little))
+    }
+
+    fn x86_row_converter() -> RowConverter {
+        RowConverter {
+            frame_pointer: X86_FP,
+            stack_pointer: X86_SP,
+            link_register: None,
+            cfa_registers: &[X86_R10, X86_R13],
+            first_frame_override: None,
+        }
+    }
+
+    #[test]
+    fn test_convert_cfa_expression() {
+        use gimli::constants::*;
+        let row_converter = x86_row_converter();
+
+        // *($rsp + 8) + 16
+        let deref_and_add = [DW_OP_breg7.0, 8, DW_OP_deref.0, DW_OP_plus_uconst.0, 16];
+        assert_eq!(
+            row_converter.convert_cfa_expression(expression(&deref_and_add), None),
+            (CfaType::DerefAndAdd, (8 << 8) | 16)
+        );
+
+        // glibc's `__restore_rt`: *($rsp + 160), with the return address at $rsp + 168.
+        let signal_frame = [DW_OP_breg7.0, 0xa0, 0x01, DW_OP_deref.0];
+        let return_address = [DW_OP_breg7.0, 0xa8, 0x01];
+        assert_eq!(
+            row_converter.convert_cfa_expression(
+                expression(&signal_frame),
+                Some(expression(&return_address))
+            ),
+            (CfaType::SignalFrame, 160)
+        );
+        assert_eq!(
+            row_converter.convert_cfa_expression(expression(&signal_frame), None),
+            (CfaType::DerefAndAdd, 160 << 8)
+        );
+
+        // Stack realignment: *($rbp - 8).
+        let realigned = [DW_OP_breg6.0, 0x78, DW_OP_deref.0];
+        assert_eq!(
+            row_converter.convert_cfa_expression(expression(&realigned), None),
+            (CfaType::FramePointerDerefAndAdd, 0xf8 << 8)
+        );
+
+        // *($rbp - 8) + 8 * $rax is not supported.
+        let unsupported = [
+            DW_OP_breg6.0,
+            0x78,
+            DW_OP_deref.0,
+            DW_OP_breg0.0,
+            0,
+            DW_OP_plus.0,
+        ];
+        assert_eq!(
+            row_converter.convert_cfa_expression(expression(&unsupported), None),
+            (CfaType::UnsupportedExpression, 0)
+        );
+    }
+
+    #[test]
+    fn test_convert_rbp_expression() {
+        use gimli::constants::*;
+        let row_converter = x86_row_converter();
+
+        assert_eq!(
+            row_converter.convert_rbp_expression(expression(&[DW_OP_breg6.0, 0])),
+            (RbpType::FramePointerOffset, 0)
+        );
+        assert_eq!(
+            row_converter.convert_rbp_expression(expression(&[DW_OP_breg7.0, 0xf8, 0x00])),
+            (RbpType::StackPointerOffset, 120)
+        );
+        assert_eq!(
+            row_converter.convert_rbp_expression(expression(&[DW_OP_breg7.0, 8, DW_OP_deref.0])),
+            (RbpType::Expression, 0)
+        );
+    }
+
+    fn format_unwind_info(unwind_info: &[CompactUnwindRow]) -> String {
+        unwind_info
+            .iter()
+            .map(|row| {
+                let (pc, cfa_type, cfa_offset) = (row.pc, row.cfa_type, row.cfa_offset);
+                let (rbp_type, rbp_offset, ra_type) = (row.rbp_type, row.rbp_offset, row.ra_type);
+                format!(
+                    "pc: {pc:x} cfa: {cfa_type:?} {cfa_offset} rbp: {rbp_type:?} {rbp_offset} ra: {ra_type:?}"
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_compact_unwind_info_arm64() {
+        let unwind_info = compact_unwind_info("tests/testdata/arm64/unwind", None).unwrap();
+        insta::assert_snapshot!(format_unwind_info(&unwind_info), @r"
+        pc: 21023c cfa: StackPointerOffset 0 rbp: UndefinedReturnAddress 0 ra: FrameRecord
+        pc: 210250 cfa: StackPointerOffset 0 rbp: Unchanged 0 ra: LinkRegister
+        pc: 210254 cfa: StackPointerOffset 32 rbp: CfaOffset -32 ra: FrameRecord
+        pc: 210258 cfa: FramePointerOffset 32 rbp: CfaOffset -32 ra: FrameRecord
+        pc: 21026c cfa: StackPointerOffset 0 rbp: Unchanged 0 ra: LinkRegister
+        pc: 210280 cfa: StackPointerOffset 16 rbp: CfaOffset -16 ra: FrameRecord
+        pc: 21028c cfa: StackPointerOffset 0 rbp: Unchanged 0 ra: LinkRegister
+        pc: 210298 cfa: StackPointerOffset 16 rbp: Unchanged -16 ra: CfaOffset
+        pc: 2102a0 cfa: StackPointerOffset 0 rbp: Unchanged 0 ra: LinkRegister
+        pc: 2102a8 cfa: StackPointerOffset 0 rbp: Unchanged 0 ra: Unsupported
+        pc: 2102b0 cfa: StackPointerOffset 0 rbp: Unchanged 0 ra: LinkRegister
+        pc: 2102b4 cfa: EndFdeMarker 0 rbp: Unchanged 0 ra: FrameRecord
+        ");
+    }
+
+    #[test]
+    fn test_merge_fde_ranges() {
+        let eh_frame = vec![
+            fde_range(0x300, 0x400, FrameSection::EhFrame),
+            fde_range(0x100, 0x200, FrameSection::EhFrame),
+        ];
+        let debug_frame = vec![
+            // Same function as in .eh_frame.
+            fde_range(0x100, 0x200, FrameSection::DebugFrame),
+            // Only in .debug_frame.
+            fde_range(0x200, 0x300, FrameSection::DebugFrame),
+            // Partially overlapping.
+            fde_range(0x3f0, 0x500, FrameSection::DebugFrame),
+            fde_range(0x500, 0x600, FrameSection::DebugFrame),
+        ];
+
+        assert_eq!(
+            merge_fde_ranges(eh_frame, debug_frame),
+            vec![
+                fde_range(0x100, 0x200, FrameSection::EhFrame),
+                fde_range(0x200, 0x300, FrameSection::DebugFrame),
+                fde_range(0x300, 0x400, FrameSection::EhFrame),
+                fde_range(0x500, 0x600, FrameSection::DebugFrame),
+            ]
+        );
+        assert_eq!(
+            merge_fde_ranges(
+                vec![],
+                vec![fde_range(0x100, 0x200, FrameSection::DebugFrame)]
+            ),
+            vec![fde_range(0x100, 0x200, FrameSection::DebugFrame)]
+        );
+    }
+}
@@ -0,0 +1,195 @@
+//! Binaries might be stripped with their unwind information in a separate debug file,
+//! which is found following the same conventions as GDB, see
+//! <https://sourceware.org/gdb/current/onlinedocs/gdb.html/Separate-Debug-Files.html>.
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use lightswitch_object::BuildId;
+use memmap2::Mmap;
+use object::Object;
+use tracing::debug;
+
+const DEBUG_DIRECTORY: &str = "usr/lib/debug";
+
+/// Finds and maps the separate debug file of an object, if any. Debug files found through
+/// `.gnu_debuglink` are checked against the object's build id, or the CRC in the link if
+/// the object doesn't have one.
+pub fn find_debug_file(path: &Path, object_file: &object::File) -> Option<Mmap> {
+    let build_id = object_file.build_id().ok().flatten();
+    let debuglink = object_file.gnu_debuglink().ok().flatten();
+
+    for candidate in debug_file_candidates(path, build_id, debuglink.map(|(name, _)| name)) {
+        if candidate == path {
+            continue;
+        }
+        let Ok(file) = File::open(&candidate) else {
+            continue;
+        };
+        let Ok(mmap) = (unsafe { Mmap::map(&file) }) else {
+            continue;
+        };
+
+        let matches = match (build_id, debuglink) {
+            (Some(build_id), _) => {
+                object::File::parse(&mmap[..])
+                    .ok()
+                    .and_then(|debug_file| debug_file.build_id().ok().flatten())
+                    == Some(build_id)
+            }
+            (None, Some((_, crc))) => crc32(&mmap) == crc,
+            (None, None) => false,
+        };
+        if matches {
+            debug!(
+                "using debug file {} for {}",
+                candidate.display(),
+                path.display()
+            );
+            return Some(mmap);
+        }
+    }
+
+    None
+}
+
+/// Returns the paths where the debug file of an object could be, in order of preference.
+/// Objects of processes in other mount namespaces are accessed through `/proc/<pid>/root`,
+/// and so are their debug files.
+pub fn debug_file_candidates(
+    path: &Path,
+    build_id: Option<&[u8]>,
+    debuglink: Option<&[u8]>,
+) -> Vec<PathBuf> {
+    let root = process_root(path);
+    let debug_directory = root.join(DEBUG_DIRECTORY);
+    let mut candidates = Vec::new();
+
+    if let Some(build_id) = build_id.and_then(|build_id| BuildId::gnu_from_bytes(build_id).ok()) {
+        let build_id = build_id.short();
+        candidates.push(
+            debug_directory
+                .join(".build-id")
+                .join(&build_id[..2])
+                .join(format!("{}.debug", &build_id[2..])),
+        );
+    }
+
+    if let (Some(debuglink), Some(directory)) = (debuglink, path.parent()) {
+        let debuglink = OsStr::from_bytes(debuglink);
+        candidates.push(directory.join(debuglink));
+        candidates.push(directory.join(".debug").join(debuglink));
+        let relative_directory = directory.strip_prefix(&root).unwrap_or(directory);
+        candidates.push(debug_directory.join(relative_directory).join(debuglink));
+    }
+
+    candidates
+}
+
+/// Returns `/proc/<pid>/root` for paths within it, and `/` otherwise.
+fn process_root(path: &Path) -> PathBuf {
+    let mut components = path.components();
+    match (
+        components.next(),
+        components.next(),
+        components.next(),
+        components.next(),
+    ) {
+        (
+            Some(Component::RootDir),
+            Some(Component::Normal(proc)),
+            Some(Component::Normal(pid)),
+            Some(Component::Normal(root)),
+        ) if proc == "proc"
+            && root == "root"
+            && pid.to_str().is_some_and(|pid| pid.parse::<u32>().is_ok()) =>
+        {
+            Path::new("/proc").join(pid).join(root)
+        }
+        _ => PathBuf::from("/"),
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// The CRC-32 used by `.gnu_debuglink`.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_file_candidates() {
+        let build_id = [0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89];
+        assert_eq!(
+            debug_file_candidates(
+                Path::new("/usr/bin/app"),
+                Some(&build_id),
+                Some(b"app.debug")
+            ),
+            vec![
+                PathBuf::from("/usr/lib/debug/.build-id/ab/cdef0123456789.debug"),
+                PathBuf::from("/usr/bin/app.debug"),
+                PathBuf::from("/usr/bin/.debug/app.debug"),
+                PathBuf::from("/usr/lib/debug/usr/bin/app.debug"),
+            ]
+        );
+        assert_eq!(
+            debug_file_candidates(
+                Path::new("/proc/123/root/opt/app"),
+                None,
+                Some(b"app.debug")
+            ),
+            vec![
+                PathBuf::from("/proc/123/root/opt/app.debug"),
+                PathBuf::from("/proc/123/root/opt/.debug/app.debug"),
+                PathBuf::from("/proc/123/root/usr/lib/debug/opt/app.debug"),
+            ]
+        );
+        assert!(debug_file_candidates(Path::new("/usr/bin/app"), None, None).is_empty());
+    }
+
+    #[test]
+    fn test_process_root() {
+        assert_eq!(
+            process_root(Path::new("/proc/123/root/usr/bin/app")),
+            PathBuf::from("/proc/123/root")
+        );
+        assert_eq!(
+            process_root(Path::new("/proc/self/root/usr/bin/app")),
+            PathBuf::from("/")
+        );
+        assert_eq!(process_root(Path::new("/usr/bin/app")), PathBuf::from("/"));
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
@@ -1,4 +1,5 @@
 mod convert;
+mod debug_file;
 pub mod manager;
 mod optimize;
 pub mod pages;
@@ -7,6 +8,8 @@ pub mod types;
 
 pub use convert::compact_unwind_info;
 pub use convert::CompactUnwindInfoBuilder;
+pub use convert::UnwindInfoError;
+pub use debug_file::find_debug_file;
 
 use std::fs::File;
 use std::path::PathBuf;
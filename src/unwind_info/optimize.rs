@@ -55,7 +55,8 @@ pub fn remove_redundant(unwind_info: &mut Vec<CompactUnwindRow>) {
             redundant = row.cfa_type == last_row_unwrapped.cfa_type
                 && row.cfa_offset == last_row_unwrapped.cfa_offset
                 && row.rbp_type == last_row_unwrapped.rbp_type
-                && row.rbp_offset == last_row_unwrapped.rbp_offset;
+                && row.rbp_offset == last_row_unwrapped.rbp_offset
+                && row.ra_type == last_row_unwrapped.ra_type;
         }
 
         if !redundant {
@@ -11,11 +11,12 @@ use thiserror::Error;
 
 use crate::unwind_info::compact_unwind_info;
 use crate::unwind_info::types::CompactUnwindRow;
+use crate::unwind_info::UnwindInfoError;
 
 // To identify this binary file type.
 const MAGIC_NUMBER: u32 = 0x1357531;
 // Any changes to the ABI / digest must bump the version.
-const VERSION: u32 = 2;
+const VERSION: u32 = 4;
 
 type UnwindInformationDigest = u64;
 
@@ -23,6 +24,8 @@ type UnwindInformationDigest = u64;
 pub enum WriterError {
     #[error("generic unwind info error {0}")]
     UnwindInfoGeneric(String),
+    #[error("unwind info error {0}")]
+    UnwindInfo(#[from] UnwindInfoError),
     #[error("i/o error")]
     Io(#[from] std::io::Error),
 }
@@ -84,7 +87,10 @@ impl Writer {
             &self.executable_path.to_string_lossy(),
             first_frame_override,
         )
-        .map_err(|e| WriterError::UnwindInfoGeneric(e.to_string()))
+        .map_err(|e| match e.downcast::<UnwindInfoError>() {
+            Ok(e) => WriterError::UnwindInfo(e),
+            Err(e) => WriterError::UnwindInfoGeneric(e.to_string()),
+        })
     }
 
     fn write_header(
@@ -245,6 +251,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_write_unwind_info_error() {
+        let mut buffer = Cursor::new(Vec::new());
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"not an object file").unwrap();
+        let writer = Writer::new(file.path(), None);
+        assert!(matches!(
+            writer.write(&mut buffer),
+            Err(WriterError::UnwindInfo(UnwindInfoError::ParsingObjectFile(
+                _
+            )))
+        ));
+    }
+
     #[test]
     fn test_bad_magic() {
         let mut buffer = Vec::new();
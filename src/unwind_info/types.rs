@@ -17,6 +17,13 @@ pub enum CfaType {
     EndFdeMarker = 7,
     UnsupportedRegisterOffset = 8,
     OffsetDidNotFit = 9,
+    /// Offset from one of the registers the unwinder knows for the first frame, with the
+    /// DWARF register number in the high byte of the offset.
+    RegisterOffset = 10,
+    /// `*($rbp + offset) + addition`, with the signed offset in the high byte.
+    FramePointerDerefAndAdd = 11,
+    /// Signal trampoline, the offset is where the interrupted stack pointer is saved.
+    SignalFrame = 12,
 }
 
 #[repr(u8)]
@@ -29,6 +36,27 @@ pub enum RbpType {
     Expression = 3,
     UndefinedReturnAddress = 4,
     OffsetDidNotFit = 5,
+    /// Saved at an offset from the current frame pointer.
+    FramePointerOffset = 6,
+    /// Saved at an offset from the current stack pointer.
+    StackPointerOffset = 7,
+}
+
+/// Where the return address of the previous frame is. Only used on arm64, as on
+/// x86_64 the return address is always pushed to the stack right below the CFA.
+#[repr(u8)]
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum RaType {
+    /// Saved next to the frame pointer, in the frame record.
+    #[default]
+    FrameRecord = 0,
+    /// Still in the link register, such as in leaf functions or before the frame
+    /// record is set up.
+    LinkRegister = 1,
+    /// Saved at an offset from the CFA, stored in the RBP offset, in code that doesn't
+    /// save the frame pointer.
+    CfaOffset = 2,
+    Unsupported = 3,
 }
 
 #[derive(Debug, Default, Copy, Clone, PartialEq)]
@@ -39,6 +67,7 @@ pub struct CompactUnwindRow {
     pub rbp_type: RbpType,
     pub cfa_offset: u16,
     pub rbp_offset: i16,
+    pub ra_type: RaType,
 }
 
 impl CompactUnwindRow {
@@ -57,6 +86,7 @@ impl CompactUnwindRow {
             rbp_type: RbpType::CfaOffset,
             cfa_offset: 16,
             rbp_offset: -16,
+            ra_type: RaType::FrameRecord,
         }
     }
 }
@@ -95,7 +125,10 @@ lazy_static! {
 // > Figure 3.36: DWARF Register Number Mapping
 pub const X86_FP: gimli::Register = gimli::Register(6); // Frame Pointer ($rbp)
 pub const X86_SP: gimli::Register = gimli::Register(7); // Stack Pointer ($rsp)
+pub const X86_R10: gimli::Register = gimli::Register(10);
+pub const X86_R13: gimli::Register = gimli::Register(13);
 
 // Source: https://github.com/ARM-software/abi-aa/blob/05abf4f7/aadwarf64/aadwarf64.rst#41dwarf-register-names
 pub const ARM64_FP: gimli::Register = gimli::Register(29); // Frame Pointer (x29)
+pub const ARM64_LR: gimli::Register = gimli::Register(30); // Link Register (x30)
 pub const ARM64_SP: gimli::Register = gimli::Register(31); // Stack Pointer (sp)
@@ -13,3 +13,50 @@ pub fn architecture() -> Architecture {
 pub fn architecture() -> Architecture {
     Architecture::X86
 }
+
+/// Bits of the virtual address size assumed if it can't be found, which is the
+/// most common configuration.
+const DEFAULT_ARM64_VIRTUAL_ADDRESS_BITS: u32 = 48;
+
+/// Mask of the bits of arm64 code addresses that are part of the address, the rest
+/// are used for pointer authentication codes and tags. The virtual address size
+/// depends on the kernel configuration, so it's derived from the highest userspace
+/// mapping, which is the stack.
+///
+/// Kernels with 52 bit addresses only map memory above 48 bits if requested, so
+/// those addresses will be truncated.
+pub fn arm64_address_mask() -> u64 {
+    let highest_address = procfs::process::Process::myself()
+        .and_then(|process| process.maps())
+        .ok()
+        .and_then(|maps| maps.iter().map(|map| map.address.1).max());
+
+    address_mask(highest_address.map_or(DEFAULT_ARM64_VIRTUAL_ADDRESS_BITS, virtual_address_bits))
+}
+
+fn virtual_address_bits(highest_address: u64) -> u32 {
+    u64::BITS - (highest_address - 1).leading_zeros()
+}
+
+fn address_mask(bits: u32) -> u64 {
+    u64::MAX >> (u64::BITS - bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arm64_address_mask() {
+        // 39, 48 and 52 bit virtual address sizes.
+        assert_eq!(virtual_address_bits(0x7f_ffff_f000), 39);
+        assert_eq!(virtual_address_bits(0xffff_ffff_f000), 48);
+        assert_eq!(virtual_address_bits(0x1_0000_0000_0000), 48);
+        assert_eq!(address_mask(39), 0x0000_007F_FFFF_FFFF);
+        assert_eq!(address_mask(48), 0x0000_FFFF_FFFF_FFFF);
+        assert_eq!(
+            arm64_address_mask().count_ones(),
+            arm64_address_mask().trailing_ones()
+        );
+    }
+}
@@ -1,7 +1,11 @@
+use std::io;
+use std::time::Duration;
 use std::{fs::File, io::Read};
 
 use anyhow::{Context, Error};
 
+use crate::process::Pid;
+
 /// Parse a set of CPU ranges. They can be either a single number of a fully qualified range
 /// which is separated to one another with a comma (`,`) and use a dash (`-`) to indicate the
 /// start and (inclusive) end of the range.
@@ -43,6 +47,17 @@ pub fn get_online_cpus() -> Result<Vec<u32>, Error> {
     _read_cpu_range(&ranges)
 }
 
+/// CPU time used by a process so far, in user and kernel space, from `/proc/<pid>/stat`.
+pub fn process_cpu_time(pid: Pid) -> io::Result<Duration> {
+    let stat = procfs::process::Process::new(pid)
+        .and_then(|process| process.stat())
+        .map_err(io::Error::other)?;
+    let ticks = stat.utime + stat.stime;
+    Ok(Duration::from_secs_f64(
+        ticks as f64 / procfs::ticks_per_second() as f64,
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,5 +1,5 @@
 use crate::process::Pid;
-use std::fs;
+use std::fs::{self, File};
 use std::io;
 use std::os::unix::fs::MetadataExt;
 use std::path::Path;
@@ -52,9 +52,62 @@ pub fn executable_path(pid: Pid, path: &Path) -> io::Result<PathBuf> {
     Ok(procfs_path)
 }
 
+/// Suffix of the paths of mappings whose file was deleted or replaced after being mapped.
+const DELETED_SUFFIX: &str = " (deleted)";
+
+/// A file mapped by a process.
+#[derive(Debug)]
+pub struct MappedFile {
+    /// Path of the file, as returned by [`executable_path`]. It won't exist if the file was
+    /// deleted or replaced.
+    pub path: PathBuf,
+    /// Path the file can be opened with.
+    pub open_path: PathBuf,
+    pub file: File,
+}
+
+impl MappedFile {
+    /// Whether the file was deleted or replaced since it was mapped, so it can't be read
+    /// from its path anymore.
+    pub fn is_deleted(&self) -> bool {
+        self.open_path != self.path
+    }
+}
+
+/// Opens a file mapped by a process at the given address range. Files that were deleted or
+/// replaced since they were mapped, for example during deploys, are opened through
+/// `/proc/<pid>/map_files`, which refers to the mapped file rather than to its path.
+pub fn open_mapped_file(
+    pid: Pid,
+    path: &Path,
+    start_address: u64,
+    end_address: u64,
+) -> io::Result<MappedFile> {
+    if path.to_string_lossy().ends_with(DELETED_SUFFIX) {
+        let open_path = PathBuf::from(format!(
+            "/proc/{pid}/map_files/{start_address:x}-{end_address:x}"
+        ));
+        return Ok(MappedFile {
+            path: path.to_path_buf(),
+            file: File::open(&open_path)?,
+            open_path,
+        });
+    }
+
+    let path = executable_path(pid, path)?;
+    Ok(MappedFile {
+        file: File::open(&path)?,
+        open_path: path.clone(),
+        path,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use memmap2::Mmap;
+    use procfs::process::{MMapPath, Process};
+    use tempfile::TempDir;
 
     #[test]
     fn test_file_id() {
@@ -63,4 +116,34 @@ mod tests {
             FileId::new(&PathBuf::from("/")).unwrap()
         );
     }
+
+    #[test]
+    fn test_open_deleted_mapped_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("deleted");
+        fs::write(&path, b"contents").unwrap();
+        let mmap = unsafe { Mmap::map(&File::open(&path).unwrap()) }.unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let maps = Process::myself().unwrap().maps().unwrap();
+        let map = maps
+            .iter()
+            .find(|map| map.address.0 == mmap.as_ptr() as u64)
+            .unwrap();
+        let MMapPath::Path(mapped_path) = &map.pathname else {
+            panic!("expected a file mapping");
+        };
+        assert!(mapped_path.to_string_lossy().ends_with(DELETED_SUFFIX));
+
+        let mapped_file = open_mapped_file(
+            std::process::id() as Pid,
+            mapped_path,
+            map.address.0,
+            map.address.1,
+        )
+        .unwrap();
+        assert_eq!(&mapped_file.path, mapped_path);
+        assert!(mapped_file.is_deleted());
+        assert_eq!(fs::read(mapped_file.open_path).unwrap(), b"contents");
+    }
 }
@@ -4,8 +4,8 @@ mod file;
 mod lpm;
 mod page;
 
-pub use arch::{architecture, Architecture};
-pub use cpu::get_online_cpus;
-pub use file::executable_path;
+pub use arch::{architecture, arm64_address_mask, Architecture};
+pub use cpu::{get_online_cpus, process_cpu_time};
+pub use file::{executable_path, open_mapped_file, MappedFile};
 pub use lpm::{summarize_address_range, AddressBlockRange};
 pub use page::{page_size, roundup_page};
@@ -7,6 +7,7 @@ use std::time::Duration;
 use crossbeam_channel::bounded;
 
 use lightswitch::collector::{AggregatorCollector, Collector};
+use lightswitch::debug_info::DebugInfoSources;
 use lightswitch::profile::symbolize_profile;
 use lightswitch::profile::AggregatedProfile;
 use lightswitch::profiler::{Profiler, ProfilerConfig};
@@ -115,7 +116,8 @@ fn test_integration() {
     p.run(collector.clone());
     let collector = collector.lock().unwrap();
     let (raw_profile, procs, objs) = collector.finish();
-    let symbolized_profile = symbolize_profile(&raw_profile, procs, objs);
+    let symbolized_profile =
+        symbolize_profile(&raw_profile, procs, objs, &DebugInfoSources::default());
 
     assert!(assert_any_stack_contains(
         &symbolized_profile,
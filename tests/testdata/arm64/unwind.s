@@ -0,0 +1,103 @@
+// Functions covering the return address rules of arm64 code, see README.md.
+
+    .text
+
+    .globl _start
+    .type _start, %function
+_start:
+    .cfi_startproc
+    // Outermost frame, there is no caller.
+    .cfi_undefined x30
+    mov x29, #0
+    mov x30, #0
+    bl main
+    mov x8, #93
+    svc #0
+    .cfi_endproc
+    .size _start, .-_start
+
+    .globl main
+    .type main, %function
+main:
+    .cfi_startproc
+    // Frame record, the frame pointer followed by the return address.
+    stp x29, x30, [sp, #-32]!
+    .cfi_def_cfa_offset 32
+    .cfi_offset x30, -24
+    .cfi_offset x29, -32
+    mov x29, sp
+    .cfi_def_cfa x29, 32
+    bl leaf
+    bl pac
+    bl lr_only
+    bl lr_in_register
+    ldp x29, x30, [sp], #32
+    .cfi_def_cfa sp, 0
+    .cfi_restore x30
+    .cfi_restore x29
+    ret
+    .cfi_endproc
+    .size main, .-main
+
+    .globl leaf
+    .type leaf, %function
+leaf:
+    .cfi_startproc
+    // The return address is only in the link register.
+    add x0, x0, #1
+    ret
+    .cfi_endproc
+    .size leaf, .-leaf
+
+    .globl pac
+    .type pac, %function
+pac:
+    .cfi_startproc
+    // Return address signed with pointer authentication.
+    paciasp
+    .cfi_negate_ra_state
+    stp x29, x30, [sp, #-16]!
+    .cfi_def_cfa_offset 16
+    .cfi_offset x30, -8
+    .cfi_offset x29, -16
+    mov x29, sp
+    bl leaf
+    ldp x29, x30, [sp], #16
+    .cfi_def_cfa_offset 0
+    .cfi_restore x30
+    .cfi_restore x29
+    autiasp
+    .cfi_negate_ra_state
+    ret
+    .cfi_endproc
+    .size pac, .-pac
+
+    .globl lr_only
+    .type lr_only, %function
+lr_only:
+    .cfi_startproc
+    // Only the link register is saved, the frame pointer is unchanged.
+    str x30, [sp, #-16]!
+    .cfi_def_cfa_offset 16
+    .cfi_offset x30, -16
+    bl leaf
+    ldr x30, [sp], #16
+    .cfi_def_cfa_offset 0
+    .cfi_restore x30
+    ret
+    .cfi_endproc
+    .size lr_only, .-lr_only
+
+    .globl lr_in_register
+    .type lr_in_register, %function
+lr_in_register:
+    .cfi_startproc
+    // The return address is moved to another register, which is not supported.
+    mov x9, x30
+    .cfi_register x30, x9
+    bl leaf
+    mov x30, x9
+    .cfi_restore x30
+    ret
+    .cfi_endproc
+    .size lr_in_register, .-lr_in_register